@@ -29,7 +29,7 @@ async function handleDelete(slug) {
     });
 
     if (!response.ok) {
-      throw new Error(await response.text());
+      throw new Error(await errorMessage(response));
     }
 
     showNotification("Image deleted successfully!", "success");
@@ -75,9 +75,101 @@ function initImagePreview() {
   }
 }
 
-// Slug generator
+// Duplicate upload check
+function initDuplicateCheck() {
+  const imageInput = document.querySelector("#image");
+  if (!imageInput) {
+    return;
+  }
+
+  const notice = document.createElement("p");
+  notice.className = "duplicate-notice";
+  notice.hidden = true;
+  imageInput.insertAdjacentElement("afterend", notice);
+
+  imageInput.addEventListener("change", async function () {
+    notice.hidden = true;
+    const file = this.files[0];
+    if (!file) {
+      return;
+    }
+
+    try {
+      const response = await fetch("/admin/duplicates", {
+        method: "POST",
+        body: file,
+      });
+      if (!response.ok) {
+        return;
+      }
+
+      const matches = await response.json();
+      if (matches.length > 0) {
+        const slugs = matches.map((image) => image.slug).join(", ");
+        notice.textContent = `This file is already uploaded as ${slugs}; saving will reuse the existing file.`;
+        notice.hidden = false;
+      }
+    } catch (error) {
+      console.error("Duplicate check error:", error);
+    }
+  });
+}
+
+// Description preview, rendered by the server exactly as the site will
+// show it; the HTML comes back sanitized
+function initMarkdownPreview() {
+  // Entry forms can have several Markdown fields, each with its own preview
+  document.querySelectorAll("textarea[data-markdown]").forEach((textarea) => {
+    const group = textarea.closest(".form-group");
+    const button = group && group.querySelector(".preview-button");
+    const preview = group && group.querySelector(".markdown-preview");
+    if (button && preview) {
+      initPreview(textarea, button, preview);
+    }
+  });
+}
+
+function initPreview(textarea, button, preview) {
+  async function update() {
+    try {
+      const response = await fetch("/admin/preview", {
+        method: "POST",
+        headers: { "Content-Type": "application/json" },
+        body: JSON.stringify({ markdown: textarea.value }),
+      });
+      if (!response.ok) {
+        throw new Error(await errorMessage(response));
+      }
+      const rendered = await response.json();
+      preview.innerHTML = rendered.html;
+    } catch (error) {
+      console.error("Preview error:", error);
+      showNotification(error.message || "Failed to preview", "error");
+    }
+  }
+
+  let timer;
+  textarea.addEventListener("input", () => {
+    if (preview.hidden) {
+      return;
+    }
+    clearTimeout(timer);
+    timer = setTimeout(update, 300);
+  });
+
+  button.addEventListener("click", () => {
+    preview.hidden = !preview.hidden;
+    button.textContent = preview.hidden ? "Preview" : "Hide preview";
+    if (!preview.hidden) {
+      update();
+    }
+  });
+}
+
+// Slug generator, from an image's alt text or a page's title
 function initSlugGenerator() {
-  const altInput = document.querySelector("#alt");
+  const altInput =
+    document.querySelector("#alt") || document.querySelector("#title");
   const slugInput = document.querySelector("#slug");
 
   if (altInput && slugInput) {
@@ -111,6 +203,12 @@ async function logout() {
   }
 }
 
+function initLogout() {
+  document.querySelectorAll(".logout-button").forEach((button) => {
+    button.addEventListener("click", logout);
+  });
+}
+
 // Handle form submissions
 async function handleFormSubmit(form, successCallback) {
   try {
@@ -129,7 +227,7 @@ async function handleFormSubmit(form, successCallback) {
     });
 
     if (!response.ok) {
-      const error = await response.text();
+      const error = await errorMessage(response);
       throw new Error(error || "Submission failed");
     }
 
@@ -151,6 +249,16 @@ async function handleFormSubmit(form, successCallback) {
   }
 }
 
+// Errors come back as {"error": "...", "status": 400}
+async function errorMessage(response) {
+  try {
+    const body = await response.json();
+    return body.error;
+  } catch {
+    return response.statusText;
+  }
+}
+
 // Notification system
 function showNotification(message, type = "success") {
   // Remove any existing notification
@@ -208,11 +316,463 @@ function initFormHandling() {
   }
 }
 
+// Extra shots: alt text and captions for new uploads, then reordering,
+// captioning and deleting the ones already saved
+function initMediaUploads() {
+  const mediaInput = document.querySelector("#media");
+  const uploads = document.querySelector(".media-uploads");
+  if (!mediaInput || !uploads) return;
+
+  // The server pairs the nth media_alt and media_caption with the nth file
+  mediaInput.addEventListener("change", function () {
+    uploads.innerHTML = "";
+    Array.from(this.files).forEach((file) => {
+      const row = document.createElement("div");
+      row.className = "media-upload";
+
+      const name = document.createElement("span");
+      name.textContent = file.name;
+
+      const alt = document.createElement("input");
+      alt.type = "text";
+      alt.name = "media_alt";
+      alt.placeholder = "Alt text";
+
+      const caption = document.createElement("input");
+      caption.type = "text";
+      caption.name = "media_caption";
+      caption.placeholder = "Caption";
+
+      row.append(name, alt, caption);
+      uploads.appendChild(row);
+    });
+  });
+}
+
+async function saveMedia(list) {
+  const media = Array.from(list.querySelectorAll(".media-item")).map(
+    (item) => ({
+      id: Number(item.getAttribute("data-id")),
+      alt: item.querySelector(".media-alt").value,
+      caption: item.querySelector(".media-caption").value,
+    }),
+  );
+
+  try {
+    const response = await fetch(`/admin/media/${list.dataset.slug}`, {
+      method: "POST",
+      headers: { "Content-Type": "application/json" },
+      body: JSON.stringify(media),
+    });
+
+    if (!response.ok) {
+      throw new Error(await errorMessage(response));
+    }
+
+    showNotification("Media saved!", "success");
+  } catch (error) {
+    console.error("Media error:", error);
+    showNotification(error.message || "Failed to save media", "error");
+  }
+}
+
+async function deleteMedia(list, item) {
+  if (!confirm("Are you sure you want to delete this shot?")) {
+    return;
+  }
+
+  try {
+    const response = await fetch(
+      `/admin/media/${list.dataset.slug}/${item.getAttribute("data-id")}`,
+      { method: "DELETE" },
+    );
+
+    if (!response.ok) {
+      throw new Error(await errorMessage(response));
+    }
+
+    showNotification("Shot deleted successfully!", "success");
+    item.remove();
+  } catch (error) {
+    console.error("Delete error:", error);
+    showNotification(error.message || "Failed to delete shot", "error");
+  }
+}
+
+// The image's preview and alt text change too, so reload to show them
+async function makeMediaPrimary(list, item) {
+  try {
+    const response = await fetch(
+      `/admin/media/${list.dataset.slug}/${item.getAttribute("data-id")}/primary`,
+      { method: "POST" },
+    );
+
+    if (!response.ok) {
+      throw new Error(await errorMessage(response));
+    }
+
+    window.location.reload();
+  } catch (error) {
+    console.error("Primary shot error:", error);
+    showNotification(error.message || "Failed to change the primary shot", "error");
+  }
+}
+
+function initMedia() {
+  initMediaUploads();
+
+  const list = document.querySelector(".media-list");
+  if (!list) return;
+
+  // Moving only changes the page; saving sends the new order
+  list.addEventListener("click", (e) => {
+    const item = e.target.closest(".media-item");
+    if (!item) return;
+
+    if (e.target.classList.contains("media-up-button")) {
+      const previous = item.previousElementSibling;
+      if (previous) list.insertBefore(item, previous);
+    } else if (e.target.classList.contains("media-down-button")) {
+      const next = item.nextElementSibling;
+      if (next) list.insertBefore(next, item);
+    } else if (e.target.classList.contains("delete-media-button")) {
+      deleteMedia(list, item);
+    } else if (e.target.classList.contains("primary-media-button")) {
+      makeMediaPrimary(list, item);
+    }
+  });
+
+  const saveButton = document.querySelector(".save-media-button");
+  if (saveButton) {
+    saveButton.addEventListener("click", () => saveMedia(list));
+  }
+}
+
+// Theme switching and settings
+async function activateTheme(name) {
+  try {
+    const response = await fetch(`/admin/themes/${name}/activate`, {
+      method: "POST",
+    });
+
+    if (!response.ok) {
+      throw new Error(await errorMessage(response));
+    }
+
+    showNotification("Theme activated!", "success");
+    setTimeout(() => window.location.reload(), 1000);
+  } catch (error) {
+    console.error("Activate error:", error);
+    showNotification(error.message || "Failed to activate theme", "error");
+  }
+}
+
+async function saveThemeSettings(form) {
+  // Sent as JSON strings; unchecked boxes aren't in FormData, so every
+  // field is read directly
+  const settings = {};
+  form.querySelectorAll("input, select").forEach((field) => {
+    settings[field.name] =
+      field.type === "checkbox" ? String(field.checked) : field.value;
+  });
+
+  const submitButton = form.querySelector('button[type="submit"]');
+  submitButton.disabled = true;
+  try {
+    const response = await fetch(form.action, {
+      method: "POST",
+      headers: { "Content-Type": "application/json" },
+      body: JSON.stringify(settings),
+    });
+
+    if (!response.ok) {
+      throw new Error(await errorMessage(response));
+    }
+
+    showNotification("Settings saved!", "success");
+  } catch (error) {
+    console.error("Settings error:", error);
+    showNotification(error.message || "Failed to save settings", "error");
+  } finally {
+    submitButton.disabled = false;
+    form.classList.remove("loading");
+  }
+}
+
+function initThemes() {
+  document.querySelectorAll(".activate-button").forEach((button) => {
+    button.addEventListener("click", () => {
+      activateTheme(button.getAttribute("data-theme"));
+    });
+  });
+
+  const settingsForm = document.querySelector("form.theme-settings");
+  if (settingsForm) {
+    settingsForm.addEventListener("submit", (e) => {
+      e.preventDefault();
+      saveThemeSettings(settingsForm);
+    });
+  }
+}
+
+// Pages, sent as JSON
+async function savePage(form) {
+  const navOrder = form.querySelector("#nav_order").value.trim();
+  const page = {
+    title: form.querySelector("#title").value,
+    slug: form.querySelector("#slug").value,
+    body: form.querySelector("#body").value,
+    template: form.querySelector("#template").value,
+    nav_order: navOrder === "" ? null : Number(navOrder),
+    published: form.querySelector("#published").checked,
+  };
+
+  const submitButton = form.querySelector('button[type="submit"]');
+  submitButton.disabled = true;
+  try {
+    const response = await fetch(form.action, {
+      method: "POST",
+      headers: { "Content-Type": "application/json" },
+      body: JSON.stringify(page),
+    });
+
+    if (!response.ok) {
+      throw new Error(await errorMessage(response));
+    }
+
+    showNotification("Page saved!", "success");
+    setTimeout(() => {
+      window.location.href = "/admin/pages";
+    }, 1000);
+  } catch (error) {
+    console.error("Page error:", error);
+    showNotification(error.message || "Failed to save page", "error");
+  } finally {
+    submitButton.disabled = false;
+    form.classList.remove("loading");
+  }
+}
+
+async function deletePage(slug) {
+  if (!confirm("Are you sure you want to delete this page?")) {
+    return;
+  }
+
+  try {
+    const response = await fetch(`/admin/pages/delete/${slug}`, {
+      method: "DELETE",
+    });
+
+    if (!response.ok) {
+      throw new Error(await errorMessage(response));
+    }
+
+    showNotification("Page deleted successfully!", "success");
+    const row = document.querySelector(`tr[data-slug="${slug}"]`);
+    if (row) {
+      row.remove();
+    }
+  } catch (error) {
+    console.error("Delete error:", error);
+    showNotification(error.message || "Failed to delete page", "error");
+  }
+}
+
+function initPages() {
+  const pageForm = document.querySelector("form.page-form");
+  if (pageForm) {
+    pageForm.addEventListener("submit", (e) => {
+      e.preventDefault();
+      savePage(pageForm);
+    });
+  }
+
+  document.querySelectorAll(".delete-page-button").forEach((button) => {
+    button.addEventListener("click", () => {
+      deletePage(button.getAttribute("data-slug"));
+    });
+  });
+}
+
+// Entries of custom content types
+async function saveEntry(form) {
+  // Values go as the form has them; the server turns numbers, booleans and
+  // lists into their stored form
+  const fields = {};
+  form.querySelectorAll("[data-field]").forEach((input) => {
+    fields[input.getAttribute("data-field")] =
+      input.type === "checkbox" ? input.checked : input.value;
+  });
+  const entry = {
+    title: form.querySelector("#title").value,
+    slug: form.querySelector("#slug").value,
+    fields,
+    published: form.querySelector("#published").checked,
+  };
+
+  const submitButton = form.querySelector('button[type="submit"]');
+  submitButton.disabled = true;
+  try {
+    const response = await fetch(form.action, {
+      method: "POST",
+      headers: { "Content-Type": "application/json" },
+      body: JSON.stringify(entry),
+    });
+
+    if (!response.ok) {
+      throw new Error(await errorMessage(response));
+    }
+
+    showNotification("Entry saved!", "success");
+    setTimeout(() => {
+      window.location.href = `/admin/content/${form.getAttribute("data-type")}`;
+    }, 1000);
+  } catch (error) {
+    console.error("Entry error:", error);
+    showNotification(error.message || "Failed to save entry", "error");
+  } finally {
+    submitButton.disabled = false;
+    form.classList.remove("loading");
+  }
+}
+
+async function deleteEntry(type, slug) {
+  if (!confirm("Are you sure you want to delete this entry?")) {
+    return;
+  }
+
+  try {
+    const response = await fetch(`/admin/content/${type}/delete/${slug}`, {
+      method: "DELETE",
+    });
+
+    if (!response.ok) {
+      throw new Error(await errorMessage(response));
+    }
+
+    showNotification("Entry deleted successfully!", "success");
+    const row = document.querySelector(`tr[data-slug="${slug}"]`);
+    if (row) {
+      row.remove();
+    }
+  } catch (error) {
+    console.error("Delete error:", error);
+    showNotification(error.message || "Failed to delete entry", "error");
+  }
+}
+
+async function saveContentType(form) {
+  const contentType = {
+    name: form.querySelector("#type_name").value,
+    definition: form.querySelector("#definition").value,
+  };
+
+  const submitButton = form.querySelector('button[type="submit"]');
+  submitButton.disabled = true;
+  try {
+    const response = await fetch(form.action, {
+      method: "POST",
+      headers: { "Content-Type": "application/json" },
+      body: JSON.stringify(contentType),
+    });
+
+    if (!response.ok) {
+      throw new Error(await errorMessage(response));
+    }
+
+    showNotification("Content type saved!", "success");
+    setTimeout(() => window.location.reload(), 1000);
+  } catch (error) {
+    console.error("Content type error:", error);
+    showNotification(error.message || "Failed to save content type", "error");
+  } finally {
+    submitButton.disabled = false;
+    form.classList.remove("loading");
+  }
+}
+
+async function deleteContentType(name) {
+  if (!confirm("Are you sure you want to delete this content type?")) {
+    return;
+  }
+
+  try {
+    const response = await fetch(`/admin/types/delete/${name}`, {
+      method: "DELETE",
+    });
+
+    if (!response.ok) {
+      throw new Error(await errorMessage(response));
+    }
+
+    showNotification("Content type deleted successfully!", "success");
+    const row = document.querySelector(`tr[data-name="${name}"]`);
+    if (row) {
+      row.remove();
+    }
+  } catch (error) {
+    console.error("Delete error:", error);
+    showNotification(error.message || "Failed to delete content type", "error");
+  }
+}
+
+function initContentTypes() {
+  const entryForm = document.querySelector("form.entry-form");
+  if (entryForm) {
+    entryForm.addEventListener("submit", (e) => {
+      e.preventDefault();
+      saveEntry(entryForm);
+    });
+  }
+
+  const entryTable = document.querySelector("table[data-type]");
+  document.querySelectorAll(".delete-entry-button").forEach((button) => {
+    button.addEventListener("click", () => {
+      deleteEntry(
+        entryTable.getAttribute("data-type"),
+        button.getAttribute("data-slug"),
+      );
+    });
+  });
+
+  const typeForm = document.querySelector("form.content-type-form");
+  if (typeForm) {
+    typeForm.addEventListener("submit", (e) => {
+      e.preventDefault();
+      saveContentType(typeForm);
+    });
+  }
+
+  // Loads a type written in the admin into the form below the table
+  document.querySelectorAll(".edit-type-button").forEach((button) => {
+    button.addEventListener("click", () => {
+      typeForm.querySelector("#type_name").value =
+        button.getAttribute("data-name");
+      typeForm.querySelector("#definition").value =
+        button.getAttribute("data-definition");
+      typeForm.scrollIntoView({ behavior: "smooth" });
+    });
+  });
+
+  document.querySelectorAll(".delete-type-button").forEach((button) => {
+    button.addEventListener("click", () => {
+      deleteContentType(button.getAttribute("data-name"));
+    });
+  });
+}
+
 // Initialize all admin functionality
 document.addEventListener("DOMContentLoaded", function () {
   initFormLoadingStates();
   initDeleteHandlers();
   initImagePreview();
+  initDuplicateCheck();
+  initMarkdownPreview();
   initSlugGenerator();
   initFormHandling();
+  initMedia();
+  initThemes();
+  initPages();
+  initContentTypes();
+  initLogout();
 });
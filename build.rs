@@ -0,0 +1,28 @@
+use std::process::Command;
+
+/// Embeds the commit being built so `/version` can report it. Builds
+/// without git (or outside a checkout) can pass `CRAFTCMS_GIT_COMMIT`.
+fn main() {
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs/heads");
+    println!("cargo:rerun-if-env-changed=CRAFTCMS_GIT_COMMIT");
+
+    let commit = std::env::var("CRAFTCMS_GIT_COMMIT").ok().or_else(|| {
+        Command::new("git")
+            .args(["rev-parse", "--short=12", "HEAD"])
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .and_then(|output| String::from_utf8(output.stdout).ok())
+            .map(|commit| commit.trim().to_string())
+    });
+    println!(
+        "cargo:rustc-env=CRAFTCMS_GIT_COMMIT={}",
+        commit.unwrap_or_else(|| "unknown".to_string())
+    );
+
+    let target = std::env::var("TARGET").unwrap_or_default();
+    let profile = std::env::var("PROFILE").unwrap_or_default();
+    println!("cargo:rustc-env=CRAFTCMS_BUILD_TARGET={}", target);
+    println!("cargo:rustc-env=CRAFTCMS_BUILD_PROFILE={}", profile);
+}
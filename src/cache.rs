@@ -0,0 +1,106 @@
+use chrono::{DateTime, NaiveDateTime, Utc};
+use warp::http::response::Builder;
+use warp::http::{header, HeaderMap, Response, StatusCode};
+
+/// Validators for a response, compared against the request's conditional
+/// headers.
+#[derive(Debug, Default, Clone)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<DateTime<Utc>>,
+}
+
+impl Validators {
+    /// True when the client's cached copy is still current. `If-None-Match`
+    /// takes precedence over `If-Modified-Since`, as RFC 9110 requires.
+    pub fn is_not_modified(&self, headers: &HeaderMap) -> bool {
+        if let Some(if_none_match) = headers.get(header::IF_NONE_MATCH) {
+            let Some(etag) = &self.etag else {
+                return false;
+            };
+            let Ok(if_none_match) = if_none_match.to_str() else {
+                return false;
+            };
+            return if_none_match
+                .split(',')
+                .map(|tag| tag.trim())
+                .any(|tag| tag == "*" || opaque_tag(tag) == opaque_tag(etag));
+        }
+
+        match (
+            &self.last_modified,
+            headers
+                .get(header::IF_MODIFIED_SINCE)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| DateTime::parse_from_rfc2822(value).ok()),
+        ) {
+            (Some(last_modified), Some(since)) => last_modified.timestamp() <= since.timestamp(),
+            _ => false,
+        }
+    }
+
+    pub fn apply(&self, mut builder: Builder) -> Builder {
+        if let Some(etag) = &self.etag {
+            builder = builder.header(header::ETAG, etag);
+        }
+        if let Some(last_modified) = &self.last_modified {
+            builder = builder.header(header::LAST_MODIFIED, http_date(last_modified));
+        }
+        builder
+    }
+}
+
+/// `If-None-Match` uses weak comparison, so `W/"x"` matches `"x"`.
+fn opaque_tag(tag: &str) -> &str {
+    tag.trim_start_matches("W/")
+}
+
+pub fn strong_etag(hash: &str) -> String {
+    format!("\"{}\"", hash)
+}
+
+pub fn cache_control(max_age: u64, immutable: bool) -> String {
+    if immutable {
+        format!("public, max-age={}, immutable", max_age)
+    } else {
+        format!("public, max-age={}", max_age)
+    }
+}
+
+/// The content hash embedded in a content-addressed storage key such as
+/// `blobs/ab/<hash>.png`, or `None` for any other key.
+pub fn fingerprint(key: &str) -> Option<&str> {
+    let name = key.strip_prefix("blobs/")?.split('/').nth(1)?;
+    let hash = name.split('.').next()?;
+    (hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit())).then_some(hash)
+}
+
+/// The `?v=` that fingerprints a static asset's URL: a short hash of its
+/// content, so the URL changes whenever the file does.
+pub fn asset_version(data: &[u8]) -> String {
+    crate::files::content_hash(data)[..12].to_string()
+}
+
+/// Parses a timestamp written by SQLite's `CURRENT_TIMESTAMP`, which is UTC.
+pub fn parse_timestamp(value: &str) -> Option<DateTime<Utc>> {
+    NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S")
+        .ok()
+        .map(|naive| naive.and_utc())
+}
+
+pub fn http_date(time: &DateTime<Utc>) -> String {
+    time.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// An empty 304 carrying the same validators and `Cache-Control` the full
+/// response would have had.
+pub fn not_modified<T: Default>(
+    validators: &Validators,
+    cache_control: Option<&str>,
+) -> Response<T> {
+    let mut builder = validators.apply(Response::builder().status(StatusCode::NOT_MODIFIED));
+    if let Some(cache_control) = cache_control {
+        builder = builder.header(header::CACHE_CONTROL, cache_control);
+    }
+    builder.body(T::default()).unwrap()
+}
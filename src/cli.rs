@@ -1,70 +1,294 @@
 use crate::files::ImageFileManager;
-use crate::models::{Image, ImageInput};
-use rusqlite::Connection;
+use crate::import::{self, DuplicatePolicy, ImportEntry, ImportOutcome, ManifestFormat};
+use crate::models::{Image, ImageFilter, ImageInput, ImagePatch};
+use crate::repository::Repository;
+use crate::{templates, themes};
+use indicatif::{ProgressBar, ProgressStyle};
+use serde::Serialize;
 use serde_json;
-use std::io::{self, Write};
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
 
-pub fn create_user_command(conn: &Connection) -> Result<(), Box<dyn std::error::Error>> {
-    print!("Enter email: ");
-    io::stdout().flush()?;
-    let mut email = String::new();
-    io::stdin().read_line(&mut email)?;
-    let email = email.trim();
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ArgEnum)]
+pub enum OutputFormat {
+    Table,
+    Json,
+    Csv,
+}
 
-    print!("Enter password: ");
-    io::stdout().flush()?;
-    let password = rpassword::read_password()?;
+/// Where a non-interactive command should read a password from. With
+/// neither set, the password is prompted for twice on the terminal.
+#[derive(Debug, Default)]
+pub struct PasswordSource {
+    pub stdin: bool,
+    pub file: Option<String>,
+}
 
-    print!("Confirm password: ");
-    io::stdout().flush()?;
-    let confirm_password = rpassword::read_password()?;
+fn read_password(source: &PasswordSource) -> Result<String, Box<dyn std::error::Error>> {
+    let password = if source.stdin {
+        let mut password = String::new();
+        io::stdin().read_line(&mut password)?;
+        password.trim_end_matches(['\r', '\n']).to_string()
+    } else if let Some(path) = &source.file {
+        std::fs::read_to_string(path)?
+            .trim_end_matches(['\r', '\n'])
+            .to_string()
+    } else {
+        print!("Enter password: ");
+        io::stdout().flush()?;
+        let password = rpassword::read_password()?;
 
-    if password != confirm_password {
-        println!("Passwords do not match!");
-        return Ok(());
+        print!("Confirm password: ");
+        io::stdout().flush()?;
+        let confirm_password = rpassword::read_password()?;
+
+        if password != confirm_password {
+            return Err("Passwords do not match".into());
+        }
+        password
+    };
+
+    if password.is_empty() {
+        return Err("Password cannot be empty".into());
     }
 
-    match crate::database::create_user(conn, email, &password) {
-        Ok(_) => println!("User created successfully!"),
-        Err(e) => println!("Error creating user: {}", e),
+    Ok(password)
+}
+
+pub async fn create_user_command(
+    repo: &dyn Repository,
+    email: Option<&str>,
+    password_source: &PasswordSource,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let email = match email {
+        Some(email) => email.trim().to_string(),
+        None => {
+            print!("Enter email: ");
+            io::stdout().flush()?;
+            let mut email = String::new();
+            io::stdin().read_line(&mut email)?;
+            email.trim().to_string()
+        }
+    };
+
+    if email.is_empty() {
+        return Err("Email cannot be empty".into());
+    }
+
+    let password = read_password(password_source)?;
+    repo.create_user(&email, &password).await?;
+    println!("User created successfully!");
+
+    Ok(())
+}
+
+pub async fn list_users_command(
+    repo: &dyn Repository,
+    format: OutputFormat,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let users = repo.list_users().await?;
+
+    match format {
+        OutputFormat::Table => {
+            println!("\nRegistered Users:");
+            println!("----------------");
+            for user in users {
+                println!(
+                    "Email: {}{}  Created: {}  Last login: {}",
+                    user.email,
+                    if user.disabled { " (disabled)" } else { "" },
+                    user.created_at.as_deref().unwrap_or("-"),
+                    user.last_login_at.as_deref().unwrap_or("never"),
+                );
+            }
+        }
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&users)?),
+        OutputFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(io::stdout());
+            writer.write_record(["email", "disabled", "created_at", "last_login_at"])?;
+            for user in &users {
+                writer.write_record([
+                    user.email.as_str(),
+                    if user.disabled { "true" } else { "false" },
+                    user.created_at.as_deref().unwrap_or(""),
+                    user.last_login_at.as_deref().unwrap_or(""),
+                ])?;
+            }
+            writer.flush()?;
+        }
     }
 
     Ok(())
 }
 
-pub fn list_users_command(conn: &Connection) -> Result<(), Box<dyn std::error::Error>> {
-    let users = crate::database::list_users(conn)?;
-    println!("\nRegistered Users:");
-    println!("----------------");
-    for user in users {
-        println!("Email: {}", user.email);
+pub async fn delete_user_command(
+    repo: &dyn Repository,
+    email: &str,
+    yes: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if !yes {
+        print!("Are you sure you want to delete user {}? (y/N): ", email);
+        io::stdout().flush()?;
+
+        let mut confirm = String::new();
+        io::stdin().read_line(&mut confirm)?;
+
+        if confirm.trim().to_lowercase() != "y" {
+            println!("Operation cancelled.");
+            return Ok(());
+        }
+    }
+
+    if repo.delete_user(email).await? == 0 {
+        return Err(format!("User '{}' not found", email).into());
     }
+    println!("User deleted successfully!");
+
     Ok(())
 }
 
-pub fn delete_user_command(
-    conn: &Connection,
+pub async fn passwd_user_command(
+    repo: &dyn Repository,
     email: &str,
+    password_source: &PasswordSource,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    print!("Are you sure you want to delete user {}? (y/N): ", email);
-    io::stdout().flush()?;
+    let password = read_password(password_source)?;
 
-    let mut confirm = String::new();
-    io::stdin().read_line(&mut confirm)?;
+    if repo.set_user_password(email, &password).await? == 0 {
+        return Err(format!("User '{}' not found", email).into());
+    }
+    println!("Password updated successfully!");
+
+    Ok(())
+}
+
+pub async fn set_user_disabled_command(
+    repo: &dyn Repository,
+    email: &str,
+    disabled: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if repo.set_user_disabled(email, disabled).await? == 0 {
+        return Err(format!("User '{}' not found", email).into());
+    }
 
-    if confirm.trim().to_lowercase() == "y" {
-        crate::database::delete_user(conn, email)?;
-        println!("User deleted successfully!");
+    if disabled {
+        println!("User disabled successfully!");
     } else {
-        println!("Operation cancelled.");
+        println!("User enabled successfully!");
     }
 
     Ok(())
 }
 
-pub fn handle_insert_image(
-    conn: &Connection,
-    file_manager: &ImageFileManager,
+pub async fn list_themes_command(
+    repo: &dyn Repository,
+    themes_dir: &Path,
+    format: OutputFormat,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let active = repo
+        .setting(themes::ACTIVE_THEME_KEY)
+        .await?
+        .unwrap_or_else(|| themes::DEFAULT_THEME.to_string());
+    let installed = themes::discover(themes_dir);
+
+    match format {
+        OutputFormat::Table => {
+            println!("\nThemes:");
+            println!("-------");
+            for theme in &installed {
+                let manifest = &theme.manifest;
+                println!(
+                    "{}{}  {} {}{}",
+                    manifest.name,
+                    if manifest.name == active {
+                        " (active)"
+                    } else {
+                        ""
+                    },
+                    manifest.title,
+                    manifest.version,
+                    if theme.is_bundled() { "  built in" } else { "" },
+                );
+            }
+        }
+        OutputFormat::Json => {
+            let manifests: Vec<_> = installed.iter().map(|theme| &theme.manifest).collect();
+            println!("{}", serde_json::to_string_pretty(&manifests)?);
+        }
+        OutputFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(io::stdout());
+            writer.write_record(["name", "title", "version", "active", "bundled"])?;
+            for theme in &installed {
+                let manifest = &theme.manifest;
+                writer.write_record([
+                    manifest.name.as_str(),
+                    manifest.title.as_str(),
+                    manifest.version.as_str(),
+                    if manifest.name == active {
+                        "true"
+                    } else {
+                        "false"
+                    },
+                    if theme.is_bundled() { "true" } else { "false" },
+                ])?;
+            }
+            writer.flush()?;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn install_theme_command(
+    source: &Path,
+    themes_dir: &Path,
+    force: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let manifest = themes::install(source, themes_dir, force)?;
+    println!(
+        "Theme '{}' installed into {}",
+        manifest.name,
+        themes_dir.join(&manifest.name).display()
+    );
+
+    Ok(())
+}
+
+/// Records the active theme. A running server picks it up on restart, or
+/// straight away when switched from the admin instead.
+pub async fn activate_theme_command(
+    repo: &dyn Repository,
+    themes_dir: &Path,
+    name: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let theme = themes::find(themes_dir, name)?;
+    templates::check(&theme)?;
+    repo.set_setting(themes::ACTIVE_THEME_KEY, name).await?;
+    println!("Theme '{}' activated; restart the server to use it", name);
+
+    Ok(())
+}
+
+/// Reads an image from disk, guessing its type from the extension.
+fn read_image_file(path: &str) -> Result<(Vec<u8>, mime::Mime), Box<dyn std::error::Error>> {
+    Ok((
+        fs::read(path)?,
+        mime_guess::from_path(path).first_or_octet_stream(),
+    ))
+}
+
+async fn get_image(repo: &dyn Repository, slug: &str) -> Result<Image, Box<dyn std::error::Error>> {
+    repo.image(slug)
+        .await?
+        .ok_or_else(|| format!("Image '{}' not found", slug).into())
+}
+
+pub async fn handle_insert_image(
+    repo: &dyn Repository,
+    file_manager: &Arc<ImageFileManager>,
     json_str: &str,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let input: ImageInput = serde_json::from_str(json_str)?;
@@ -76,6 +300,369 @@ pub fn handle_insert_image(
         filename: String::new(), // Will be set during save
     };
 
-    // Use insert_image_from_path since we have a file path in input.url
-    crate::commands::insert_image_from_path(conn, file_manager, &input.url, image)
+    let (data, mime_type) = read_image_file(&input.url)?;
+    repo.insert_image(file_manager.clone(), data, mime_type, image)
+        .await?;
+    Ok(())
+}
+
+pub async fn list_images_command(
+    repo: &dyn Repository,
+    filter: &ImageFilter,
+    format: OutputFormat,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let images = filter.apply(repo.images().await?);
+    print_images(&images, format)
+}
+
+pub async fn show_image_command(
+    repo: &dyn Repository,
+    slug: &str,
+    format: OutputFormat,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let image = get_image(repo, slug).await?;
+
+    match format {
+        OutputFormat::Table => {
+            println!("Slug:        {}", image.slug);
+            println!("Alt:         {}", image.alt);
+            println!("Description: {}", image.description);
+            println!("Keywords:    {}", image.keywords.join(", "));
+            println!("Filename:    {}", image.filename);
+            Ok(())
+        }
+        OutputFormat::Json => {
+            println!("{}", serde_json::to_string_pretty(&image)?);
+            Ok(())
+        }
+        OutputFormat::Csv => print_images(&[image], format),
+    }
+}
+
+fn print_images(images: &[Image], format: OutputFormat) -> Result<(), Box<dyn std::error::Error>> {
+    match format {
+        OutputFormat::Table => {
+            let slug_width = images
+                .iter()
+                .map(|image| image.slug.len())
+                .chain(std::iter::once(4))
+                .max()
+                .unwrap_or(4);
+            let alt_width = images
+                .iter()
+                .map(|image| image.alt.chars().count())
+                .chain(std::iter::once(3))
+                .max()
+                .unwrap_or(3);
+
+            println!(
+                "{:<slug_width$}  {:<alt_width$}  KEYWORDS",
+                "SLUG",
+                "ALT",
+                slug_width = slug_width,
+                alt_width = alt_width
+            );
+            for image in images {
+                println!(
+                    "{:<slug_width$}  {:<alt_width$}  {}",
+                    image.slug,
+                    image.alt,
+                    image.keywords.join(", "),
+                    slug_width = slug_width,
+                    alt_width = alt_width
+                );
+            }
+        }
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(images)?),
+        OutputFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(io::stdout());
+            writer.write_record(["slug", "alt", "description", "keywords", "filename"])?;
+            for image in images {
+                writer.write_record([
+                    image.slug.as_str(),
+                    image.alt.as_str(),
+                    image.description.as_str(),
+                    image.keywords.join(",").as_str(),
+                    image.filename.as_str(),
+                ])?;
+            }
+            writer.flush()?;
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn update_image_command(
+    repo: &dyn Repository,
+    file_manager: &Arc<ImageFileManager>,
+    slug: &str,
+    patch: ImagePatch,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let existing = get_image(repo, slug).await?;
+
+    let image = Image {
+        alt: patch.alt.unwrap_or(existing.alt),
+        description: patch.description.unwrap_or(existing.description),
+        slug: patch.slug.unwrap_or(existing.slug),
+        keywords: patch.keywords.unwrap_or(existing.keywords),
+        filename: String::new(), // Will be set during save
+    };
+
+    if image.slug.is_empty() || image.alt.is_empty() {
+        return Err("Slug and alt text cannot be empty".into());
+    }
+
+    let image_data = match patch.path.as_deref() {
+        Some(path) => Some(read_image_file(path)?),
+        None => None,
+    };
+    repo.update_image(file_manager.clone(), slug, image_data, image)
+        .await?;
+    println!("Image updated successfully!");
+    Ok(())
+}
+
+pub async fn delete_image_command(
+    repo: &dyn Repository,
+    file_manager: &Arc<ImageFileManager>,
+    slug: &str,
+    yes: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Fail before prompting if the image doesn't exist
+    get_image(repo, slug).await?;
+
+    if !yes {
+        print!("Are you sure you want to delete image {}? (y/N): ", slug);
+        io::stdout().flush()?;
+
+        let mut confirm = String::new();
+        io::stdin().read_line(&mut confirm)?;
+
+        if confirm.trim().to_lowercase() != "y" {
+            println!("Operation cancelled.");
+            return Ok(());
+        }
+    }
+
+    repo.delete_image(file_manager.clone(), slug).await?;
+    println!("Image deleted successfully!");
+    Ok(())
+}
+
+/// Manifest row written by `export_images_command`, readable by
+/// `images import`.
+#[derive(Serialize)]
+struct ExportEntry<'a> {
+    path: &'a str,
+    alt: &'a str,
+    description: &'a str,
+    slug: &'a str,
+    keywords: &'a [String],
+    media: Vec<ExportMedia>,
+}
+
+#[derive(Serialize)]
+struct ExportMedia {
+    path: String,
+    alt: String,
+    caption: String,
+}
+
+/// Copies every matching image and its extra shots into `dest` alongside a
+/// `manifest.json` that can be fed back into `images import`.
+pub async fn export_images_command(
+    repo: &dyn Repository,
+    file_manager: &Arc<ImageFileManager>,
+    filter: &ImageFilter,
+    dest: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let images = filter.apply(repo.images().await?);
+    let dest = Path::new(dest);
+    fs::create_dir_all(dest)?;
+
+    // Stored files are named by hash, so exports use the slug instead
+    let export_names: Vec<String> = images
+        .iter()
+        .map(|image| format!("{}.{}", image.slug, file_extension(&image.filename)))
+        .collect();
+
+    let mut manifest = Vec::with_capacity(images.len());
+    for (image, name) in images.iter().zip(&export_names) {
+        let data = file_manager.read_file(&image.filename)?;
+        fs::write(dest.join(name), data)?;
+
+        // Extra shots go under media/<slug>/ in gallery order
+        let mut media = Vec::new();
+        for (position, item) in repo.media(&image.slug).await?.into_iter().enumerate() {
+            let path = format!(
+                "media/{}/{}.{}",
+                image.slug,
+                position + 1,
+                file_extension(&item.filename)
+            );
+            let target = dest.join(&path);
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(target, file_manager.read_file(&item.filename)?)?;
+            media.push(ExportMedia {
+                path,
+                alt: item.alt,
+                caption: item.caption,
+            });
+        }
+
+        manifest.push(ExportEntry {
+            path: name,
+            alt: &image.alt,
+            description: &image.description,
+            slug: &image.slug,
+            keywords: &image.keywords,
+            media,
+        });
+    }
+    fs::write(
+        dest.join("manifest.json"),
+        serde_json::to_string_pretty(&manifest)?,
+    )?;
+
+    println!("Exported {} images to {}", images.len(), dest.display());
+    Ok(())
+}
+
+fn file_extension(filename: &str) -> &str {
+    Path::new(filename)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or("jpg")
+}
+
+pub async fn dedupe_images_command(
+    repo: &dyn Repository,
+    file_manager: &Arc<ImageFileManager>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let count = repo.migrate_to_blobs(file_manager.clone()).await?;
+    println!("Moved {} images into content-addressed storage", count);
+    Ok(())
+}
+
+/// Reads import entries from a manifest file, a directory of images, or
+/// stdin when no source is given.
+pub fn load_import_entries(
+    source: Option<&str>,
+    format: Option<ManifestFormat>,
+) -> Result<Vec<ImportEntry>, Box<dyn std::error::Error>> {
+    match source {
+        Some(source) => {
+            let path = Path::new(source);
+            let format = match format {
+                Some(format) => format,
+                None if path.is_dir() => ManifestFormat::Dir,
+                None => {
+                    let content = std::fs::read_to_string(path)?;
+                    let format = import::detect_format(Some(path), Some(&content))
+                        .ok_or("Could not detect manifest format, use --format")?;
+                    let base_dir = path.parent().unwrap_or_else(|| Path::new("."));
+                    return import::parse_manifest(&content, format, base_dir);
+                }
+            };
+
+            if format == ManifestFormat::Dir {
+                return import::scan_directory(path);
+            }
+
+            let content = std::fs::read_to_string(path)?;
+            let base_dir = path.parent().unwrap_or_else(|| Path::new("."));
+            import::parse_manifest(&content, format, base_dir)
+        }
+        None => {
+            if atty::is(atty::Stream::Stdin) {
+                return Err(
+                    "No input detected. Usage: craftcms images import <PATH> or cat manifest.json | craftcms images import"
+                        .into(),
+                );
+            }
+
+            let mut content = String::new();
+            io::stdin().read_to_string(&mut content)?;
+            let format = format
+                .or_else(|| import::detect_format(None, Some(&content)))
+                .ok_or("Could not detect manifest format, use --format")?;
+            if format == ManifestFormat::Dir {
+                return Err("A directory import needs a path".into());
+            }
+            import::parse_manifest(&content, format, Path::new("."))
+        }
+    }
+}
+
+pub async fn handle_import_images(
+    repo: &dyn Repository,
+    file_manager: &Arc<ImageFileManager>,
+    entries: &[ImportEntry],
+    policy: DuplicatePolicy,
+    dry_run: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Slugs are tracked here rather than looked up, so a dry run still sees
+    // conflicts within the batch
+    let mut taken: HashSet<String> = repo
+        .images()
+        .await?
+        .into_iter()
+        .map(|image| image.slug)
+        .collect();
+
+    let progress = ProgressBar::new(entries.len() as u64);
+    progress.set_style(
+        ProgressStyle::with_template("{bar:40} {pos}/{len} {wide_msg}")?.progress_chars("=> "),
+    );
+
+    let mut inserted = 0;
+    let mut overwritten = 0;
+    let mut renamed = Vec::new();
+    let mut skipped = Vec::new();
+    let mut failures = Vec::new();
+
+    for entry in entries {
+        progress.set_message(entry.path.clone());
+
+        match import::import_entry(repo, file_manager, entry, policy, dry_run, &mut taken).await {
+            Ok(ImportOutcome::Inserted(_)) => inserted += 1,
+            Ok(ImportOutcome::Overwritten(_)) => overwritten += 1,
+            Ok(ImportOutcome::Renamed { from, to }) => renamed.push((from, to)),
+            Ok(ImportOutcome::Skipped(slug)) => skipped.push(slug),
+            Err(e) => failures.push((entry.path.clone(), e.to_string())),
+        }
+
+        progress.inc(1);
+    }
+
+    progress.finish_and_clear();
+
+    if dry_run {
+        println!("\nDry run - no changes were saved.");
+    }
+    println!("\nImport Summary:");
+    println!("---------------");
+    println!("Inserted:    {}", inserted);
+    println!("Overwritten: {}", overwritten);
+    println!("Renamed:     {}", renamed.len());
+    for (from, to) in &renamed {
+        println!("  {} -> {}", from, to);
+    }
+    println!("Skipped:     {}", skipped.len());
+    for slug in &skipped {
+        println!("  {}", slug);
+    }
+    println!("Failed:      {}", failures.len());
+    for (path, error) in &failures {
+        println!("  {}: {}", path, error);
+    }
+
+    if !failures.is_empty() {
+        return Err(format!("{} of {} entries failed", failures.len(), entries.len()).into());
+    }
+
+    Ok(())
 }
@@ -1,8 +1,9 @@
 use crate::database;
 use crate::files::ImageFileManager;
-use crate::models::Image;
+use crate::models::{Image, Media, MediaUpload};
+use crate::render_cache;
+use crate::repository;
 use rusqlite::Connection;
-use std::fs;
 
 pub fn insert_image(
     conn: &Connection,
@@ -11,14 +12,31 @@ pub fn insert_image(
     mime_type: &mime::Mime, // Take mime type as parameter
     image: Image,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let filename = file_manager.save_file(image_data, &image.slug, mime_type)?;
+    let blob = file_manager.store_blob(image_data, mime_type)?;
 
-    let image_to_save = Image { filename, ..image };
+    let image_to_save = Image {
+        filename: blob.key.clone(),
+        ..image
+    };
+
+    let result = (|| {
+        let tx = conn.unchecked_transaction()?;
+        database::insert_image(&tx, &image_to_save)?;
+        database::acquire_blob(&tx, &blob)?;
+        database::link_image_blob(&tx, &image_to_save.slug, &blob.hash)?;
+        tx.commit()
+    })();
+
+    if let Err(e) = result {
+        // Only remove the file if this upload created it
+        if blob.created {
+            let _ = file_manager.delete_file(&blob.key);
+        }
+        return Err(e.into());
+    }
 
-    database::insert_image(conn, &image_to_save).map_err(|e| {
-        let _ = file_manager.delete_file(&image_to_save.filename);
-        e.into()
-    })
+    render_cache::invalidate_image(&image_to_save.slug);
+    Ok(())
 }
 
 pub fn update_image(
@@ -30,24 +48,61 @@ pub fn update_image(
 ) -> Result<(), Box<dyn std::error::Error>> {
     let existing = database::get_image_by_slug(conn, old_slug)?;
 
-    let filename = if let Some((data, mime_type)) = image_data {
-        // Delete old file
-        file_manager.delete_file(&existing.filename)?;
-
-        // Save new file
-        file_manager.save_file(&data, &image.slug, &mime_type)?
-    } else {
-        // Keep existing file but might need to rename if slug changed
-        if old_slug != image.slug {
-            file_manager.rename_file(&existing.filename, &image.slug)?
-        } else {
-            existing.filename
+    let (data, mime_type) = match image_data {
+        Some(image_data) => image_data,
+        None => {
+            // Files are addressed by content, so a slug change leaves them alone
+            let image_to_save = Image {
+                filename: existing.filename,
+                ..image
+            };
+            database::update_image(conn, old_slug, &image_to_save)?;
+            render_cache::invalidate_image(old_slug);
+            render_cache::invalidate_image(&image_to_save.slug);
+            return Ok(());
+        }
+    };
+
+    let old_hash = database::get_image_blob_hash(conn, old_slug)?;
+    let blob = file_manager.store_blob(&data, &mime_type)?;
+
+    let image_to_save = Image {
+        filename: blob.key.clone(),
+        ..image
+    };
+
+    let result = (|| {
+        let tx = conn.unchecked_transaction()?;
+        database::update_image(&tx, old_slug, &image_to_save)?;
+        database::acquire_blob(&tx, &blob)?;
+        database::link_image_blob(&tx, &image_to_save.slug, &blob.hash)?;
+        let orphan = match &old_hash {
+            Some(hash) => database::release_blob(&tx, hash)?,
+            // Images from before content-addressing own their file outright
+            None => Some(existing.filename.clone()),
+        };
+        tx.commit()?;
+        Ok::<_, rusqlite::Error>(orphan)
+    })();
+
+    let orphan = match result {
+        Ok(orphan) => orphan,
+        Err(e) => {
+            if blob.created {
+                let _ = file_manager.delete_file(&blob.key);
+            }
+            return Err(e.into());
         }
     };
 
-    let image_to_save = Image { filename, ..image };
+    render_cache::invalidate_image(old_slug);
+    render_cache::invalidate_image(&image_to_save.slug);
 
-    database::update_image(conn, old_slug, &image_to_save).map_err(|e| e.into())
+    match orphan {
+        Some(orphan) if orphan != blob.key => file_manager.delete_file(&orphan)?,
+        _ => {}
+    }
+    Ok(())
 }
 
 pub fn delete_image(
@@ -56,20 +111,193 @@ pub fn delete_image(
     slug: &str,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let image = database::get_image_by_slug(conn, slug)?;
-    file_manager.delete_file(&image.filename)?;
-    database::delete_image(conn, slug)?;
+    let hash = database::get_image_blob_hash(conn, slug)?;
+    let media_hashes = database::get_media_blob_hashes(conn, slug)?;
+
+    let tx = conn.unchecked_transaction()?;
+    database::delete_image(&tx, slug)?;
+    let mut orphans = Vec::new();
+    orphans.extend(match &hash {
+        Some(hash) => database::release_blob(&tx, hash)?,
+        None => Some(image.filename),
+    });
+    for hash in &media_hashes {
+        orphans.extend(database::release_blob(&tx, hash)?);
+    }
+    tx.commit()?;
+    render_cache::invalidate_image(slug);
+
+    // The file is only removed once nothing references it
+    for orphan in orphans {
+        file_manager.delete_file(&orphan)?;
+    }
     Ok(())
 }
 
-/// CLI usage
-pub fn insert_image_from_path(
+/// Appends uploaded files to an image's media, in the order given.
+pub fn add_media(
     conn: &Connection,
     file_manager: &ImageFileManager,
-    source_path: &str,
-    image: Image,
+    slug: &str,
+    uploads: Vec<MediaUpload>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if uploads.is_empty() {
+        return Ok(());
+    }
+
+    let mut blobs = Vec::with_capacity(uploads.len());
+    for upload in &uploads {
+        match file_manager.store_blob(&upload.data, &upload.mime_type) {
+            Ok(blob) => blobs.push(blob),
+            Err(e) => {
+                discard_created(file_manager, &blobs);
+                return Err(e.into());
+            }
+        }
+    }
+
+    let result = (|| {
+        let tx = conn.unchecked_transaction()?;
+        for (upload, blob) in uploads.iter().zip(&blobs) {
+            database::acquire_blob(&tx, blob)?;
+            if database::insert_media(&tx, slug, blob, &upload.alt, &upload.caption)? == 0 {
+                return Err(rusqlite::Error::QueryReturnedNoRows);
+            }
+        }
+        database::touch_image(&tx, slug)?;
+        tx.commit()
+    })();
+
+    if let Err(e) = result {
+        discard_created(file_manager, &blobs);
+        return Err(e.into());
+    }
+
+    render_cache::invalidate_image(slug);
+    Ok(())
+}
+
+/// Saves the alt text and captions of an image's media and puts them in
+/// the order given. `media` must list every item the image has.
+pub fn update_media(
+    conn: &Connection,
+    slug: &str,
+    media: &[Media],
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let image_data = fs::read(source_path)?;
-    let mime_type = mime_guess::from_path(source_path).first_or_octet_stream();
+    let tx = conn.unchecked_transaction()?;
+    let current: Vec<i64> = database::get_media(&tx, slug)?
+        .iter()
+        .map(|item| item.id)
+        .collect();
+    repository::check_media_list(&current, media)?;
+    for (position, item) in media.iter().enumerate() {
+        if database::update_media(&tx, slug, item, position)? == 0 {
+            return Err(rusqlite::Error::QueryReturnedNoRows.into());
+        }
+    }
+    database::touch_image(&tx, slug)?;
+    tx.commit()?;
+
+    render_cache::invalidate_image(slug);
+    Ok(())
+}
+
+pub fn delete_media(
+    conn: &Connection,
+    file_manager: &ImageFileManager,
+    slug: &str,
+    id: i64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let tx = conn.unchecked_transaction()?;
+    let hash = database::delete_media(&tx, slug, id)?;
+    let orphan = database::release_blob(&tx, &hash)?;
+    database::touch_image(&tx, slug)?;
+    tx.commit()?;
+    render_cache::invalidate_image(slug);
+
+    if let Some(orphan) = orphan {
+        file_manager.delete_file(&orphan)?;
+    }
+    Ok(())
+}
+
+/// Swaps an image's file and alt text with one of its media items. Both
+/// blobs stay referenced once, so no ref counts change.
+pub fn make_media_primary(
+    conn: &Connection,
+    slug: &str,
+    id: i64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let tx = conn.unchecked_transaction()?;
+    let mut image = database::get_image_by_slug(&tx, slug)?;
+    let (media, media_hash) = database::get_media_file(&tx, slug, id)?;
+    let image_hash = database::get_image_blob_hash(&tx, slug)?
+        .ok_or_else(|| repository::not_in_blob_storage(slug))?;
+
+    database::set_media_file(&tx, id, &image.filename, &image_hash, &image.alt)?;
+    image.filename = media.filename;
+    image.alt = media.alt;
+    database::update_image(&tx, slug, &image)?;
+    database::link_image_blob(&tx, slug, &media_hash)?;
+    tx.commit()?;
+
+    render_cache::invalidate_image(slug);
+    Ok(())
+}
+
+/// Removes the files a failed upload wrote, leaving ones it found already
+/// stored.
+fn discard_created(file_manager: &ImageFileManager, blobs: &[crate::files::StoredBlob]) {
+    for blob in blobs.iter().filter(|blob| blob.created) {
+        let _ = file_manager.delete_file(&blob.key);
+    }
+}
+
+/// Existing images whose file has exactly the same content as `data`.
+pub fn find_duplicates(
+    conn: &Connection,
+    data: &[u8],
+) -> Result<Vec<Image>, Box<dyn std::error::Error>> {
+    let hash = crate::files::content_hash(data);
+    Ok(database::find_images_by_blob(conn, &hash)?)
+}
+
+/// Moves images stored before content-addressing into blob storage,
+/// merging identical files. Returns the number of images migrated.
+pub fn migrate_to_blobs(
+    conn: &Connection,
+    file_manager: &ImageFileManager,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let mut migrated = 0;
+
+    for image in database::get_images(conn)? {
+        if database::get_image_blob_hash(conn, &image.slug)?.is_some() {
+            continue;
+        }
+
+        let data = file_manager.read_file(&image.filename)?;
+        let mime_type = mime_guess::from_path(&image.filename).first_or_octet_stream();
+        let blob = file_manager.store_blob(&data, &mime_type)?;
+
+        let tx = conn.unchecked_transaction()?;
+        database::acquire_blob(&tx, &blob)?;
+        database::link_image_blob(&tx, &image.slug, &blob.hash)?;
+        database::update_image(
+            &tx,
+            &image.slug,
+            &Image {
+                filename: blob.key.clone(),
+                ..image.clone()
+            },
+        )?;
+        tx.commit()?;
+        render_cache::invalidate_image(&image.slug);
+
+        if image.filename != blob.key {
+            file_manager.delete_file(&image.filename)?;
+        }
+        migrated += 1;
+    }
 
-    insert_image(conn, file_manager, &image_data, &mime_type, image)
+    Ok(migrated)
 }
@@ -0,0 +1,560 @@
+//! Custom content types such as events, workshops or stockists. Each is a
+//! schema of typed fields, declared under `[content_types.<name>]` in
+//! `config.toml` or written in the admin, whose entries are stored with
+//! their values as JSON and served at `/<path>` and `/<path>/<slug>`.
+
+use crate::config::Config;
+use crate::models::Entry;
+use crate::pages;
+use crate::render_cache::RENDER_CACHE;
+use crate::repository::{RepoError, Repository};
+use crate::templates::TEMPLATES;
+use chrono::NaiveDate;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, RwLock};
+
+/// Settings key for the types written in the admin, stored as a JSON object
+/// keyed by name.
+pub const SETTINGS_KEY: &str = "content_types";
+
+/// What a type renders with when the theme has no `<name>_list.html` or
+/// `<name>_detail.html` of its own.
+pub const LIST_TEMPLATE: &str = "entry_list.html";
+pub const DETAIL_TEMPLATE: &str = "entry_detail.html";
+
+const MAX_TITLE_LENGTH: usize = 200;
+const MAX_TEXT_LENGTH: usize = 1000;
+const MAX_MARKDOWN_LENGTH: usize = 100_000;
+const MAX_LIST_ITEMS: usize = 100;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldKind {
+    #[default]
+    Text,
+    /// Rendered to sanitized HTML for templates
+    Markdown,
+    Number,
+    /// A calendar day, `YYYY-MM-DD`
+    Date,
+    Boolean,
+    /// The slug of an image in the gallery
+    Image,
+    /// One of the field's `options`
+    Select,
+    /// Lines of text, such as opening hours
+    List,
+}
+
+/// One `[[fields]]` entry of a content type.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FieldSpec {
+    pub key: String,
+    #[serde(rename = "type", default)]
+    pub kind: FieldKind,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub label: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub description: String,
+    #[serde(default)]
+    pub required: bool,
+    /// The choices of a `select` field
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub options: Vec<String>,
+}
+
+/// Names and keys end up in template variables, so they must be valid
+/// identifiers there.
+fn is_identifier(value: &str) -> bool {
+    value.starts_with(|c: char| c.is_ascii_lowercase())
+        && value
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+fn number(value: &Value) -> Option<Value> {
+    match value {
+        Value::Null => Some(Value::Null),
+        Value::Number(_) => Some(value.clone()),
+        Value::String(text) if text.trim().is_empty() => Some(Value::Null),
+        Value::String(text) => {
+            let text = text.trim();
+            text.parse::<i64>().map(Value::from).ok().or_else(|| {
+                text.parse::<f64>()
+                    .ok()
+                    .and_then(serde_json::Number::from_f64)
+                    .map(Value::Number)
+            })
+        }
+        _ => None,
+    }
+}
+
+fn boolean(value: &Value) -> Option<Value> {
+    match value {
+        Value::Null => Some(Value::Bool(false)),
+        Value::Bool(_) => Some(value.clone()),
+        Value::String(text) => match text.trim() {
+            "true" => Some(Value::Bool(true)),
+            "false" | "" => Some(Value::Bool(false)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Forms send lists as one item per line; the API may send an array.
+fn list(value: &Value) -> Option<Value> {
+    let items: Vec<&str> = match value {
+        Value::Null => Vec::new(),
+        Value::String(text) => text.lines().collect(),
+        Value::Array(items) => items.iter().map(Value::as_str).collect::<Option<_>>()?,
+        _ => return None,
+    };
+    Some(Value::from(
+        items
+            .into_iter()
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(String::from)
+            .collect::<Vec<_>>(),
+    ))
+}
+
+impl FieldSpec {
+    pub fn label(&self) -> &str {
+        if self.label.is_empty() {
+            &self.key
+        } else {
+            &self.label
+        }
+    }
+
+    /// Checks a value from the admin or the API, returning it as it is
+    /// stored: text trimmed, numbers and booleans as JSON ones even when a
+    /// form sent them as text, and lists as arrays. Empty values are null.
+    pub fn validate(&self, value: Option<&Value>) -> Result<Value, String> {
+        let label = self.label();
+        let value = value.unwrap_or(&Value::Null);
+        let stored = match self.kind {
+            FieldKind::Number => {
+                number(value).ok_or_else(|| format!("{} must be a number", label))?
+            }
+            FieldKind::Boolean => {
+                boolean(value).ok_or_else(|| format!("{} must be true or false", label))?
+            }
+            FieldKind::List => {
+                let items =
+                    list(value).ok_or_else(|| format!("{} must be a list of text", label))?;
+                if items.as_array().map_or(0, Vec::len) > MAX_LIST_ITEMS {
+                    return Err(format!(
+                        "{} can have at most {} items",
+                        label, MAX_LIST_ITEMS
+                    ));
+                }
+                items
+            }
+            _ => {
+                let text = match value {
+                    Value::Null => "",
+                    Value::String(text) => text.trim(),
+                    _ => return Err(format!("{} must be text", label)),
+                };
+                self.check_text(text)
+                    .map_err(|e| format!("{} {}", label, e))?;
+                match text {
+                    "" => Value::Null,
+                    text => Value::String(text.to_string()),
+                }
+            }
+        };
+
+        let blank = match &stored {
+            Value::Null => true,
+            Value::Array(items) => items.is_empty(),
+            _ => false,
+        };
+        if self.required && blank {
+            return Err(format!("{} is required", label));
+        }
+        Ok(stored)
+    }
+
+    fn check_text(&self, text: &str) -> Result<(), String> {
+        let length = text.chars().count();
+        match self.kind {
+            FieldKind::Markdown if length > MAX_MARKDOWN_LENGTH => Err("is too long".to_string()),
+            FieldKind::Markdown => Ok(()),
+            _ if length > MAX_TEXT_LENGTH => Err("is too long".to_string()),
+            FieldKind::Date
+                if !text.is_empty() && NaiveDate::parse_from_str(text, "%Y-%m-%d").is_err() =>
+            {
+                Err("must be a date such as 2025-06-30".to_string())
+            }
+            FieldKind::Select
+                if !text.is_empty() && !self.options.iter().any(|option| option == text) =>
+            {
+                Err(format!("must be one of: {}", self.options.join(", ")))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Null values sort after everything else whichever way a listing runs, so
+/// only present values are compared here.
+fn compare(a: &Value, b: &Value) -> Ordering {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a
+            .as_f64()
+            .partial_cmp(&b.as_f64())
+            .unwrap_or(Ordering::Equal),
+        (Value::String(a), Value::String(b)) => a.cmp(b),
+        (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+        (a, b) => a.to_string().cmp(&b.to_string()),
+    }
+}
+
+/// A content type's schema, as declared in `config.toml` or the admin.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContentType {
+    /// The key it is declared under, which names its templates
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub name: String,
+    /// Plural, for headings such as "Events"; the name when unset
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub label: String,
+    /// Such as "Event"; the label when unset
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub singular: String,
+    /// The URL segment of its pages; the name, with hyphens, when unset
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub path: String,
+    /// How listings are ordered: `title` or a field's key, prefixed with
+    /// `-` for descending. Entries without a value go last.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub sort: String,
+    #[serde(default)]
+    pub fields: Vec<FieldSpec>,
+}
+
+impl ContentType {
+    /// Parses a type written in the admin, in the same TOML as a
+    /// `[content_types.<name>]` table.
+    pub fn parse(name: &str, source: &str) -> Result<Self, String> {
+        let content_type: ContentType =
+            toml::from_str(source).map_err(|e| format!("Invalid definition: {}", e))?;
+        content_type.prepare(name)
+    }
+
+    /// The definition as the admin edits it.
+    pub fn to_toml(&self) -> String {
+        let mut definition = self.clone();
+        definition.name = String::new();
+        toml::to_string(&definition).unwrap_or_default()
+    }
+
+    /// Checks the schema and fills in defaults, naming the type `name`.
+    fn prepare(mut self, name: &str) -> Result<Self, String> {
+        if !is_identifier(name) {
+            return Err(format!(
+                "Invalid content type name '{}': use lowercase letters, digits and '_', \
+                 starting with a letter",
+                name
+            ));
+        }
+        self.name = name.to_string();
+        if self.label.trim().is_empty() {
+            self.label = name.replace('_', " ");
+        }
+        if self.singular.trim().is_empty() {
+            self.singular = self.label.clone();
+        }
+        if self.path.is_empty() {
+            self.path = name.replace('_', "-");
+        }
+        if !pages::is_valid_slug(&self.path) {
+            return Err(format!(
+                "Invalid path '{}': use lowercase letters, digits and single hyphens",
+                self.path
+            ));
+        }
+
+        let mut seen = BTreeSet::new();
+        for field in &self.fields {
+            if !is_identifier(&field.key) {
+                return Err(format!(
+                    "Invalid field key '{}': use lowercase letters, digits and '_', \
+                     starting with a letter",
+                    field.key
+                ));
+            }
+            if !seen.insert(field.key.as_str()) {
+                return Err(format!("Field '{}' is declared twice", field.key));
+            }
+            match (field.kind, field.options.is_empty()) {
+                (FieldKind::Select, true) => {
+                    return Err(format!("Select field '{}' needs options", field.key))
+                }
+                (FieldKind::Select, false) | (_, true) => {}
+                (_, false) => {
+                    return Err(format!(
+                        "Field '{}' has options but isn't a select field",
+                        field.key
+                    ))
+                }
+            }
+        }
+
+        let sort_key = self.sort.strip_prefix('-').unwrap_or(&self.sort);
+        if !sort_key.is_empty() && sort_key != "title" && self.field(sort_key).is_none() {
+            return Err(format!(
+                "Can't sort by '{}': use title or one of the fields",
+                sort_key
+            ));
+        }
+        Ok(self)
+    }
+
+    pub fn field(&self, key: &str) -> Option<&FieldSpec> {
+        self.fields.iter().find(|field| field.key == key)
+    }
+
+    /// Checks an entry from the admin or the API, trimming its title and
+    /// replacing its values with their stored form, in schema order.
+    pub fn validate(&self, entry: &mut Entry) -> Result<(), String> {
+        entry.title = entry.title.trim().to_string();
+        if entry.title.is_empty() {
+            return Err(format!("{} needs a title", self.singular));
+        }
+        if entry.title.chars().count() > MAX_TITLE_LENGTH {
+            return Err(format!(
+                "Titles can be at most {} characters",
+                MAX_TITLE_LENGTH
+            ));
+        }
+        if !pages::is_valid_slug(&entry.slug) {
+            return Err(
+                "Slugs use lowercase letters, digits and single hyphens, like 'spring-market'"
+                    .to_string(),
+            );
+        }
+        if let Some(key) = entry.fields.keys().find(|key| self.field(key).is_none()) {
+            return Err(format!("{} has no field '{}'", self.label, key));
+        }
+
+        let mut fields = Map::new();
+        for spec in &self.fields {
+            fields.insert(
+                spec.key.clone(),
+                spec.validate(entry.fields.get(&spec.key))?,
+            );
+        }
+        entry.fields = fields;
+        Ok(())
+    }
+
+    /// The slugs an entry's image fields point at, which have to exist.
+    pub fn image_refs<'a>(&self, entry: &'a Entry) -> Vec<&'a str> {
+        self.fields
+            .iter()
+            .filter(|spec| spec.kind == FieldKind::Image)
+            .filter_map(|spec| entry.fields.get(&spec.key).and_then(Value::as_str))
+            .collect()
+    }
+
+    /// Orders entries as `sort` asks, then by title.
+    pub fn sort_entries(&self, entries: &mut [Entry]) {
+        let (key, descending) = match self.sort.strip_prefix('-') {
+            Some(key) => (key, true),
+            None => (self.sort.as_str(), false),
+        };
+        let directed = |ordering: Ordering| {
+            if descending {
+                ordering.reverse()
+            } else {
+                ordering
+            }
+        };
+        if key.is_empty() || key == "title" {
+            entries.sort_by(|a, b| directed(a.title.cmp(&b.title)));
+            return;
+        }
+        entries.sort_by(|a, b| {
+            let present = |value: &&Value| !value.is_null();
+            let ordering = match (
+                a.fields.get(key).filter(present),
+                b.fields.get(key).filter(present),
+            ) {
+                (Some(a), Some(b)) => directed(compare(a, b)),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => Ordering::Equal,
+            };
+            ordering.then_with(|| a.title.cmp(&b.title))
+        });
+    }
+
+    pub fn url(&self) -> String {
+        format!("/{}", self.path)
+    }
+
+    pub fn entry_url(&self, slug: &str) -> String {
+        format!("/{}/{}", self.path, slug)
+    }
+
+    /// The theme's `<name>_list.html`, or the generic listing.
+    pub fn list_template(&self) -> String {
+        let own = format!("{}_list.html", self.name);
+        if TEMPLATES.has_template(&own) {
+            own
+        } else {
+            LIST_TEMPLATE.to_string()
+        }
+    }
+
+    /// The theme's `<name>_detail.html`, or the generic detail page.
+    pub fn detail_template(&self) -> String {
+        let own = format!("{}_detail.html", self.name);
+        if TEMPLATES.has_template(&own) {
+            own
+        } else {
+            DETAIL_TEMPLATE.to_string()
+        }
+    }
+}
+
+/// Every content type in service, from the configuration and the admin.
+#[derive(Debug, Default)]
+pub struct Registry {
+    types: BTreeMap<String, Arc<ContentType>>,
+    /// Declared in `config.toml`, so the admin can't change them
+    configured: BTreeSet<String>,
+}
+
+impl Registry {
+    /// Combines the configured types with those saved from the admin,
+    /// checking that no two share a name or a path and that none takes a
+    /// path the site already answers.
+    pub fn build(config: &Config, saved: &BTreeMap<String, ContentType>) -> Result<Self, String> {
+        let mut registry = Registry::default();
+        for (name, content_type) in &config.content_types {
+            let content_type = content_type
+                .clone()
+                .prepare(name)
+                .map_err(|e| format!("content_types.{}: {}", name, e))?;
+            registry.add(content_type, config)?;
+            registry.configured.insert(name.clone());
+        }
+        for (name, content_type) in saved {
+            if registry.configured.contains(name) {
+                return Err(format!(
+                    "Content type '{}' is already declared in the configuration",
+                    name
+                ));
+            }
+            registry.add(content_type.clone().prepare(name)?, config)?;
+        }
+        Ok(registry)
+    }
+
+    fn add(&mut self, content_type: ContentType, config: &Config) -> Result<(), String> {
+        if pages::is_reserved(&content_type.path, config) {
+            return Err(format!(
+                "'/{}' is already used by the site; give content type '{}' another path",
+                content_type.path, content_type.name
+            ));
+        }
+        if let Some(other) = self.by_path(&content_type.path) {
+            return Err(format!(
+                "Content types '{}' and '{}' both use the path '/{}'",
+                other.name, content_type.name, content_type.path
+            ));
+        }
+        self.types
+            .insert(content_type.name.clone(), Arc::new(content_type));
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<ContentType>> {
+        self.types.get(name).cloned()
+    }
+
+    pub fn by_path(&self, path: &str) -> Option<Arc<ContentType>> {
+        self.types
+            .values()
+            .find(|content_type| content_type.path == path)
+            .cloned()
+    }
+
+    /// By name.
+    pub fn types(&self) -> impl Iterator<Item = &Arc<ContentType>> {
+        self.types.values()
+    }
+
+    pub fn is_configured(&self, name: &str) -> bool {
+        self.configured.contains(name)
+    }
+}
+
+lazy_static! {
+    static ref ACTIVE: RwLock<Arc<Registry>> = RwLock::new(Arc::new(Registry::default()));
+}
+
+pub fn active() -> Arc<Registry> {
+    ACTIVE.read().unwrap().clone()
+}
+
+/// Puts a set of types in service. Every rendered page is dropped, since
+/// paths and templates may have changed.
+pub fn apply(registry: Registry) {
+    *ACTIVE.write().unwrap() = Arc::new(registry);
+    RENDER_CACHE.clear();
+}
+
+/// The types written in the admin, as last saved.
+pub async fn saved(repo: &dyn Repository) -> Result<BTreeMap<String, ContentType>, RepoError> {
+    Ok(match repo.setting(SETTINGS_KEY).await? {
+        Some(json) => serde_json::from_str(&json).unwrap_or_else(|e| {
+            tracing::warn!(error = %e, "Ignoring unreadable content types");
+            BTreeMap::new()
+        }),
+        None => BTreeMap::new(),
+    })
+}
+
+/// Loads the content types at startup. Configured types that don't check
+/// out are an error; if the ones saved from the admin no longer fit beside
+/// them, say after a configured type took a name, those are reported and
+/// left out. Pages a type hides are reported too.
+pub async fn load(config: &Config, repo: &dyn Repository) -> Result<(), String> {
+    let saved = saved(repo)
+        .await
+        .map_err(|e| format!("Failed to read content types: {}", e))?;
+    let registry = match Registry::build(config, &saved) {
+        Ok(registry) => registry,
+        Err(e) if !saved.is_empty() => {
+            tracing::error!(error = %e, "Leaving out the content types saved in the admin");
+            Registry::build(config, &BTreeMap::new())?
+        }
+        Err(e) => return Err(e),
+    };
+    // A configured type can still land on a page's slug; its route wins
+    if let Ok(pages) = repo.pages().await {
+        for page in pages {
+            if let Some(content_type) = registry.by_path(&page.slug) {
+                tracing::warn!(
+                    page = %page.slug,
+                    content_type = %content_type.name,
+                    "Page is hidden by a content type at the same path"
+                );
+            }
+        }
+    }
+    apply(registry);
+    Ok(())
+}
@@ -1,5 +1,10 @@
-use crate::models::{Image, User};
+use crate::files::StoredBlob;
+use crate::migrations::MIGRATIONS;
+use crate::models::{Entry, Image, Media, Page, User};
+use rand::RngCore;
 use rusqlite::{params, Connection, Error};
+use sha2::{Digest, Sha256};
+use std::path::Path;
 use uuid::Uuid;
 
 use argon2::{
@@ -7,43 +12,26 @@ use argon2::{
     Argon2,
 };
 
-pub fn init_db() -> Result<Connection, Error> {
-    let conn = Connection::open("data/craftcms.db")?;
+pub fn init_db(path: &Path) -> Result<Connection, Error> {
+    let conn = Connection::open(path)?;
     Ok(conn)
 }
 
+/// How many entries of `MIGRATIONS` have been applied.
+pub fn schema_version(conn: &Connection) -> Result<usize, Error> {
+    conn.query_row("PRAGMA user_version", [], |row| row.get(0))
+}
+
 pub fn run_migrations(conn: &Connection) -> Result<(), Error> {
-    conn.execute_batch(
-        r#"
-
-        CREATE TABLE IF NOT EXISTS images (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            alt TEXT NOT NULL,
-            description TEXT,
-            slug TEXT UNIQUE NOT NULL,
-            keywords TEXT,
-            filename TEXT NOT NULL,
-            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
-            updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
-        );
-
-        CREATE TABLE IF NOT EXISTS users (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            email TEXT UNIQUE NOT NULL,
-            password_hash TEXT NOT NULL,
-            created_at DATETIME DEFAULT CURRENT_TIMESTAMP
-        );
-
-        CREATE TABLE IF NOT EXISTS sessions (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            session_id TEXT UNIQUE NOT NULL,
-            user_id INTEGER NOT NULL,
-            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
-            expires_at DATETIME NOT NULL,
-            FOREIGN KEY(user_id) REFERENCES users(id)
-        );
-        "#,
-    )?;
+    let applied = schema_version(conn)?;
+
+    for (version, migration) in MIGRATIONS.iter().enumerate().skip(applied) {
+        let tx = conn.unchecked_transaction()?;
+        tx.execute_batch(migration.sqlite)?;
+        tx.pragma_update(None, "user_version", version + 1)?;
+        tx.commit()?;
+    }
+
     Ok(())
 }
 
@@ -53,21 +41,7 @@ pub fn create_user(
     email: &str,
     password: &str,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    // Generate a random salt
-    let salt = SaltString::generate(&mut OsRng);
-
-    // Create Argon2 instance
-    let argon2 = Argon2::default();
-
-    // Hash the password
-    let password_hash = match argon2.hash_password(password.as_bytes(), &salt) {
-        Ok(hash) => hash.to_string(),
-        Err(e) => {
-            return Err(Box::new(rusqlite::Error::InvalidParameterName(
-                e.to_string(),
-            )))
-        }
-    };
+    let password_hash = hash_password(password)?;
 
     // Store the user with the hashed password
     conn.execute(
@@ -78,22 +52,89 @@ pub fn create_user(
     Ok(())
 }
 
+pub(crate) fn hash_password(password: &str) -> Result<String, Box<dyn std::error::Error>> {
+    // Generate a random salt
+    let salt = SaltString::generate(&mut OsRng);
+
+    // Create Argon2 instance
+    let argon2 = Argon2::default();
+
+    // Hash the password
+    match argon2.hash_password(password.as_bytes(), &salt) {
+        Ok(hash) => Ok(hash.to_string()),
+        Err(e) => Err(Box::new(rusqlite::Error::InvalidParameterName(
+            e.to_string(),
+        ))),
+    }
+}
+
 pub fn list_users(conn: &Connection) -> rusqlite::Result<Vec<User>> {
-    let mut stmt = conn.prepare("SELECT id, email FROM users")?;
+    let mut stmt = conn.prepare(
+        "SELECT id, email, disabled, created_at, last_login_at FROM users ORDER BY email",
+    )?;
     let users = stmt.query_map([], |row| {
         Ok(User {
             id: row.get(0)?,
             email: row.get(1)?,
             password_hash: String::new(), // We don't need to return this
             access_token: None,
+            disabled: row.get(2)?,
+            created_at: row.get(3)?,
+            last_login_at: row.get(4)?,
         })
     })?;
 
     users.collect()
 }
 
-pub fn delete_user(conn: &Connection, email: &str) -> rusqlite::Result<()> {
-    conn.execute("DELETE FROM users WHERE email = ?", [email])?;
+/// Returns the number of users deleted, so callers can report unknown emails.
+pub fn delete_user(conn: &Connection, email: &str) -> rusqlite::Result<usize> {
+    conn.execute(
+        "DELETE FROM sessions WHERE user_id = (SELECT id FROM users WHERE email = ?)",
+        [email],
+    )?;
+    conn.execute(
+        "DELETE FROM password_resets WHERE user_id = (SELECT id FROM users WHERE email = ?)",
+        [email],
+    )?;
+    conn.execute("DELETE FROM users WHERE email = ?", [email])
+}
+
+pub fn set_user_password(
+    conn: &Connection,
+    email: &str,
+    password: &str,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let password_hash = hash_password(password)?;
+    let updated = conn.execute(
+        "UPDATE users SET password_hash = ? WHERE email = ?",
+        [&password_hash, email],
+    )?;
+    Ok(updated)
+}
+
+/// Disabling a user also ends all of their sessions.
+pub fn set_user_disabled(conn: &Connection, email: &str, disabled: bool) -> Result<usize, Error> {
+    let updated = conn.execute(
+        "UPDATE users SET disabled = ? WHERE email = ?",
+        params![disabled, email],
+    )?;
+
+    if disabled {
+        conn.execute(
+            "DELETE FROM sessions WHERE user_id = (SELECT id FROM users WHERE email = ?)",
+            [email],
+        )?;
+    }
+
+    Ok(updated)
+}
+
+pub fn record_login(conn: &Connection, user_id: i64) -> Result<(), Error> {
+    conn.execute(
+        "UPDATE users SET last_login_at = CURRENT_TIMESTAMP WHERE id = ?",
+        [user_id],
+    )?;
     Ok(())
 }
 
@@ -102,14 +143,25 @@ pub fn verify_user(
     email: &str,
     password: &str,
 ) -> Result<bool, Box<dyn std::error::Error>> {
-    let hash: String = conn.query_row(
-        "SELECT password_hash FROM users WHERE email = ?",
+    let (hash, disabled): (String, bool) = conn.query_row(
+        "SELECT password_hash, disabled FROM users WHERE email = ?",
         [email],
-        |row| row.get(0),
+        |row| Ok((row.get(0)?, row.get(1)?)),
     )?;
 
+    if disabled {
+        return Ok(false);
+    }
+
+    verify_password_hash(&hash, password)
+}
+
+pub(crate) fn verify_password_hash(
+    hash: &str,
+    password: &str,
+) -> Result<bool, Box<dyn std::error::Error>> {
     // Parse the stored hash string
-    let parsed_hash = match PasswordHash::new(&hash) {
+    let parsed_hash = match PasswordHash::new(hash) {
         Ok(hash) => hash,
         Err(e) => {
             return Err(Box::new(rusqlite::Error::InvalidParameterName(
@@ -124,6 +176,93 @@ pub fn verify_user(
         .is_ok())
 }
 
+// Password reset operations
+pub(crate) fn hash_reset_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+pub(crate) fn generate_reset_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Issues a single-use reset token for an enabled user. Returns `None` for
+/// unknown or disabled accounts so callers can respond identically either
+/// way. Only the token's hash is stored.
+pub fn create_password_reset(
+    conn: &Connection,
+    email: &str,
+    ttl_minutes: i64,
+) -> Result<Option<String>, Error> {
+    let user_id: Option<i64> = match conn.query_row(
+        "SELECT id FROM users WHERE email = ? AND disabled = 0",
+        [email],
+        |row| row.get(0),
+    ) {
+        Ok(id) => Some(id),
+        Err(Error::QueryReturnedNoRows) => None,
+        Err(e) => return Err(e),
+    };
+
+    let user_id = match user_id {
+        Some(id) => id,
+        None => return Ok(None),
+    };
+
+    let token = generate_reset_token();
+    let expires_at = (chrono::Utc::now() + chrono::Duration::minutes(ttl_minutes))
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string();
+
+    conn.execute(
+        "INSERT INTO password_resets (user_id, token_hash, expires_at) VALUES (?, ?, ?)",
+        params![user_id, hash_reset_token(&token), expires_at],
+    )?;
+
+    Ok(Some(token))
+}
+
+/// Sets a new password if `token` is valid, unused and unexpired. All of the
+/// user's outstanding reset tokens and sessions are invalidated. Returns
+/// `false` when the token is rejected.
+pub fn consume_password_reset(
+    conn: &Connection,
+    token: &str,
+    new_password: &str,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let user_id: i64 = match conn.query_row(
+        "SELECT user_id FROM password_resets
+         WHERE token_hash = ? AND used_at IS NULL AND expires_at > datetime('now')",
+        [hash_reset_token(token)],
+        |row| row.get(0),
+    ) {
+        Ok(id) => id,
+        Err(Error::QueryReturnedNoRows) => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+
+    let password_hash = hash_password(new_password)?;
+
+    let tx = conn.unchecked_transaction()?;
+    tx.execute(
+        "UPDATE users SET password_hash = ? WHERE id = ?",
+        params![password_hash, user_id],
+    )?;
+    tx.execute(
+        "UPDATE password_resets SET used_at = CURRENT_TIMESTAMP
+         WHERE user_id = ? AND used_at IS NULL",
+        [user_id],
+    )?;
+    tx.execute("DELETE FROM sessions WHERE user_id = ?", [user_id])?;
+    tx.commit()?;
+
+    Ok(true)
+}
+
 // Session operations
 pub fn create_session(conn: &Connection, user_id: i64) -> Result<String, Error> {
     let session_id = Uuid::new_v4().to_string();
@@ -139,13 +278,27 @@ pub fn create_session(conn: &Connection, user_id: i64) -> Result<String, Error>
     Ok(session_id)
 }
 
-pub fn verify_session(conn: &Connection, session_id: &str) -> Result<bool, Error> {
-    let count: i64 = conn.query_row(
-        "SELECT COUNT(*) FROM sessions WHERE session_id = ? AND expires_at > datetime('now')",
+/// The email of the user a live session belongs to, or `None` if the
+/// session is unknown or expired.
+pub fn get_session_user(conn: &Connection, session_id: &str) -> Result<Option<String>, Error> {
+    match conn.query_row(
+        "SELECT u.email FROM sessions s JOIN users u ON u.id = s.user_id
+         WHERE s.session_id = ? AND s.expires_at > datetime('now')",
         [session_id],
         |row| row.get(0),
-    )?;
-    Ok(count > 0)
+    ) {
+        Ok(email) => Ok(Some(email)),
+        Err(Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn count_active_sessions(conn: &Connection) -> Result<i64, Error> {
+    conn.query_row(
+        "SELECT COUNT(*) FROM sessions WHERE expires_at > datetime('now')",
+        [],
+        |row| row.get(0),
+    )
 }
 
 pub fn delete_session(conn: &Connection, session_id: &str) -> Result<(), Error> {
@@ -153,6 +306,182 @@ pub fn delete_session(conn: &Connection, session_id: &str) -> Result<(), Error>
     Ok(())
 }
 
+pub fn get_setting(conn: &Connection, key: &str) -> Result<Option<String>, Error> {
+    match conn.query_row("SELECT value FROM settings WHERE key = ?", [key], |row| {
+        row.get(0)
+    }) {
+        Ok(value) => Ok(Some(value)),
+        Err(Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn set_setting(conn: &Connection, key: &str, value: &str) -> Result<(), Error> {
+    conn.execute(
+        "INSERT INTO settings (key, value) VALUES (?1, ?2)
+         ON CONFLICT(key) DO UPDATE SET value = excluded.value, updated_at = CURRENT_TIMESTAMP",
+        params![key, value],
+    )?;
+    Ok(())
+}
+
+const PAGE_COLUMNS: &str = "slug, title, body, template, nav_order, published, updated_at";
+
+fn page_from_row(row: &rusqlite::Row) -> Result<Page, Error> {
+    Ok(Page {
+        slug: row.get(0)?,
+        title: row.get(1)?,
+        body: row.get(2)?,
+        template: row.get(3)?,
+        nav_order: row.get(4)?,
+        published: row.get(5)?,
+        updated_at: row.get(6)?,
+    })
+}
+
+/// Every page, published or not, in menu order and then by title.
+pub fn get_pages(conn: &Connection) -> Result<Vec<Page>, Error> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM pages ORDER BY nav_order IS NULL, nav_order, title",
+        PAGE_COLUMNS
+    ))?;
+    let rows = stmt.query_map([], page_from_row)?;
+    rows.collect()
+}
+
+pub fn get_page(conn: &Connection, slug: &str) -> Result<Option<Page>, Error> {
+    match conn.query_row(
+        &format!("SELECT {} FROM pages WHERE slug = ?", PAGE_COLUMNS),
+        [slug],
+        page_from_row,
+    ) {
+        Ok(page) => Ok(Some(page)),
+        Err(Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn insert_page(conn: &Connection, page: &Page) -> Result<(), Error> {
+    conn.execute(
+        "INSERT INTO pages (slug, title, body, template, nav_order, published)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            &page.slug,
+            &page.title,
+            &page.body,
+            &page.template,
+            page.nav_order,
+            page.published,
+        ],
+    )?;
+    Ok(())
+}
+
+/// Returns the number of pages updated, 0 when none has `slug`.
+pub fn update_page(conn: &Connection, slug: &str, page: &Page) -> Result<usize, Error> {
+    conn.execute(
+        "UPDATE pages SET slug = ?1, title = ?2, body = ?3, template = ?4, nav_order = ?5,
+         published = ?6, updated_at = CURRENT_TIMESTAMP WHERE slug = ?7",
+        params![
+            &page.slug,
+            &page.title,
+            &page.body,
+            &page.template,
+            page.nav_order,
+            page.published,
+            slug,
+        ],
+    )
+}
+
+pub fn delete_page(conn: &Connection, slug: &str) -> Result<usize, Error> {
+    conn.execute("DELETE FROM pages WHERE slug = ?", [slug])
+}
+
+const ENTRY_COLUMNS: &str = "content_type, slug, title, fields, published, updated_at";
+
+/// Values are stored as a JSON object; one that can't be read shows as
+/// having none rather than failing the whole listing.
+fn entry_from_row(row: &rusqlite::Row) -> Result<Entry, Error> {
+    let fields: String = row.get(3)?;
+    Ok(Entry {
+        content_type: row.get(0)?,
+        slug: row.get(1)?,
+        title: row.get(2)?,
+        fields: serde_json::from_str(&fields).unwrap_or_default(),
+        published: row.get(4)?,
+        updated_at: row.get(5)?,
+    })
+}
+
+/// Every entry of `content_type`, published or not, by title.
+pub fn get_entries(conn: &Connection, content_type: &str) -> Result<Vec<Entry>, Error> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM entries WHERE content_type = ? ORDER BY title",
+        ENTRY_COLUMNS
+    ))?;
+    let rows = stmt.query_map([content_type], entry_from_row)?;
+    rows.collect()
+}
+
+pub fn get_entry(
+    conn: &Connection,
+    content_type: &str,
+    slug: &str,
+) -> Result<Option<Entry>, Error> {
+    match conn.query_row(
+        &format!(
+            "SELECT {} FROM entries WHERE content_type = ? AND slug = ?",
+            ENTRY_COLUMNS
+        ),
+        [content_type, slug],
+        entry_from_row,
+    ) {
+        Ok(entry) => Ok(Some(entry)),
+        Err(Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn insert_entry(conn: &Connection, entry: &Entry) -> Result<(), Error> {
+    conn.execute(
+        "INSERT INTO entries (content_type, slug, title, fields, published)
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            &entry.content_type,
+            &entry.slug,
+            &entry.title,
+            serde_json::Value::Object(entry.fields.clone()).to_string(),
+            entry.published,
+        ],
+    )?;
+    Ok(())
+}
+
+/// Returns the number of entries updated, 0 when the type has none at
+/// `slug`.
+pub fn update_entry(conn: &Connection, slug: &str, entry: &Entry) -> Result<usize, Error> {
+    conn.execute(
+        "UPDATE entries SET slug = ?1, title = ?2, fields = ?3, published = ?4,
+         updated_at = CURRENT_TIMESTAMP WHERE content_type = ?5 AND slug = ?6",
+        params![
+            &entry.slug,
+            &entry.title,
+            serde_json::Value::Object(entry.fields.clone()).to_string(),
+            entry.published,
+            &entry.content_type,
+            slug,
+        ],
+    )
+}
+
+pub fn delete_entry(conn: &Connection, content_type: &str, slug: &str) -> Result<usize, Error> {
+    conn.execute(
+        "DELETE FROM entries WHERE content_type = ? AND slug = ?",
+        [content_type, slug],
+    )
+}
+
 pub fn get_images(conn: &Connection) -> Result<Vec<Image>, Error> {
     let mut stmt = conn.prepare(
         "SELECT alt, description, slug, keywords, filename FROM images ORDER BY created_at DESC",
@@ -185,6 +514,29 @@ pub fn get_image_by_slug(conn: &Connection, slug: &str) -> Result<Image, Error>
     })
 }
 
+/// When an image row was last written, as stored by SQLite (UTC).
+pub fn get_image_updated_at(conn: &Connection, slug: &str) -> Result<Option<String>, Error> {
+    match conn.query_row(
+        "SELECT updated_at FROM images WHERE slug = ?",
+        params![slug],
+        |row| row.get(0),
+    ) {
+        Ok(updated_at) => Ok(updated_at),
+        Err(Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// When any image was last inserted, updated or deleted. Unlike the newest
+/// `updated_at`, this also moves forward when an image is removed.
+pub fn get_content_updated_at(conn: &Connection) -> Result<Option<String>, Error> {
+    conn.query_row(
+        "SELECT updated_at FROM content_state WHERE id = 1",
+        [],
+        |row| row.get(0),
+    )
+}
+
 pub fn insert_image(conn: &Connection, image: &Image) -> Result<(), Error> {
     conn.execute(
         "INSERT INTO images (alt, description, slug, keywords, filename)
@@ -202,7 +554,8 @@ pub fn insert_image(conn: &Connection, image: &Image) -> Result<(), Error> {
 
 pub fn update_image(conn: &Connection, slug: &str, image: &Image) -> Result<(), Error> {
     conn.execute(
-        "UPDATE images SET alt = ?1, description = ?2, slug = ?3, keywords = ?4, image_data = ?5 WHERE slug = ?6",
+        "UPDATE images SET alt = ?1, description = ?2, slug = ?3, keywords = ?4, filename = ?5,
+         updated_at = CURRENT_TIMESTAMP WHERE slug = ?6",
         params![
             &image.alt,
             &image.description,
@@ -216,6 +569,243 @@ pub fn update_image(conn: &Connection, slug: &str, image: &Image) -> Result<(),
 }
 
 pub fn delete_image(conn: &Connection, slug: &str) -> Result<(), Error> {
+    conn.execute(
+        "DELETE FROM image_media WHERE image_id = (SELECT id FROM images WHERE slug = ?)",
+        params![slug],
+    )?;
+    conn.execute(
+        "DELETE FROM image_blobs WHERE image_id = (SELECT id FROM images WHERE slug = ?)",
+        params![slug],
+    )?;
     conn.execute("DELETE FROM images WHERE slug = ?", params![slug])?;
     Ok(())
 }
+
+/// Marks an image as changed when only its media did, so its page's
+/// `Last-Modified` moves forward.
+pub fn touch_image(conn: &Connection, slug: &str) -> Result<(), Error> {
+    conn.execute(
+        "UPDATE images SET updated_at = CURRENT_TIMESTAMP WHERE slug = ?",
+        params![slug],
+    )?;
+    Ok(())
+}
+
+// Media operations
+
+/// An image's extra shots, in order.
+pub fn get_media(conn: &Connection, slug: &str) -> Result<Vec<Media>, Error> {
+    let mut stmt = conn.prepare(
+        "SELECT m.id, m.filename, m.alt, m.caption
+         FROM image_media m JOIN images i ON i.id = m.image_id
+         WHERE i.slug = ? ORDER BY m.position, m.id",
+    )?;
+    let rows = stmt.query_map(params![slug], |row| {
+        Ok(Media {
+            id: row.get(0)?,
+            filename: row.get(1)?,
+            alt: row.get(2)?,
+            caption: row.get(3)?,
+        })
+    })?;
+
+    rows.collect()
+}
+
+/// The blobs behind an image's media, one per item.
+pub fn get_media_blob_hashes(conn: &Connection, slug: &str) -> Result<Vec<String>, Error> {
+    let mut stmt = conn.prepare(
+        "SELECT m.blob_hash FROM image_media m JOIN images i ON i.id = m.image_id
+         WHERE i.slug = ?",
+    )?;
+    let rows = stmt.query_map(params![slug], |row| row.get(0))?;
+    rows.collect()
+}
+
+/// Appends a media item after the image's others. Returns the number of
+/// rows written, 0 when there is no such image.
+pub fn insert_media(
+    conn: &Connection,
+    slug: &str,
+    blob: &StoredBlob,
+    alt: &str,
+    caption: &str,
+) -> Result<usize, Error> {
+    conn.execute(
+        "INSERT INTO image_media (image_id, position, filename, blob_hash, alt, caption)
+         SELECT i.id,
+                (SELECT COALESCE(MAX(position) + 1, 0) FROM image_media WHERE image_id = i.id),
+                ?2, ?3, ?4, ?5
+         FROM images i WHERE i.slug = ?1",
+        params![slug, &blob.key, &blob.hash, alt, caption],
+    )
+}
+
+pub fn update_media(
+    conn: &Connection,
+    slug: &str,
+    media: &Media,
+    position: usize,
+) -> Result<usize, Error> {
+    conn.execute(
+        "UPDATE image_media SET position = ?1, alt = ?2, caption = ?3
+         WHERE id = ?4 AND image_id = (SELECT id FROM images WHERE slug = ?5)",
+        params![position as i64, &media.alt, &media.caption, media.id, slug],
+    )
+}
+
+/// A media item's file and blob hash, for swapping it with the image's.
+pub fn get_media_file(conn: &Connection, slug: &str, id: i64) -> Result<(Media, String), Error> {
+    conn.query_row(
+        "SELECT m.id, m.filename, m.alt, m.caption, m.blob_hash
+         FROM image_media m JOIN images i ON i.id = m.image_id
+         WHERE m.id = ?1 AND i.slug = ?2",
+        params![id, slug],
+        |row| {
+            Ok((
+                Media {
+                    id: row.get(0)?,
+                    filename: row.get(1)?,
+                    alt: row.get(2)?,
+                    caption: row.get(3)?,
+                },
+                row.get(4)?,
+            ))
+        },
+    )
+}
+
+/// Points a media item at another file, keeping its place and caption.
+pub fn set_media_file(
+    conn: &Connection,
+    id: i64,
+    filename: &str,
+    hash: &str,
+    alt: &str,
+) -> Result<(), Error> {
+    conn.execute(
+        "UPDATE image_media SET filename = ?1, blob_hash = ?2, alt = ?3 WHERE id = ?4",
+        params![filename, hash, alt, id],
+    )?;
+    Ok(())
+}
+
+/// Removes a media item, returning its blob's hash for the caller to
+/// release.
+pub fn delete_media(conn: &Connection, slug: &str, id: i64) -> Result<String, Error> {
+    let hash: String = conn.query_row(
+        "SELECT m.blob_hash FROM image_media m JOIN images i ON i.id = m.image_id
+         WHERE m.id = ?1 AND i.slug = ?2",
+        params![id, slug],
+        |row| row.get(0),
+    )?;
+    conn.execute("DELETE FROM image_media WHERE id = ?", params![id])?;
+    Ok(hash)
+}
+
+// Blob operations
+
+/// Records a new reference to `blob`, creating its row on first use.
+pub fn acquire_blob(conn: &Connection, blob: &StoredBlob) -> Result<(), Error> {
+    conn.execute(
+        "INSERT INTO blobs (hash, storage_key, content_type, size, ref_count)
+         VALUES (?1, ?2, ?3, ?4, 1)
+         ON CONFLICT(hash) DO UPDATE SET ref_count = ref_count + 1",
+        params![&blob.hash, &blob.key, &blob.content_type, blob.size as i64],
+    )?;
+    Ok(())
+}
+
+/// Drops one reference to a blob. When none remain the row is deleted and
+/// its storage key returned so the caller can remove the file once the
+/// surrounding transaction has committed.
+pub fn release_blob(conn: &Connection, hash: &str) -> Result<Option<String>, Error> {
+    conn.execute(
+        "UPDATE blobs SET ref_count = ref_count - 1 WHERE hash = ?",
+        params![hash],
+    )?;
+
+    let (storage_key, ref_count): (String, i64) = match conn.query_row(
+        "SELECT storage_key, ref_count FROM blobs WHERE hash = ?",
+        params![hash],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    ) {
+        Ok(row) => row,
+        Err(Error::QueryReturnedNoRows) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    if ref_count > 0 {
+        return Ok(None);
+    }
+
+    conn.execute("DELETE FROM blobs WHERE hash = ?", params![hash])?;
+    Ok(Some(storage_key))
+}
+
+pub fn link_image_blob(conn: &Connection, slug: &str, hash: &str) -> Result<(), Error> {
+    conn.execute(
+        "INSERT OR REPLACE INTO image_blobs (image_id, blob_hash)
+         SELECT id, ?1 FROM images WHERE slug = ?2",
+        params![hash, slug],
+    )?;
+    Ok(())
+}
+
+/// The blob behind an image, or `None` for images stored before
+/// content-addressing was introduced.
+pub fn get_image_blob_hash(conn: &Connection, slug: &str) -> Result<Option<String>, Error> {
+    match conn.query_row(
+        "SELECT b.blob_hash FROM image_blobs b JOIN images i ON i.id = b.image_id
+         WHERE i.slug = ?",
+        params![slug],
+        |row| row.get(0),
+    ) {
+        Ok(hash) => Ok(Some(hash)),
+        Err(Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Image counts by storage status: `blob` for content-addressed files and
+/// `legacy` for files still stored under their slug.
+pub fn count_images_by_status(conn: &Connection) -> Result<Vec<(String, i64)>, Error> {
+    let mut stmt = conn.prepare(
+        "SELECT CASE WHEN b.image_id IS NULL THEN 'legacy' ELSE 'blob' END AS status, COUNT(*)
+         FROM images i LEFT JOIN image_blobs b ON b.image_id = i.id
+         GROUP BY status",
+    )?;
+    let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    rows.collect()
+}
+
+pub fn get_blob_created_at(conn: &Connection, hash: &str) -> Result<Option<String>, Error> {
+    match conn.query_row(
+        "SELECT created_at FROM blobs WHERE hash = ?",
+        params![hash],
+        |row| row.get(0),
+    ) {
+        Ok(created_at) => Ok(created_at),
+        Err(Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn find_images_by_blob(conn: &Connection, hash: &str) -> Result<Vec<Image>, Error> {
+    let mut stmt = conn.prepare(
+        "SELECT i.alt, i.description, i.slug, i.keywords, i.filename
+         FROM images i JOIN image_blobs b ON b.image_id = i.id
+         WHERE b.blob_hash = ? ORDER BY i.created_at DESC",
+    )?;
+    let rows = stmt.query_map(params![hash], |row| {
+        Ok(Image {
+            alt: row.get(0)?,
+            description: row.get(1)?,
+            slug: row.get(2)?,
+            keywords: serde_json::from_str(&row.get::<_, String>(3)?).unwrap_or_default(),
+            filename: row.get(4)?,
+        })
+    })?;
+
+    rows.collect()
+}
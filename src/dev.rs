@@ -0,0 +1,136 @@
+use crate::render_cache::RENDER_CACHE;
+use crate::server;
+use crate::themes;
+use notify::{EventKind, RecursiveMode, Watcher};
+use std::convert::Infallible;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, watch};
+use warp::Filter;
+
+/// Reconnects on its own after a restart, so a page left open keeps
+/// following the server.
+const RELOAD_JS: &str = r#"// Reloads the page when `craftcms serve --dev` sees a file change
+(() => {
+  const source = new EventSource("/__dev/reload");
+  source.addEventListener("reload", () => window.location.reload());
+})();
+"#;
+
+/// How long to wait for an editor to finish writing before reloading, so a
+/// save that touches several files reloads once.
+const SETTLE: Duration = Duration::from_millis(150);
+
+/// What `serve --dev` turns on.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DevOptions {
+    /// Reload templates when they change on disk
+    pub enabled: bool,
+    /// Also refresh open pages after a reload
+    pub live_reload: bool,
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Watches the themes and the site's template and static directories. A
+/// change to a theme or template re-reads the active theme and recompiles
+/// its templates; any change clears the render cache and is announced on
+/// the returned channel so open pages can refresh.
+pub fn watch(
+    themes_dir: &Path,
+    templates_dir: &Path,
+    static_dir: &Path,
+) -> Result<broadcast::Sender<()>, String> {
+    let themes_dir = canonical(themes_dir);
+    let templates_dir = canonical(templates_dir);
+    let static_dir = canonical(static_dir);
+
+    let (events_tx, mut events_rx) = mpsc::unbounded_channel();
+    let mut watcher =
+        notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+            Ok(event) if !matches!(event.kind, EventKind::Access(_)) => {
+                let _ = events_tx.send(event.paths);
+            }
+            Ok(_) => {}
+            Err(e) => tracing::warn!(error = %e, "File watcher error"),
+        })
+        .map_err(|e| format!("Failed to start file watcher: {}", e))?;
+
+    for dir in [&themes_dir, &templates_dir, &static_dir] {
+        if !dir.is_dir() {
+            continue;
+        }
+        watcher
+            .watch(dir, RecursiveMode::Recursive)
+            .map_err(|e| format!("Failed to watch {}: {}", dir.display(), e))?;
+        tracing::info!(path = %dir.display(), "Watching for changes");
+    }
+
+    let (reload_tx, _) = broadcast::channel(16);
+    let reload = reload_tx.clone();
+    tokio::spawn(async move {
+        // Dropping the watcher would stop the events
+        let _watcher = watcher;
+        while let Some(mut paths) = events_rx.recv().await {
+            tokio::time::sleep(SETTLE).await;
+            while let Ok(more) = events_rx.try_recv() {
+                paths.extend(more);
+            }
+
+            if paths
+                .iter()
+                .any(|path| path.starts_with(&templates_dir) || path.starts_with(&themes_dir))
+            {
+                if let Err(e) = tokio::task::spawn_blocking(themes::refresh)
+                    .await
+                    .unwrap_or_else(|e| Err(e.to_string()))
+                {
+                    tracing::error!(error = %e, "Failed to reload templates, keeping the previous ones");
+                    continue;
+                }
+                tracing::info!("Reloaded templates");
+            }
+            RENDER_CACHE.clear();
+            let _ = reload.send(());
+        }
+    });
+
+    Ok(reload_tx)
+}
+
+/// `/__dev/reload.js`, which pages load when live reload is on, and the
+/// `/__dev/reload` event stream it listens to. The stream ends when the
+/// server shuts down so it doesn't hold up the drain.
+pub fn routes(
+    reload: broadcast::Sender<()>,
+    shutdown: watch::Receiver<bool>,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    let script = warp::path!("__dev" / "reload.js")
+        .and(warp::get())
+        .map(|| warp::reply::with_header(RELOAD_JS, "content-type", "application/javascript"));
+
+    let events = warp::path!("__dev" / "reload")
+        .and(warp::get())
+        .map(move || {
+            let stream = futures::stream::unfold(
+                (reload.subscribe(), shutdown.clone()),
+                |(mut changes, shutdown)| async move {
+                    tokio::select! {
+                        _ = server::shutdown_requested(shutdown.clone()) => None,
+                        change = changes.recv() => match change {
+                            Ok(()) | Err(broadcast::error::RecvError::Lagged(_)) => {
+                                let event = warp::sse::Event::default().event("reload").data("");
+                                Some((Ok::<_, Infallible>(event), (changes, shutdown)))
+                            }
+                            Err(broadcast::error::RecvError::Closed) => None,
+                        },
+                    }
+                },
+            );
+            warp::sse::reply(warp::sse::keep_alive().stream(stream))
+        });
+
+    script.or(events)
+}
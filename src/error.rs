@@ -0,0 +1,219 @@
+use crate::config::Config;
+use crate::models::RedirectToLogin;
+use crate::templates::TEMPLATES;
+use std::convert::Infallible;
+use std::fmt;
+use tera::Context;
+use warp::http::{header, HeaderValue, Response, StatusCode};
+use warp::reject::Reject;
+use warp::reply::Response as ReplyResponse;
+
+/// What a handler can fail with. Each variant carries the message for the
+/// client, except `Internal`, whose message is only logged.
+#[derive(Debug)]
+pub enum AppError {
+    NotFound(String),
+    Unauthorized(String),
+    Forbidden(String),
+    Validation(String),
+    Conflict(String),
+    PayloadTooLarge(String),
+    Internal(String),
+}
+
+impl AppError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::Validation(_) => StatusCode::BAD_REQUEST,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// The message that is safe to show the client.
+    pub fn public_message(&self) -> &str {
+        match self {
+            AppError::NotFound(message)
+            | AppError::Unauthorized(message)
+            | AppError::Forbidden(message)
+            | AppError::Validation(message)
+            | AppError::Conflict(message)
+            | AppError::PayloadTooLarge(message) => message,
+            AppError::Internal(_) => "Internal server error",
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AppError::Internal(message) => write!(f, "{}", message),
+            e => write!(f, "{}", e.public_message()),
+        }
+    }
+}
+
+impl std::error::Error for AppError {}
+
+/// Warp converts any `Reject` into a `Rejection`, so handlers can return an
+/// `AppError` with `?`.
+impl Reject for AppError {}
+
+/// Attached to error responses by `handle_rejection`, so the outer filter
+/// can render them as a page or as JSON once it knows what the client
+/// accepts.
+#[derive(Clone, Debug)]
+pub struct ErrorInfo {
+    pub status: StatusCode,
+    pub message: String,
+}
+
+fn error_response(status: StatusCode, message: &str) -> ReplyResponse {
+    let mut response = Response::new(message.to_string().into());
+    *response.status_mut() = status;
+    response.extensions_mut().insert(ErrorInfo {
+        status,
+        message: message.to_string(),
+    });
+    response
+}
+
+pub async fn handle_rejection(err: warp::Rejection) -> Result<ReplyResponse, Infallible> {
+    if err.is_not_found() {
+        return Ok(error_response(StatusCode::NOT_FOUND, "Page not found"));
+    }
+    if err.find::<RedirectToLogin>().is_some() {
+        // Browsers are sent to the login page; scripts get a 401 instead
+        let mut response = error_response(StatusCode::UNAUTHORIZED, "Please log in again");
+        *response.status_mut() = StatusCode::FOUND;
+        response
+            .headers_mut()
+            .insert(header::LOCATION, HeaderValue::from_static("/admin/login"));
+        return Ok(response);
+    }
+    if let Some(e) = err.find::<AppError>() {
+        if let AppError::Internal(details) = e {
+            tracing::error!(error = %details, "Request failed");
+        }
+        return Ok(error_response(e.status(), e.public_message()));
+    }
+
+    // A request rejected by several routes carries all their reasons; a
+    // route that matched the method knows more than one that didn't
+    let (status, message) = if err.find::<warp::reject::PayloadTooLarge>().is_some() {
+        (StatusCode::PAYLOAD_TOO_LARGE, "Request body too large")
+    } else if err.find::<warp::reject::UnsupportedMediaType>().is_some() {
+        (
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Unsupported content type",
+        )
+    } else if err.find::<warp::reject::LengthRequired>().is_some() {
+        (StatusCode::LENGTH_REQUIRED, "Content-Length required")
+    } else if err.find::<warp::body::BodyDeserializeError>().is_some()
+        || err.find::<warp::reject::InvalidQuery>().is_some()
+        || err.find::<warp::reject::InvalidHeader>().is_some()
+        || err.find::<warp::reject::MissingHeader>().is_some()
+        || err.find::<warp::reject::MissingCookie>().is_some()
+    {
+        (StatusCode::BAD_REQUEST, "Bad request")
+    } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
+        (StatusCode::METHOD_NOT_ALLOWED, "Method not allowed")
+    } else {
+        tracing::error!(rejection = ?err, "Unhandled rejection");
+        (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error")
+    };
+    tracing::debug!(rejection = ?err, status = status.as_u16(), "Rejected request");
+    Ok(error_response(status, message))
+}
+
+/// Whether the client is a script rather than a browser navigating: any
+/// request to the public API, an `XMLHttpRequest`, a request that asks for
+/// JSON and not HTML, or any admin request that doesn't ask for HTML (as
+/// `fetch` doesn't by default).
+fn wants_json(path: &str, accept: Option<&str>, requested_with: Option<&str>) -> bool {
+    if path == "/api" || path.starts_with("/api/") {
+        return true;
+    }
+    if requested_with.is_some_and(|value| value.eq_ignore_ascii_case("XMLHttpRequest")) {
+        return true;
+    }
+    let accept = accept.unwrap_or_default();
+    if accept.contains("text/html") {
+        return false;
+    }
+    let admin = path == "/admin" || path.starts_with("/admin/");
+    admin || accept.contains("application/json")
+}
+
+/// Renders `{status}.html` from the site templates, falling back to
+/// `500.html` and then to plain text.
+fn render_page(info: &ErrorInfo, config: &Config) -> Option<String> {
+    let names = [
+        format!("{}.html", info.status.as_u16()),
+        "500.html".to_string(),
+    ];
+    let name = names.iter().find(|name| TEMPLATES.has_template(name))?;
+
+    let mut context = Context::new();
+    context.insert("status", &info.status.as_u16());
+    context.insert("reason", info.status.canonical_reason().unwrap_or("Error"));
+    context.insert("message", &info.message);
+    context.insert(
+        "title",
+        &format!(
+            "{} - {}",
+            info.status.canonical_reason().unwrap_or("Error"),
+            config.site.name
+        ),
+    );
+    context.insert("description", &config.site.description);
+    context.insert("site_name", &config.site.name);
+    context.insert("base_url", &config.site.base_url);
+    context.insert("author", &config.meta.author);
+
+    crate::metrics::time_render(name, || TEMPLATES.render(name, &context))
+        .map_err(|e| tracing::error!(template = %name, error = ?e, "Failed to render error page"))
+        .ok()
+}
+
+/// Turns an error response from `handle_rejection` into what the client
+/// expects: JSON for scripts, otherwise the site's error page. Other
+/// responses pass through untouched.
+pub fn render(
+    response: ReplyResponse,
+    path: &str,
+    accept: Option<&str>,
+    requested_with: Option<&str>,
+    config: &Config,
+) -> ReplyResponse {
+    let Some(info) = response.extensions().get::<ErrorInfo>().cloned() else {
+        return response;
+    };
+
+    if wants_json(path, accept, requested_with) {
+        let body = serde_json::json!({
+            "error": info.message,
+            "status": info.status.as_u16(),
+        });
+        let mut json = warp::reply::Reply::into_response(warp::reply::json(&body));
+        *json.status_mut() = info.status;
+        return json;
+    }
+    if response.status().is_redirection() {
+        return response;
+    }
+
+    let (content_type, body) = match render_page(&info, config) {
+        Some(html) => ("text/html; charset=utf-8", html),
+        None => ("text/plain; charset=utf-8", info.message.clone()),
+    };
+    Response::builder()
+        .status(info.status)
+        .header(header::CONTENT_TYPE, content_type)
+        .body(body.into())
+        .unwrap()
+}
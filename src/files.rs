@@ -1,83 +1,239 @@
+use crate::cache;
+use crate::storage::StorageBackend;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat};
 use mime::Mime;
-use std::path::{Path, PathBuf};
+use std::io::{Cursor, Error, ErrorKind};
+use std::sync::Arc;
 
+/// A file written by `ImageFileManager::store_blob`.
+#[derive(Debug, Clone)]
+pub struct StoredBlob {
+    pub hash: String,
+    pub key: String,
+    pub size: usize,
+    pub content_type: String,
+    pub created: bool,
+}
+
+/// Hex BLAKE3 digest used to address stored files.
+pub fn content_hash(data: &[u8]) -> String {
+    blake3::hash(data).to_hex().to_string()
+}
+
+/// What a resized variant can be encoded as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VariantFormat {
+    Jpeg,
+    Png,
+    Webp,
+}
+
+impl VariantFormat {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "jpg" | "jpeg" => Some(VariantFormat::Jpeg),
+            "png" => Some(VariantFormat::Png),
+            "webp" => Some(VariantFormat::Webp),
+            _ => None,
+        }
+    }
+
+    /// The format of a stored file, judged by its extension.
+    pub fn of_key(key: &str) -> Self {
+        key.rsplit_once('.')
+            .and_then(|(_, extension)| Self::parse(extension))
+            .unwrap_or(VariantFormat::Jpeg)
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            VariantFormat::Jpeg => "jpg",
+            VariantFormat::Png => "png",
+            VariantFormat::Webp => "webp",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            VariantFormat::Jpeg => "image/jpeg",
+            VariantFormat::Png => "image/png",
+            VariantFormat::Webp => "image/webp",
+        }
+    }
+}
+
+/// Where resized copies of `key` are stored. Variants of a content-addressed
+/// blob share its hash, so they can be found and removed along with it.
+fn variant_prefix(key: &str) -> String {
+    let hash = cache::fingerprint(key)
+        .map(str::to_string)
+        .unwrap_or_else(|| content_hash(key.as_bytes()));
+    format!("variants/{}/{}/", &hash[..2], hash)
+}
+
+pub fn variant_key(key: &str, width: u32, format: VariantFormat, quality: u8) -> String {
+    format!(
+        "{}{}-q{}.{}",
+        variant_prefix(key),
+        width,
+        quality,
+        format.extension()
+    )
+}
+
+/// Scales an image down to `width` pixels wide, never up, and encodes it.
+fn resize(
+    source: &[u8],
+    width: u32,
+    format: VariantFormat,
+    quality: u8,
+) -> image::ImageResult<Vec<u8>> {
+    let image = image::load_from_memory(source)?;
+    let image = if image.width() > width {
+        image.resize(width, u32::MAX, FilterType::Lanczos3)
+    } else {
+        image
+    };
+
+    let mut data = Vec::new();
+    match format {
+        VariantFormat::Jpeg => {
+            let encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(
+                &mut data,
+                quality.clamp(1, 100),
+            );
+            DynamicImage::from(image.to_rgb8()).write_with_encoder(encoder)?;
+        }
+        VariantFormat::Png => image.write_to(&mut Cursor::new(&mut data), ImageFormat::Png)?,
+        // The WebP encoder only takes 8-bit RGB(A)
+        VariantFormat::Webp => DynamicImage::from(image.to_rgba8())
+            .write_to(&mut Cursor::new(&mut data), ImageFormat::WebP)?,
+    }
+    Ok(data)
+}
+
+/// Stores image files in a `StorageBackend`, content-addressed by hash.
 pub struct ImageFileManager {
-    base_path: PathBuf,
+    storage: Arc<dyn StorageBackend>,
 }
 
 impl ImageFileManager {
-    pub fn new(base_path: impl Into<PathBuf>) -> Self {
-        Self {
-            base_path: base_path.into(),
-        }
+    pub fn new(storage: Arc<dyn StorageBackend>) -> Self {
+        Self { storage }
     }
 
-    pub fn save_file(
-        &self,
-        data: &[u8],
-        slug: &str,
-        mime_type: &Mime,
-    ) -> Result<String, std::io::Error> {
-        println!("Saving file with mime type: {:?}", mime_type);
+    pub fn storage(&self) -> &Arc<dyn StorageBackend> {
+        &self.storage
+    }
+
+    /// Stores `data` under a path derived from its BLAKE3 hash, so identical
+    /// uploads share one file. `created` is false when the blob was already
+    /// stored.
+    pub fn store_blob(&self, data: &[u8], mime_type: &Mime) -> Result<StoredBlob, std::io::Error> {
         let extension = match (mime_type.type_(), mime_type.subtype()) {
             (mime::IMAGE, mime::JPEG) => "jpg",
             (mime::IMAGE, mime::PNG) => "png",
             _ => {
-                println!("Unrecognized mime type, defaulting to jpg: {:?}", mime_type);
+                tracing::warn!(mime_type = %mime_type, "Unrecognized image type, storing as jpg");
                 "jpg"
             }
         };
 
-        let filename = format!("{}.{}", slug, extension);
-        let file_path = self.base_path.join(&filename);
+        let hash = content_hash(data);
+        let key = format!("blobs/{}/{}.{}", &hash[..2], hash, extension);
 
-        println!("Attempting to save file: {:?}", file_path);
-        println!("Data length: {} bytes", data.len());
+        tracing::trace!(key = %key, bytes = data.len(), mime_type = %mime_type, "Storing blob");
 
-        match std::fs::write(&file_path, data) {
+        if self.storage.exists(&key)? {
+            tracing::debug!(key = %key, "Blob already stored, reusing it");
+            return Ok(StoredBlob {
+                hash,
+                key,
+                size: data.len(),
+                content_type: mime_type.essence_str().to_string(),
+                created: false,
+            });
+        }
+
+        match self.storage.put(&key, data, mime_type.essence_str()) {
             Ok(_) => {
-                println!("Successfully wrote file: {:?}", file_path);
-                Ok(filename)
+                tracing::debug!(key = %key, bytes = data.len(), "Stored blob");
+                Ok(StoredBlob {
+                    hash,
+                    key,
+                    size: data.len(),
+                    content_type: mime_type.essence_str().to_string(),
+                    created: true,
+                })
             }
             Err(e) => {
-                println!("Error writing file: {:?}", e);
+                tracing::error!(key = %key, error = %e, "Failed to write blob");
                 Err(e)
             }
         }
     }
 
-    pub fn delete_file(&self, filename: &str) -> std::io::Result<()> {
-        let file_path = self.base_path.join(filename);
+    pub fn read_file(&self, filename: &str) -> std::io::Result<Vec<u8>> {
+        self.storage.get(filename)
+    }
 
-        // Debug info
-        println!("Attempting to delete file: {:?}", file_path);
+    /// Deletes a file and any resized variants of it.
+    pub fn delete_file(&self, filename: &str) -> std::io::Result<()> {
+        tracing::debug!(key = %filename, "Deleting file");
 
-        if !file_path.exists() {
-            println!("File doesn't exist, considering this a success");
-            return Ok(());
-        }
+        self.storage.delete(filename).map_err(|e| {
+            tracing::error!(key = %filename, error = %e, "Failed to delete file");
+            e
+        })?;
 
-        match std::fs::remove_file(&file_path) {
-            Ok(_) => Ok(()),
-            Err(e) => {
-                println!("Error deleting file: {:?}", e);
-                println!("File permissions: {:?}", std::fs::metadata(&file_path));
-                Err(e)
+        let variants = self
+            .storage
+            .list(&variant_prefix(filename))
+            .unwrap_or_default();
+        for variant in variants {
+            if let Err(e) = self.storage.delete(&variant) {
+                tracing::warn!(key = %variant, error = %e, "Failed to delete image variant");
             }
         }
+        Ok(())
     }
 
-    pub fn rename_file(&self, old_filename: &str, new_filename: &str) -> std::io::Result<String> {
-        let old_path = self.base_path.join(old_filename);
-        let extension = Path::new(old_filename)
-            .extension()
-            .and_then(|ext| ext.to_str())
-            .unwrap_or("jpg");
+    /// A copy of the file at `key` at most `width` pixels wide, encoded as
+    /// `format`. It is made on first request and stored for next time.
+    /// Returns the variant's key with its bytes.
+    pub fn variant(
+        &self,
+        key: &str,
+        width: u32,
+        format: VariantFormat,
+        quality: u8,
+    ) -> std::io::Result<(String, Vec<u8>)> {
+        let variant = variant_key(key, width, format, quality);
+        match self.storage.get(&variant) {
+            Ok(data) => return Ok((variant, data)),
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
 
-        let new_filename = format!("{}.{}", new_filename, extension);
-        let new_path = self.base_path.join(&new_filename);
+        let source = self.storage.get(key)?;
+        let data = resize(&source, width, format, quality).map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Failed to resize {}: {}", key, e),
+            )
+        })?;
+        self.storage.put(&variant, &data, format.content_type())?;
+        tracing::debug!(key = %variant, bytes = data.len(), "Stored image variant");
+        Ok((variant, data))
+    }
 
-        std::fs::rename(old_path, &new_path)?;
-        Ok(new_filename) // Return just the filename, not the full path
+    /// Writes and removes a small probe object, for readiness checks. Each
+    /// probe gets its own key so instances sharing a bucket can't remove
+    /// one another's.
+    pub fn check_writable(&self) -> std::io::Result<()> {
+        let key = format!(".readyz-probe-{}", uuid::Uuid::new_v4().simple());
+        self.storage.put(&key, b"ok", "text/plain")?;
+        self.storage.delete(&key)
     }
 }
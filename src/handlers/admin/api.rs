@@ -1,8 +1,20 @@
-use crate::commands;
+use crate::config::Config;
+use crate::content_types::{self, ContentType, Registry};
+use crate::error::AppError;
 use crate::files::ImageFileManager;
-use crate::models::{CustomError, Image, LoginCredentials};
-use rusqlite::Connection;
-use std::sync::{Arc, Mutex};
+use crate::logging;
+use crate::mailer::{Email, Mailer};
+use crate::markdown;
+use crate::metrics;
+use crate::models::{
+    ContentTypeForm, Entry, ForgotPasswordRequest, Image, LoginCredentials, MarkdownPreview, Media,
+    MediaUpload, Page, ResetPasswordRequest,
+};
+use crate::pages;
+use crate::repository::{RepoError, Repository};
+use crate::themes;
+use std::collections::BTreeMap;
+use std::sync::Arc;
 use warp::multipart::FormData;
 use warp::Reply;
 
@@ -11,26 +23,22 @@ use futures::TryStreamExt;
 
 pub async fn admin_create_image_handler(
     form: FormData,
-    conn: Arc<Mutex<Connection>>,
+    repo: Arc<dyn Repository>,
     file_manager: Arc<ImageFileManager>,
 ) -> Result<impl Reply, warp::Rejection> {
-    let (image, image_data) = process_image_form(form).await?;
+    let (image, image_data, media) = process_image_form(form).await?;
 
-    let (data, mime_type) = image_data.ok_or_else(|| {
-        println!("No image data provided in form");
-        warp::reject::custom(CustomError::new("No image data provided".to_string()))
-    })?;
-
-    let conn_guard = conn.lock().map_err(|e| {
-        println!("Failed to acquire database lock: {}", e);
-        CustomError::new(e.to_string())
-    })?;
+    let (data, mime_type) =
+        image_data.ok_or_else(|| AppError::Validation("No image data provided".to_string()))?;
 
-    commands::insert_image(&conn_guard, &file_manager, &data, &mime_type, image).map_err(|e| {
-        println!("Error in insert_image command: {}", e);
-        warp::reject::custom(CustomError::new(e.to_string()))
-    })?;
-    println!("Image inserted successfully");
+    let slug = image.slug.clone();
+    repo.insert_image(file_manager.clone(), data, mime_type, image)
+        .await
+        .map_err(|e| write_error(e, &slug, "create"))?;
+    repo.add_media(file_manager, &slug, media)
+        .await
+        .map_err(|e| AppError::Internal(format!("Failed to add media to {}: {}", slug, e)))?;
+    tracing::info!(slug = %slug, "Image created");
 
     Ok(warp::reply::with_status(
         "Image created successfully!",
@@ -41,22 +49,21 @@ pub async fn admin_create_image_handler(
 pub async fn admin_update_image_handler(
     slug: String,
     form: FormData,
-    conn: Arc<Mutex<Connection>>,
+    repo: Arc<dyn Repository>,
     file_manager: Arc<ImageFileManager>,
 ) -> Result<impl Reply, warp::Rejection> {
-    let (image, image_data) = process_image_form(form).await?;
-
-    let conn_guard = conn.lock().map_err(|_| {
-        warp::reject::custom(CustomError {
-            message: "Internal server error".to_string(),
-        })
-    })?;
-
-    commands::update_image(&conn_guard, &file_manager, &slug, image_data, image).map_err(|e| {
-        warp::reject::custom(CustomError {
-            message: format!("Failed to update image: {}", e),
-        })
-    })?;
+    let (image, image_data, media) = process_image_form(form).await?;
+
+    let new_slug = image.slug.clone();
+    repo.update_image(file_manager.clone(), &slug, image_data, image)
+        .await
+        .map_err(|e| match e.is_not_found() {
+            true => AppError::NotFound("Image not found".to_string()),
+            false => write_error(e, &new_slug, "update"),
+        })?;
+    repo.add_media(file_manager, &new_slug, media)
+        .await
+        .map_err(|e| AppError::Internal(format!("Failed to add media to {}: {}", new_slug, e)))?;
 
     Ok(warp::reply::with_status(
         "Image updated successfully!",
@@ -66,20 +73,15 @@ pub async fn admin_update_image_handler(
 
 pub async fn admin_delete_image_handler(
     slug: String,
-    conn: Arc<Mutex<Connection>>,
+    repo: Arc<dyn Repository>,
     file_manager: Arc<ImageFileManager>,
 ) -> Result<impl Reply, warp::Rejection> {
-    let conn_guard = conn.lock().map_err(|_| {
-        warp::reject::custom(CustomError {
-            message: "Internal server error".to_string(),
-        })
-    })?;
-
-    commands::delete_image(&conn_guard, &file_manager, &slug).map_err(|e| {
-        warp::reject::custom(CustomError {
-            message: format!("Failed to delete image: {}", e),
-        })
-    })?;
+    repo.delete_image(file_manager, &slug)
+        .await
+        .map_err(|e| match e.is_not_found() {
+            true => AppError::NotFound("Image not found".to_string()),
+            false => AppError::Internal(format!("Failed to delete image {}: {}", slug, e)),
+        })?;
 
     Ok(warp::reply::with_status(
         "Image deleted successfully!",
@@ -87,50 +89,446 @@ pub async fn admin_delete_image_handler(
     ))
 }
 
-pub async fn admin_login_handler(
-    credentials: LoginCredentials,
-    conn: Arc<Mutex<Connection>>,
+/// Saves the alt text and captions of an image's media and reorders them
+/// to match the list sent, which must name every item.
+pub async fn admin_update_media_handler(
+    slug: String,
+    media: Vec<Media>,
+    repo: Arc<dyn Repository>,
 ) -> Result<impl Reply, warp::Rejection> {
-    let conn_guard = conn.lock().map_err(|_| {
-        warp::reject::custom(CustomError {
-            message: "Internal server error".to_string(),
-        })
+    if media.iter().any(|item| item.alt.trim().is_empty()) {
+        return Err(AppError::Validation("Every media item needs alt text".to_string()).into());
+    }
+
+    repo.update_media(&slug, media).await.map_err(|e| match e {
+        RepoError::Invalid(message) => AppError::Validation(message),
+        e if e.is_not_found() => AppError::NotFound("Media not found".to_string()),
+        e => AppError::Internal(format!("Failed to update media of {}: {}", slug, e)),
     })?;
 
-    // Verify credentials
-    let is_valid =
-        crate::database::verify_user(&conn_guard, &credentials.email, &credentials.password)
-            .map_err(|_| {
-                warp::reject::custom(CustomError {
-                    message: "Authentication error".to_string(),
-                })
-            })?;
-
-    if !is_valid {
-        return Err(warp::reject::custom(CustomError {
-            message: "Invalid credentials".to_string(),
-        }));
+    Ok(warp::reply::with_status(
+        "Media updated successfully!",
+        warp::http::StatusCode::OK,
+    ))
+}
+
+pub async fn admin_delete_media_handler(
+    slug: String,
+    id: i64,
+    repo: Arc<dyn Repository>,
+    file_manager: Arc<ImageFileManager>,
+) -> Result<impl Reply, warp::Rejection> {
+    repo.delete_media(file_manager, &slug, id)
+        .await
+        .map_err(|e| match e.is_not_found() {
+            true => AppError::NotFound("Media not found".to_string()),
+            false => {
+                AppError::Internal(format!("Failed to delete media {} of {}: {}", id, slug, e))
+            }
+        })?;
+    tracing::info!(slug = %slug, id, "Media deleted");
+
+    Ok(warp::reply::with_status(
+        "Media deleted successfully!",
+        warp::http::StatusCode::OK,
+    ))
+}
+
+/// Makes a media item the image's primary shot; the old primary shot
+/// takes its place in the media list.
+pub async fn admin_primary_media_handler(
+    slug: String,
+    id: i64,
+    repo: Arc<dyn Repository>,
+) -> Result<impl Reply, warp::Rejection> {
+    repo.make_media_primary(&slug, id)
+        .await
+        .map_err(|e| match e {
+            RepoError::Invalid(message) => AppError::Validation(message),
+            e if e.is_not_found() => AppError::NotFound("Media not found".to_string()),
+            e => AppError::Internal(format!(
+                "Failed to make media {} of {} primary: {}",
+                id, slug, e
+            )),
+        })?;
+    tracing::info!(slug = %slug, id, "Media made primary");
+
+    Ok(warp::reply::with_status(
+        "Primary shot updated!",
+        warp::http::StatusCode::OK,
+    ))
+}
+
+/// Lists images whose file matches the uploaded bytes, so the form can warn
+/// that saving will reuse an existing file.
+pub async fn admin_duplicates_handler(
+    body: bytes::Bytes,
+    repo: Arc<dyn Repository>,
+) -> Result<impl Reply, warp::Rejection> {
+    let matches = repo
+        .find_duplicates(body)
+        .await
+        .map_err(|e| AppError::Internal(format!("Failed to check for duplicates: {}", e)))?;
+
+    Ok(warp::reply::json(&matches))
+}
+
+/// Renders a description the way the site will show it, for the edit
+/// forms' preview. Drafts skip the render cache so they don't push out
+/// published descriptions.
+pub async fn admin_markdown_preview_handler(
+    preview: MarkdownPreview,
+) -> Result<impl Reply, warp::Rejection> {
+    let html = markdown::to_html(&preview.markdown);
+    let excerpt = markdown::truncate(
+        &markdown::to_text(&preview.markdown),
+        markdown::EXCERPT_LENGTH,
+    );
+    Ok(warp::reply::json(&serde_json::json!({
+        "html": html,
+        "excerpt": excerpt,
+    })))
+}
+
+pub async fn admin_create_page_handler(
+    mut page: Page,
+    config: Arc<Config>,
+    repo: Arc<dyn Repository>,
+) -> Result<impl Reply, warp::Rejection> {
+    page.title = page.title.trim().to_string();
+    pages::validate(&page, &config, None).map_err(AppError::Validation)?;
+
+    let slug = page.slug.clone();
+    repo.insert_page(page)
+        .await
+        .map_err(|e| page_write_error(e, &slug, "create"))?;
+    tracing::info!(slug = %slug, "Page created");
+
+    Ok(warp::reply::with_status(
+        "Page created successfully!",
+        warp::http::StatusCode::OK,
+    ))
+}
+
+pub async fn admin_update_page_handler(
+    slug: String,
+    mut page: Page,
+    config: Arc<Config>,
+    repo: Arc<dyn Repository>,
+) -> Result<impl Reply, warp::Rejection> {
+    let current = repo
+        .page(&slug)
+        .await
+        .map_err(|e| AppError::Internal(format!("Failed to get page {}: {}", slug, e)))?
+        .ok_or_else(|| AppError::NotFound("Page not found".to_string()))?;
+    page.title = page.title.trim().to_string();
+    pages::validate(&page, &config, Some(&current.template)).map_err(AppError::Validation)?;
+
+    let new_slug = page.slug.clone();
+    repo.update_page(&slug, page)
+        .await
+        .map_err(|e| match e.is_not_found() {
+            true => AppError::NotFound("Page not found".to_string()),
+            false => page_write_error(e, &new_slug, "update"),
+        })?;
+
+    Ok(warp::reply::with_status(
+        "Page updated successfully!",
+        warp::http::StatusCode::OK,
+    ))
+}
+
+pub async fn admin_delete_page_handler(
+    slug: String,
+    repo: Arc<dyn Repository>,
+) -> Result<impl Reply, warp::Rejection> {
+    repo.delete_page(&slug)
+        .await
+        .map_err(|e| match e.is_not_found() {
+            true => AppError::NotFound("Page not found".to_string()),
+            false => AppError::Internal(format!("Failed to delete page {}: {}", slug, e)),
+        })?;
+    tracing::info!(slug = %slug, "Page deleted");
+
+    Ok(warp::reply::with_status(
+        "Page deleted successfully!",
+        warp::http::StatusCode::OK,
+    ))
+}
+
+/// Saves a content type written in the admin, adding it or replacing the
+/// one of the same name, and puts it in service.
+pub async fn admin_save_content_type_handler(
+    form: ContentTypeForm,
+    config: Arc<Config>,
+    repo: Arc<dyn Repository>,
+) -> Result<impl Reply, warp::Rejection> {
+    let name = form.name.trim();
+    let content_type = ContentType::parse(name, &form.definition).map_err(AppError::Validation)?;
+    // The type's routes run before the pages', so a page at its path would
+    // become unreachable
+    let pages = repo
+        .pages()
+        .await
+        .map_err(|e| AppError::Internal(format!("Failed to check pages: {}", e)))?;
+    if let Some(page) = pages.iter().find(|page| page.slug == content_type.path) {
+        return Err(AppError::Conflict(format!(
+            "The page '{}' already uses the path '/{}'",
+            page.title, content_type.path
+        ))
+        .into());
     }
 
-    // Get user ID
-    let user_id: i64 = conn_guard
-        .query_row(
-            "SELECT id FROM users WHERE email = ?",
-            [&credentials.email],
-            |row| row.get(0),
-        )
-        .map_err(|_| {
-            warp::reject::custom(CustomError {
-                message: "User not found".to_string(),
-            })
+    let mut saved = saved_content_types(repo.as_ref()).await?;
+    saved.insert(name.to_string(), content_type);
+    store_content_types(&saved, &config, repo.as_ref()).await?;
+    tracing::info!(content_type = %name, "Content type saved");
+
+    Ok(warp::reply::with_status(
+        "Content type saved successfully!",
+        warp::http::StatusCode::OK,
+    ))
+}
+
+/// Removes a content type written in the admin. Its entries have to be
+/// deleted first, so none are left unreachable.
+pub async fn admin_delete_content_type_handler(
+    name: String,
+    config: Arc<Config>,
+    repo: Arc<dyn Repository>,
+) -> Result<impl Reply, warp::Rejection> {
+    if content_types::active().is_configured(&name) {
+        return Err(AppError::Validation(format!(
+            "'{}' is declared in the configuration; remove it there",
+            name
+        ))
+        .into());
+    }
+    let mut saved = saved_content_types(repo.as_ref()).await?;
+    if saved.remove(&name).is_none() {
+        return Err(AppError::NotFound("Content type not found".to_string()).into());
+    }
+    let entries = repo
+        .entries(&name)
+        .await
+        .map_err(|e| AppError::Internal(format!("Failed to get {} entries: {}", name, e)))?;
+    if !entries.is_empty() {
+        return Err(
+            AppError::Conflict(format!("'{}' still has entries; delete them first", name)).into(),
+        );
+    }
+
+    store_content_types(&saved, &config, repo.as_ref()).await?;
+    tracing::info!(content_type = %name, "Content type deleted");
+
+    Ok(warp::reply::with_status(
+        "Content type deleted successfully!",
+        warp::http::StatusCode::OK,
+    ))
+}
+
+async fn saved_content_types(
+    repo: &dyn Repository,
+) -> Result<BTreeMap<String, ContentType>, AppError> {
+    content_types::saved(repo)
+        .await
+        .map_err(|e| AppError::Internal(format!("Failed to read content types: {}", e)))
+}
+
+/// Checks the admin's types still fit beside the configured ones before
+/// saving them and putting them in service.
+async fn store_content_types(
+    saved: &BTreeMap<String, ContentType>,
+    config: &Config,
+    repo: &dyn Repository,
+) -> Result<(), AppError> {
+    let registry = Registry::build(config, saved).map_err(AppError::Validation)?;
+    let json = serde_json::to_string(saved)
+        .map_err(|e| AppError::Internal(format!("Failed to encode content types: {}", e)))?;
+    repo.set_setting(content_types::SETTINGS_KEY, &json)
+        .await
+        .map_err(|e| AppError::Internal(format!("Failed to save content types: {}", e)))?;
+    content_types::apply(registry);
+    Ok(())
+}
+
+pub async fn admin_create_entry_handler(
+    name: String,
+    mut entry: Entry,
+    repo: Arc<dyn Repository>,
+) -> Result<impl Reply, warp::Rejection> {
+    let content_type = entry_content_type(&name)?;
+    check_entry(&content_type, &mut entry, repo.as_ref()).await?;
+
+    let slug = entry.slug.clone();
+    repo.insert_entry(entry)
+        .await
+        .map_err(|e| entry_write_error(e, &content_type, &slug, "create"))?;
+    tracing::info!(content_type = %name, slug = %slug, "Entry created");
+
+    Ok(warp::reply::with_status(
+        "Entry created successfully!",
+        warp::http::StatusCode::OK,
+    ))
+}
+
+pub async fn admin_update_entry_handler(
+    name: String,
+    slug: String,
+    mut entry: Entry,
+    repo: Arc<dyn Repository>,
+) -> Result<impl Reply, warp::Rejection> {
+    let content_type = entry_content_type(&name)?;
+    check_entry(&content_type, &mut entry, repo.as_ref()).await?;
+
+    let new_slug = entry.slug.clone();
+    repo.update_entry(&slug, entry)
+        .await
+        .map_err(|e| match e.is_not_found() {
+            true => AppError::NotFound(format!("{} not found", content_type.singular)),
+            false => entry_write_error(e, &content_type, &new_slug, "update"),
         })?;
 
-    // Create session
-    let session_id = crate::database::create_session(&conn_guard, user_id).map_err(|_| {
-        warp::reject::custom(CustomError {
-            message: "Failed to create session".to_string(),
-        })
-    })?;
+    Ok(warp::reply::with_status(
+        "Entry updated successfully!",
+        warp::http::StatusCode::OK,
+    ))
+}
+
+pub async fn admin_delete_entry_handler(
+    name: String,
+    slug: String,
+    repo: Arc<dyn Repository>,
+) -> Result<impl Reply, warp::Rejection> {
+    let content_type = entry_content_type(&name)?;
+    repo.delete_entry(&name, &slug)
+        .await
+        .map_err(|e| match e.is_not_found() {
+            true => AppError::NotFound(format!("{} not found", content_type.singular)),
+            false => AppError::Internal(format!("Failed to delete entry {}: {}", slug, e)),
+        })?;
+    tracing::info!(content_type = %name, slug = %slug, "Entry deleted");
+
+    Ok(warp::reply::with_status(
+        "Entry deleted successfully!",
+        warp::http::StatusCode::OK,
+    ))
+}
+
+fn entry_content_type(name: &str) -> Result<Arc<ContentType>, AppError> {
+    content_types::active()
+        .get(name)
+        .ok_or_else(|| AppError::NotFound("Content type not found".to_string()))
+}
+
+/// Validates an entry against its type, including that the images it
+/// points at are in the gallery.
+async fn check_entry(
+    content_type: &ContentType,
+    entry: &mut Entry,
+    repo: &dyn Repository,
+) -> Result<(), AppError> {
+    entry.content_type = content_type.name.clone();
+    content_type.validate(entry).map_err(AppError::Validation)?;
+    for slug in content_type.image_refs(entry) {
+        let image = repo
+            .image(slug)
+            .await
+            .map_err(|e| AppError::Internal(format!("Failed to get image {}: {}", slug, e)))?;
+        if image.is_none() {
+            return Err(AppError::Validation(format!("No image '{}'", slug)));
+        }
+    }
+    Ok(())
+}
+
+pub async fn admin_cache_stats_handler() -> Result<impl Reply, warp::Rejection> {
+    Ok(warp::reply::json(
+        &crate::render_cache::RENDER_CACHE.stats(),
+    ))
+}
+
+pub async fn admin_db_stats_handler(
+    repo: Arc<dyn Repository>,
+) -> Result<impl Reply, warp::Rejection> {
+    Ok(warp::reply::json(&repo.stats()))
+}
+
+/// Switches the site to another installed theme, with whatever settings
+/// were last saved for it.
+pub async fn admin_activate_theme_handler(
+    name: String,
+    config: Arc<Config>,
+    repo: Arc<dyn Repository>,
+) -> Result<impl Reply, warp::Rejection> {
+    let themes_dir = config.storage.themes_path();
+    let lookup = name.clone();
+    let theme = tokio::task::spawn_blocking(move || themes::find(&themes_dir, &lookup))
+        .await
+        .map_err(|e| AppError::Internal(format!("Theme lookup task failed: {}", e)))?
+        .map_err(AppError::NotFound)?;
+    let values = themes::saved_values(repo.as_ref(), &name)
+        .await
+        .map_err(|e| AppError::Internal(format!("Failed to read theme settings: {}", e)))?;
+
+    let previous = themes::active();
+    apply_theme(theme, values).await?;
+    if let Err(e) = repo.set_setting(themes::ACTIVE_THEME_KEY, &name).await {
+        let _ = apply_theme(previous.theme.clone(), previous.values.clone()).await;
+        return Err(AppError::Internal(format!("Failed to save the active theme: {}", e)).into());
+    }
+    tracing::info!(theme = %name, "Theme activated");
+
+    Ok(warp::reply::with_status(
+        "Theme activated successfully!",
+        warp::http::StatusCode::OK,
+    ))
+}
+
+/// Saves the active theme's settings. Settings left out of the body keep
+/// their current value.
+pub async fn admin_theme_settings_handler(
+    input: BTreeMap<String, String>,
+    repo: Arc<dyn Repository>,
+) -> Result<impl Reply, warp::Rejection> {
+    let active = themes::active();
+    let values = active.validate(&input).map_err(AppError::Validation)?;
+    let json = serde_json::to_string(&values)
+        .map_err(|e| AppError::Internal(format!("Failed to encode theme settings: {}", e)))?;
+    repo.set_setting(&themes::settings_key(active.theme.name()), &json)
+        .await
+        .map_err(|e| AppError::Internal(format!("Failed to save theme settings: {}", e)))?;
+    apply_theme(active.theme.clone(), values).await?;
+    tracing::info!(theme = %active.theme.name(), "Theme settings saved");
+
+    Ok(warp::reply::with_status(
+        "Theme settings saved successfully!",
+        warp::http::StatusCode::OK,
+    ))
+}
+
+/// Compiling templates reads from disk, so it's kept off the runtime.
+async fn apply_theme(
+    theme: themes::Theme,
+    values: BTreeMap<String, String>,
+) -> Result<(), AppError> {
+    let name = theme.name().to_string();
+    tokio::task::spawn_blocking(move || themes::apply(theme, values))
+        .await
+        .map_err(|e| AppError::Internal(format!("Theme task failed: {}", e)))?
+        .map_err(|e| AppError::Internal(format!("Failed to load theme {}: {}", name, e)))
+}
+
+pub async fn admin_login_handler(
+    credentials: LoginCredentials,
+    repo: Arc<dyn Repository>,
+) -> Result<impl Reply, warp::Rejection> {
+    let session_id = repo
+        .login(&credentials.email, &credentials.password)
+        .await
+        .map_err(|e| AppError::Internal(format!("Failed to log in: {}", e)))?
+        .ok_or_else(|| AppError::Unauthorized("Invalid credentials".to_string()))?;
+    logging::record_user(&credentials.email);
 
     // Create response with session cookie
     let cookie = format!("session={}; Path=/; HttpOnly; SameSite=Strict", session_id);
@@ -144,19 +542,12 @@ pub async fn admin_login_handler(
 
 pub async fn admin_logout_handler(
     session_id: Option<String>,
-    conn: Arc<Mutex<Connection>>,
+    repo: Arc<dyn Repository>,
 ) -> Result<impl Reply, warp::Rejection> {
     // If we have a session cookie, delete it from the database
     if let Some(session_id) = session_id {
-        let conn_guard = conn.lock().map_err(|_| {
-            warp::reject::custom(CustomError {
-                message: "Internal server error".to_string(),
-            })
-        })?;
-
-        // Delete the session from the database
-        if let Err(e) = crate::database::delete_session(&conn_guard, &session_id) {
-            eprintln!("Failed to delete session: {:?}", e);
+        if let Err(e) = repo.delete_session(&session_id).await {
+            tracing::error!(error = %e, "Failed to delete session");
         }
     }
 
@@ -170,157 +561,177 @@ pub async fn admin_logout_handler(
     ))
 }
 
+pub async fn admin_forgot_password_handler(
+    request: ForgotPasswordRequest,
+    config: Arc<Config>,
+    repo: Arc<dyn Repository>,
+    mailer: Arc<dyn Mailer>,
+) -> Result<impl Reply, warp::Rejection> {
+    let token = repo
+        .create_password_reset(request.email.trim(), config.mail.reset_token_minutes)
+        .await
+        .map_err(|e| AppError::Internal(format!("Failed to create password reset: {}", e)))?;
+
+    // Send in the background and respond the same way whether or not the
+    // account exists, so the endpoint can't be used to probe for emails.
+    if let Some(token) = token {
+        let email = Email {
+            to: request.email.trim().to_string(),
+            subject: format!("Reset your {} admin password", config.site.name),
+            body: format!(
+                "Someone requested a password reset for your {} admin account.\n\n\
+                 To choose a new password, open this link within {} minutes:\n\n\
+                 {}/admin/reset?token={}\n\n\
+                 If you didn't request this, you can ignore this email.\n",
+                config.site.name,
+                config.mail.reset_token_minutes,
+                config.site.base_url.trim_end_matches('/'),
+                token
+            ),
+        };
+
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = mailer.send(&email) {
+                tracing::error!(error = %e, "Failed to send password reset email");
+            }
+        });
+    }
+
+    Ok(warp::reply::with_status(
+        "If that account exists, a reset link has been sent.",
+        warp::http::StatusCode::OK,
+    ))
+}
+
+pub async fn admin_reset_password_handler(
+    request: ResetPasswordRequest,
+    repo: Arc<dyn Repository>,
+) -> Result<impl Reply, warp::Rejection> {
+    if request.password.is_empty() {
+        return Err(AppError::Validation("Password cannot be empty".to_string()).into());
+    }
+
+    let reset = repo
+        .consume_password_reset(&request.token, &request.password)
+        .await
+        .map_err(|e| AppError::Internal(format!("Failed to reset password: {}", e)))?;
+
+    if !reset {
+        return Err(
+            AppError::Validation("This reset link is invalid or has expired".to_string()).into(),
+        );
+    }
+
+    Ok(warp::reply::with_status(
+        "Password reset successfully",
+        warp::http::StatusCode::OK,
+    ))
+}
+
+/// A slug that's already taken is the client's to fix; anything else is ours.
+fn write_error(e: RepoError, slug: &str, action: &str) -> AppError {
+    if e.is_unique_violation() {
+        AppError::Conflict(format!("An image with the slug '{}' already exists", slug))
+    } else {
+        AppError::Internal(format!("Failed to {} image {}: {}", action, slug, e))
+    }
+}
+
+fn page_write_error(e: RepoError, slug: &str, action: &str) -> AppError {
+    if e.is_unique_violation() {
+        AppError::Conflict(format!("A page with the slug '{}' already exists", slug))
+    } else {
+        AppError::Internal(format!("Failed to {} page {}: {}", action, slug, e))
+    }
+}
+
+fn entry_write_error(
+    e: RepoError,
+    content_type: &ContentType,
+    slug: &str,
+    action: &str,
+) -> AppError {
+    if e.is_unique_violation() {
+        AppError::Conflict(format!(
+            "{} already has an entry with the slug '{}'",
+            content_type.label, slug
+        ))
+    } else {
+        AppError::Internal(format!("Failed to {} entry {}: {}", action, slug, e))
+    }
+}
+
+async fn read_part(part: warp::multipart::Part) -> Result<Vec<u8>, AppError> {
+    part.stream()
+        .try_fold(Vec::new(), |mut vec, data| {
+            vec.extend_from_slice(data.chunk());
+            async move { Ok(vec) }
+        })
+        .await
+        .map_err(|e| AppError::Validation(format!("Failed to read form: {}", e)))
+}
+
+async fn read_text(part: warp::multipart::Part) -> Result<String, AppError> {
+    let name = part.name().to_string();
+    String::from_utf8(read_part(part).await?)
+        .map_err(|_| AppError::Validation(format!("Field '{}' is not valid UTF-8", name)))
+}
+
+/// Reads an uploaded file, rejecting ones over the size limit.
+async fn read_upload(part: warp::multipart::Part) -> Result<(Vec<u8>, mime::Mime), AppError> {
+    let mime_type = part
+        .content_type()
+        .and_then(|ct| ct.parse::<mime::Mime>().ok())
+        .unwrap_or(mime::APPLICATION_OCTET_STREAM);
+
+    let bytes = read_part(part).await.inspect_err(|e| {
+        tracing::warn!(error = %e, "Failed to read uploaded image");
+    })?;
+
+    tracing::debug!(bytes = bytes.len(), mime_type = %mime_type, "Received image upload");
+    metrics::observe_upload(bytes.len());
+
+    if !bytes.is_empty() && bytes.len() > 10_000_000 {
+        return Err(AppError::PayloadTooLarge("File too large".to_string()));
+    }
+
+    Ok((bytes, mime_type))
+}
+
+/// Reads the image form. Besides the main `image` file it may carry any
+/// number of `media` files, each followed by its `media_alt` and
+/// `media_caption`, which are appended to the image's media in order.
 async fn process_image_form(
     mut form: FormData,
-) -> Result<(Image, Option<(Vec<u8>, mime::Mime)>), warp::Rejection> {
-    println!("Starting to process image form");
-
+) -> Result<(Image, Option<(Vec<u8>, mime::Mime)>, Vec<MediaUpload>), AppError> {
     let mut alt = String::new();
     let mut description = String::new();
     let mut slug = String::new();
     let mut keywords_str = String::new();
     let mut image_data = None;
+    let mut media_files = Vec::new();
+    let mut media_alts = Vec::new();
+    let mut media_captions = Vec::new();
 
     while let Ok(Some(part)) = form.try_next().await {
-        println!("Processing form part: {}", part.name());
-
-        let mime_type = if part.name() == "image" {
-            let content_type = part.content_type();
-            println!("Image content type from form: {:?}", content_type);
-
-            content_type
-                .map(|ct| {
-                    let parsed = ct
-                        .parse::<mime::Mime>()
-                        .unwrap_or(mime::APPLICATION_OCTET_STREAM);
-                    println!("Parsed mime type: {:?}", parsed);
-                    parsed
-                })
-                .unwrap_or_else(|| {
-                    println!("No content type provided, using default");
-                    mime::APPLICATION_OCTET_STREAM
-                })
-        } else {
-            mime::APPLICATION_OCTET_STREAM
-        };
+        tracing::trace!(part = part.name(), "Reading form part");
 
         match part.name() {
-            "alt" => {
-                let bytes = part
-                    .stream()
-                    .try_fold(Vec::new(), |mut vec, data| {
-                        vec.extend_from_slice(data.chunk());
-                        async move { Ok(vec) }
-                    })
-                    .await
-                    .map_err(|e| {
-                        warp::reject::custom(CustomError {
-                            message: e.to_string(),
-                        })
-                    })?;
-                alt = String::from_utf8(bytes).map_err(|e| {
-                    warp::reject::custom(CustomError {
-                        message: e.to_string(),
-                    })
-                })?;
-            }
-            "description" => {
-                let bytes = part
-                    .stream()
-                    .try_fold(Vec::new(), |mut vec, data| {
-                        vec.extend_from_slice(data.chunk());
-                        async move { Ok(vec) }
-                    })
-                    .await
-                    .map_err(|e| {
-                        warp::reject::custom(CustomError {
-                            message: e.to_string(),
-                        })
-                    })?;
-                description = String::from_utf8(bytes).map_err(|e| {
-                    warp::reject::custom(CustomError {
-                        message: e.to_string(),
-                    })
-                })?;
-            }
-            "slug" => {
-                let bytes = part
-                    .stream()
-                    .try_fold(Vec::new(), |mut vec, data| {
-                        vec.extend_from_slice(data.chunk());
-                        async move { Ok(vec) }
-                    })
-                    .await
-                    .map_err(|e| {
-                        warp::reject::custom(CustomError {
-                            message: e.to_string(),
-                        })
-                    })?;
-                slug = String::from_utf8(bytes).map_err(|e| {
-                    warp::reject::custom(CustomError {
-                        message: e.to_string(),
-                    })
-                })?;
-            }
-            "keywords" => {
-                let bytes = part
-                    .stream()
-                    .try_fold(Vec::new(), |mut vec, data| {
-                        vec.extend_from_slice(data.chunk());
-                        async move { Ok(vec) }
-                    })
-                    .await
-                    .map_err(|e| {
-                        warp::reject::custom(CustomError {
-                            message: e.to_string(),
-                        })
-                    })?;
-                keywords_str = String::from_utf8(bytes).map_err(|e| {
-                    warp::reject::custom(CustomError {
-                        message: e.to_string(),
-                    })
-                })?;
-            }
-            "image" => {
-                let bytes = part
-                    .stream()
-                    .try_fold(Vec::new(), |mut vec, data| {
-                        vec.extend_from_slice(data.chunk());
-                        async move { Ok(vec) }
-                    })
-                    .await
-                    .map_err(|e| {
-                        println!("Error reading image data: {:?}", e);
-                        warp::reject::custom(CustomError {
-                            message: e.to_string(),
-                        })
-                    })?;
-
-                println!("Received image data: {} bytes", bytes.len());
-
-                if !bytes.is_empty() && bytes.len() > 10_000_000 {
-                    println!("File too large: {} bytes", bytes.len());
-                    return Err(warp::reject::custom(CustomError::new(
-                        "File too large".to_string(),
-                    )));
-                }
-
-                println!(
-                    "Successfully stored image data with mime type: {:?}",
-                    mime_type
-                );
-                image_data = Some((bytes, mime_type));
-            }
+            "alt" => alt = read_text(part).await?,
+            "description" => description = read_text(part).await?,
+            "slug" => slug = read_text(part).await?,
+            "keywords" => keywords_str = read_text(part).await?,
+            "image" => image_data = Some(read_upload(part).await?),
+            "media" => media_files.push(read_upload(part).await?),
+            "media_alt" => media_alts.push(read_text(part).await?),
+            "media_caption" => media_captions.push(read_text(part).await?),
             _ => (),
         }
     }
 
     if alt.is_empty() || slug.is_empty() {
-        return Err(warp::reject::custom(CustomError::new(
-            "Missing required fields".to_string(),
-        )));
+        return Err(AppError::Validation("Missing required fields".to_string()));
     }
-
     // Convert keywords string to Vec<String>
     let keywords = keywords_str
         .split(',')
@@ -328,6 +739,28 @@ async fn process_image_form(
         .map(|s| s.trim().to_string())
         .collect();
 
+    // A file input left empty still sends an empty part, as do its fields
+    let mut media_alts = media_alts.into_iter();
+    let mut media_captions = media_captions.into_iter();
+    let media = media_files
+        .into_iter()
+        .map(|(data, mime_type)| {
+            let item_alt = media_alts.next().unwrap_or_default();
+            let caption = media_captions.next().unwrap_or_default();
+            (data, mime_type, item_alt, caption)
+        })
+        .filter(|(data, ..)| !data.is_empty())
+        .map(|(data, mime_type, item_alt, caption)| MediaUpload {
+            data,
+            mime_type,
+            alt: match item_alt.trim() {
+                "" => alt.clone(),
+                item_alt => item_alt.to_string(),
+            },
+            caption: caption.trim().to_string(),
+        })
+        .collect();
+
     Ok((
         Image {
             alt,
@@ -337,5 +770,6 @@ async fn process_image_form(
             filename: String::new(), // Will be set by command
         },
         image_data,
+        media,
     ))
 }
@@ -1,8 +1,12 @@
 use crate::config::Config;
-use crate::models::CustomError;
+use crate::content_types::{self, ContentType};
+use crate::error::AppError;
+use crate::models::{Entry, ResetPasswordQuery};
+use crate::pages;
+use crate::repository::Repository;
 use crate::template_utils::render_template;
-use rusqlite::Connection;
-use std::sync::{Arc, Mutex};
+use crate::themes;
+use std::sync::Arc;
 use tera::Context;
 use warp::Reply;
 
@@ -12,27 +16,34 @@ pub async fn admin_login_page_handler(config: Arc<Config>) -> Result<impl Reply,
     render_template("admin/login.html", &context).await
 }
 
-pub async fn admin_page_handler(
+pub async fn admin_forgot_password_page_handler(
     config: Arc<Config>,
-    conn: Arc<Mutex<Connection>>,
 ) -> Result<impl Reply, warp::Rejection> {
     let mut context = Context::new();
+    context.insert("site_name", &config.site.name);
+    render_template("admin/forgot_password.html", &context).await
+}
 
-    let images = {
-        let conn_guard = conn.lock().map_err(|e| {
-            eprintln!("Failed to lock mutex: {:?}", e);
-            warp::reject::custom(CustomError {
-                message: "Internal server error".to_string(),
-            })
-        })?;
+pub async fn admin_reset_password_page_handler(
+    query: ResetPasswordQuery,
+    config: Arc<Config>,
+) -> Result<impl Reply, warp::Rejection> {
+    let mut context = Context::new();
+    context.insert("site_name", &config.site.name);
+    context.insert("token", &query.token.unwrap_or_default());
+    render_template("admin/reset_password.html", &context).await
+}
 
-        crate::database::get_images(&conn_guard).map_err(|e| {
-            eprintln!("Failed to get images: {:?}", e);
-            warp::reject::custom(CustomError {
-                message: "Failed to load images".to_string(),
-            })
-        })?
-    };
+pub async fn admin_page_handler(
+    config: Arc<Config>,
+    repo: Arc<dyn Repository>,
+) -> Result<impl Reply, warp::Rejection> {
+    let mut context = Context::new();
+
+    let images = repo
+        .images()
+        .await
+        .map_err(|e| AppError::Internal(format!("Failed to get images: {}", e)))?;
 
     // Add all config-related context
     context.insert("site_name", &config.site.name);
@@ -60,7 +71,7 @@ pub async fn admin_new_image_handler(config: Arc<Config>) -> Result<impl Reply,
 pub async fn admin_edit_image_handler(
     slug: String,
     config: Arc<Config>,
-    conn: Arc<Mutex<Connection>>,
+    repo: Arc<dyn Repository>,
 ) -> Result<impl Reply, warp::Rejection> {
     let mut context = Context::new();
 
@@ -71,22 +82,236 @@ pub async fn admin_edit_image_handler(
     context.insert("images_path", &config.routes.images_path);
 
     // Load the image by slug
-    let image = {
-        let conn_guard = conn.lock().map_err(|e| {
-            eprintln!("Failed to lock mutex: {:?}", e);
-            warp::reject::custom(CustomError {
-                message: "Internal server error".to_string(),
+    let image = repo
+        .image(&slug)
+        .await
+        .map_err(|e| AppError::Internal(format!("Failed to get image {}: {}", slug, e)))?
+        .ok_or_else(|| AppError::NotFound("Image not found".to_string()))?;
+    let media = repo
+        .media(&slug)
+        .await
+        .map_err(|e| AppError::Internal(format!("Failed to get media of {}: {}", slug, e)))?;
+    context.insert("image", &image);
+    context.insert("media", &media);
+
+    render_template("admin/admin_edit_image.html", &context).await
+}
+
+pub async fn admin_themes_handler(config: Arc<Config>) -> Result<impl Reply, warp::Rejection> {
+    let mut context = Context::new();
+
+    context.insert("site_name", &config.site.name);
+    context.insert("title", &format!("Admin - {}", &config.site.name));
+    context.insert("base_url", &config.site.base_url);
+
+    let active = themes::active();
+    let themes_dir = config.storage.themes_path();
+    let installed = tokio::task::spawn_blocking(move || themes::discover(&themes_dir))
+        .await
+        .map_err(|e| AppError::Internal(format!("Theme discovery task failed: {}", e)))?;
+    let installed: Vec<_> = installed
+        .iter()
+        .map(|theme| {
+            serde_json::json!({
+                "manifest": theme.manifest,
+                "bundled": theme.is_bundled(),
+                "active": theme.name() == active.theme.name(),
+            })
+        })
+        .collect();
+    let settings: Vec<_> = active
+        .theme
+        .manifest
+        .settings
+        .iter()
+        .map(|spec| {
+            serde_json::json!({
+                "spec": spec,
+                "label": spec.label(),
+                "value": active.values.get(&spec.key),
             })
-        })?;
+        })
+        .collect();
+    context.insert("themes", &installed);
+    context.insert("active_theme", &active.theme.manifest);
+    context.insert("settings", &settings);
+
+    render_template("admin/admin_themes.html", &context).await
+}
+
+pub async fn admin_pages_handler(
+    config: Arc<Config>,
+    repo: Arc<dyn Repository>,
+) -> Result<impl Reply, warp::Rejection> {
+    let mut context = Context::new();
+
+    context.insert("site_name", &config.site.name);
+    context.insert("title", &format!("Admin - {}", &config.site.name));
+    context.insert("base_url", &config.site.base_url);
+
+    let pages = repo
+        .pages()
+        .await
+        .map_err(|e| AppError::Internal(format!("Failed to get pages: {}", e)))?;
+    context.insert("pages", &pages);
+
+    render_template("admin/admin_pages.html", &context).await
+}
+
+pub async fn admin_new_page_handler(config: Arc<Config>) -> Result<impl Reply, warp::Rejection> {
+    let mut context = Context::new();
+
+    context.insert("site_name", &config.site.name);
+    context.insert("title", &format!("Admin - {}", &config.site.name));
+    context.insert("base_url", &config.site.base_url);
+    context.insert("templates", &pages::templates());
+
+    render_template("admin/admin_page_form.html", &context).await
+}
+
+pub async fn admin_edit_page_handler(
+    slug: String,
+    config: Arc<Config>,
+    repo: Arc<dyn Repository>,
+) -> Result<impl Reply, warp::Rejection> {
+    let mut context = Context::new();
 
-        crate::database::get_image_by_slug(&conn_guard, &slug).map_err(|e| {
-            eprintln!("Failed to get image: {:?}", e);
-            warp::reject::custom(CustomError {
-                message: "Image not found".to_string(),
+    context.insert("site_name", &config.site.name);
+    context.insert("title", &format!("Admin - {}", &config.site.name));
+    context.insert("base_url", &config.site.base_url);
+
+    let page = repo
+        .page(&slug)
+        .await
+        .map_err(|e| AppError::Internal(format!("Failed to get page {}: {}", slug, e)))?
+        .ok_or_else(|| AppError::NotFound("Page not found".to_string()))?;
+
+    // Keep a template the active theme lacks selectable, so saving
+    // doesn't silently change it
+    let mut templates = pages::templates();
+    if !templates.contains(&page.template) {
+        templates.push(page.template.clone());
+    }
+    context.insert("templates", &templates);
+    context.insert("page", &page);
+
+    render_template("admin/admin_page_form.html", &context).await
+}
+
+pub async fn admin_content_types_handler(
+    config: Arc<Config>,
+) -> Result<impl Reply, warp::Rejection> {
+    let mut context = Context::new();
+
+    context.insert("site_name", &config.site.name);
+    context.insert("title", &format!("Admin - {}", &config.site.name));
+    context.insert("base_url", &config.site.base_url);
+
+    // Types from config.toml are listed but only editable there
+    let registry = content_types::active();
+    let types: Vec<_> = registry
+        .types()
+        .map(|content_type| {
+            let configured = registry.is_configured(&content_type.name);
+            serde_json::json!({
+                "content_type": content_type.as_ref(),
+                "configured": configured,
+                "definition": if configured { String::new() } else { content_type.to_toml() },
             })
-        })?
-    };
-    context.insert("image", &image);
+        })
+        .collect();
+    context.insert("types", &types);
 
-    render_template("admin/admin_edit_image.html", &context).await
+    render_template("admin/admin_types.html", &context).await
+}
+
+pub async fn admin_entries_handler(
+    name: String,
+    config: Arc<Config>,
+    repo: Arc<dyn Repository>,
+) -> Result<impl Reply, warp::Rejection> {
+    let mut context = Context::new();
+
+    context.insert("site_name", &config.site.name);
+    context.insert("title", &format!("Admin - {}", &config.site.name));
+    context.insert("base_url", &config.site.base_url);
+
+    let content_type = admin_content_type(&name)?;
+    let mut entries = repo
+        .entries(&name)
+        .await
+        .map_err(|e| AppError::Internal(format!("Failed to get {} entries: {}", name, e)))?;
+    content_type.sort_entries(&mut entries);
+    context.insert("content_type", content_type.as_ref());
+    context.insert("entries", &entries);
+
+    render_template("admin/admin_entries.html", &context).await
+}
+
+pub async fn admin_new_entry_handler(
+    name: String,
+    config: Arc<Config>,
+    repo: Arc<dyn Repository>,
+) -> Result<impl Reply, warp::Rejection> {
+    let content_type = admin_content_type(&name)?;
+    render_entry_form(&content_type, None, &config, repo.as_ref()).await
+}
+
+pub async fn admin_edit_entry_handler(
+    name: String,
+    slug: String,
+    config: Arc<Config>,
+    repo: Arc<dyn Repository>,
+) -> Result<impl Reply, warp::Rejection> {
+    let content_type = admin_content_type(&name)?;
+    let entry = repo
+        .entry(&name, &slug)
+        .await
+        .map_err(|e| AppError::Internal(format!("Failed to get entry {}: {}", slug, e)))?
+        .ok_or_else(|| AppError::NotFound(format!("{} not found", content_type.singular)))?;
+    render_entry_form(&content_type, Some(&entry), &config, repo.as_ref()).await
+}
+
+fn admin_content_type(name: &str) -> Result<Arc<ContentType>, AppError> {
+    content_types::active()
+        .get(name)
+        .ok_or_else(|| AppError::NotFound("Content type not found".to_string()))
+}
+
+/// The form is built from the type's fields, each with its current value.
+async fn render_entry_form(
+    content_type: &ContentType,
+    entry: Option<&Entry>,
+    config: &Config,
+    repo: &dyn Repository,
+) -> Result<impl Reply, warp::Rejection> {
+    let mut context = Context::new();
+
+    context.insert("site_name", &config.site.name);
+    context.insert("title", &format!("Admin - {}", &config.site.name));
+    context.insert("base_url", &config.site.base_url);
+
+    let fields: Vec<_> = content_type
+        .fields
+        .iter()
+        .map(|spec| {
+            serde_json::json!({
+                "spec": spec,
+                "label": spec.label(),
+                "value": entry.and_then(|entry| entry.fields.get(&spec.key)),
+            })
+        })
+        .collect();
+    let images = repo
+        .images()
+        .await
+        .map_err(|e| AppError::Internal(format!("Failed to get images: {}", e)))?;
+    context.insert("content_type", content_type);
+    context.insert("fields", &fields);
+    context.insert("images", &images);
+    if let Some(entry) = entry {
+        context.insert("entry", entry);
+    }
+
+    render_template("admin/admin_entry_form.html", &context).await
 }
@@ -0,0 +1,52 @@
+use warp::http::StatusCode;
+use warp::Reply;
+
+/// The fields worth logging from a report, under both the legacy
+/// `report-uri` names and the Reporting API's.
+const REPORT_FIELDS: [(&str, &str); 4] = [
+    ("document-uri", "documentURL"),
+    ("violated-directive", "effectiveDirective"),
+    ("blocked-uri", "blockedURL"),
+    ("source-file", "sourceFile"),
+];
+
+fn log_report(report: &serde_json::Value) {
+    let field = |(legacy, modern): (&str, &str)| {
+        report
+            .get(legacy)
+            .or_else(|| report.get(modern))
+            .and_then(|value| value.as_str())
+            .unwrap_or("-")
+            .to_string()
+    };
+    let [document, directive, blocked, source] = REPORT_FIELDS.map(field);
+    tracing::warn!(
+        target: "craftcms::csp",
+        document,
+        directive,
+        blocked,
+        source,
+        "Content-Security-Policy violation"
+    );
+}
+
+/// Collects violation reports sent by browsers, either a single
+/// `{"csp-report": {...}}` object or a Reporting API batch.
+pub async fn csp_report_handler(body: bytes::Bytes) -> Result<impl Reply, warp::Rejection> {
+    match serde_json::from_slice::<serde_json::Value>(&body) {
+        Ok(serde_json::Value::Array(reports)) => {
+            for report in &reports {
+                if let Some(body) = report.get("body") {
+                    log_report(body);
+                }
+            }
+        }
+        Ok(value) => {
+            if let Some(report) = value.get("csp-report") {
+                log_report(report);
+            }
+        }
+        Err(e) => tracing::debug!(error = %e, "Ignoring malformed CSP report"),
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,116 @@
+use crate::files::ImageFileManager;
+use crate::migrations::MIGRATIONS;
+use crate::repository::Repository;
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use warp::http::StatusCode;
+use warp::Reply;
+
+/// How long a storage check's result answers later probes. Probes come
+/// every few seconds from each load balancer, and each check is a write.
+const STORAGE_CHECK_TTL: Duration = Duration::from_secs(5);
+
+lazy_static! {
+    static ref STORAGE_CHECK: Mutex<Option<(Instant, Result<(), String>)>> = Mutex::new(None);
+}
+
+/// Liveness: the process is up and answering requests.
+pub async fn healthz_handler() -> Result<impl Reply, warp::Rejection> {
+    Ok("ok")
+}
+
+#[derive(Serialize)]
+struct Check {
+    name: &'static str,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Check {
+    fn new(name: &'static str, result: Result<(), String>) -> Self {
+        if let Err(e) = &result {
+            tracing::warn!(check = name, error = %e, "Readiness check failed");
+        }
+        Check {
+            name,
+            ok: result.is_ok(),
+            error: result.err(),
+        }
+    }
+}
+
+/// Readiness: the database answers with every migration applied and the
+/// image store accepted a write within the last few seconds. Answers 503
+/// listing what failed otherwise.
+pub async fn readyz_handler(
+    repo: Arc<dyn Repository>,
+    file_manager: Arc<ImageFileManager>,
+) -> Result<impl Reply, warp::Rejection> {
+    let (database, migrations) = match repo.schema_version().await {
+        Ok(version) if version >= MIGRATIONS.len() => (Ok(()), Ok(())),
+        Ok(version) => (
+            Ok(()),
+            Err(format!(
+                "{} of {} migrations applied",
+                version,
+                MIGRATIONS.len()
+            )),
+        ),
+        Err(e) => (Err(e.to_string()), Err("database unreachable".to_string())),
+    };
+    let storage = check_storage(file_manager).await;
+
+    let checks = vec![
+        Check::new("database", database),
+        Check::new("migrations", migrations),
+        Check::new("storage", storage),
+    ];
+    let status = if checks.iter().all(|check| check.ok) {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    Ok(warp::reply::with_status(warp::reply::json(&checks), status))
+}
+
+/// Runs the storage check at most once per `STORAGE_CHECK_TTL`; probes
+/// arriving while one runs wait for its result.
+async fn check_storage(file_manager: Arc<ImageFileManager>) -> Result<(), String> {
+    let mut cached = STORAGE_CHECK.lock().await;
+    if let Some((checked_at, result)) = cached.as_ref() {
+        if checked_at.elapsed() < STORAGE_CHECK_TTL {
+            return result.clone();
+        }
+    }
+
+    let result = tokio::task::spawn_blocking(move || file_manager.check_writable())
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|result| result.map_err(|e| e.to_string()));
+    *cached = Some((Instant::now(), result.clone()));
+    result
+}
+
+#[derive(Serialize)]
+struct VersionInfo {
+    name: &'static str,
+    version: &'static str,
+    commit: &'static str,
+    target: &'static str,
+    profile: &'static str,
+}
+
+/// Build information, set at compile time by `build.rs`.
+pub async fn version_handler() -> Result<impl Reply, warp::Rejection> {
+    Ok(warp::reply::json(&VersionInfo {
+        name: env!("CARGO_PKG_NAME"),
+        version: env!("CARGO_PKG_VERSION"),
+        commit: env!("CRAFTCMS_GIT_COMMIT"),
+        target: env!("CRAFTCMS_BUILD_TARGET"),
+        profile: env!("CRAFTCMS_BUILD_PROFILE"),
+    }))
+}
@@ -0,0 +1,44 @@
+use crate::config::Config;
+use crate::metrics;
+use crate::repository::Repository;
+use std::sync::Arc;
+use warp::http::{header, Response, StatusCode};
+use warp::Reply;
+
+/// Compares without stopping at the first differing byte, so response
+/// timing doesn't reveal how much of a guessed token was right.
+fn tokens_match(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// `/metrics` in the Prometheus text format. When `server.metrics_token` is
+/// set the request must carry it as a bearer token.
+pub async fn metrics_handler(
+    authorization: Option<String>,
+    config: Arc<Config>,
+    repo: Arc<dyn Repository>,
+) -> Result<impl Reply, warp::Rejection> {
+    if let Some(token) = &config.server.metrics_token {
+        let given = authorization
+            .as_deref()
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .unwrap_or("");
+        if !tokens_match(given, token) {
+            return Ok(Response::builder()
+                .status(StatusCode::UNAUTHORIZED)
+                .header(header::WWW_AUTHENTICATE, "Bearer")
+                .body("Unauthorized".to_string())
+                .unwrap());
+        }
+    }
+
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)
+        .body(metrics::gather(repo.as_ref()).await)
+        .unwrap())
+}
@@ -1,6 +1,12 @@
 pub mod admin;
+pub mod csp;
+pub mod health;
+pub mod metrics;
 pub mod site;
 
 // Re-export commonly used items
 pub use admin::*;
+pub use csp::*;
+pub use health::*;
+pub use metrics::*;
 pub use site::*;
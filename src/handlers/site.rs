@@ -1,30 +1,45 @@
-use crate::config::Config;
-use crate::models::CustomError;
+use crate::cache::{self, Validators};
+use crate::config::{Config, ServeMode};
+use crate::content_types::{self, ContentType};
+use crate::error::AppError;
+use crate::files::{ImageFileManager, VariantFormat};
+use crate::metrics;
+use crate::models::{
+    AssetQuery, Entry, EntryView, GalleryItem, ImageVariantQuery, ImageView, PageView,
+};
+use crate::pages;
+use crate::render_cache::{self, CachedPage, RenderCache, RENDER_CACHE};
+use crate::repository::Repository;
 use crate::templates::TEMPLATES;
-use rusqlite::Connection;
-use std::sync::{Arc, Mutex};
-use tera::{Context, Tera};
+use crate::themes;
+use std::sync::Arc;
+use std::time::Duration;
+use tera::Context;
+use warp::http::{header, HeaderMap, Response, StatusCode};
 use warp::Reply;
 
 pub async fn home_handler(
     config: Arc<Config>,
-    conn: Arc<Mutex<Connection>>,
+    repo: Arc<dyn Repository>,
+    query: String,
+    headers: HeaderMap,
 ) -> Result<impl Reply, warp::Rejection> {
+    let cache_key = RenderCache::key("/", &query);
+    if let Some(page) = RENDER_CACHE.get(&cache_key) {
+        return Ok(page_response(page, true, &config, &headers));
+    }
+    let generation = RENDER_CACHE.generation();
+
+    refresh_nav(&config, repo.as_ref()).await;
     let mut context = Context::new();
 
-    let conn_guard = conn.lock().map_err(|e| {
-        eprintln!("Failed to lock mutex: {:?}", e);
-        warp::reject::custom(CustomError {
-            message: "Internal server error".to_string(),
-        })
-    })?;
+    let images = repo
+        .images()
+        .await
+        .map_err(|e| AppError::Internal(format!("Failed to get images: {}", e)))?;
+    let images: Vec<ImageView> = images.into_iter().map(ImageView::from).collect();
 
-    let images = crate::database::get_images(&conn_guard).map_err(|e| {
-        eprintln!("Failed to get images: {:?}", e);
-        warp::reject::custom(CustomError {
-            message: "Failed to load images".to_string(),
-        })
-    })?;
+    let updated_at = repo.content_updated_at().await.ok().flatten();
 
     context.insert("title", &config.site.title);
     context.insert("description", &config.site.description);
@@ -34,55 +49,626 @@ pub async fn home_handler(
     context.insert("detail_path", &config.routes.detail_path);
     context.insert("author", &config.meta.author);
 
-    let rendered = TEMPLATES.render("home.html", &context).map_err(|e| {
-        eprintln!("Template rendering error: {:?}", e);
-        warp::reject::custom(CustomError {
-            message: "Failed to render page".to_string(),
-        })
-    })?;
+    let (rendered, mut tags) = render_cache::track_dependencies(|| {
+        metrics::time_render("home.html", || TEMPLATES.render("home.html", &context))
+    });
+    let rendered =
+        rendered.map_err(|e| AppError::Internal(format!("Failed to render home.html: {:?}", e)))?;
 
-    Ok(warp::reply::html(rendered))
+    let page = CachedPage {
+        body: rendered,
+        updated_at,
+    };
+    tags.push(render_cache::TAG_IMAGES.to_string());
+    RENDER_CACHE.insert(cache_key, page.clone(), tags, generation);
+
+    Ok(page_response(page, false, &config, &headers))
 }
 
 pub async fn post_detail_handler(
     slug: String,
     config: Arc<Config>,
-    conn: Arc<Mutex<Connection>>,
+    repo: Arc<dyn Repository>,
+    query: String,
+    headers: HeaderMap,
 ) -> Result<impl Reply, warp::Rejection> {
-    let tera = Tera::new("templates/**/*.html").expect("Failed to load templates");
+    let cache_key = RenderCache::key(&format!("/{}/{}", config.routes.detail_path, slug), &query);
+    if let Some(page) = RENDER_CACHE.get(&cache_key) {
+        return Ok(page_response(page, true, &config, &headers));
+    }
+    let generation = RENDER_CACHE.generation();
+
+    refresh_nav(&config, repo.as_ref()).await;
     let mut context = Context::new();
 
-    let conn_guard = conn.lock().map_err(|e| {
-        eprintln!("Failed to lock mutex: {:?}", e);
-        warp::reject::custom(CustomError {
-            message: "Internal server error".to_string(),
-        })
-    })?;
+    let image = repo
+        .image(&slug)
+        .await
+        .map_err(|e| AppError::Internal(format!("Failed to get image {}: {}", slug, e)))?
+        .ok_or_else(|| AppError::NotFound("Image not found".to_string()))?;
+    let media = repo
+        .media(&slug)
+        .await
+        .map_err(|e| AppError::Internal(format!("Failed to get media of {}: {}", slug, e)))?;
+    let gallery = GalleryItem::list(&image, media);
+    let image = ImageView::from(image);
 
-    let image = crate::database::get_image_by_slug(&conn_guard, &slug).map_err(|e| {
-        eprintln!("Failed to get image: {:?}", e);
-        warp::reject::custom(CustomError {
-            message: "Image not found".to_string(),
-        })
-    })?;
+    let updated_at = repo.image_updated_at(&slug).await.ok().flatten();
 
     context.insert(
         "title",
-        &format!("{} - {}", image.alt, config.meta.creator_suffix),
+        &format!("{} - {}", image.image.alt, config.meta.creator_suffix),
     );
-    context.insert("description", &image.description);
+    context.insert("description", &image.excerpt);
     context.insert("image", &image);
+    context.insert("gallery", &gallery);
     context.insert("url", &config.get_detail_url(&slug));
     context.insert("site_name", &config.site.name);
     context.insert("base_url", &config.site.base_url);
     context.insert("author", &config.meta.author);
 
-    let rendered = tera.render("post_detail.html", &context).map_err(|e| {
-        eprintln!("Template rendering error: {:?}", e);
-        warp::reject::custom(CustomError {
-            message: "Failed to render page".to_string(),
+    let (rendered, mut tags) = render_cache::track_dependencies(|| {
+        metrics::time_render("post_detail.html", || {
+            TEMPLATES.render("post_detail.html", &context)
         })
-    })?;
+    });
+    let rendered = rendered
+        .map_err(|e| AppError::Internal(format!("Failed to render post_detail.html: {:?}", e)))?;
+
+    let page = CachedPage {
+        body: rendered,
+        updated_at,
+    };
+    tags.push(render_cache::image_tag(&slug));
+    RENDER_CACHE.insert(cache_key, page.clone(), tags, generation);
+
+    Ok(page_response(page, false, &config, &headers))
+}
+
+/// Serves a published page at `/<slug>`. Registered after every other
+/// route, and page slugs can't take the paths those use.
+pub async fn page_handler(
+    slug: String,
+    config: Arc<Config>,
+    repo: Arc<dyn Repository>,
+    query: String,
+    headers: HeaderMap,
+) -> Result<impl Reply, warp::Rejection> {
+    let cache_key = RenderCache::key(&format!("/{}", slug), &query);
+    if let Some(page) = RENDER_CACHE.get(&cache_key) {
+        return Ok(page_response(page, true, &config, &headers));
+    }
+    let generation = RENDER_CACHE.generation();
+
+    let page = repo
+        .page(&slug)
+        .await
+        .map_err(|e| AppError::Internal(format!("Failed to get page {}: {}", slug, e)))?
+        .filter(|page| page.published);
+    // A plain not-found, unlike `AppError::NotFound`, never outranks what
+    // the other routes rejected this path with, such as `/admin` asking
+    // for a login
+    let Some(page) = page else {
+        return Err(warp::reject::not_found());
+    };
+
+    refresh_nav(&config, repo.as_ref()).await;
+    // A template the active theme doesn't have falls back to the default,
+    // which the bundled theme always provides
+    let template = if TEMPLATES.has_template(&page.template) {
+        page.template.clone()
+    } else {
+        pages::DEFAULT_TEMPLATE.to_string()
+    };
+    let updated_at = page.updated_at.clone();
+    let page = PageView::from(page);
+
+    let mut context = Context::new();
+    context.insert(
+        "title",
+        &format!("{} - {}", page.page.title, config.site.name),
+    );
+    context.insert("description", &page.excerpt);
+    context.insert("page", &page);
+    context.insert("url", &format!("{}/{}", config.site.base_url, slug));
+    context.insert("site_name", &config.site.name);
+    context.insert("base_url", &config.site.base_url);
+    context.insert("author", &config.meta.author);
+
+    let (rendered, tags) = render_cache::track_dependencies(|| {
+        metrics::time_render(&template, || TEMPLATES.render(&template, &context))
+    });
+    let rendered = rendered
+        .map_err(|e| AppError::Internal(format!("Failed to render {}: {:?}", template, e)))?;
+
+    let page = CachedPage {
+        body: rendered,
+        updated_at,
+    };
+    RENDER_CACHE.insert(cache_key, page.clone(), tags, generation);
+
+    Ok(page_response(page, false, &config, &headers))
+}
+
+/// Lists a content type's published entries at `/<path>`. A path no type
+/// uses is left to the page route after this one.
+pub async fn entries_handler(
+    path: String,
+    config: Arc<Config>,
+    repo: Arc<dyn Repository>,
+    query: String,
+    headers: HeaderMap,
+) -> Result<impl Reply, warp::Rejection> {
+    let Some(content_type) = content_types::active().by_path(&path) else {
+        return Err(warp::reject::not_found());
+    };
+    let cache_key = RenderCache::key(&format!("/{}", path), &query);
+    if let Some(page) = RENDER_CACHE.get(&cache_key) {
+        return Ok(page_response(page, true, &config, &headers));
+    }
+    let generation = RENDER_CACHE.generation();
+
+    refresh_nav(&config, repo.as_ref()).await;
+    let entries = published_entries(repo.as_ref(), &content_type).await?;
+    let updated_at = repo.content_updated_at().await.ok().flatten();
+
+    let mut context = Context::new();
+    context.insert(
+        "title",
+        &format!("{} - {}", content_type.label, config.site.name),
+    );
+    context.insert("description", &config.site.description);
+    context.insert("content_type", content_type.as_ref());
+    context.insert("entries", &entries);
+    context.insert(
+        "url",
+        &format!("{}{}", config.site.base_url, content_type.url()),
+    );
+    context.insert("site_name", &config.site.name);
+    context.insert("base_url", &config.site.base_url);
+    context.insert("author", &config.meta.author);
+
+    let template = content_type.list_template();
+    let (rendered, mut tags) = render_cache::track_dependencies(|| {
+        metrics::time_render(&template, || TEMPLATES.render(&template, &context))
+    });
+    let rendered = rendered
+        .map_err(|e| AppError::Internal(format!("Failed to render {}: {:?}", template, e)))?;
+
+    let page = CachedPage {
+        body: rendered,
+        updated_at,
+    };
+    tags.push(render_cache::entries_tag(&content_type.name));
+    RENDER_CACHE.insert(cache_key, page.clone(), tags, generation);
+
+    Ok(page_response(page, false, &config, &headers))
+}
+
+/// Serves a published entry at `/<path>/<slug>`.
+pub async fn entry_handler(
+    path: String,
+    slug: String,
+    config: Arc<Config>,
+    repo: Arc<dyn Repository>,
+    query: String,
+    headers: HeaderMap,
+) -> Result<impl Reply, warp::Rejection> {
+    let Some(content_type) = content_types::active().by_path(&path) else {
+        return Err(warp::reject::not_found());
+    };
+    let cache_key = RenderCache::key(&format!("/{}/{}", path, slug), &query);
+    if let Some(page) = RENDER_CACHE.get(&cache_key) {
+        return Ok(page_response(page, true, &config, &headers));
+    }
+    let generation = RENDER_CACHE.generation();
+
+    let entry = published_entry(repo.as_ref(), &content_type, &slug).await?;
+    refresh_nav(&config, repo.as_ref()).await;
+    let updated_at = entry.updated_at.clone();
+    let entry = EntryView::new(entry, &content_type);
+
+    let mut context = Context::new();
+    context.insert(
+        "title",
+        &format!("{} - {}", entry.entry.title, config.site.name),
+    );
+    context.insert("description", &entry.excerpt);
+    context.insert("content_type", content_type.as_ref());
+    context.insert("entry", &entry);
+    context.insert("url", &format!("{}{}", config.site.base_url, entry.url));
+    context.insert("site_name", &config.site.name);
+    context.insert("base_url", &config.site.base_url);
+    context.insert("author", &config.meta.author);
+
+    let template = content_type.detail_template();
+    let (rendered, mut tags) = render_cache::track_dependencies(|| {
+        metrics::time_render(&template, || TEMPLATES.render(&template, &context))
+    });
+    let rendered = rendered
+        .map_err(|e| AppError::Internal(format!("Failed to render {}: {:?}", template, e)))?;
+
+    let page = CachedPage {
+        body: rendered,
+        updated_at,
+    };
+    tags.push(render_cache::entry_tag(&content_type.name, &slug));
+    RENDER_CACHE.insert(cache_key, page.clone(), tags, generation);
+
+    Ok(page_response(page, false, &config, &headers))
+}
+
+/// `GET /api/<type>`: a content type's published entries as JSON, in
+/// listing order.
+pub async fn api_entries_handler(
+    name: String,
+    repo: Arc<dyn Repository>,
+) -> Result<impl Reply, warp::Rejection> {
+    let content_type = content_types::active()
+        .get(&name)
+        .ok_or_else(|| AppError::NotFound(format!("No content type '{}'", name)))?;
+    let entries = published_entries(repo.as_ref(), &content_type).await?;
+    Ok(warp::reply::json(&entries))
+}
+
+/// `GET /api/<type>/<slug>`: one published entry as JSON.
+pub async fn api_entry_handler(
+    name: String,
+    slug: String,
+    repo: Arc<dyn Repository>,
+) -> Result<impl Reply, warp::Rejection> {
+    let content_type = content_types::active()
+        .get(&name)
+        .ok_or_else(|| AppError::NotFound(format!("No content type '{}'", name)))?;
+    let entry = published_entry(repo.as_ref(), &content_type, &slug).await?;
+    Ok(warp::reply::json(&EntryView::new(entry, &content_type)))
+}
+
+async fn published_entries(
+    repo: &dyn Repository,
+    content_type: &ContentType,
+) -> Result<Vec<EntryView>, AppError> {
+    let mut entries: Vec<Entry> = repo
+        .entries(&content_type.name)
+        .await
+        .map_err(|e| {
+            AppError::Internal(format!(
+                "Failed to get {} entries: {}",
+                content_type.name, e
+            ))
+        })?
+        .into_iter()
+        .filter(|entry| entry.published)
+        .collect();
+    content_type.sort_entries(&mut entries);
+    Ok(entries
+        .into_iter()
+        .map(|entry| EntryView::new(entry, content_type))
+        .collect())
+}
+
+async fn published_entry(
+    repo: &dyn Repository,
+    content_type: &ContentType,
+    slug: &str,
+) -> Result<Entry, AppError> {
+    repo.entry(&content_type.name, slug)
+        .await
+        .map_err(|e| AppError::Internal(format!("Failed to get entry {}: {}", slug, e)))?
+        .filter(|entry| entry.published)
+        .ok_or_else(|| AppError::NotFound(format!("{} not found", content_type.singular)))
+}
+
+/// Brings `nav` up to date before a render. On failure the menu stays as it
+/// was rather than failing the page.
+async fn refresh_nav(config: &Config, repo: &dyn Repository) {
+    if let Err(e) = pages::refresh_nav(repo, config.cache.render_cache_ttl()).await {
+        tracing::warn!(error = %e, "Failed to refresh the page menu");
+    }
+}
+
+/// Wraps a rendered page with a short `Cache-Control`, an ETag of the body
+/// and a `Last-Modified` from the content's `updated_at`, answering 304 when
+/// the client's copy is still current. `X-Cache` reports whether the page
+/// came from the render cache.
+fn page_response(
+    page: CachedPage,
+    cached: bool,
+    config: &Config,
+    headers: &HeaderMap,
+) -> Response<String> {
+    let builder = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+        .header("x-cache", if cached { "HIT" } else { "MISS" });
+
+    let Some(cache_control) = config.cache.page_cache_control() else {
+        return builder.body(page.body).unwrap();
+    };
+
+    let validators = Validators {
+        etag: Some(cache::strong_etag(&crate::files::content_hash(
+            page.body.as_bytes(),
+        ))),
+        last_modified: page.updated_at.as_deref().and_then(cache::parse_timestamp),
+    };
+    if validators.is_not_modified(headers) {
+        return cache::not_modified(&validators, Some(&cache_control));
+    }
+
+    validators
+        .apply(builder.header(header::CACHE_CONTROL, cache_control))
+        .body(page.body)
+        .unwrap()
+}
+
+/// Serves `/images/<key>`, where the key is either a storage key such as
+/// `blobs/ab/<hash>.png` or an image slug. Legacy `<slug>.<ext>` URLs resolve
+/// through the slug too, since files are no longer stored under that name.
+/// Depending on `storage.serve_mode` the file is streamed through this
+/// server or the client is redirected to a CDN or signed URL. `?w=` and
+/// `?fmt=` ask for a resized variant, made on first request.
+pub async fn image_handler(
+    tail: warp::path::Tail,
+    variant: ImageVariantQuery,
+    config: Arc<Config>,
+    repo: Arc<dyn Repository>,
+    file_manager: Arc<ImageFileManager>,
+    headers: HeaderMap,
+) -> Result<impl Reply, warp::Rejection> {
+    let name = tail.as_str().to_string();
+    if name.is_empty() {
+        return Err(warp::reject::not_found());
+    }
+    let storage = file_manager.storage().clone();
+
+    let exists = {
+        let storage = storage.clone();
+        let key = name.clone();
+        tokio::task::spawn_blocking(move || storage.exists(&key))
+            .await
+            .map_err(|e| AppError::Internal(format!("Storage task failed: {}", e)))?
+            .map_err(|e| {
+                if e.kind() == std::io::ErrorKind::InvalidInput {
+                    return warp::reject::not_found();
+                }
+                AppError::Internal(format!("Failed to check image {}: {}", name, e)).into()
+            })?
+    };
+
+    // Requests by content-hash key get an immutable response; requests by
+    // slug can start pointing at a different file after an edit.
+    let (key, fingerprinted, updated_at) = if exists {
+        let updated_at = match cache::fingerprint(&name) {
+            Some(hash) => repo.blob_created_at(hash).await.ok().flatten(),
+            None => None,
+        };
+        let fingerprinted = cache::fingerprint(&name).is_some();
+        (name, fingerprinted, updated_at)
+    } else {
+        let stem = std::path::Path::new(&name)
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .unwrap_or(&name)
+            .to_string();
+        let image = match repo.image(&name).await {
+            Ok(Some(image)) => image,
+            Ok(None) if stem != name => repo
+                .image(&stem)
+                .await
+                .ok()
+                .flatten()
+                .ok_or_else(warp::reject::not_found)?,
+            Ok(None) => return Err(warp::reject::not_found()),
+            Err(e) => {
+                return Err(
+                    AppError::Internal(format!("Failed to look up image {}: {}", name, e)).into(),
+                )
+            }
+        };
+        let updated_at = repo.image_updated_at(&image.slug).await.ok().flatten();
+        (image.filename, false, updated_at)
+    };
+
+    // A resized variant stands in for the original from here on
+    let (key, variant_data) = match variant_request(&config, &variant, &key)? {
+        Some((width, format)) => {
+            let file_manager = file_manager.clone();
+            let quality = config.images.quality;
+            let (variant_key, data) = tokio::task::spawn_blocking(move || {
+                file_manager.variant(&key, width, format, quality)
+            })
+            .await
+            .map_err(|e| AppError::Internal(format!("Image variant task failed: {}", e)))?
+            .map_err(|e| {
+                if e.kind() == std::io::ErrorKind::NotFound {
+                    warp::reject::not_found()
+                } else {
+                    AppError::Internal(format!("Failed to make image variant: {}", e)).into()
+                }
+            })?;
+            (variant_key, Some(data))
+        }
+        None => (key, None),
+    };
+
+    let cache_control = config.cache.image_cache_control(fingerprinted);
+    let mut validators = Validators {
+        etag: cache::fingerprint(&key).map(cache::strong_etag),
+        last_modified: updated_at.as_deref().and_then(cache::parse_timestamp),
+    };
+    if config.cache.enabled && validators.is_not_modified(&headers) {
+        return Ok(cache::not_modified(&validators, cache_control.as_deref()));
+    }
+
+    if config.storage.serve_mode == ServeMode::Redirect {
+        let location = match &config.storage.public_url {
+            Some(public_url) => Some(format!("{}/{}", public_url.trim_end_matches('/'), key)),
+            None => {
+                let storage = storage.clone();
+                let key = key.clone();
+                let expires_in = Duration::from_secs(config.storage.signed_url_seconds);
+                tokio::task::spawn_blocking(move || storage.signed_url(&key, expires_in))
+                    .await
+                    .ok()
+                    .and_then(|result| {
+                        result
+                            .map_err(|e| tracing::error!(error = %e, "Failed to sign image URL"))
+                            .ok()
+                    })
+                    .flatten()
+            }
+        };
+
+        if let Some(location) = location {
+            return Ok(Response::builder()
+                .status(StatusCode::FOUND)
+                .header(header::LOCATION, location)
+                .body(Vec::new())
+                .unwrap());
+        }
+    }
+
+    let content_type = mime_guess::from_path(&key).first_or_octet_stream();
+    let data = match variant_data {
+        Some(data) => data,
+        None => tokio::task::spawn_blocking(move || storage.get(&key))
+            .await
+            .map_err(|e| AppError::Internal(format!("Storage task failed: {}", e)))?
+            .map_err(|e| {
+                if e.kind() == std::io::ErrorKind::NotFound {
+                    warp::reject::not_found()
+                } else {
+                    AppError::Internal(format!("Failed to read image: {}", e)).into()
+                }
+            })?,
+    };
+
+    if !config.cache.enabled {
+        return Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, content_type.essence_str())
+            .body(data)
+            .unwrap());
+    }
+
+    // Files stored before content-addressing have no hash in their key
+    if validators.etag.is_none() {
+        validators.etag = Some(cache::strong_etag(&crate::files::content_hash(&data)));
+        if validators.is_not_modified(&headers) {
+            return Ok(cache::not_modified(&validators, cache_control.as_deref()));
+        }
+    }
+
+    let mut builder = validators.apply(
+        Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, content_type.essence_str()),
+    );
+    if let Some(cache_control) = &cache_control {
+        builder = builder.header(header::CACHE_CONTROL, cache_control);
+    }
+    Ok(builder.body(data).unwrap())
+}
+
+/// The width and format a request's `?w=` and `?fmt=` ask for, or `None`
+/// for the original. A width on its own keeps the original's format; a
+/// format on its own gets the largest configured width.
+fn variant_request(
+    config: &Config,
+    query: &ImageVariantQuery,
+    key: &str,
+) -> Result<Option<(u32, VariantFormat)>, AppError> {
+    if query.w.is_none() && query.fmt.is_none() {
+        return Ok(None);
+    }
+    let format =
+        match &query.fmt {
+            Some(name) => Some(VariantFormat::parse(name).ok_or_else(|| {
+                AppError::Validation(format!("Unsupported image format '{}'", name))
+            })?),
+            None => None,
+        };
+    let Some(width) = config.images.snap_width(query.w.unwrap_or(u32::MAX)) else {
+        return Ok(None);
+    };
+    Ok(Some((
+        width,
+        format.unwrap_or_else(|| VariantFormat::of_key(key)),
+    )))
+}
+
+/// Files under `/static/`, from the site's static directory or the active
+/// theme. Requested with the `?v=` of their current content, as `url_for`
+/// links them, they are cached as immutable.
+pub async fn static_handler(
+    tail: warp::path::Tail,
+    query: AssetQuery,
+    config: Arc<Config>,
+    headers: HeaderMap,
+) -> Result<impl Reply, warp::Rejection> {
+    let override_dir = config.storage.static_path();
+    let name = tail.as_str().to_string();
+    let data = tokio::task::spawn_blocking(move || themes::static_file(&override_dir, &name))
+        .await
+        .map_err(|e| AppError::Internal(format!("Static file task failed: {}", e)))?
+        .ok_or_else(warp::reject::not_found)?;
+    let content_type = mime_guess::from_path(tail.as_str()).first_or_octet_stream();
+    let fingerprinted = query
+        .v
+        .is_some_and(|version| version == cache::asset_version(&data));
+    Ok(cached_asset(
+        data,
+        content_type.essence_str(),
+        fingerprinted,
+        &config,
+        &headers,
+    ))
+}
+
+/// The active theme's color and font settings as CSS variables, immutable
+/// when requested with the theme's current `?v={{ theme.revision }}`.
+pub async fn theme_css_handler(
+    query: AssetQuery,
+    config: Arc<Config>,
+    headers: HeaderMap,
+) -> Result<impl Reply, warp::Rejection> {
+    let active = themes::active();
+    let fingerprinted = query.v.is_some_and(|version| version == active.revision);
+    let css = active.css().into_bytes();
+    Ok(cached_asset(
+        css,
+        "text/css",
+        fingerprinted,
+        &config,
+        &headers,
+    ))
+}
+
+fn cached_asset(
+    data: Vec<u8>,
+    content_type: &str,
+    fingerprinted: bool,
+    config: &Config,
+    headers: &HeaderMap,
+) -> Response<Vec<u8>> {
+    let builder = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, content_type);
+    let Some(cache_control) = config.cache.static_cache_control(fingerprinted) else {
+        return builder.body(data).unwrap();
+    };
 
-    Ok(warp::reply::html(rendered))
+    let validators = Validators {
+        etag: Some(cache::strong_etag(&crate::files::content_hash(&data))),
+        last_modified: None,
+    };
+    if validators.is_not_modified(headers) {
+        return cache::not_modified(&validators, Some(&cache_control));
+    }
+    validators
+        .apply(builder)
+        .header(header::CACHE_CONTROL, cache_control)
+        .body(data)
+        .unwrap()
 }
@@ -0,0 +1,420 @@
+use crate::files::ImageFileManager;
+use crate::models::{Image, MediaUpload};
+use crate::repository::Repository;
+use serde::de::{self, Deserializer, SeqAccess, Visitor};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+const IMAGE_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png"];
+
+/// What to do when an imported image's slug is already taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ArgEnum)]
+pub enum DuplicatePolicy {
+    /// Leave the existing image untouched
+    Skip,
+    /// Replace the existing image's metadata and file
+    Overwrite,
+    /// Import under the next free slug (`slug-2`, `slug-3`, ...)
+    Rename,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ArgEnum)]
+pub enum ManifestFormat {
+    Json,
+    Ndjson,
+    Csv,
+    Dir,
+}
+
+/// A single image to import, as read from a manifest or sidecar file.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ImportEntry {
+    #[serde(default, alias = "URL", alias = "url", alias = "Url", alias = "file")]
+    pub path: String,
+    #[serde(default, alias = "Alt")]
+    pub alt: String,
+    #[serde(default, alias = "Description")]
+    pub description: String,
+    #[serde(default, alias = "Slug")]
+    pub slug: Option<String>,
+    #[serde(default, alias = "Keywords", deserialize_with = "deserialize_keywords")]
+    pub keywords: Vec<String>,
+    /// Extra shots in gallery order. CSV manifests have no column for them.
+    #[serde(default)]
+    pub media: Vec<ImportMedia>,
+}
+
+/// An extra shot of an imported image.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ImportMedia {
+    #[serde(default, alias = "file")]
+    pub path: String,
+    #[serde(default)]
+    pub alt: String,
+    #[serde(default)]
+    pub caption: String,
+}
+
+impl ImportEntry {
+    /// Uses the explicit slug if there is one, otherwise derives it from the
+    /// alt text or, failing that, the file name.
+    pub fn resolved_slug(&self) -> String {
+        let explicit = self.slug.as_deref().map(slugify).unwrap_or_default();
+        if !explicit.is_empty() {
+            return explicit;
+        }
+
+        let from_alt = slugify(&self.alt);
+        if !from_alt.is_empty() {
+            return from_alt;
+        }
+
+        Path::new(&self.path)
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .map(slugify)
+            .unwrap_or_default()
+    }
+
+    /// Resolves relative image and media paths against `base_dir`.
+    fn resolve_paths(&mut self, base_dir: &Path) {
+        let paths =
+            std::iter::once(&mut self.path).chain(self.media.iter_mut().map(|item| &mut item.path));
+        for path in paths {
+            if !path.is_empty() && Path::new(path.as_str()).is_relative() {
+                *path = base_dir.join(path.as_str()).to_string_lossy().into_owned();
+            }
+        }
+    }
+
+    /// Falls back to a humanised file name when no alt text was given.
+    pub fn resolved_alt(&self) -> String {
+        if !self.alt.trim().is_empty() {
+            return self.alt.trim().to_string();
+        }
+
+        Path::new(&self.path)
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .map(|stem| stem.replace(['-', '_'], " "))
+            .unwrap_or_default()
+    }
+}
+
+/// Lowercases and replaces every run of non-alphanumeric characters with a
+/// single dash, matching the slug generator in the admin forms.
+pub fn slugify(input: &str) -> String {
+    let mut slug = String::with_capacity(input.len());
+    for c in input.trim().to_lowercase().chars() {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.trim_end_matches('-').to_string()
+}
+
+/// Works out the manifest format from the source path, or from the content
+/// itself when reading from stdin.
+pub fn detect_format(source: Option<&Path>, content: Option<&str>) -> Option<ManifestFormat> {
+    if let Some(path) = source {
+        if path.is_dir() {
+            return Some(ManifestFormat::Dir);
+        }
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("csv") => return Some(ManifestFormat::Csv),
+            Some("ndjson") | Some("jsonl") => return Some(ManifestFormat::Ndjson),
+            _ => {}
+        }
+    }
+
+    let content = content?.trim_start();
+    if content.starts_with('[') {
+        Some(ManifestFormat::Json)
+    } else if content.starts_with('{') {
+        Some(ManifestFormat::Ndjson)
+    } else {
+        None
+    }
+}
+
+/// Parses manifest content into import entries. Relative image paths are
+/// resolved against `base_dir`.
+pub fn parse_manifest(
+    content: &str,
+    format: ManifestFormat,
+    base_dir: &Path,
+) -> Result<Vec<ImportEntry>, Box<dyn std::error::Error>> {
+    let mut entries: Vec<ImportEntry> = match format {
+        ManifestFormat::Json => serde_json::from_str(content)?,
+        ManifestFormat::Ndjson => content
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(i, line)| {
+                serde_json::from_str(line).map_err(|e| format!("line {}: {}", i + 1, e).into())
+            })
+            .collect::<Result<_, Box<dyn std::error::Error>>>()?,
+        ManifestFormat::Csv => csv::Reader::from_reader(content.as_bytes())
+            .deserialize()
+            .collect::<Result<_, _>>()?,
+        ManifestFormat::Dir => {
+            return Err("Directory imports must be read with scan_directory".into());
+        }
+    };
+
+    for entry in entries.iter_mut() {
+        entry.resolve_paths(base_dir);
+    }
+
+    Ok(entries)
+}
+
+/// Collects every image in `dir`, reading metadata from a `.json`, `.yaml`
+/// or `.yml` sidecar with the same file stem when one exists.
+pub fn scan_directory(dir: &Path) -> Result<Vec<ImportEntry>, Box<dyn std::error::Error>> {
+    let mut image_paths: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.is_file() && is_image_path(path))
+        .collect();
+    image_paths.sort();
+
+    let mut entries = Vec::with_capacity(image_paths.len());
+    for image_path in image_paths {
+        let mut entry = read_sidecar(&image_path)?.unwrap_or_default();
+        entry.resolve_paths(dir);
+        entry.path = image_path.to_string_lossy().into_owned();
+        entries.push(entry);
+    }
+
+    Ok(entries)
+}
+
+fn is_image_path(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| IMAGE_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+        .unwrap_or(false)
+}
+
+fn read_sidecar(image_path: &Path) -> Result<Option<ImportEntry>, Box<dyn std::error::Error>> {
+    let json_path = image_path.with_extension("json");
+    if json_path.is_file() {
+        let content = fs::read_to_string(&json_path)?;
+        let entry = serde_json::from_str(&content)
+            .map_err(|e| format!("{}: {}", json_path.display(), e))?;
+        return Ok(Some(entry));
+    }
+
+    for ext in ["yaml", "yml"] {
+        let yaml_path = image_path.with_extension(ext);
+        if yaml_path.is_file() {
+            let content = fs::read_to_string(&yaml_path)?;
+            let entry = serde_yaml::from_str(&content)
+                .map_err(|e| format!("{}: {}", yaml_path.display(), e))?;
+            return Ok(Some(entry));
+        }
+    }
+
+    Ok(None)
+}
+
+/// What happened to a single entry during a bulk import.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ImportOutcome {
+    Inserted(String),
+    Overwritten(String),
+    Renamed { from: String, to: String },
+    Skipped(String),
+}
+
+/// Imports one manifest entry, applying `policy` when its slug is in
+/// `taken`. `taken` starts as the slugs already in use and gains each slug
+/// imported, so conflicts within a batch are caught even when `dry_run`
+/// leaves the repository untouched. A dry run still reads and decodes every
+/// file and checks the entry's fields; only the writes are skipped.
+pub async fn import_entry(
+    repo: &dyn Repository,
+    file_manager: &Arc<ImageFileManager>,
+    entry: &ImportEntry,
+    policy: DuplicatePolicy,
+    dry_run: bool,
+    taken: &mut HashSet<String>,
+) -> Result<ImportOutcome, Box<dyn std::error::Error>> {
+    if entry.path.is_empty() {
+        return Err("Entry has no image path".into());
+    }
+
+    let slug = entry.resolved_slug();
+    if slug.is_empty() {
+        return Err(format!("Could not derive a slug for {}", entry.path).into());
+    }
+
+    let (image_data, mime_type) = read_image(&entry.path)?;
+    let mut uploads = Vec::with_capacity(entry.media.len());
+    for item in &entry.media {
+        let (data, mime_type) = read_image(&item.path)?;
+        uploads.push(MediaUpload {
+            data,
+            mime_type,
+            alt: item.alt.trim().to_string(),
+            caption: item.caption.trim().to_string(),
+        });
+    }
+    if uploads.iter().any(|upload| upload.alt.is_empty()) {
+        return Err(format!("Every extra shot of {} needs alt text", entry.path).into());
+    }
+
+    let alt = entry.resolved_alt();
+    if alt.is_empty() {
+        return Err(format!("{} needs alt text", entry.path).into());
+    }
+
+    let mut image = Image {
+        alt,
+        description: entry.description.clone(),
+        slug: slug.clone(),
+        keywords: entry.keywords.clone(),
+        filename: String::new(), // Will be set during save
+    };
+
+    if !taken.contains(&slug) {
+        if !dry_run {
+            insert_with_media(repo, file_manager, image_data, mime_type, image, uploads).await?;
+        }
+        taken.insert(slug.clone());
+        return Ok(ImportOutcome::Inserted(slug));
+    }
+
+    match policy {
+        DuplicatePolicy::Skip => Ok(ImportOutcome::Skipped(slug)),
+        DuplicatePolicy::Overwrite => {
+            if !dry_run {
+                repo.update_image(
+                    file_manager.clone(),
+                    &slug,
+                    Some((image_data, mime_type)),
+                    image,
+                )
+                .await?;
+                // Entries that list no media leave the existing ones alone
+                if !uploads.is_empty() {
+                    for item in repo.media(&slug).await? {
+                        repo.delete_media(file_manager.clone(), &slug, item.id)
+                            .await?;
+                    }
+                    repo.add_media(file_manager.clone(), &slug, uploads).await?;
+                }
+            }
+            Ok(ImportOutcome::Overwritten(slug))
+        }
+        DuplicatePolicy::Rename => {
+            let mut suffix = 2;
+            let new_slug = loop {
+                let candidate = format!("{}-{}", slug, suffix);
+                if !taken.contains(&candidate) {
+                    break candidate;
+                }
+                suffix += 1;
+            };
+            image.slug = new_slug.clone();
+
+            if !dry_run {
+                insert_with_media(repo, file_manager, image_data, mime_type, image, uploads)
+                    .await?;
+            }
+            taken.insert(new_slug.clone());
+            Ok(ImportOutcome::Renamed {
+                from: slug,
+                to: new_slug,
+            })
+        }
+    }
+}
+
+/// Stores a new image and its extra shots. If the shots can't be added the
+/// image is deleted again, so a failed entry leaves its slug free for a re-run.
+async fn insert_with_media(
+    repo: &dyn Repository,
+    file_manager: &Arc<ImageFileManager>,
+    image_data: Vec<u8>,
+    mime_type: mime::Mime,
+    image: Image,
+    uploads: Vec<MediaUpload>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let slug = image.slug.clone();
+    repo.insert_image(file_manager.clone(), image_data, mime_type, image)
+        .await?;
+    if let Err(e) = repo.add_media(file_manager.clone(), &slug, uploads).await {
+        if let Err(cleanup) = repo.delete_image(file_manager.clone(), &slug).await {
+            return Err(format!(
+                "{} (and '{}' could not be removed again: {})",
+                e, slug, cleanup
+            )
+            .into());
+        }
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+/// Reads an image file and decodes it, so a file the site couldn't resize
+/// fails the import, dry run included, rather than being stored.
+fn read_image(path: &str) -> Result<(Vec<u8>, mime::Mime), Box<dyn std::error::Error>> {
+    let data = fs::read(path)?;
+    let mime_type = mime_guess::from_path(path).first_or_octet_stream();
+    if mime_type.type_() != mime::IMAGE {
+        return Err(format!("{} is not an image ({})", path, mime_type).into());
+    }
+    image::load_from_memory(&data).map_err(|e| format!("{} can't be decoded: {}", path, e))?;
+    Ok((data, mime_type))
+}
+
+/// Accepts keywords either as a list or as a single comma-separated string,
+/// which is how they naturally appear in CSV manifests.
+fn deserialize_keywords<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct KeywordsVisitor;
+
+    impl<'de> Visitor<'de> for KeywordsVisitor {
+        type Value = Vec<String>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "a list of keywords or a comma-separated string")
+        }
+
+        fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+            Ok(value
+                .split(',')
+                .map(|s| s.trim())
+                .filter(|s| !s.is_empty())
+                .map(String::from)
+                .collect())
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut keywords = Vec::new();
+            while let Some(keyword) = seq.next_element::<String>()? {
+                keywords.push(keyword);
+            }
+            Ok(keywords)
+        }
+
+        fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> {
+            Ok(Vec::new())
+        }
+
+        fn visit_none<E: de::Error>(self) -> Result<Self::Value, E> {
+            Ok(Vec::new())
+        }
+    }
+
+    deserializer.deserialize_any(KeywordsVisitor)
+}
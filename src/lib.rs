@@ -1,27 +1,48 @@
 pub mod admin_assets;
+pub mod cache;
 pub mod cli;
 pub mod commands;
 pub mod config;
+pub mod content_types;
 pub mod database;
+pub mod dev;
+pub mod error;
 pub mod files;
 pub mod handlers;
+pub mod import;
+pub mod logging;
+pub mod mailer;
+pub mod markdown;
+pub mod metrics;
 pub mod middleware;
+pub mod migrations;
 pub mod models;
+pub mod pages;
+pub mod render_cache;
+pub mod repository;
 pub mod routes;
+pub mod security;
+pub mod server;
+pub mod storage;
+pub mod template_helpers;
 pub mod template_utils;
 pub mod templates;
+pub mod themes;
 
 use files::ImageFileManager;
-use models::{CustomError, RedirectToLogin};
-use rusqlite::Connection;
-use std::sync::{Arc, Mutex};
-use warp::http::{header::LOCATION, Response, StatusCode};
-use warp::Filter;
-
-fn with_db(
-    db: Arc<Mutex<Connection>>,
-) -> impl Filter<Extract = (Arc<Mutex<Connection>>,), Error = std::convert::Infallible> + Clone {
-    warp::any().map(move || db.clone())
+use repository::Repository;
+use std::sync::Arc;
+use warp::{Filter, Reply};
+
+fn with_repo(
+    repo: Arc<dyn Repository>,
+) -> impl Filter<Extract = (Arc<dyn Repository>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || repo.clone())
+}
+
+/// The raw query string, or an empty string when the request has none.
+fn with_raw_query() -> impl Filter<Extract = (String,), Error = std::convert::Infallible> + Clone {
+    warp::query::raw().or(warp::any().map(String::new)).unify()
 }
 
 fn with_config(
@@ -30,93 +51,381 @@ fn with_config(
     warp::any().map(move || config.clone())
 }
 
-pub async fn handle_rejection(
-    err: warp::Rejection,
-) -> Result<impl warp::Reply, std::convert::Infallible> {
-    if err.is_not_found() {
-        let response = Response::builder()
-            .status(StatusCode::NOT_FOUND)
-            .body("Not Found".to_string())
-            .unwrap();
-        Ok(response)
-    } else if err.find::<RedirectToLogin>().is_some() {
-        let response = Response::builder()
-            .status(StatusCode::FOUND)
-            .header(LOCATION, "/admin/login".to_string())
-            .body("".to_string())
-            .unwrap();
-        Ok(response)
-    } else if let Some(e) = err.find::<CustomError>() {
-        let response = Response::builder()
-            .status(StatusCode::UNAUTHORIZED)
-            .body(e.message.clone())
-            .unwrap();
-        Ok(response)
-    } else if let Some(_) = err.find::<warp::reject::PayloadTooLarge>() {
-        println!("Payload too large");
-        let response = Response::builder()
-            .status(StatusCode::BAD_REQUEST)
-            .body("File too large. Maximum size is 5MB.".to_string())
-            .unwrap();
-        Ok(response)
-    } else {
-        let response = Response::builder()
-            .status(StatusCode::INTERNAL_SERVER_ERROR)
-            .body("Internal Server Error".to_string())
-            .unwrap();
-        Ok(response)
+pub async fn run_server(mut config: config::Config, dev: dev::DevOptions) {
+    if dev.enabled {
+        // Browsers would otherwise keep serving stale CSS and scripts
+        config.cache.enabled = false;
+    }
+    let config = Arc::new(config);
+
+    config
+        .storage
+        .ensure_dirs()
+        .expect("Failed to create data directories");
+    templates::set_override_dir(config.storage.templates_path());
+    templates::set_live_reload(dev.live_reload);
+    render_cache::configure(
+        config.cache.render_cache_size,
+        config.cache.render_cache_ttl(),
+    );
+
+    // Open the database pool, applying any pending migrations
+    let repo = repository::open(&config)
+        .await
+        .expect("Failed to initialize database");
+
+    template_helpers::init(config.clone(), repo.clone());
+    if let Err(e) = pages::refresh_nav(repo.as_ref(), config.cache.render_cache_ttl()).await {
+        tracing::error!(error = %e, "Failed to load the page menu");
+    }
+
+    if let Err(e) = content_types::load(&config, repo.as_ref()).await {
+        tracing::error!(error = %e, "Failed to load content types");
+        std::process::exit(1);
     }
-}
 
-pub async fn run_server() {
-    // Load configuration
-    let config = Arc::new(config::Config::load().expect("Failed to load configuration"));
+    // The active theme is recorded in the database; loading it compiles the
+    // templates
+    if let Err(e) = themes::load_active(repo.as_ref(), &config.storage.themes_path()).await {
+        if !dev.enabled {
+            tracing::error!(error = %e, "Failed to load templates");
+            std::process::exit(1);
+        }
+        tracing::error!(error = %e, "Failed to load templates, fix them and save to reload");
+    }
 
-    // Initialize database connection
-    let conn = Arc::new(Mutex::new(
-        database::init_db().expect("Failed to initialize database"),
-    ));
+    // Initialize file storage
+    let storage: Arc<dyn storage::StorageBackend> =
+        Arc::from(storage::from_config(&config.storage).expect("Failed to configure storage"));
+    let file_manager = Arc::new(ImageFileManager::new(storage));
 
-    // Initialize file manager
-    let file_manager = Arc::new(ImageFileManager::new("data/images"));
+    // Initialize mailer
+    let mailer: Arc<dyn mailer::Mailer> =
+        Arc::from(mailer::from_config(&config).expect("Failed to configure mailer"));
 
     // Define routes
     let home_route = warp::path::end()
         .and(warp::get())
         .and(with_config(config.clone()))
-        .and(with_db(conn.clone()))
+        .and(with_repo(repo.clone()))
+        .and(with_raw_query())
+        .and(warp::header::headers_cloned())
         .and_then(handlers::home_handler);
 
     let post_detail_route = warp::path(config.routes.detail_path.clone())
         .and(warp::path::param())
         .and(warp::get())
         .and(with_config(config.clone()))
-        .and(with_db(conn.clone()))
+        .and(with_repo(repo.clone()))
+        .and(with_raw_query())
+        .and(warp::header::headers_cloned())
         .and_then(handlers::post_detail_handler);
 
-    // Admin routes from routes module
-    let admin_routes = routes::admin_routes(config.clone(), conn.clone(), file_manager.clone());
+    // Content types answer at their own paths, which page slugs can't take
+    let entries_route = warp::path::param()
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_config(config.clone()))
+        .and(with_repo(repo.clone()))
+        .and(with_raw_query())
+        .and(warp::header::headers_cloned())
+        .and_then(handlers::entries_handler);
+
+    let entry_route = warp::path::param()
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_config(config.clone()))
+        .and(with_repo(repo.clone()))
+        .and(with_raw_query())
+        .and(warp::header::headers_cloned())
+        .and_then(handlers::entry_handler);
+
+    // Published entries as JSON, by type name
+    let api_entries_route = warp::path("api")
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_repo(repo.clone()))
+        .and_then(handlers::api_entries_handler);
+
+    let api_entry_route = warp::path("api")
+        .and(warp::path::param())
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_repo(repo.clone()))
+        .and_then(handlers::api_entry_handler);
+
+    // Pages answer any single segment nothing else claims, so this route
+    // goes last
+    let page_route = warp::path::param()
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_config(config.clone()))
+        .and(with_repo(repo.clone()))
+        .and(with_raw_query())
+        .and(warp::header::headers_cloned())
+        .and_then(handlers::page_handler);
 
-    let image_routes = warp::path("images").and(warp::fs::dir("data/images"));
+    // Admin routes from routes module, boxed to keep the combined filter
+    // type within the compiler's recursion limit
+    let admin_routes: server::Routes = routes::admin_routes(
+        config.clone(),
+        repo.clone(),
+        file_manager.clone(),
+        mailer.clone(),
+    )
+    .map(Reply::into_response)
+    .boxed();
 
-    let static_files = warp::path("static").and(warp::fs::dir("static"));
+    let image_routes = warp::path(config.routes.images_path.clone())
+        .and(warp::path::tail())
+        .and(warp::get())
+        .and(warp::query::<models::ImageVariantQuery>())
+        .and(with_config(config.clone()))
+        .and(with_repo(repo.clone()))
+        .and(middleware::with_file_manager(file_manager.clone()))
+        .and(warp::header::headers_cloned())
+        .and_then(handlers::image_handler);
+
+    let static_files = warp::path("static")
+        .and(warp::path::tail())
+        .and(warp::get())
+        .and(warp::query::<models::AssetQuery>())
+        .and(with_config(config.clone()))
+        .and(warp::header::headers_cloned())
+        .and_then(handlers::static_handler);
+
+    let theme_css_route = warp::path("theme.css")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::query::<models::AssetQuery>())
+        .and(with_config(config.clone()))
+        .and(warp::header::headers_cloned())
+        .and_then(handlers::theme_css_handler);
+
+    let metrics_route = warp::path("metrics")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(with_config(config.clone()))
+        .and(with_repo(repo.clone()))
+        .and_then(handlers::metrics_handler);
+
+    // Every listener stops accepting connections once this flips, then
+    // drains what it already has
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    let mut listeners = Vec::new();
+
+    // With a separate bind address metrics are only served there; otherwise
+    // they share the main listener, but only behind a token
+    if let Some(bind) = &config.server.metrics_bind {
+        let addr: std::net::SocketAddr = bind.parse().expect("Invalid server.metrics_bind");
+        let metrics_only = metrics_route.clone().map(Reply::into_response).boxed();
+        listeners.push(
+            server::serve_http(metrics_only, addr, shutdown_rx.clone())
+                .expect("Failed to serve metrics"),
+        );
+    }
+    let public_metrics =
+        config.server.metrics_bind.is_none() && config.server.metrics_token.is_some();
+    let metrics_route = warp::any()
+        .and_then(move || async move {
+            if public_metrics {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        })
+        .untuple_one()
+        .and(metrics_route);
+
+    let healthz_route = warp::path("healthz")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and_then(handlers::healthz_handler);
+
+    let readyz_route = warp::path("readyz")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_repo(repo.clone()))
+        .and(middleware::with_file_manager(file_manager.clone()))
+        .and_then(handlers::readyz_handler);
+
+    let version_route = warp::path("version")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and_then(handlers::version_handler);
+
+    let csp_report_route = warp::path("csp-report")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::body::content_length_limit(64 * 1024))
+        .and(warp::body::bytes())
+        .and_then(handlers::csp_report_handler);
+
+    let dev_routes: server::Routes = if dev.enabled {
+        let reload = dev::watch(
+            &config.storage.themes_path(),
+            &config.storage.templates_path(),
+            &config.storage.static_path(),
+        )
+        .expect("Failed to watch for changes");
+        dev::routes(reload, shutdown_rx.clone())
+            .map(Reply::into_response)
+            .boxed()
+    } else {
+        warp::any()
+            .and_then(|| async { Err(warp::reject::not_found()) })
+            .boxed()
+    };
 
     let routes = home_route
+        .or(healthz_route)
+        .or(readyz_route)
+        .or(version_route)
         .or(post_detail_route)
         .or(image_routes)
         .or(admin_routes)
-        .or(static_files);
+        .or(metrics_route)
+        .or(csp_report_route)
+        .or(dev_routes)
+        .or(theme_css_route)
+        .or(static_files)
+        .or(api_entries_route)
+        .or(api_entry_route)
+        .or(entry_route)
+        .or(entries_route)
+        .or(page_route);
+
+    // Every request runs in a span carrying its ID, which is also echoed
+    // back so clients and proxies can quote it
+    let access_log = config.log.access_log;
+    let route_config = config.routes.clone();
+    let security_headers = Arc::new(security::SecurityHeaders::new(&config));
+    let error_config = config.clone();
+    let routes = logging::request_id()
+        .and(warp::path::full())
+        .and(warp::header::optional::<String>("accept"))
+        .and(warp::header::optional::<String>("x-requested-with"))
+        .and(routes.recover(error::handle_rejection))
+        .map(
+            move |request_id: String,
+                  path: warp::path::FullPath,
+                  accept: Option<String>,
+                  requested_with: Option<String>,
+                  reply| {
+                let response = error::render(
+                    Reply::into_response(reply),
+                    path.as_str(),
+                    accept.as_deref(),
+                    requested_with.as_deref(),
+                    &error_config,
+                );
+                let response = security_headers.apply(path.as_str(), response);
+                warp::reply::with_header(response, "x-request-id", request_id)
+            },
+        )
+        .with(warp::log::custom(move |info| {
+            metrics::observe_request(&info, &route_config);
+            if access_log {
+                logging::access_log(info)
+            }
+        }))
+        .with(warp::trace(logging::request_span));
 
-    let routes = routes.recover(handle_rejection);
+    let routes: server::Routes = routes.map(Reply::into_response).boxed();
 
-    // Start the server
-    warp::serve(routes)
-        .run((config.server.get_ip_addr(), config.server.port))
-        .await;
+    // Start the listeners
+    let addrs = config
+        .server
+        .bind_addrs()
+        .expect("Invalid server bind address");
+    for addr in &addrs {
+        let tls = &config.server.tls;
+        let listener = if tls.is_enabled() {
+            server::serve_https(routes.clone(), *addr, tls.clone(), shutdown_rx.clone()).await
+        } else {
+            server::serve_http(routes.clone(), *addr, shutdown_rx.clone())
+        };
+        listeners.push(listener.expect("Failed to start server"));
+    }
+    if let (true, Some(https)) = (config.server.tls.is_enabled(), addrs.first()) {
+        for bind in &config.server.tls.redirect_bind {
+            for addr in config::resolve_addr(bind, 80).expect("Invalid server.tls.redirect_bind") {
+                listeners.push(
+                    server::serve_redirect(addr, https.port(), shutdown_rx.clone())
+                        .expect("Failed to start redirect listener"),
+                );
+            }
+        }
+    }
+    if let Some(path) = &config.server.unix_socket {
+        let mode = config
+            .server
+            .unix_socket_mode()
+            .expect("Invalid server.unix_socket_mode");
+        listeners.push(
+            server::serve_unix(
+                routes.clone(),
+                std::path::Path::new(path),
+                mode,
+                shutdown_rx.clone(),
+            )
+            .expect("Failed to start server"),
+        );
+    }
+    if listeners.is_empty() {
+        panic!("Nothing to listen on: set server.bind, server.host or server.unix_socket");
+    }
+
+    shutdown_signal().await;
+    let timeout = std::time::Duration::from_secs(config.server.shutdown_timeout_seconds);
+    tracing::info!(
+        timeout_seconds = timeout.as_secs(),
+        "Shutting down, waiting for in-flight requests"
+    );
+    let _ = shutdown_tx.send(true);
+    match tokio::time::timeout(timeout, futures::future::join_all(listeners)).await {
+        Ok(_) => tracing::info!("Shutdown complete"),
+        Err(_) => tracing::warn!("Drain timeout elapsed, dropping remaining requests"),
+    }
+}
+
+/// Resolves on SIGINT (Ctrl-C) or, on Unix, SIGTERM.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!(error = %e, "Failed to listen for Ctrl-C");
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                tracing::error!(error = %e, "Failed to listen for SIGTERM");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
 }
 
-pub fn setup_database() -> Result<(), rusqlite::Error> {
-    let conn = database::init_db()?;
-    database::run_migrations(&conn)?;
+/// Creates the database schema for whichever backend is configured.
+pub async fn setup_database(config: &config::Config) -> Result<(), Box<dyn std::error::Error>> {
+    config.storage.ensure_dirs()?;
+    repository::open(config).await?;
     Ok(())
 }
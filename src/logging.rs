@@ -0,0 +1,93 @@
+use crate::config::{LogConfig, LogFormat};
+use tracing::field::Empty;
+use tracing::Span;
+use tracing_subscriber::EnvFilter;
+use uuid::Uuid;
+use warp::Filter;
+
+/// Installs the global subscriber. Logs go to stderr so command output on
+/// stdout stays usable in pipelines.
+pub fn init(config: &LogConfig) {
+    let filter = EnvFilter::try_from_default_env()
+        .or_else(|_| EnvFilter::try_new(&config.level))
+        .unwrap_or_else(|e| {
+            eprintln!("Invalid log level {:?}, using info: {}", config.level, e);
+            EnvFilter::new("info")
+        })
+        // warp's own per-request lines repeat what the access log records
+        .add_directive("warp::filters::trace=off".parse().unwrap());
+
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+    let result = match config.format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder
+            .json()
+            .with_current_span(true)
+            .with_span_list(false)
+            .try_init(),
+    };
+    if let Err(e) = result {
+        eprintln!("Failed to initialize logging: {}", e);
+    }
+}
+
+/// The span every request runs in, so all of its log lines share the
+/// request ID and, once authenticated, the user.
+pub fn request_span(info: warp::trace::Info) -> Span {
+    tracing::info_span!(
+        "request",
+        request_id = Empty,
+        method = %info.method(),
+        path = %info.path(),
+        user = Empty,
+    )
+}
+
+/// Proxies may pass their own ID; anything long or unusual is replaced
+/// rather than echoed into logs and headers.
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 64
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+/// The request's ID, taken from `X-Request-Id` when the client or a proxy
+/// sent a valid one and generated otherwise. It is recorded on the request
+/// span and should be echoed back in the response.
+pub fn request_id() -> impl Filter<Extract = (String,), Error = std::convert::Infallible> + Clone {
+    warp::header::optional::<String>("x-request-id")
+        .or(warp::any().map(|| None))
+        .unify()
+        .map(|id: Option<String>| {
+            let id = id
+                .filter(|id| is_valid_request_id(id))
+                .unwrap_or_else(|| Uuid::new_v4().simple().to_string());
+            Span::current().record("request_id", id.as_str());
+            id
+        })
+}
+
+/// Attributes the current request to `user` in the access log.
+pub fn record_user(user: &str) {
+    Span::current().record("user", user);
+}
+
+/// One line per finished request. Method, path, request ID and user come
+/// from the enclosing request span.
+pub fn access_log(info: warp::log::Info) {
+    let status = info.status().as_u16();
+    let latency_ms = info.elapsed().as_secs_f64() * 1000.0;
+    let remote_addr = info.remote_addr().map(|addr| addr.ip().to_string());
+    tracing::info!(
+        target: "craftcms::access",
+        status,
+        latency_ms,
+        remote_addr = remote_addr.as_deref().unwrap_or("-"),
+        user_agent = info.user_agent().unwrap_or("-"),
+        "request completed"
+    );
+}
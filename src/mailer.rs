@@ -0,0 +1,125 @@
+use crate::config::{Config, MailBackend, SmtpSecurity};
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Message, SendmailTransport, SmtpTransport, Transport};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Delivers outgoing mail. Implementations block, so call them from
+/// `tokio::task::spawn_blocking` inside request handlers.
+pub trait Mailer: Send + Sync {
+    fn send(&self, email: &Email) -> Result<(), Box<dyn std::error::Error>>;
+}
+
+pub fn from_config(config: &Config) -> Result<Box<dyn Mailer>, Box<dyn std::error::Error>> {
+    let log_path = config.mail_log_path();
+    let config = &config.mail;
+    let from: Mailbox = config.from.parse()?;
+
+    let mailer: Box<dyn Mailer> = match config.backend {
+        MailBackend::Smtp => {
+            let builder = match config.smtp_security {
+                SmtpSecurity::None => SmtpTransport::builder_dangerous(&config.smtp_host),
+                SmtpSecurity::Starttls => SmtpTransport::starttls_relay(&config.smtp_host)?,
+                SmtpSecurity::Tls => SmtpTransport::relay(&config.smtp_host)?,
+            };
+            let builder = builder.port(config.smtp_port);
+            let builder = match (&config.smtp_username, &config.smtp_password) {
+                (Some(username), Some(password)) => {
+                    builder.credentials(Credentials::new(username.clone(), password.clone()))
+                }
+                _ => builder,
+            };
+            Box::new(SmtpMailer {
+                from,
+                transport: builder.build(),
+            })
+        }
+        MailBackend::Sendmail => Box::new(SendmailMailer {
+            from,
+            transport: SendmailTransport::new_with_command(&config.sendmail_command),
+        }),
+        MailBackend::File => Box::new(FileMailer {
+            from,
+            path: log_path,
+        }),
+        MailBackend::Stdout => Box::new(StdoutMailer { from }),
+    };
+
+    Ok(mailer)
+}
+
+fn build_message(from: &Mailbox, email: &Email) -> Result<Message, Box<dyn std::error::Error>> {
+    Ok(Message::builder()
+        .from(from.clone())
+        .to(email.to.parse()?)
+        .subject(&email.subject)
+        .body(email.body.clone())?)
+}
+
+pub struct SmtpMailer {
+    from: Mailbox,
+    transport: SmtpTransport,
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&self, email: &Email) -> Result<(), Box<dyn std::error::Error>> {
+        self.transport.send(&build_message(&self.from, email)?)?;
+        Ok(())
+    }
+}
+
+pub struct SendmailMailer {
+    from: Mailbox,
+    transport: SendmailTransport,
+}
+
+impl Mailer for SendmailMailer {
+    fn send(&self, email: &Email) -> Result<(), Box<dyn std::error::Error>> {
+        self.transport.send(&build_message(&self.from, email)?)?;
+        Ok(())
+    }
+}
+
+/// Appends each message to a file, which makes sent mail easy to inspect
+/// in tests and on development machines.
+pub struct FileMailer {
+    from: Mailbox,
+    path: PathBuf,
+}
+
+impl Mailer for FileMailer {
+    fn send(&self, email: &Email) -> Result<(), Box<dyn std::error::Error>> {
+        let message = build_message(&self.from, email)?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(&message.formatted())?;
+        file.write_all(b"\r\n\r\n")?;
+        Ok(())
+    }
+}
+
+/// Prints each message, body included, for local development. Reset
+/// tokens end up wherever stdout is logged, so it's never the default.
+pub struct StdoutMailer {
+    from: Mailbox,
+}
+
+impl Mailer for StdoutMailer {
+    fn send(&self, email: &Email) -> Result<(), Box<dyn std::error::Error>> {
+        let message = build_message(&self.from, email)?;
+        let mut stdout = std::io::stdout().lock();
+        stdout.write_all(&message.formatted())?;
+        stdout.write_all(b"\n")?;
+        Ok(())
+    }
+}
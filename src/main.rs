@@ -1,6 +1,13 @@
-use clap::{Parser, Subcommand};
-use craftcms::{cli, database, files, run_server, setup_database};
+use clap::{Args, Parser, Subcommand};
+use craftcms::cli::{OutputFormat, PasswordSource};
+use craftcms::config::Config;
+use craftcms::dev::DevOptions;
+use craftcms::import::{DuplicatePolicy, ManifestFormat};
+use craftcms::models::{ImageFilter, ImagePatch};
+use craftcms::{cli, files, logging, repository, run_server, setup_database, storage};
 use std::io::Read; // Add this import
+use std::path::Path;
+use std::sync::Arc;
 
 #[derive(Parser)]
 #[clap(
@@ -9,6 +16,14 @@ use std::io::Read; // Add this import
     about = "A simple CMS for managing and serving templated HTML documents with images."
 )]
 struct Cli {
+    #[clap(
+        long,
+        global = true,
+        help = "Path to the config file (defaults to $CRAFTCMS_CONFIG or ./config.toml)"
+    )]
+    config: Option<String>,
+    #[clap(long, global = true, help = "Override storage.data_dir")]
+    data_dir: Option<String>,
     #[clap(subcommand)]
     command: Commands,
 }
@@ -16,7 +31,30 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     /// Start the web server
-    Serve,
+    Serve {
+        #[clap(long, help = "Override server.host")]
+        host: Option<String>,
+        #[clap(long, help = "Override server.port")]
+        port: Option<u16>,
+        #[clap(long, help = "Override site.base_url")]
+        base_url: Option<String>,
+        #[clap(
+            long,
+            help = "Reload templates when they change and turn off HTTP caching"
+        )]
+        dev: bool,
+        #[clap(
+            long,
+            requires = "dev",
+            help = "Refresh open pages after templates or static files change"
+        )]
+        live_reload: bool,
+    },
+    /// Configuration commands
+    Config {
+        #[clap(subcommand)]
+        command: ConfigCommands,
+    },
     /// Initialize the database
     InitDb,
     /// User management commands
@@ -29,66 +67,334 @@ enum Commands {
         #[clap(subcommand)]
         command: ImageCommands,
     },
+    /// Theme management commands
+    Themes {
+        #[clap(subcommand)]
+        command: ThemeCommands,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigCommands {
+    /// Print the effective configuration and where each value came from
+    Show,
 }
 
 #[derive(Subcommand)]
 enum UserCommands {
     /// Create a new user
-    Create,
+    Create {
+        #[clap(long, help = "Email of the new user (prompted for if omitted)")]
+        email: Option<String>,
+        #[clap(flatten)]
+        password: PasswordArgs,
+    },
     /// List all users
-    List,
-    // /// Delete a user
+    List {
+        #[clap(long, arg_enum, default_value = "table")]
+        format: OutputFormat,
+    },
+    /// Delete a user
     Delete {
         #[clap(help = "Email of the user to delete")]
         email: String,
+        #[clap(long, short, help = "Skip the confirmation prompt")]
+        yes: bool,
+    },
+    /// Reset a user's password
+    Passwd {
+        email: String,
+        #[clap(flatten)]
+        password: PasswordArgs,
+    },
+    /// Prevent a user from logging in and end their sessions
+    Disable { email: String },
+    /// Allow a disabled user to log in again
+    Enable { email: String },
+}
+
+#[derive(Subcommand)]
+enum ThemeCommands {
+    /// List the built-in theme and the installed ones
+    List {
+        #[clap(long, arg_enum, default_value = "table")]
+        format: OutputFormat,
+    },
+    /// Install a theme from a directory or a .zip, .tar.gz or .tgz archive
+    Install {
+        #[clap(help = "Theme directory or archive")]
+        source: String,
+        #[clap(long, help = "Replace an installed theme with the same name")]
+        force: bool,
     },
+    /// Make a theme the active one
+    Activate { name: String },
+}
+
+#[derive(Args)]
+struct PasswordArgs {
+    #[clap(long, help = "Read the password from the first line of stdin")]
+    password_stdin: bool,
+    #[clap(
+        long,
+        conflicts_with = "password-stdin",
+        help = "Read the password from a file"
+    )]
+    password_file: Option<String>,
+}
+
+impl From<PasswordArgs> for PasswordSource {
+    fn from(args: PasswordArgs) -> Self {
+        PasswordSource {
+            stdin: args.password_stdin,
+            file: args.password_file,
+        }
+    }
 }
 
 #[derive(Subcommand)]
 enum ImageCommands {
     /// Insert a new image from JSON input
     Insert,
+    /// Import many images from a JSON, NDJSON or CSV manifest, or a directory
+    Import {
+        #[clap(help = "Manifest file or image directory (reads stdin if omitted)")]
+        source: Option<String>,
+        #[clap(long, arg_enum, help = "Manifest format (detected if omitted)")]
+        format: Option<ManifestFormat>,
+        #[clap(
+            long,
+            arg_enum,
+            default_value = "skip",
+            help = "What to do when a slug already exists"
+        )]
+        on_duplicate: DuplicatePolicy,
+        #[clap(
+            long,
+            help = "Read, decode and check every entry without saving anything"
+        )]
+        dry_run: bool,
+    },
+    /// List images
+    List {
+        #[clap(flatten)]
+        filter: FilterArgs,
+        #[clap(long, arg_enum, default_value = "table")]
+        format: OutputFormat,
+    },
+    /// Show a single image
+    Show {
+        slug: String,
+        #[clap(long, arg_enum, default_value = "table")]
+        format: OutputFormat,
+    },
+    /// Update an image from field flags or JSON on stdin
+    Update {
+        slug: String,
+        #[clap(long)]
+        alt: Option<String>,
+        #[clap(long)]
+        description: Option<String>,
+        #[clap(long = "new-slug", help = "Change the image's slug")]
+        new_slug: Option<String>,
+        #[clap(long, help = "Comma-separated keywords, replacing the existing ones")]
+        keywords: Option<String>,
+        #[clap(long, help = "Path to a replacement image file")]
+        file: Option<String>,
+        #[clap(long, help = "Read a JSON object of fields to change from stdin")]
+        json: bool,
+    },
+    /// Delete an image and its file
+    Delete {
+        slug: String,
+        #[clap(long, short, help = "Skip the confirmation prompt")]
+        yes: bool,
+    },
+    /// Export image metadata and files to a directory
+    Export {
+        #[clap(help = "Directory to write manifest.json and image files to")]
+        dest: String,
+        #[clap(flatten)]
+        filter: FilterArgs,
+    },
+    /// Move images stored under their slug into content-addressed storage
+    Dedupe,
+}
+
+#[derive(Args)]
+struct FilterArgs {
+    #[clap(long, help = "Only include images with this keyword")]
+    keyword: Option<String>,
+    #[clap(
+        long,
+        help = "Only include images whose alt, description or slug matches"
+    )]
+    search: Option<String>,
+    #[clap(long, help = "Maximum number of images")]
+    limit: Option<usize>,
+}
+
+impl From<FilterArgs> for ImageFilter {
+    fn from(args: FilterArgs) -> Self {
+        ImageFilter {
+            keyword: args.keyword,
+            search: args.search,
+            limit: args.limit,
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() {
     let cli = Cli::parse();
 
+    // Command line flags are the highest-precedence configuration layer
+    let mut overrides = Vec::new();
+    if let Some(data_dir) = &cli.data_dir {
+        overrides.push(("storage.data_dir", data_dir.clone()));
+    }
+    if let Commands::Serve {
+        host,
+        port,
+        base_url,
+        ..
+    } = &cli.command
+    {
+        if let Some(host) = host {
+            overrides.push(("server.host", host.clone()));
+        }
+        if let Some(port) = port {
+            overrides.push(("server.port", port.to_string()));
+        }
+        if let Some(base_url) = base_url {
+            overrides.push(("site.base_url", base_url.clone()));
+        }
+    }
+
+    let loaded = match Config::load_layered(cli.config.as_deref().map(Path::new), &overrides) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("Error loading configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    logging::init(&loaded.config.log);
+
     match cli.command {
-        Commands::Serve => {
-            println!("Starting server...");
-            run_server().await;
+        Commands::Serve {
+            dev, live_reload, ..
+        } => {
+            let dev = DevOptions {
+                enabled: dev,
+                live_reload,
+            };
+            run_server(loaded.config, dev).await;
         }
+        Commands::Config { command } => match command {
+            ConfigCommands::Show => match loaded.describe() {
+                Ok(description) => print!("{}", description),
+                Err(e) => {
+                    eprintln!("Error showing configuration: {}", e);
+                    std::process::exit(1);
+                }
+            },
+        },
         Commands::InitDb => {
             println!("Initializing database...");
-            setup_database().expect("Failed to initialize database");
+            setup_database(&loaded.config)
+                .await
+                .expect("Failed to initialize database");
         }
         Commands::Users { command } => {
-            let conn = database::init_db().expect("Failed to open database");
+            let repo = repository::open(&loaded.config)
+                .await
+                .expect("Failed to open database");
+            let repo = repo.as_ref();
             match command {
-                UserCommands::Create => {
-                    if let Err(e) = cli::create_user_command(&conn) {
+                UserCommands::Create { email, password } => {
+                    if let Err(e) =
+                        cli::create_user_command(repo, email.as_deref(), &password.into()).await
+                    {
                         eprintln!("Error creating user: {}", e);
                         std::process::exit(1);
                     }
                 }
-                UserCommands::List => {
-                    if let Err(e) = cli::list_users_command(&conn) {
+                UserCommands::List { format } => {
+                    if let Err(e) = cli::list_users_command(repo, format).await {
                         eprintln!("Error listing users: {}", e);
                         std::process::exit(1);
                     }
                 }
-                UserCommands::Delete { email } => {
-                    if let Err(e) = cli::delete_user_command(&conn, &email) {
+                UserCommands::Delete { email, yes } => {
+                    if let Err(e) = cli::delete_user_command(repo, &email, yes).await {
                         eprintln!("Error deleting user: {}", e);
                         std::process::exit(1);
                     }
                 }
+                UserCommands::Passwd { email, password } => {
+                    if let Err(e) = cli::passwd_user_command(repo, &email, &password.into()).await {
+                        eprintln!("Error updating password: {}", e);
+                        std::process::exit(1);
+                    }
+                }
+                UserCommands::Disable { email } => {
+                    if let Err(e) = cli::set_user_disabled_command(repo, &email, true).await {
+                        eprintln!("Error disabling user: {}", e);
+                        std::process::exit(1);
+                    }
+                }
+                UserCommands::Enable { email } => {
+                    if let Err(e) = cli::set_user_disabled_command(repo, &email, false).await {
+                        eprintln!("Error enabling user: {}", e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+        }
+        Commands::Themes { command } => {
+            let themes_dir = loaded.config.storage.themes_path();
+            match command {
+                ThemeCommands::List { format } => {
+                    let repo = repository::open(&loaded.config)
+                        .await
+                        .expect("Failed to open database");
+                    if let Err(e) =
+                        cli::list_themes_command(repo.as_ref(), &themes_dir, format).await
+                    {
+                        eprintln!("Error listing themes: {}", e);
+                        std::process::exit(1);
+                    }
+                }
+                ThemeCommands::Install { source, force } => {
+                    if let Err(e) =
+                        cli::install_theme_command(Path::new(&source), &themes_dir, force)
+                    {
+                        eprintln!("Error installing theme: {}", e);
+                        std::process::exit(1);
+                    }
+                }
+                ThemeCommands::Activate { name } => {
+                    let repo = repository::open(&loaded.config)
+                        .await
+                        .expect("Failed to open database");
+                    if let Err(e) =
+                        cli::activate_theme_command(repo.as_ref(), &themes_dir, &name).await
+                    {
+                        eprintln!("Error activating theme: {}", e);
+                        std::process::exit(1);
+                    }
+                }
             }
         }
         Commands::Images { command } => {
-            let conn = database::init_db().expect("Failed to open database");
-            let file_manager = files::ImageFileManager::new("data/images");
+            let repo = repository::open(&loaded.config)
+                .await
+                .expect("Failed to open database");
+            let repo = repo.as_ref();
+            let storage =
+                storage::from_config(&loaded.config.storage).expect("Failed to configure storage");
+            let file_manager = Arc::new(files::ImageFileManager::new(Arc::from(storage)));
             match command {
                 ImageCommands::Insert => {
                     if !atty::is(atty::Stream::Stdin) {
@@ -97,7 +403,8 @@ async fn main() {
                             .read_to_string(&mut input)
                             .expect("Failed to read from stdin");
 
-                        if let Err(e) = cli::handle_insert_image(&conn, &file_manager, &input) {
+                        if let Err(e) = cli::handle_insert_image(repo, &file_manager, &input).await
+                        {
                             eprintln!("Error inserting image: {}", e);
                             std::process::exit(1);
                         }
@@ -108,6 +415,113 @@ async fn main() {
                         std::process::exit(1);
                     }
                 }
+                ImageCommands::Import {
+                    source,
+                    format,
+                    on_duplicate,
+                    dry_run,
+                } => {
+                    let entries = match cli::load_import_entries(source.as_deref(), format) {
+                        Ok(entries) => entries,
+                        Err(e) => {
+                            eprintln!("Error reading import source: {}", e);
+                            std::process::exit(1);
+                        }
+                    };
+
+                    if let Err(e) = cli::handle_import_images(
+                        repo,
+                        &file_manager,
+                        &entries,
+                        on_duplicate,
+                        dry_run,
+                    )
+                    .await
+                    {
+                        eprintln!("Error importing images: {}", e);
+                        std::process::exit(1);
+                    }
+                }
+                ImageCommands::List { filter, format } => {
+                    if let Err(e) = cli::list_images_command(repo, &filter.into(), format).await {
+                        eprintln!("Error listing images: {}", e);
+                        std::process::exit(1);
+                    }
+                }
+                ImageCommands::Show { slug, format } => {
+                    if let Err(e) = cli::show_image_command(repo, &slug, format).await {
+                        eprintln!("Error showing image: {}", e);
+                        std::process::exit(1);
+                    }
+                }
+                ImageCommands::Update {
+                    slug,
+                    alt,
+                    description,
+                    new_slug,
+                    keywords,
+                    file,
+                    json,
+                } => {
+                    let mut patch = if json {
+                        let mut input = String::new();
+                        std::io::stdin()
+                            .read_to_string(&mut input)
+                            .expect("Failed to read from stdin");
+                        match serde_json::from_str::<ImagePatch>(&input) {
+                            Ok(patch) => patch,
+                            Err(e) => {
+                                eprintln!("Invalid JSON input: {}", e);
+                                std::process::exit(1);
+                            }
+                        }
+                    } else {
+                        ImagePatch::default()
+                    };
+
+                    // Flags take precedence over JSON fields
+                    patch.alt = alt.or(patch.alt);
+                    patch.description = description.or(patch.description);
+                    patch.slug = new_slug.or(patch.slug);
+                    patch.path = file.or(patch.path);
+                    if let Some(keywords) = keywords {
+                        patch.keywords = Some(
+                            keywords
+                                .split(',')
+                                .filter(|s| !s.trim().is_empty())
+                                .map(|s| s.trim().to_string())
+                                .collect(),
+                        );
+                    }
+
+                    if let Err(e) =
+                        cli::update_image_command(repo, &file_manager, &slug, patch).await
+                    {
+                        eprintln!("Error updating image: {}", e);
+                        std::process::exit(1);
+                    }
+                }
+                ImageCommands::Delete { slug, yes } => {
+                    if let Err(e) = cli::delete_image_command(repo, &file_manager, &slug, yes).await
+                    {
+                        eprintln!("Error deleting image: {}", e);
+                        std::process::exit(1);
+                    }
+                }
+                ImageCommands::Export { dest, filter } => {
+                    if let Err(e) =
+                        cli::export_images_command(repo, &file_manager, &filter.into(), &dest).await
+                    {
+                        eprintln!("Error exporting images: {}", e);
+                        std::process::exit(1);
+                    }
+                }
+                ImageCommands::Dedupe => {
+                    if let Err(e) = cli::dedupe_images_command(repo, &file_manager).await {
+                        eprintln!("Error deduplicating images: {}", e);
+                        std::process::exit(1);
+                    }
+                }
             }
         }
     }
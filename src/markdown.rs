@@ -0,0 +1,102 @@
+use lazy_static::lazy_static;
+use lru::LruCache;
+use pulldown_cmark::{Event, Options, Parser, TagEnd};
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+
+/// Length of the plain-text excerpt kept with each rendered document, sized
+/// for meta descriptions.
+pub const EXCERPT_LENGTH: usize = 160;
+
+/// How many rendered documents are kept; a gallery's descriptions fit with
+/// room to spare.
+const RENDERED_CAPACITY: usize = 1024;
+
+/// A Markdown document rendered for templates.
+#[derive(Debug)]
+pub struct Rendered {
+    pub html: String,
+    pub excerpt: String,
+}
+
+lazy_static! {
+    static ref RENDERED: Mutex<LruCache<String, Arc<Rendered>>> =
+        Mutex::new(LruCache::new(NonZeroUsize::new(RENDERED_CAPACITY).unwrap()));
+}
+
+/// CommonMark plus the GitHub extensions artists are likely to reach for.
+fn options() -> Options {
+    Options::ENABLE_TABLES
+        | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_FOOTNOTES
+        | Options::ENABLE_GFM
+}
+
+/// Renders Markdown to HTML that is safe to print unescaped: raw HTML in
+/// the source is cleaned down to an allow-list of tags, and links may only
+/// use http(s) or mailto and never open with access to the page.
+pub fn to_html(source: &str) -> String {
+    let mut html = String::new();
+    pulldown_cmark::html::push_html(&mut html, Parser::new_ext(source, options()));
+    ammonia::Builder::default()
+        .url_schemes(["http", "https", "mailto"].into())
+        .link_rel(Some("noopener noreferrer nofollow"))
+        .clean(&html)
+        .to_string()
+}
+
+/// Renders `source` to HTML and an excerpt, reusing the result for as long
+/// as the text is unchanged. Entries are keyed by a hash of the text, so
+/// each revision of a description is rendered once and stale revisions age
+/// out.
+pub fn render(source: &str) -> Arc<Rendered> {
+    let key = crate::files::content_hash(source.as_bytes());
+    if let Some(rendered) = RENDERED
+        .lock()
+        .ok()
+        .and_then(|mut cache| cache.get(&key).cloned())
+    {
+        return rendered;
+    }
+
+    let rendered = Arc::new(Rendered {
+        html: to_html(source),
+        excerpt: truncate(&to_text(source), EXCERPT_LENGTH),
+    });
+    if let Ok(mut cache) = RENDERED.lock() {
+        cache.put(key, rendered.clone());
+    }
+    rendered
+}
+
+/// The text of a Markdown document without its markup, with whitespace
+/// collapsed, for meta descriptions and excerpts.
+pub fn to_text(source: &str) -> String {
+    let mut text = String::new();
+    for event in Parser::new_ext(source, options()) {
+        match event {
+            Event::Text(part) | Event::Code(part) => text.push_str(&part),
+            Event::SoftBreak | Event::HardBreak => text.push(' '),
+            Event::End(TagEnd::Paragraph | TagEnd::Heading(_) | TagEnd::Item) => text.push(' '),
+            _ => {}
+        }
+    }
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Cuts `text` to at most `length` characters at a word boundary, adding an
+/// ellipsis when anything was removed.
+pub fn truncate(text: &str, length: usize) -> String {
+    if text.chars().count() <= length {
+        return text.to_string();
+    }
+    let cut: String = text.chars().take(length).collect();
+    let cut = match cut.rfind(char::is_whitespace) {
+        Some(end) if end > 0 => &cut[..end],
+        _ => &cut,
+    };
+    format!(
+        "{}…",
+        cut.trim_end_matches(|c: char| c.is_whitespace() || c.is_ascii_punctuation())
+    )
+}
@@ -0,0 +1,230 @@
+use crate::config::RoutesConfig;
+use crate::content_types;
+use crate::render_cache::RENDER_CACHE;
+use crate::repository::Repository;
+use lazy_static::lazy_static;
+use prometheus::{
+    exponential_buckets, register_counter_vec, register_histogram, register_histogram_vec,
+    register_int_counter_vec, register_int_gauge, register_int_gauge_vec, CounterVec, Encoder,
+    Histogram, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, TextEncoder,
+};
+use std::time::Instant;
+use warp::http::StatusCode;
+
+lazy_static! {
+    static ref HTTP_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "craftcms_http_requests_total",
+        "HTTP requests by route, method and status",
+        &["route", "method", "status"]
+    )
+    .unwrap();
+    static ref HTTP_DURATION: HistogramVec = register_histogram_vec!(
+        "craftcms_http_request_duration_seconds",
+        "Time to answer HTTP requests by route and method",
+        &["route", "method"]
+    )
+    .unwrap();
+    static ref UPLOAD_SIZE: Histogram = register_histogram!(
+        "craftcms_upload_size_bytes",
+        "Size of uploaded image files",
+        exponential_buckets(16.0 * 1024.0, 4.0, 7).unwrap()
+    )
+    .unwrap();
+    static ref RENDER_DURATION: HistogramVec = register_histogram_vec!(
+        "craftcms_template_render_seconds",
+        "Time spent rendering templates",
+        &["template"],
+        exponential_buckets(0.0005, 2.0, 12).unwrap()
+    )
+    .unwrap();
+    static ref IMAGES: IntGaugeVec = register_int_gauge_vec!(
+        "craftcms_images",
+        "Images by storage status: blob (content-addressed) or legacy (stored by slug)",
+        &["status"]
+    )
+    .unwrap();
+    static ref ACTIVE_SESSIONS: IntGauge = register_int_gauge!(
+        "craftcms_active_sessions",
+        "Admin sessions that have not expired"
+    )
+    .unwrap();
+    static ref DB_CONNECTIONS: IntGaugeVec = register_int_gauge_vec!(
+        "craftcms_db_connections",
+        "Pooled database connections by state",
+        &["backend", "state"]
+    )
+    .unwrap();
+    static ref DB_OPERATIONS: CounterVec = register_counter_vec!(
+        "craftcms_db_operations_total",
+        "Database connection checkouts by kind, and failed operations",
+        &["kind"]
+    )
+    .unwrap();
+    static ref DB_WAIT: CounterVec = register_counter_vec!(
+        "craftcms_db_wait_seconds_total",
+        "Time spent waiting for a database connection or the write lock",
+        &["kind"]
+    )
+    .unwrap();
+    static ref RENDER_CACHE_EVENTS: CounterVec = register_counter_vec!(
+        "craftcms_render_cache_events_total",
+        "Render cache lookups and removals",
+        &["event"]
+    )
+    .unwrap();
+    static ref RENDER_CACHE_ENTRIES: IntGauge = register_int_gauge!(
+        "craftcms_render_cache_entries",
+        "Pages currently held in the render cache"
+    )
+    .unwrap();
+}
+
+/// Paths served under `/admin/`, by their first segment.
+const ADMIN_ACTIONS: &[&str] = &[
+    "login",
+    "logout",
+    "forgot",
+    "reset",
+    "new",
+    "create",
+    "edit",
+    "update",
+    "delete",
+    "media",
+    "duplicates",
+    "pages",
+    "types",
+    "content",
+    "preview",
+    "cache",
+    "db",
+    "themes",
+];
+
+/// Single-segment paths with a route of their own.
+const FIXED_PATHS: &[&str] = &[
+    "healthz",
+    "readyz",
+    "version",
+    "metrics",
+    "csp-report",
+    "theme.css",
+];
+
+/// A low-cardinality name for the route that answered `path`, so slugs and
+/// file keys don't each become their own time series. Anything unknown is
+/// `"other"`, since labels are recorded before auth.
+pub fn route_label(path: &str, status: StatusCode, routes: &RoutesConfig) -> String {
+    if status == StatusCode::NOT_FOUND || status == StatusCode::METHOD_NOT_ALLOWED {
+        return "unmatched".to_string();
+    }
+
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match segments.as_slice() {
+        [""] => "/".to_string(),
+        [first, ..] if *first == routes.images_path => format!("/{}/*", routes.images_path),
+        ["static", ..] => "/static/*".to_string(),
+        ["admin"] => "/admin".to_string(),
+        ["admin", "assets", ..] => "/admin/assets/*".to_string(),
+        ["admin", action] if ADMIN_ACTIONS.contains(action) => format!("/admin/{}", action),
+        ["admin", action, ..] if ADMIN_ACTIONS.contains(action) => format!("/admin/{}/*", action),
+        ["__dev", ..] => "/__dev/*".to_string(),
+        [first, _] if *first == routes.detail_path => format!("/{}/:slug", routes.detail_path),
+        ["api", _] => "/api/:type".to_string(),
+        ["api", _, _] => "/api/:type/:slug".to_string(),
+        [first] if FIXED_PATHS.contains(first) => format!("/{}", first),
+        [first] if content_types::active().by_path(first).is_some() => format!("/{}", first),
+        [first, _] if content_types::active().by_path(first).is_some() => {
+            format!("/{}/:slug", first)
+        }
+        [_] => "/:page".to_string(),
+        _ => "other".to_string(),
+    }
+}
+
+/// Counts a finished request. Called from the access log filter.
+pub fn observe_request(info: &warp::log::Info, routes: &RoutesConfig) {
+    let route = route_label(info.path(), info.status(), routes);
+    let method = info.method().as_str();
+    HTTP_REQUESTS
+        .with_label_values(&[&route, method, info.status().as_str()])
+        .inc();
+    HTTP_DURATION
+        .with_label_values(&[&route, method])
+        .observe(info.elapsed().as_secs_f64());
+}
+
+pub fn observe_upload(bytes: usize) {
+    UPLOAD_SIZE.observe(bytes as f64);
+}
+
+/// Runs `render`, recording how long it took under `template`.
+pub fn time_render<T>(template: &str, render: impl FnOnce() -> T) -> T {
+    let started = Instant::now();
+    let result = render();
+    RENDER_DURATION
+        .with_label_values(&[template])
+        .observe(started.elapsed().as_secs_f64());
+    result
+}
+
+/// Brings a counter up to a running total kept elsewhere.
+fn sync_counter(counters: &CounterVec, label: &str, total: f64) {
+    let counter = counters.with_label_values(&[label]);
+    let delta = total - counter.get();
+    if delta > 0.0 {
+        counter.inc_by(delta);
+    }
+}
+
+/// Refreshes the values read at scrape time and encodes every metric in the
+/// Prometheus text format. Database figures that can't be read are left at
+/// their previous values.
+pub async fn gather(repo: &dyn Repository) -> String {
+    match repo.image_counts().await {
+        Ok(counts) => {
+            IMAGES.reset();
+            for (status, count) in counts {
+                IMAGES.with_label_values(&[&status]).set(count);
+            }
+        }
+        Err(e) => tracing::warn!(error = %e, "Failed to count images for metrics"),
+    }
+    match repo.active_sessions().await {
+        Ok(count) => ACTIVE_SESSIONS.set(count),
+        Err(e) => tracing::warn!(error = %e, "Failed to count sessions for metrics"),
+    }
+
+    let pool = repo.stats();
+    DB_CONNECTIONS
+        .with_label_values(&[pool.backend, "max"])
+        .set(pool.max_connections.into());
+    DB_CONNECTIONS
+        .with_label_values(&[pool.backend, "open"])
+        .set(pool.connections.into());
+    DB_CONNECTIONS
+        .with_label_values(&[pool.backend, "idle"])
+        .set(pool.idle_connections.into());
+    sync_counter(&DB_OPERATIONS, "read", pool.reads as f64);
+    sync_counter(&DB_OPERATIONS, "write", pool.writes as f64);
+    sync_counter(&DB_OPERATIONS, "error", pool.errors as f64);
+    sync_counter(&DB_WAIT, "read", pool.read_wait_micros as f64 / 1e6);
+    sync_counter(&DB_WAIT, "write", pool.write_wait_micros as f64 / 1e6);
+
+    let cache = RENDER_CACHE.stats();
+    RENDER_CACHE_ENTRIES.set(cache.entries as i64);
+    sync_counter(&RENDER_CACHE_EVENTS, "hit", cache.hits as f64);
+    sync_counter(&RENDER_CACHE_EVENTS, "miss", cache.misses as f64);
+    sync_counter(&RENDER_CACHE_EVENTS, "eviction", cache.evictions as f64);
+    sync_counter(
+        &RENDER_CACHE_EVENTS,
+        "invalidation",
+        cache.invalidations as f64,
+    );
+
+    let mut buffer = Vec::new();
+    if let Err(e) = TextEncoder::new().encode(&prometheus::gather(), &mut buffer) {
+        tracing::error!(error = %e, "Failed to encode metrics");
+    }
+    String::from_utf8(buffer).unwrap_or_default()
+}
@@ -1,37 +1,33 @@
+use crate::error::AppError;
 use crate::files::ImageFileManager;
-use crate::{CustomError, RedirectToLogin};
-use rusqlite::Connection;
-use std::sync::{Arc, Mutex};
+use crate::logging;
+use crate::mailer::Mailer;
+use crate::models::RedirectToLogin;
+use crate::repository::Repository;
+use std::sync::Arc;
 use warp::Filter;
 
 pub fn with_auth(
-    conn: Arc<Mutex<Connection>>,
+    repo: Arc<dyn Repository>,
 ) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
     warp::cookie::optional("session")
-        .and(warp::any().map(move || conn.clone()))
+        .and(warp::any().map(move || repo.clone()))
         .and_then(
-            |session: Option<String>, conn: Arc<Mutex<Connection>>| async move {
+            |session: Option<String>, repo: Arc<dyn Repository>| async move {
                 match session {
                     None => Err(warp::reject::custom(RedirectToLogin)),
                     Some(session_id) => {
-                        let conn_guard = conn.lock().map_err(|_| {
-                            warp::reject::custom(CustomError {
-                                message: "Internal server error".to_string(),
-                            })
+                        let user = repo.session_user(&session_id).await.map_err(|e| {
+                            AppError::Internal(format!("Failed to verify session: {}", e))
                         })?;
 
-                        let is_valid = crate::database::verify_session(&conn_guard, &session_id)
-                            .map_err(|_| {
-                                warp::reject::custom(CustomError {
-                                    message: "Authentication error".to_string(),
-                                })
-                            })?;
-
-                        if !is_valid {
-                            return Err(warp::reject::custom(RedirectToLogin));
+                        match user {
+                            Some(user) => {
+                                logging::record_user(&user);
+                                Ok(())
+                            }
+                            None => Err(warp::reject::custom(RedirectToLogin)),
                         }
-
-                        Ok(())
                     }
                 }
             },
@@ -44,3 +40,9 @@ pub fn with_file_manager(
 ) -> impl Filter<Extract = (Arc<ImageFileManager>,), Error = std::convert::Infallible> + Clone {
     warp::any().map(move || file_manager.clone())
 }
+
+pub fn with_mailer(
+    mailer: Arc<dyn Mailer>,
+) -> impl Filter<Extract = (Arc<dyn Mailer>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || mailer.clone())
+}
@@ -0,0 +1,328 @@
+/// One schema change, written once per supported database. Both backends
+/// apply the same list in the same order, so a given version number means
+/// the same schema everywhere.
+pub struct Migration {
+    pub sqlite: &'static str,
+    pub postgres: &'static str,
+}
+
+/// Schema changes, applied in order. SQLite records how many have run in
+/// `PRAGMA user_version`, PostgreSQL in the `schema_migrations` table, so
+/// each entry runs exactly once per database. Never edit an entry that has
+/// shipped; append a new one instead.
+///
+/// PostgreSQL timestamps are stored as UTC `TIMESTAMP` to match SQLite's
+/// `CURRENT_TIMESTAMP`.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        sqlite: r#"
+    CREATE TABLE IF NOT EXISTS images (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        alt TEXT NOT NULL,
+        description TEXT,
+        slug TEXT UNIQUE NOT NULL,
+        keywords TEXT,
+        filename TEXT NOT NULL,
+        created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+        updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+    );
+
+    CREATE TABLE IF NOT EXISTS users (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        email TEXT UNIQUE NOT NULL,
+        password_hash TEXT NOT NULL,
+        created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+    );
+
+    CREATE TABLE IF NOT EXISTS sessions (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        session_id TEXT UNIQUE NOT NULL,
+        user_id INTEGER NOT NULL,
+        created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+        expires_at DATETIME NOT NULL,
+        FOREIGN KEY(user_id) REFERENCES users(id)
+    );
+    "#,
+        postgres: r#"
+    CREATE TABLE IF NOT EXISTS images (
+        id BIGSERIAL PRIMARY KEY,
+        alt TEXT NOT NULL,
+        description TEXT,
+        slug TEXT UNIQUE NOT NULL,
+        keywords TEXT,
+        filename TEXT NOT NULL,
+        created_at TIMESTAMP DEFAULT (now() AT TIME ZONE 'utc'),
+        updated_at TIMESTAMP DEFAULT (now() AT TIME ZONE 'utc')
+    );
+
+    CREATE TABLE IF NOT EXISTS users (
+        id BIGSERIAL PRIMARY KEY,
+        email TEXT UNIQUE NOT NULL,
+        password_hash TEXT NOT NULL,
+        created_at TIMESTAMP DEFAULT (now() AT TIME ZONE 'utc')
+    );
+
+    CREATE TABLE IF NOT EXISTS sessions (
+        id BIGSERIAL PRIMARY KEY,
+        session_id TEXT UNIQUE NOT NULL,
+        user_id BIGINT NOT NULL REFERENCES users(id),
+        created_at TIMESTAMP DEFAULT (now() AT TIME ZONE 'utc'),
+        expires_at TIMESTAMP NOT NULL
+    );
+    "#,
+    },
+    Migration {
+        sqlite: r#"
+    ALTER TABLE users ADD COLUMN disabled INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE users ADD COLUMN last_login_at DATETIME;
+    "#,
+        postgres: r#"
+    ALTER TABLE users ADD COLUMN disabled BOOLEAN NOT NULL DEFAULT FALSE;
+    ALTER TABLE users ADD COLUMN last_login_at TIMESTAMP;
+    "#,
+    },
+    Migration {
+        sqlite: r#"
+    CREATE TABLE IF NOT EXISTS password_resets (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        user_id INTEGER NOT NULL,
+        token_hash TEXT UNIQUE NOT NULL,
+        created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+        expires_at DATETIME NOT NULL,
+        used_at DATETIME,
+        FOREIGN KEY(user_id) REFERENCES users(id)
+    );
+    "#,
+        postgres: r#"
+    CREATE TABLE IF NOT EXISTS password_resets (
+        id BIGSERIAL PRIMARY KEY,
+        user_id BIGINT NOT NULL REFERENCES users(id),
+        token_hash TEXT UNIQUE NOT NULL,
+        created_at TIMESTAMP DEFAULT (now() AT TIME ZONE 'utc'),
+        expires_at TIMESTAMP NOT NULL,
+        used_at TIMESTAMP
+    );
+    "#,
+    },
+    Migration {
+        sqlite: r#"
+    CREATE TABLE IF NOT EXISTS blobs (
+        hash TEXT PRIMARY KEY,
+        storage_key TEXT NOT NULL,
+        content_type TEXT NOT NULL,
+        size INTEGER NOT NULL,
+        ref_count INTEGER NOT NULL DEFAULT 0,
+        created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+    );
+
+    CREATE TABLE IF NOT EXISTS image_blobs (
+        image_id INTEGER PRIMARY KEY,
+        blob_hash TEXT NOT NULL,
+        FOREIGN KEY(image_id) REFERENCES images(id),
+        FOREIGN KEY(blob_hash) REFERENCES blobs(hash)
+    );
+    "#,
+        postgres: r#"
+    CREATE TABLE IF NOT EXISTS blobs (
+        hash TEXT PRIMARY KEY,
+        storage_key TEXT NOT NULL,
+        content_type TEXT NOT NULL,
+        size BIGINT NOT NULL,
+        ref_count BIGINT NOT NULL DEFAULT 0,
+        created_at TIMESTAMP DEFAULT (now() AT TIME ZONE 'utc')
+    );
+
+    CREATE TABLE IF NOT EXISTS image_blobs (
+        image_id BIGINT PRIMARY KEY REFERENCES images(id),
+        blob_hash TEXT NOT NULL REFERENCES blobs(hash)
+    );
+    "#,
+    },
+    Migration {
+        sqlite: r#"
+    CREATE TABLE IF NOT EXISTS content_state (
+        id INTEGER PRIMARY KEY CHECK (id = 1),
+        updated_at DATETIME DEFAULT CURRENT_TIMESTAMP
+    );
+    INSERT OR IGNORE INTO content_state (id) VALUES (1);
+
+    CREATE TRIGGER IF NOT EXISTS images_touch_insert AFTER INSERT ON images
+    BEGIN
+        UPDATE content_state SET updated_at = CURRENT_TIMESTAMP;
+    END;
+    CREATE TRIGGER IF NOT EXISTS images_touch_update AFTER UPDATE ON images
+    BEGIN
+        UPDATE content_state SET updated_at = CURRENT_TIMESTAMP;
+    END;
+    CREATE TRIGGER IF NOT EXISTS images_touch_delete AFTER DELETE ON images
+    BEGIN
+        UPDATE content_state SET updated_at = CURRENT_TIMESTAMP;
+    END;
+    "#,
+        postgres: r#"
+    CREATE TABLE IF NOT EXISTS content_state (
+        id INTEGER PRIMARY KEY CHECK (id = 1),
+        updated_at TIMESTAMP DEFAULT (now() AT TIME ZONE 'utc')
+    );
+    INSERT INTO content_state (id) VALUES (1) ON CONFLICT DO NOTHING;
+
+    CREATE OR REPLACE FUNCTION touch_content_state() RETURNS trigger AS $$
+    BEGIN
+        UPDATE content_state SET updated_at = now() AT TIME ZONE 'utc';
+        RETURN NULL;
+    END;
+    $$ LANGUAGE plpgsql;
+
+    CREATE TRIGGER images_touch AFTER INSERT OR UPDATE OR DELETE ON images
+    FOR EACH STATEMENT EXECUTE FUNCTION touch_content_state();
+    "#,
+    },
+    Migration {
+        sqlite: r#"
+    CREATE TABLE IF NOT EXISTS settings (
+        key TEXT PRIMARY KEY,
+        value TEXT NOT NULL,
+        updated_at DATETIME DEFAULT CURRENT_TIMESTAMP
+    );
+
+    CREATE TRIGGER IF NOT EXISTS settings_touch_insert AFTER INSERT ON settings
+    BEGIN
+        UPDATE content_state SET updated_at = CURRENT_TIMESTAMP;
+    END;
+    CREATE TRIGGER IF NOT EXISTS settings_touch_update AFTER UPDATE ON settings
+    BEGIN
+        UPDATE content_state SET updated_at = CURRENT_TIMESTAMP;
+    END;
+    "#,
+        postgres: r#"
+    CREATE TABLE IF NOT EXISTS settings (
+        key TEXT PRIMARY KEY,
+        value TEXT NOT NULL,
+        updated_at TIMESTAMP DEFAULT (now() AT TIME ZONE 'utc')
+    );
+
+    CREATE TRIGGER settings_touch AFTER INSERT OR UPDATE ON settings
+    FOR EACH STATEMENT EXECUTE FUNCTION touch_content_state();
+    "#,
+    },
+    Migration {
+        sqlite: r#"
+    CREATE TABLE IF NOT EXISTS pages (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        slug TEXT UNIQUE NOT NULL,
+        title TEXT NOT NULL,
+        body TEXT NOT NULL DEFAULT '',
+        template TEXT NOT NULL DEFAULT 'page.html',
+        nav_order INTEGER,
+        published INTEGER NOT NULL DEFAULT 0,
+        created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+        updated_at DATETIME DEFAULT CURRENT_TIMESTAMP
+    );
+
+    CREATE TRIGGER IF NOT EXISTS pages_touch_insert AFTER INSERT ON pages
+    BEGIN
+        UPDATE content_state SET updated_at = CURRENT_TIMESTAMP;
+    END;
+    CREATE TRIGGER IF NOT EXISTS pages_touch_update AFTER UPDATE ON pages
+    BEGIN
+        UPDATE content_state SET updated_at = CURRENT_TIMESTAMP;
+    END;
+    CREATE TRIGGER IF NOT EXISTS pages_touch_delete AFTER DELETE ON pages
+    BEGIN
+        UPDATE content_state SET updated_at = CURRENT_TIMESTAMP;
+    END;
+    "#,
+        postgres: r#"
+    CREATE TABLE IF NOT EXISTS pages (
+        id BIGSERIAL PRIMARY KEY,
+        slug TEXT UNIQUE NOT NULL,
+        title TEXT NOT NULL,
+        body TEXT NOT NULL DEFAULT '',
+        template TEXT NOT NULL DEFAULT 'page.html',
+        nav_order INTEGER,
+        published BOOLEAN NOT NULL DEFAULT FALSE,
+        created_at TIMESTAMP DEFAULT (now() AT TIME ZONE 'utc'),
+        updated_at TIMESTAMP DEFAULT (now() AT TIME ZONE 'utc')
+    );
+
+    CREATE TRIGGER pages_touch AFTER INSERT OR UPDATE OR DELETE ON pages
+    FOR EACH STATEMENT EXECUTE FUNCTION touch_content_state();
+    "#,
+    },
+    Migration {
+        sqlite: r#"
+    CREATE TABLE IF NOT EXISTS entries (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        content_type TEXT NOT NULL,
+        slug TEXT NOT NULL,
+        title TEXT NOT NULL,
+        fields TEXT NOT NULL DEFAULT '{}',
+        published INTEGER NOT NULL DEFAULT 0,
+        created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+        updated_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+        UNIQUE(content_type, slug)
+    );
+
+    CREATE TRIGGER IF NOT EXISTS entries_touch_insert AFTER INSERT ON entries
+    BEGIN
+        UPDATE content_state SET updated_at = CURRENT_TIMESTAMP;
+    END;
+    CREATE TRIGGER IF NOT EXISTS entries_touch_update AFTER UPDATE ON entries
+    BEGIN
+        UPDATE content_state SET updated_at = CURRENT_TIMESTAMP;
+    END;
+    CREATE TRIGGER IF NOT EXISTS entries_touch_delete AFTER DELETE ON entries
+    BEGIN
+        UPDATE content_state SET updated_at = CURRENT_TIMESTAMP;
+    END;
+    "#,
+        postgres: r#"
+    CREATE TABLE IF NOT EXISTS entries (
+        id BIGSERIAL PRIMARY KEY,
+        content_type TEXT NOT NULL,
+        slug TEXT NOT NULL,
+        title TEXT NOT NULL,
+        fields TEXT NOT NULL DEFAULT '{}',
+        published BOOLEAN NOT NULL DEFAULT FALSE,
+        created_at TIMESTAMP DEFAULT (now() AT TIME ZONE 'utc'),
+        updated_at TIMESTAMP DEFAULT (now() AT TIME ZONE 'utc'),
+        UNIQUE(content_type, slug)
+    );
+
+    CREATE TRIGGER entries_touch AFTER INSERT OR UPDATE OR DELETE ON entries
+    FOR EACH STATEMENT EXECUTE FUNCTION touch_content_state();
+    "#,
+    },
+    Migration {
+        sqlite: r#"
+    CREATE TABLE IF NOT EXISTS image_media (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        image_id INTEGER NOT NULL,
+        position INTEGER NOT NULL,
+        filename TEXT NOT NULL,
+        blob_hash TEXT NOT NULL,
+        alt TEXT NOT NULL,
+        caption TEXT NOT NULL DEFAULT '',
+        created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+        FOREIGN KEY(image_id) REFERENCES images(id),
+        FOREIGN KEY(blob_hash) REFERENCES blobs(hash)
+    );
+
+    CREATE INDEX IF NOT EXISTS image_media_position ON image_media (image_id, position);
+    "#,
+        postgres: r#"
+    CREATE TABLE IF NOT EXISTS image_media (
+        id BIGSERIAL PRIMARY KEY,
+        image_id BIGINT NOT NULL REFERENCES images(id),
+        position INTEGER NOT NULL,
+        filename TEXT NOT NULL,
+        blob_hash TEXT NOT NULL REFERENCES blobs(hash),
+        alt TEXT NOT NULL,
+        caption TEXT NOT NULL DEFAULT '',
+        created_at TIMESTAMP DEFAULT (now() AT TIME ZONE 'utc')
+    );
+
+    CREATE INDEX IF NOT EXISTS image_media_position ON image_media (image_id, position);
+    "#,
+    },
+];
@@ -1,13 +1,21 @@
+use crate::content_types::{ContentType, FieldKind};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
+#[derive(Serialize)]
 pub struct User {
     pub id: i64,
     pub email: String,
+    #[serde(skip)]
     pub password_hash: String,
+    #[serde(skip)]
     pub access_token: Option<String>,
+    pub disabled: bool,
+    pub created_at: Option<String>,
+    pub last_login_at: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Image {
     pub alt: String,
     pub description: String,
@@ -16,6 +24,195 @@ pub struct Image {
     pub filename: String,
 }
 
+/// An image as templates see it: its fields plus the description rendered
+/// from Markdown.
+#[derive(Debug, Serialize)]
+pub struct ImageView {
+    #[serde(flatten)]
+    pub image: Image,
+    /// Sanitized HTML, printed with `| safe`
+    pub description_html: String,
+    /// Plain text for meta descriptions
+    pub excerpt: String,
+}
+
+impl From<Image> for ImageView {
+    fn from(image: Image) -> Self {
+        let rendered = crate::markdown::render(&image.description);
+        Self {
+            description_html: rendered.html.clone(),
+            excerpt: rendered.excerpt.clone(),
+            image,
+        }
+    }
+}
+
+/// An extra shot of an image, such as its back or a glaze close-up. The
+/// image's own file stays its primary picture and thumbnail; media follow
+/// it in order.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Media {
+    pub id: i64,
+    /// The stored file's key, usable as `image_url(slug=...)`
+    #[serde(default, skip_deserializing)]
+    pub filename: String,
+    pub alt: String,
+    #[serde(default)]
+    pub caption: String,
+}
+
+/// A file uploaded to add to an image's media.
+pub struct MediaUpload {
+    pub data: Vec<u8>,
+    pub mime_type: mime::Mime,
+    pub alt: String,
+    pub caption: String,
+}
+
+/// One picture of an image's gallery on its detail page.
+#[derive(Debug, Serialize)]
+pub struct GalleryItem {
+    pub filename: String,
+    pub alt: String,
+    pub caption: String,
+    /// The image's own file, which the gallery starts with
+    pub primary: bool,
+}
+
+impl GalleryItem {
+    /// The image's own file followed by its media.
+    pub fn list(image: &Image, media: Vec<Media>) -> Vec<Self> {
+        let primary = GalleryItem {
+            filename: image.filename.clone(),
+            alt: image.alt.clone(),
+            caption: String::new(),
+            primary: true,
+        };
+        std::iter::once(primary)
+            .chain(media.into_iter().map(|media| GalleryItem {
+                filename: media.filename,
+                alt: media.alt,
+                caption: media.caption,
+                primary: false,
+            }))
+            .collect()
+    }
+}
+
+fn default_page_template() -> String {
+    crate::pages::DEFAULT_TEMPLATE.to_string()
+}
+
+/// A standalone page such as About or Commissions, served at `/<slug>`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Page {
+    pub slug: String,
+    pub title: String,
+    /// Markdown
+    #[serde(default)]
+    pub body: String,
+    /// The theme template it renders with, `page.html` or a `page-*.html`
+    #[serde(default = "default_page_template")]
+    pub template: String,
+    /// Position in `nav`; pages without one are left out of menus
+    #[serde(default)]
+    pub nav_order: Option<i32>,
+    #[serde(default)]
+    pub published: bool,
+    #[serde(default, skip_deserializing)]
+    pub updated_at: Option<String>,
+}
+
+/// A page as templates see it, with its body rendered from Markdown.
+#[derive(Debug, Serialize)]
+pub struct PageView {
+    #[serde(flatten)]
+    pub page: Page,
+    pub body_html: String,
+    pub excerpt: String,
+}
+
+impl From<Page> for PageView {
+    fn from(page: Page) -> Self {
+        let rendered = crate::markdown::render(&page.body);
+        Self {
+            body_html: rendered.html.clone(),
+            excerpt: rendered.excerpt.clone(),
+            page,
+        }
+    }
+}
+
+/// An entry of a custom content type, such as an event or a stockist. Its
+/// values are checked against the type's fields before they are saved.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    /// The type's name, taken from the URL rather than the body
+    #[serde(default, skip_deserializing)]
+    pub content_type: String,
+    pub slug: String,
+    pub title: String,
+    /// Values keyed by field
+    #[serde(default)]
+    pub fields: serde_json::Map<String, serde_json::Value>,
+    #[serde(default)]
+    pub published: bool,
+    #[serde(default, skip_deserializing)]
+    pub updated_at: Option<String>,
+}
+
+/// An entry as templates and the API see it, with its Markdown fields
+/// rendered and its URL.
+#[derive(Debug, Serialize)]
+pub struct EntryView {
+    #[serde(flatten)]
+    pub entry: Entry,
+    /// Sanitized HTML of each Markdown field, printed with `| safe`
+    pub html: BTreeMap<String, String>,
+    /// Plain text of the first Markdown field, for meta descriptions
+    pub excerpt: String,
+    pub url: String,
+}
+
+impl EntryView {
+    pub fn new(entry: Entry, content_type: &ContentType) -> Self {
+        let mut html = BTreeMap::new();
+        let mut excerpt = None;
+        for spec in &content_type.fields {
+            if spec.kind != FieldKind::Markdown {
+                continue;
+            }
+            let source = entry
+                .fields
+                .get(&spec.key)
+                .and_then(serde_json::Value::as_str)
+                .unwrap_or_default();
+            let rendered = crate::markdown::render(source);
+            excerpt.get_or_insert_with(|| rendered.excerpt.clone());
+            html.insert(spec.key.clone(), rendered.html.clone());
+        }
+        Self {
+            url: content_type.entry_url(&entry.slug),
+            excerpt: excerpt.unwrap_or_default(),
+            html,
+            entry,
+        }
+    }
+}
+
+/// A content type written in the admin: its name and its TOML definition.
+#[derive(Debug, Deserialize)]
+pub struct ContentTypeForm {
+    pub name: String,
+    pub definition: String,
+}
+
+/// Markdown from an admin form, rendered for a preview.
+#[derive(Debug, Deserialize)]
+pub struct MarkdownPreview {
+    pub markdown: String,
+}
+
 #[derive(Deserialize)]
 pub struct ImageForm {
     pub alt: String,
@@ -42,27 +239,54 @@ pub struct ImageInput {
     pub image_type: String,
 }
 
-use std::fmt;
-use warp::reject::Reject;
-
-#[derive(Debug)]
-pub struct CustomError {
-    pub message: String,
+/// Partial image update; fields left as `None` keep their current value.
+#[derive(Debug, Default, Deserialize)]
+pub struct ImagePatch {
+    #[serde(default, alias = "URL", alias = "url", alias = "Url")]
+    pub path: Option<String>,
+    #[serde(default, alias = "Alt")]
+    pub alt: Option<String>,
+    #[serde(default, alias = "Description")]
+    pub description: Option<String>,
+    #[serde(default, alias = "Slug")]
+    pub slug: Option<String>,
+    #[serde(default, alias = "Keywords")]
+    pub keywords: Option<Vec<String>>,
 }
 
-impl CustomError {
-    pub fn new(message: String) -> CustomError {
-        CustomError { message }
-    }
+#[derive(Debug, Default)]
+pub struct ImageFilter {
+    pub keyword: Option<String>,
+    pub search: Option<String>,
+    pub limit: Option<usize>,
 }
 
-impl fmt::Display for CustomError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.message)
+impl ImageFilter {
+    /// The images that match, in the order given, up to the limit.
+    pub fn apply(&self, images: Vec<Image>) -> Vec<Image> {
+        let keyword = self.keyword.as_ref().map(|k| k.to_lowercase());
+        let search = self.search.as_ref().map(|s| s.to_lowercase());
+
+        images
+            .into_iter()
+            .filter(|image| match &keyword {
+                Some(keyword) => image.keywords.iter().any(|k| k.to_lowercase() == *keyword),
+                None => true,
+            })
+            .filter(|image| match &search {
+                Some(search) => {
+                    image.alt.to_lowercase().contains(search)
+                        || image.description.to_lowercase().contains(search)
+                        || image.slug.contains(search)
+                }
+                None => true,
+            })
+            .take(self.limit.unwrap_or(usize::MAX))
+            .collect()
     }
 }
 
-impl Reject for CustomError {}
+use warp::reject::Reject;
 
 #[derive(Deserialize)]
 pub struct LoginCredentials {
@@ -70,6 +294,35 @@ pub struct LoginCredentials {
     pub password: String,
 }
 
+#[derive(Deserialize)]
+pub struct ForgotPasswordRequest {
+    pub email: String,
+}
+
+#[derive(Deserialize)]
+pub struct ResetPasswordRequest {
+    pub token: String,
+    pub password: String,
+}
+
+/// `?w=640&fmt=webp` on an image URL, asking for a resized variant.
+#[derive(Debug, Default, Deserialize)]
+pub struct ImageVariantQuery {
+    pub w: Option<u32>,
+    pub fmt: Option<String>,
+}
+
+/// `?v=` on a static asset URL, fingerprinting the version linked to.
+#[derive(Debug, Default, Deserialize)]
+pub struct AssetQuery {
+    pub v: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ResetPasswordQuery {
+    pub token: Option<String>,
+}
+
 #[derive(Serialize)]
 pub struct LoginResponse {
     pub access_token: String,
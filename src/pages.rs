@@ -0,0 +1,182 @@
+//! Standalone pages such as About or Commissions, and the `nav` menu every
+//! public template gets from them.
+
+use crate::config::Config;
+use crate::content_types;
+use crate::models::Page;
+use crate::render_cache::RENDER_CACHE;
+use crate::repository::{RepoError, Repository};
+use crate::templates::TEMPLATES;
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+/// The template pages render with unless they pick another.
+pub const DEFAULT_TEMPLATE: &str = "page.html";
+
+/// Top-level paths that something other than a page answers; see
+/// `run_server`. The detail and image paths come from `[routes]`, and
+/// content types have their own.
+const RESERVED_SLUGS: &[&str] = &[
+    "admin",
+    "api",
+    "static",
+    "healthz",
+    "readyz",
+    "version",
+    "metrics",
+    "csp-report",
+];
+
+const MAX_SLUG_LENGTH: usize = 100;
+const MAX_TITLE_LENGTH: usize = 200;
+
+/// A link in the `nav` menu.
+#[derive(Debug, Clone, Serialize)]
+pub struct NavItem {
+    pub title: String,
+    pub slug: String,
+    pub url: String,
+}
+
+struct Nav {
+    items: Arc<Vec<NavItem>>,
+    /// `None` once a page has changed, so the next refresh reloads
+    loaded_at: Option<Instant>,
+}
+
+lazy_static! {
+    static ref NAV: RwLock<Nav> = RwLock::new(Nav {
+        items: Arc::new(Vec::new()),
+        loaded_at: None,
+    });
+}
+
+/// Bumped by every page write, so a refresh that raced one doesn't mark
+/// what it read as current.
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Whether `name` is a template pages can use: `page.html` or a
+/// `page-<name>.html` variant.
+pub fn is_page_template(name: &str) -> bool {
+    name == DEFAULT_TEMPLATE
+        || (name.starts_with("page-") && name.ends_with(".html") && !name.contains('/'))
+}
+
+/// The page templates the active theme provides, for the admin to offer.
+pub fn templates() -> Vec<String> {
+    let mut names: Vec<String> = TEMPLATES
+        .template_names()
+        .into_iter()
+        .filter(|name| is_page_template(name))
+        .collect();
+    names.sort();
+    names
+}
+
+/// Lowercase letters, digits and single hyphens, as page and entry slugs
+/// and content type paths use.
+pub fn is_valid_slug(slug: &str) -> bool {
+    !slug.is_empty()
+        && slug.len() <= MAX_SLUG_LENGTH
+        && slug.split('-').all(|part| {
+            !part.is_empty()
+                && part
+                    .bytes()
+                    .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit())
+        })
+}
+
+/// Whether `/<slug>` is answered by a built-in route or `[routes]`, leaving
+/// it to neither a page nor a content type.
+pub fn is_reserved(slug: &str, config: &Config) -> bool {
+    RESERVED_SLUGS.contains(&slug)
+        || slug == config.routes.detail_path
+        || slug == config.routes.images_path
+}
+
+/// Checks a page from the admin before it is saved, returning a message
+/// for the form. `current_template` is what an existing page uses now,
+/// which stays allowed after switching to a theme that lacks it.
+pub fn validate(
+    page: &Page,
+    config: &Config,
+    current_template: Option<&str>,
+) -> Result<(), String> {
+    let title = page.title.trim();
+    if title.is_empty() {
+        return Err("A page needs a title".to_string());
+    }
+    if title.chars().count() > MAX_TITLE_LENGTH {
+        return Err(format!(
+            "Titles can be at most {} characters",
+            MAX_TITLE_LENGTH
+        ));
+    }
+    if !is_valid_slug(&page.slug) {
+        return Err(
+            "Slugs use lowercase letters, digits and single hyphens, like 'about-me'".to_string(),
+        );
+    }
+    if is_reserved(&page.slug, config) || content_types::active().by_path(&page.slug).is_some() {
+        return Err(format!(
+            "'/{}' is already used by the site; pick another slug",
+            page.slug
+        ));
+    }
+    let kept = current_template == Some(page.template.as_str());
+    if !is_page_template(&page.template) || !(kept || TEMPLATES.has_template(&page.template)) {
+        return Err(format!(
+            "The theme has no page template '{}'",
+            page.template
+        ));
+    }
+    Ok(())
+}
+
+/// The published pages that have a menu position, in order.
+pub fn nav() -> Arc<Vec<NavItem>> {
+    NAV.read().unwrap().items.clone()
+}
+
+/// Called after every page write: drops all rendered pages, since each
+/// shows `nav`, and has the next refresh reload the menu.
+pub fn invalidate() {
+    GENERATION.fetch_add(1, Ordering::SeqCst);
+    NAV.write().unwrap().loaded_at = None;
+    RENDER_CACHE.clear();
+}
+
+/// Reloads `nav` if a page changed in this process or it is older than
+/// `max_age`, which bounds how long edits made through another server
+/// sharing the database take to show up.
+pub async fn refresh_nav(repo: &dyn Repository, max_age: Duration) -> Result<(), RepoError> {
+    let fresh = NAV
+        .read()
+        .unwrap()
+        .loaded_at
+        .is_some_and(|loaded_at| loaded_at.elapsed() < max_age);
+    if fresh {
+        return Ok(());
+    }
+
+    let generation = GENERATION.load(Ordering::SeqCst);
+    let items: Vec<NavItem> = repo
+        .pages()
+        .await?
+        .into_iter()
+        .filter(|page| page.published && page.nav_order.is_some())
+        .map(|page| NavItem {
+            url: format!("/{}", page.slug),
+            title: page.title,
+            slug: page.slug,
+        })
+        .collect();
+
+    let mut nav = NAV.write().unwrap();
+    nav.items = Arc::new(items);
+    nav.loaded_at = (GENERATION.load(Ordering::SeqCst) == generation).then(Instant::now);
+    Ok(())
+}
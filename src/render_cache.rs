@@ -0,0 +1,236 @@
+use lazy_static::lazy_static;
+use lru::LruCache;
+use serde::Serialize;
+use std::cell::RefCell;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// Tag carried by every page that lists images, such as the home page.
+pub const TAG_IMAGES: &str = "images";
+
+/// Tag for pages that show a single image.
+pub fn image_tag(slug: &str) -> String {
+    format!("image:{}", slug)
+}
+
+/// Tag carried by every page that lists entries of `content_type`.
+pub fn entries_tag(content_type: &str) -> String {
+    format!("entries:{}", content_type)
+}
+
+/// Tag for pages that show a single entry.
+pub fn entry_tag(content_type: &str, slug: &str) -> String {
+    format!("entry:{}:{}", content_type, slug)
+}
+
+/// A rendered page plus what's needed to answer conditional requests.
+#[derive(Debug, Clone)]
+pub struct CachedPage {
+    pub body: String,
+    pub updated_at: Option<String>,
+}
+
+struct Entry {
+    page: CachedPage,
+    tags: Vec<String>,
+    inserted_at: Instant,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct CacheStats {
+    pub capacity: usize,
+    pub entries: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub invalidations: u64,
+}
+
+/// LRU cache of rendered pages keyed by route and query string. Entries are
+/// tagged with the content they show so a change only drops the pages it
+/// affects. A capacity of zero disables caching.
+pub struct RenderCache {
+    entries: Option<Mutex<LruCache<String, Entry>>>,
+    capacity: usize,
+    ttl: Duration,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    invalidations: AtomicU64,
+    /// Bumped by every invalidation, so a render that raced one isn't
+    /// cached with what it read before the change.
+    generation: AtomicU64,
+}
+
+impl RenderCache {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            entries: NonZeroUsize::new(capacity).map(|size| Mutex::new(LruCache::new(size))),
+            capacity,
+            ttl,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+            invalidations: AtomicU64::new(0),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    pub fn key(route: &str, query: &str) -> String {
+        if query.is_empty() {
+            route.to_string()
+        } else {
+            format!("{}?{}", route, query)
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<CachedPage> {
+        let entries = self.entries.as_ref()?;
+        let mut entries = entries.lock().ok()?;
+
+        let page = match entries.get(key) {
+            Some(entry) if entry.inserted_at.elapsed() < self.ttl => Some(entry.page.clone()),
+            Some(_) => {
+                entries.pop(key);
+                self.evictions.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+            None => None,
+        };
+
+        match page {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        page
+    }
+
+    /// Read before loading what a page shows and passed back to `insert`.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    /// Caches `page` unless something was invalidated since `generation`
+    /// was read.
+    pub fn insert(&self, key: String, page: CachedPage, tags: Vec<String>, generation: u64) {
+        let Some(Ok(mut entries)) = self.entries.as_ref().map(|entries| entries.lock()) else {
+            return;
+        };
+        if self.generation.load(Ordering::SeqCst) != generation {
+            return;
+        }
+
+        let entry = Entry {
+            page,
+            tags,
+            inserted_at: Instant::now(),
+        };
+        if let Some((evicted, _)) = entries.push(key.clone(), entry) {
+            if evicted != key {
+                self.evictions.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Drops every page carrying any of `tags`.
+    pub fn invalidate(&self, tags: &[String]) {
+        let Some(Ok(mut entries)) = self.entries.as_ref().map(|entries| entries.lock()) else {
+            return;
+        };
+        self.generation.fetch_add(1, Ordering::SeqCst);
+
+        let stale: Vec<String> = entries
+            .iter()
+            .filter(|(_, entry)| entry.tags.iter().any(|tag| tags.contains(tag)))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &stale {
+            entries.pop(key);
+        }
+        self.invalidations
+            .fetch_add(stale.len() as u64, Ordering::Relaxed);
+    }
+
+    /// Drops every page, e.g. after templates or configuration are reloaded.
+    pub fn clear(&self) {
+        let Some(Ok(mut entries)) = self.entries.as_ref().map(|entries| entries.lock()) else {
+            return;
+        };
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.invalidations
+            .fetch_add(entries.len() as u64, Ordering::Relaxed);
+        entries.clear();
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            capacity: self.capacity,
+            entries: self
+                .entries
+                .as_ref()
+                .and_then(|entries| entries.lock().ok())
+                .map(|entries| entries.len())
+                .unwrap_or(0),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            invalidations: self.invalidations.load(Ordering::Relaxed),
+        }
+    }
+}
+
+static RENDER_CACHE_SETTINGS: OnceLock<(usize, Duration)> = OnceLock::new();
+
+/// Sets the size and TTL of `RENDER_CACHE`. Must be called before the first
+/// page is cached; later calls are ignored.
+pub fn configure(capacity: usize, ttl: Duration) {
+    let _ = RENDER_CACHE_SETTINGS.set((capacity, ttl));
+}
+
+lazy_static! {
+    pub static ref RENDER_CACHE: RenderCache = {
+        let (capacity, ttl) = RENDER_CACHE_SETTINGS
+            .get()
+            .copied()
+            .unwrap_or((128, Duration::from_secs(300)));
+        RenderCache::new(capacity, ttl)
+    };
+}
+
+/// Drops the pages that show `slug`, plus every listing page.
+pub fn invalidate_image(slug: &str) {
+    RENDER_CACHE.invalidate(&[TAG_IMAGES.to_string(), image_tag(slug)]);
+}
+
+/// Drops the pages that show the entry `slug`, plus every listing of its
+/// type.
+pub fn invalidate_entry(content_type: &str, slug: &str) {
+    RENDER_CACHE.invalidate(&[entries_tag(content_type), entry_tag(content_type, slug)]);
+}
+
+thread_local! {
+    static DEPENDENCIES: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
+}
+
+/// Runs a render, returning the tags of any content its templates fetched
+/// for themselves, such as through `get_images`, so the page can be tagged
+/// with them too.
+pub fn track_dependencies<T>(render: impl FnOnce() -> T) -> (T, Vec<String>) {
+    let outer = DEPENDENCIES.with(|tags| tags.borrow_mut().replace(Vec::new()));
+    let result = render();
+    let tags = DEPENDENCIES.with(|tags| std::mem::replace(&mut *tags.borrow_mut(), outer));
+    (result, tags.unwrap_or_default())
+}
+
+/// Records that the page being rendered shows content tagged `tag`.
+pub fn depend_on(tag: &str) {
+    DEPENDENCIES.with(|tags| {
+        if let Some(tags) = tags.borrow_mut().as_mut() {
+            if !tags.iter().any(|existing| existing == tag) {
+                tags.push(tag.to_string());
+            }
+        }
+    });
+}
@@ -0,0 +1,398 @@
+mod postgres;
+mod sqlite;
+
+pub use postgres::PostgresRepository;
+pub use sqlite::SqliteRepository;
+
+use crate::config::Config;
+use crate::files::ImageFileManager;
+use crate::models::{Entry, Image, Media, MediaUpload, Page, User};
+use async_trait::async_trait;
+use serde::Serialize;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+#[derive(Debug)]
+pub enum RepoError {
+    Sqlite(rusqlite::Error),
+    Postgres(tokio_postgres::Error),
+    Pool(String),
+    Task(tokio::task::JoinError),
+    NotFound(String),
+    /// The request conflicts with what is stored, such as a reorder that
+    /// leaves items out
+    Invalid(String),
+    Other(String),
+}
+
+impl fmt::Display for RepoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RepoError::Sqlite(e) => write!(f, "{}", e),
+            // The server's message says what went wrong; the client's is just "db error"
+            RepoError::Postgres(e) => match e.as_db_error() {
+                Some(db_error) => write!(f, "{}", db_error),
+                None => write!(f, "{}", e),
+            },
+            RepoError::Pool(message) => write!(f, "Connection pool error: {}", message),
+            RepoError::Task(e) => write!(f, "Database task failed: {}", e),
+            RepoError::NotFound(message)
+            | RepoError::Invalid(message)
+            | RepoError::Other(message) => {
+                write!(f, "{}", message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RepoError {}
+
+impl RepoError {
+    /// Whether the row being read, updated or deleted doesn't exist.
+    pub fn is_not_found(&self) -> bool {
+        matches!(
+            self,
+            RepoError::NotFound(_) | RepoError::Sqlite(rusqlite::Error::QueryReturnedNoRows)
+        )
+    }
+
+    /// Whether a write clashed with a unique column, such as an image slug
+    /// that is already taken.
+    pub fn is_unique_violation(&self) -> bool {
+        match self {
+            RepoError::Sqlite(rusqlite::Error::SqliteFailure(e, _)) => matches!(
+                e.extended_code,
+                rusqlite::ffi::SQLITE_CONSTRAINT_UNIQUE
+                    | rusqlite::ffi::SQLITE_CONSTRAINT_PRIMARYKEY
+            ),
+            RepoError::Postgres(e) => {
+                e.code() == Some(&tokio_postgres::error::SqlState::UNIQUE_VIOLATION)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl From<rusqlite::Error> for RepoError {
+    fn from(e: rusqlite::Error) -> Self {
+        RepoError::Sqlite(e)
+    }
+}
+
+impl From<tokio_postgres::Error> for RepoError {
+    fn from(e: tokio_postgres::Error) -> Self {
+        RepoError::Postgres(e)
+    }
+}
+
+impl From<r2d2::Error> for RepoError {
+    fn from(e: r2d2::Error) -> Self {
+        RepoError::Pool(e.to_string())
+    }
+}
+
+impl From<deadpool_postgres::PoolError> for RepoError {
+    fn from(e: deadpool_postgres::PoolError) -> Self {
+        match e {
+            deadpool_postgres::PoolError::Backend(e) => RepoError::Postgres(e),
+            e => RepoError::Pool(e.to_string()),
+        }
+    }
+}
+
+impl From<std::io::Error> for RepoError {
+    fn from(e: std::io::Error) -> Self {
+        RepoError::Other(e.to_string())
+    }
+}
+
+/// Commands return boxed errors, which can't cross threads.
+impl From<Box<dyn std::error::Error>> for RepoError {
+    fn from(e: Box<dyn std::error::Error>) -> Self {
+        let e = match e.downcast::<RepoError>() {
+            Ok(e) => return *e,
+            Err(e) => e,
+        };
+        match e.downcast::<rusqlite::Error>() {
+            Ok(e) => RepoError::Sqlite(*e),
+            Err(e) => RepoError::Other(e.to_string()),
+        }
+    }
+}
+
+/// An image stored before content-addressing has no blob to swap with a
+/// media item's.
+pub(crate) fn not_in_blob_storage(slug: &str) -> RepoError {
+    RepoError::Invalid(format!(
+        "Image '{}' predates content-addressed storage; run `images dedupe` first",
+        slug
+    ))
+}
+
+/// Rejects a reorder of an image's media that doesn't name each of its
+/// items exactly once, which would leave positions colliding.
+pub(crate) fn check_media_list(current: &[i64], media: &[Media]) -> Result<(), RepoError> {
+    let mut current = current.to_vec();
+    let mut sent: Vec<i64> = media.iter().map(|item| item.id).collect();
+    current.sort_unstable();
+    sent.sort_unstable();
+    if current != sent {
+        return Err(RepoError::Invalid(
+            "The media list must name each of the image's items once".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+fn page_not_found(slug: &str) -> RepoError {
+    RepoError::NotFound(format!("Page '{}' not found", slug))
+}
+
+fn entry_not_found(content_type: &str, slug: &str) -> RepoError {
+    RepoError::NotFound(format!("No {} entry '{}'", content_type, slug))
+}
+
+#[derive(Debug, Serialize)]
+pub struct PoolStats {
+    pub backend: &'static str,
+    /// For SQLite these describe the read-only pool; the single writer
+    /// connection is not counted.
+    pub max_connections: u32,
+    pub connections: u32,
+    pub idle_connections: u32,
+    pub reads: u64,
+    pub writes: u64,
+    pub errors: u64,
+    /// Total time spent waiting for a connection, in microseconds
+    pub read_wait_micros: u64,
+    pub write_wait_micros: u64,
+}
+
+/// Usage counters shared by every backend.
+#[derive(Debug, Default)]
+struct Counters {
+    reads: AtomicU64,
+    writes: AtomicU64,
+    errors: AtomicU64,
+    read_wait_micros: AtomicU64,
+    write_wait_micros: AtomicU64,
+}
+
+impl Counters {
+    fn record_checkout(&self, write: bool, started: Instant) {
+        let waited = started.elapsed().as_micros() as u64;
+        if write {
+            self.write_wait_micros.fetch_add(waited, Ordering::Relaxed);
+            self.writes.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.read_wait_micros.fetch_add(waited, Ordering::Relaxed);
+            self.reads.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn record_result<T>(&self, result: &Result<T, RepoError>) {
+        if result.is_err() {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn stats(&self, backend: &'static str, max: u32, connections: u32, idle: u32) -> PoolStats {
+        PoolStats {
+            backend,
+            max_connections: max,
+            connections,
+            idle_connections: idle,
+            reads: self.reads.load(Ordering::Relaxed),
+            writes: self.writes.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            read_wait_micros: self.read_wait_micros.load(Ordering::Relaxed),
+            write_wait_micros: self.write_wait_micros.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Everything the server and CLI need from the database. Image writes take
+/// the file manager because storing a file and recording it must succeed or
+/// fail together; implementations also drop the affected rendered pages.
+#[async_trait]
+pub trait Repository: Send + Sync {
+    fn stats(&self) -> PoolStats;
+
+    /// How many entries of `MIGRATIONS` have been applied. Also serves as a
+    /// round trip to check the database is reachable.
+    async fn schema_version(&self) -> Result<usize, RepoError>;
+
+    // Images
+
+    async fn images(&self) -> Result<Vec<Image>, RepoError>;
+
+    /// `None` if no image has this slug.
+    async fn image(&self, slug: &str) -> Result<Option<Image>, RepoError>;
+
+    /// Timestamps are UTC, formatted `%Y-%m-%d %H:%M:%S` by every backend.
+    async fn image_updated_at(&self, slug: &str) -> Result<Option<String>, RepoError>;
+
+    async fn content_updated_at(&self) -> Result<Option<String>, RepoError>;
+
+    async fn blob_created_at(&self, hash: &str) -> Result<Option<String>, RepoError>;
+
+    /// Existing images whose file has exactly the same content as `data`.
+    async fn find_duplicates(&self, data: bytes::Bytes) -> Result<Vec<Image>, RepoError>;
+
+    /// Image counts keyed by storage status, `blob` or `legacy`.
+    async fn image_counts(&self) -> Result<Vec<(String, i64)>, RepoError>;
+
+    async fn insert_image(
+        &self,
+        file_manager: Arc<ImageFileManager>,
+        data: Vec<u8>,
+        mime_type: mime::Mime,
+        image: Image,
+    ) -> Result<(), RepoError>;
+
+    async fn update_image(
+        &self,
+        file_manager: Arc<ImageFileManager>,
+        slug: &str,
+        image_data: Option<(Vec<u8>, mime::Mime)>,
+        image: Image,
+    ) -> Result<(), RepoError>;
+
+    async fn delete_image(
+        &self,
+        file_manager: Arc<ImageFileManager>,
+        slug: &str,
+    ) -> Result<(), RepoError>;
+
+    /// Moves images stored before content-addressing into blob storage,
+    /// merging identical files. Returns the number of images migrated.
+    async fn migrate_to_blobs(
+        &self,
+        file_manager: Arc<ImageFileManager>,
+    ) -> Result<usize, RepoError>;
+
+    /// An image's extra shots, in order. Empty if there is no such image.
+    async fn media(&self, slug: &str) -> Result<Vec<Media>, RepoError>;
+
+    /// Appends files to an image's media. Not found if there is no such
+    /// image.
+    async fn add_media(
+        &self,
+        file_manager: Arc<ImageFileManager>,
+        slug: &str,
+        uploads: Vec<MediaUpload>,
+    ) -> Result<(), RepoError>;
+
+    /// Saves alt text and captions and reorders to match `media`, which
+    /// must list every item of the image by id once; `Invalid` if it
+    /// doesn't.
+    async fn update_media(&self, slug: &str, media: Vec<Media>) -> Result<(), RepoError>;
+
+    async fn delete_media(
+        &self,
+        file_manager: Arc<ImageFileManager>,
+        slug: &str,
+        id: i64,
+    ) -> Result<(), RepoError>;
+
+    /// Swaps the image's file and alt text with media item `id`, which
+    /// takes the old primary shot in its place. `Invalid` for images not
+    /// yet in blob storage.
+    async fn make_media_primary(&self, slug: &str, id: i64) -> Result<(), RepoError>;
+
+    // Pages
+
+    /// Every page, published or not, in menu order and then by title.
+    async fn pages(&self) -> Result<Vec<Page>, RepoError>;
+
+    /// `None` if no page has this slug.
+    async fn page(&self, slug: &str) -> Result<Option<Page>, RepoError>;
+
+    async fn insert_page(&self, page: Page) -> Result<(), RepoError>;
+
+    /// Fails with `NotFound` if no page has `slug`, as does `delete_page`.
+    async fn update_page(&self, slug: &str, page: Page) -> Result<(), RepoError>;
+
+    async fn delete_page(&self, slug: &str) -> Result<(), RepoError>;
+
+    // Entries of custom content types, keyed by type and slug
+
+    /// Every entry of `content_type`, published or not, by title.
+    async fn entries(&self, content_type: &str) -> Result<Vec<Entry>, RepoError>;
+
+    /// `None` if the type has no entry with this slug.
+    async fn entry(&self, content_type: &str, slug: &str) -> Result<Option<Entry>, RepoError>;
+
+    /// The entry's `content_type` says which type it belongs to, as for
+    /// `update_entry`.
+    async fn insert_entry(&self, entry: Entry) -> Result<(), RepoError>;
+
+    /// Fails with `NotFound` if the type has no entry at `slug`, as does
+    /// `delete_entry`.
+    async fn update_entry(&self, slug: &str, entry: Entry) -> Result<(), RepoError>;
+
+    async fn delete_entry(&self, content_type: &str, slug: &str) -> Result<(), RepoError>;
+
+    // Users and sessions
+
+    async fn create_user(&self, email: &str, password: &str) -> Result<(), RepoError>;
+
+    async fn list_users(&self) -> Result<Vec<User>, RepoError>;
+
+    /// Returns the number of users deleted, so callers can report unknown
+    /// emails. The same holds for the other per-user updates.
+    async fn delete_user(&self, email: &str) -> Result<usize, RepoError>;
+
+    async fn set_user_password(&self, email: &str, password: &str) -> Result<usize, RepoError>;
+
+    /// Disabling a user also ends all of their sessions.
+    async fn set_user_disabled(&self, email: &str, disabled: bool) -> Result<usize, RepoError>;
+
+    /// The email of the user a live session belongs to, or `None` if the
+    /// session is unknown or expired.
+    async fn session_user(&self, session_id: &str) -> Result<Option<String>, RepoError>;
+
+    async fn active_sessions(&self) -> Result<i64, RepoError>;
+
+    /// Checks the credentials and starts a session, returning its id, or
+    /// `None` if the email or password is wrong or the account is disabled.
+    async fn login(&self, email: &str, password: &str) -> Result<Option<String>, RepoError>;
+
+    async fn delete_session(&self, session_id: &str) -> Result<(), RepoError>;
+
+    /// Issues a single-use reset token for an enabled user, or `None` for
+    /// unknown and disabled accounts.
+    async fn create_password_reset(
+        &self,
+        email: &str,
+        ttl_minutes: i64,
+    ) -> Result<Option<String>, RepoError>;
+
+    /// Sets a new password if `token` is valid, unused and unexpired,
+    /// ending the user's sessions. Returns `false` when it is rejected.
+    async fn consume_password_reset(&self, token: &str, password: &str) -> Result<bool, RepoError>;
+
+    // Site settings, such as the active theme
+
+    async fn setting(&self, key: &str) -> Result<Option<String>, RepoError>;
+
+    async fn set_setting(&self, key: &str, value: &str) -> Result<(), RepoError>;
+}
+
+/// Opens the backend selected by `database.url`, applying any pending
+/// migrations. Without a PostgreSQL URL the SQLite file is used.
+pub async fn open(config: &Config) -> Result<Arc<dyn Repository>, RepoError> {
+    if config.database.is_postgres() {
+        let repo = PostgresRepository::open(&config.database).await?;
+        Ok(Arc::new(repo))
+    } else {
+        let path = config.sqlite_path();
+        let database = config.database.clone();
+        let repo = tokio::task::spawn_blocking(move || SqliteRepository::open(&path, &database))
+            .await
+            .map_err(RepoError::Task)??;
+        Ok(Arc::new(repo))
+    }
+}
@@ -0,0 +1,1559 @@
+use super::{
+    check_media_list, entry_not_found, not_in_blob_storage, page_not_found, Counters, PoolStats,
+    RepoError, Repository,
+};
+use crate::config::DatabaseConfig;
+use crate::database;
+use crate::files::{self, ImageFileManager, StoredBlob};
+use crate::migrations::MIGRATIONS;
+use crate::models::{Entry, Image, Media, MediaUpload, Page, User};
+use crate::pages;
+use crate::render_cache;
+use async_trait::async_trait;
+use deadpool_postgres::{Manager, ManagerConfig, Object, Pool, RecyclingMethod, Runtime};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio_postgres::{NoTls, Row, Transaction};
+use uuid::Uuid;
+
+/// Advisory lock key held while migrating, so instances starting together
+/// don't apply the same migration twice.
+const MIGRATION_LOCK: i64 = 0x6372_6166_7463_6d73;
+
+/// Current time in UTC as a `TIMESTAMP`, matching SQLite's `CURRENT_TIMESTAMP`.
+const NOW: &str = "(now() AT TIME ZONE 'utc')";
+
+/// Timestamps are returned as text in SQLite's format so callers see the
+/// same values from either backend.
+fn timestamp(column: &str) -> String {
+    format!("to_char({}, 'YYYY-MM-DD HH24:MI:SS')", column)
+}
+
+fn image_from_row(row: &Row) -> Image {
+    Image {
+        alt: row.get(0),
+        description: row.get::<_, Option<String>>(1).unwrap_or_default(),
+        slug: row.get(2),
+        keywords: row
+            .get::<_, Option<String>>(3)
+            .and_then(|keywords| serde_json::from_str(&keywords).ok())
+            .unwrap_or_default(),
+        filename: row.get(4),
+    }
+}
+
+fn page_columns() -> String {
+    format!(
+        "slug, title, body, template, nav_order, published, {}",
+        timestamp("updated_at")
+    )
+}
+
+fn page_from_row(row: &Row) -> Page {
+    Page {
+        slug: row.get(0),
+        title: row.get(1),
+        body: row.get(2),
+        template: row.get(3),
+        nav_order: row.get(4),
+        published: row.get(5),
+        updated_at: row.get(6),
+    }
+}
+
+fn entry_columns() -> String {
+    format!(
+        "content_type, slug, title, fields, published, {}",
+        timestamp("updated_at")
+    )
+}
+
+fn entry_from_row(row: &Row) -> Entry {
+    Entry {
+        content_type: row.get(0),
+        slug: row.get(1),
+        title: row.get(2),
+        fields: serde_json::from_str(row.get(3)).unwrap_or_default(),
+        published: row.get(4),
+        updated_at: row.get(5),
+    }
+}
+
+fn image_not_found(slug: &str) -> RepoError {
+    RepoError::NotFound(format!("Image '{}' not found", slug))
+}
+
+fn media_not_found(slug: &str, id: i64) -> RepoError {
+    RepoError::NotFound(format!("Image '{}' has no media item {}", slug, id))
+}
+
+/// Runs CPU-bound or filesystem work off the async workers.
+async fn blocking<T, F>(f: F) -> Result<T, RepoError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, RepoError> + Send + 'static,
+{
+    let span = tracing::Span::current();
+    tokio::task::spawn_blocking(move || span.in_scope(f))
+        .await
+        .map_err(RepoError::Task)?
+}
+
+async fn store_blob(
+    file_manager: &Arc<ImageFileManager>,
+    data: Vec<u8>,
+    mime_type: mime::Mime,
+) -> Result<StoredBlob, RepoError> {
+    let file_manager = file_manager.clone();
+    blocking(move || Ok(file_manager.store_blob(&data, &mime_type)?)).await
+}
+
+async fn delete_file(file_manager: &Arc<ImageFileManager>, key: &str) -> Result<(), RepoError> {
+    let file_manager = file_manager.clone();
+    let key = key.to_string();
+    blocking(move || Ok(file_manager.delete_file(&key)?)).await
+}
+
+/// A connection holding session-level locks on blobs, which serialize
+/// writers that touch the same blobs. Between releasing a blob's last
+/// reference and deleting its file another upload of the same content could
+/// otherwise reuse the file, which SQLite avoids by having one writer. The
+/// locks outlast transactions so they can cover storing and deleting files.
+///
+/// `release` unlocks and hands the connection back to the pool. If the guard
+/// is dropped first, because the request was cancelled or a step failed, the
+/// connection is taken out of the pool and closed, which ends the locks with
+/// its session.
+struct BlobLocks {
+    client: Option<Object>,
+}
+
+impl BlobLocks {
+    async fn lock(client: Object, hashes: &[&str]) -> Result<Self, RepoError> {
+        let locks = BlobLocks {
+            client: Some(client),
+        };
+        let mut hashes = hashes.to_vec();
+        hashes.sort_unstable();
+        hashes.dedup();
+        for hash in hashes {
+            locks
+                .execute("SELECT pg_advisory_lock(hashtextextended($1, 0))", &[&hash])
+                .await?;
+        }
+        Ok(locks)
+    }
+
+    async fn release(mut self) {
+        // Unlock while the guard still owns the connection, so a cancelled
+        // release discards it too
+        match self.execute("SELECT pg_advisory_unlock_all()", &[]).await {
+            Ok(_) => drop(self.client.take()),
+            Err(e) => tracing::error!(error = %e, "Failed to release blob locks"),
+        }
+    }
+}
+
+impl std::ops::Deref for BlobLocks {
+    type Target = Object;
+
+    fn deref(&self) -> &Object {
+        self.client.as_ref().expect("blob locks already released")
+    }
+}
+
+impl std::ops::DerefMut for BlobLocks {
+    fn deref_mut(&mut self) -> &mut Object {
+        self.client.as_mut().expect("blob locks already released")
+    }
+}
+
+impl Drop for BlobLocks {
+    fn drop(&mut self) {
+        if let Some(client) = self.client.take() {
+            tracing::warn!("Closing a connection that may still hold blob locks");
+            drop(Object::take(client));
+        }
+    }
+}
+
+/// Records a new reference to `blob`, creating its row on first use.
+async fn acquire_blob(tx: &Transaction<'_>, blob: &StoredBlob) -> Result<(), RepoError> {
+    tx.execute(
+        "INSERT INTO blobs (hash, storage_key, content_type, size, ref_count)
+         VALUES ($1, $2, $3, $4, 1)
+         ON CONFLICT (hash) DO UPDATE SET ref_count = blobs.ref_count + 1",
+        &[
+            &blob.hash,
+            &blob.key,
+            &blob.content_type,
+            &(blob.size as i64),
+        ],
+    )
+    .await?;
+    Ok(())
+}
+
+/// Drops one reference to a blob, returning its storage key once nothing
+/// references it.
+async fn release_blob(tx: &Transaction<'_>, hash: &str) -> Result<Option<String>, RepoError> {
+    let row = tx
+        .query_opt(
+            "UPDATE blobs SET ref_count = ref_count - 1 WHERE hash = $1
+             RETURNING storage_key, ref_count",
+            &[&hash],
+        )
+        .await?;
+
+    let Some(row) = row else {
+        return Ok(None);
+    };
+    if row.get::<_, i64>(1) > 0 {
+        return Ok(None);
+    }
+
+    tx.execute("DELETE FROM blobs WHERE hash = $1", &[&hash])
+        .await?;
+    Ok(Some(row.get(0)))
+}
+
+async fn link_image_blob(tx: &Transaction<'_>, slug: &str, hash: &str) -> Result<(), RepoError> {
+    tx.execute(
+        "INSERT INTO image_blobs (image_id, blob_hash)
+         SELECT id, $1 FROM images WHERE slug = $2
+         ON CONFLICT (image_id) DO UPDATE SET blob_hash = EXCLUDED.blob_hash",
+        &[&hash, &slug],
+    )
+    .await?;
+    Ok(())
+}
+
+/// PostgreSQL access through a connection pool, for running several server
+/// instances against one database. Image files still live in the configured
+/// storage backend, which must then be shared between instances.
+pub struct PostgresRepository {
+    pool: Pool,
+    counters: Arc<Counters>,
+}
+
+impl PostgresRepository {
+    /// Connects to `config.url` and applies pending migrations.
+    pub async fn open(config: &DatabaseConfig) -> Result<Self, RepoError> {
+        let url = config
+            .url
+            .as_deref()
+            .ok_or_else(|| RepoError::Other("database.url is not set".to_string()))?;
+        let pg_config: tokio_postgres::Config = url.parse()?;
+
+        let manager = Manager::from_config(
+            pg_config,
+            NoTls,
+            ManagerConfig {
+                recycling_method: RecyclingMethod::Fast,
+            },
+        );
+        let pool = Pool::builder(manager)
+            .max_size(config.max_connections.max(1) as usize)
+            .runtime(Runtime::Tokio1)
+            .build()
+            .map_err(|e| RepoError::Pool(e.to_string()))?;
+
+        let repo = Self {
+            pool,
+            counters: Arc::new(Counters::default()),
+        };
+        repo.run_migrations().await?;
+        Ok(repo)
+    }
+
+    /// Applies pending migrations in one transaction, recording each in
+    /// `schema_migrations`.
+    async fn run_migrations(&self) -> Result<(), RepoError> {
+        let mut client = self.pool.get().await?;
+        let tx = client.transaction().await?;
+        tx.execute("SELECT pg_advisory_xact_lock($1)", &[&MIGRATION_LOCK])
+            .await?;
+        tx.batch_execute(&format!(
+            "CREATE TABLE IF NOT EXISTS schema_migrations (
+                version INTEGER PRIMARY KEY,
+                applied_at TIMESTAMP DEFAULT {}
+            )",
+            NOW
+        ))
+        .await?;
+
+        let applied: i64 = tx
+            .query_one("SELECT COUNT(*) FROM schema_migrations", &[])
+            .await?
+            .get(0);
+        for (version, migration) in MIGRATIONS.iter().enumerate().skip(applied as usize) {
+            tx.batch_execute(migration.postgres).await?;
+            tx.execute(
+                "INSERT INTO schema_migrations (version) VALUES ($1)",
+                &[&(version as i32 + 1)],
+            )
+            .await?;
+        }
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn client(&self, write: bool) -> Result<Object, RepoError> {
+        let started = Instant::now();
+        let client = self.pool.get().await?;
+        self.counters.record_checkout(write, started);
+        Ok(client)
+    }
+
+    async fn tracked<T>(
+        &self,
+        operation: impl Future<Output = Result<T, RepoError>>,
+    ) -> Result<T, RepoError> {
+        let result = operation.await;
+        self.counters.record_result(&result);
+        result
+    }
+
+    async fn blob_hash_and_filename(
+        client: &Object,
+        slug: &str,
+    ) -> Result<Option<(Option<String>, String)>, RepoError> {
+        let row = client
+            .query_opt(
+                "SELECT b.blob_hash, i.filename
+                 FROM images i LEFT JOIN image_blobs b ON b.image_id = i.id
+                 WHERE i.slug = $1",
+                &[&slug],
+            )
+            .await?;
+        Ok(row.map(|row| (row.get(0), row.get(1))))
+    }
+
+    async fn media_blob_hashes(client: &Object, slug: &str) -> Result<Vec<String>, RepoError> {
+        let rows = client
+            .query(
+                "SELECT m.blob_hash FROM image_media m JOIN images i ON i.id = m.image_id
+                 WHERE i.slug = $1",
+                &[&slug],
+            )
+            .await?;
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+}
+
+#[async_trait]
+impl Repository for PostgresRepository {
+    fn stats(&self) -> PoolStats {
+        let status = self.pool.status();
+        self.counters.stats(
+            "postgres",
+            status.max_size as u32,
+            status.size as u32,
+            status.available as u32,
+        )
+    }
+
+    async fn schema_version(&self) -> Result<usize, RepoError> {
+        self.tracked(async {
+            let client = self.client(false).await?;
+            let applied: i64 = client
+                .query_one("SELECT COUNT(*) FROM schema_migrations", &[])
+                .await?
+                .get(0);
+            Ok(applied as usize)
+        })
+        .await
+    }
+
+    // Images
+
+    async fn images(&self) -> Result<Vec<Image>, RepoError> {
+        self.tracked(async {
+            let client = self.client(false).await?;
+            let rows = client
+                .query(
+                    "SELECT alt, description, slug, keywords, filename FROM images
+                     ORDER BY created_at DESC",
+                    &[],
+                )
+                .await?;
+            Ok(rows.iter().map(image_from_row).collect())
+        })
+        .await
+    }
+
+    async fn image(&self, slug: &str) -> Result<Option<Image>, RepoError> {
+        self.tracked(async {
+            let client = self.client(false).await?;
+            let row = client
+                .query_opt(
+                    "SELECT alt, description, slug, keywords, filename FROM images
+                     WHERE slug = $1",
+                    &[&slug],
+                )
+                .await?;
+            Ok(row.as_ref().map(image_from_row))
+        })
+        .await
+    }
+
+    async fn image_updated_at(&self, slug: &str) -> Result<Option<String>, RepoError> {
+        self.tracked(async {
+            let client = self.client(false).await?;
+            let row = client
+                .query_opt(
+                    &format!(
+                        "SELECT {} FROM images WHERE slug = $1",
+                        timestamp("updated_at")
+                    ),
+                    &[&slug],
+                )
+                .await?;
+            Ok(row.and_then(|row| row.get(0)))
+        })
+        .await
+    }
+
+    async fn content_updated_at(&self) -> Result<Option<String>, RepoError> {
+        self.tracked(async {
+            let client = self.client(false).await?;
+            let row = client
+                .query_opt(
+                    &format!(
+                        "SELECT {} FROM content_state WHERE id = 1",
+                        timestamp("updated_at")
+                    ),
+                    &[],
+                )
+                .await?;
+            Ok(row.and_then(|row| row.get(0)))
+        })
+        .await
+    }
+
+    async fn image_counts(&self) -> Result<Vec<(String, i64)>, RepoError> {
+        self.tracked(async {
+            let client = self.client(false).await?;
+            let rows = client
+                .query(
+                    "SELECT CASE WHEN b.image_id IS NULL THEN 'legacy' ELSE 'blob' END AS status,
+                            COUNT(*)
+                     FROM images i LEFT JOIN image_blobs b ON b.image_id = i.id
+                     GROUP BY status",
+                    &[],
+                )
+                .await?;
+            Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+        })
+        .await
+    }
+
+    async fn blob_created_at(&self, hash: &str) -> Result<Option<String>, RepoError> {
+        self.tracked(async {
+            let client = self.client(false).await?;
+            let row = client
+                .query_opt(
+                    &format!(
+                        "SELECT {} FROM blobs WHERE hash = $1",
+                        timestamp("created_at")
+                    ),
+                    &[&hash],
+                )
+                .await?;
+            Ok(row.and_then(|row| row.get(0)))
+        })
+        .await
+    }
+
+    async fn find_duplicates(&self, data: bytes::Bytes) -> Result<Vec<Image>, RepoError> {
+        self.tracked(async {
+            let hash = files::content_hash(&data);
+            let client = self.client(false).await?;
+            let rows = client
+                .query(
+                    "SELECT i.alt, i.description, i.slug, i.keywords, i.filename
+                     FROM images i JOIN image_blobs b ON b.image_id = i.id
+                     WHERE b.blob_hash = $1 ORDER BY i.created_at DESC",
+                    &[&hash],
+                )
+                .await?;
+            Ok(rows.iter().map(image_from_row).collect())
+        })
+        .await
+    }
+
+    async fn insert_image(
+        &self,
+        file_manager: Arc<ImageFileManager>,
+        data: Vec<u8>,
+        mime_type: mime::Mime,
+        image: Image,
+    ) -> Result<(), RepoError> {
+        self.tracked(async {
+            let hash = files::content_hash(&data);
+            let mut client = BlobLocks::lock(self.client(true).await?, &[&hash]).await?;
+
+            let result = async {
+                let blob = store_blob(&file_manager, data, mime_type).await?;
+                let image = Image {
+                    filename: blob.key.clone(),
+                    ..image
+                };
+
+                let saved = async {
+                    let tx = client.transaction().await?;
+                    tx.execute(
+                        "INSERT INTO images (alt, description, slug, keywords, filename)
+                         VALUES ($1, $2, $3, $4, $5)",
+                        &[
+                            &image.alt,
+                            &image.description,
+                            &image.slug,
+                            &serde_json::to_string(&image.keywords).unwrap(),
+                            &image.filename,
+                        ],
+                    )
+                    .await?;
+                    acquire_blob(&tx, &blob).await?;
+                    link_image_blob(&tx, &image.slug, &blob.hash).await?;
+                    tx.commit().await?;
+                    Ok::<_, RepoError>(())
+                }
+                .await;
+
+                if let Err(e) = saved {
+                    // Only remove the file if this upload created it
+                    if blob.created {
+                        let _ = delete_file(&file_manager, &blob.key).await;
+                    }
+                    return Err(e);
+                }
+
+                render_cache::invalidate_image(&image.slug);
+                Ok(())
+            }
+            .await;
+
+            client.release().await;
+            result
+        })
+        .await
+    }
+
+    async fn update_image(
+        &self,
+        file_manager: Arc<ImageFileManager>,
+        slug: &str,
+        image_data: Option<(Vec<u8>, mime::Mime)>,
+        image: Image,
+    ) -> Result<(), RepoError> {
+        self.tracked(async {
+            let client = self.client(true).await?;
+
+            let Some((data, mime_type)) = image_data else {
+                // Files are addressed by content, so a slug change leaves them alone
+                let updated = client
+                    .execute(
+                        &format!(
+                            "UPDATE images SET alt = $1, description = $2, slug = $3,
+                             keywords = $4, updated_at = {} WHERE slug = $5",
+                            NOW
+                        ),
+                        &[
+                            &image.alt,
+                            &image.description,
+                            &image.slug,
+                            &serde_json::to_string(&image.keywords).unwrap(),
+                            &slug,
+                        ],
+                    )
+                    .await?;
+                if updated == 0 {
+                    return Err(image_not_found(slug));
+                }
+                render_cache::invalidate_image(slug);
+                render_cache::invalidate_image(&image.slug);
+                return Ok(());
+            };
+
+            let (old_hash, old_filename) = Self::blob_hash_and_filename(&client, slug)
+                .await?
+                .ok_or_else(|| image_not_found(slug))?;
+            let hash = files::content_hash(&data);
+            let mut locked = vec![hash.as_str()];
+            locked.extend(old_hash.as_deref());
+            let mut client = BlobLocks::lock(client, &locked).await?;
+
+            let result = async {
+                let blob = store_blob(&file_manager, data, mime_type).await?;
+                let image = Image {
+                    filename: blob.key.clone(),
+                    ..image
+                };
+
+                let saved = async {
+                    let tx = client.transaction().await?;
+                    tx.execute(
+                        &format!(
+                            "UPDATE images SET alt = $1, description = $2, slug = $3,
+                             keywords = $4, filename = $5, updated_at = {} WHERE slug = $6",
+                            NOW
+                        ),
+                        &[
+                            &image.alt,
+                            &image.description,
+                            &image.slug,
+                            &serde_json::to_string(&image.keywords).unwrap(),
+                            &image.filename,
+                            &slug,
+                        ],
+                    )
+                    .await?;
+                    acquire_blob(&tx, &blob).await?;
+                    link_image_blob(&tx, &image.slug, &blob.hash).await?;
+                    let orphan = match &old_hash {
+                        Some(hash) => release_blob(&tx, hash).await?,
+                        // Images from before content-addressing own their file outright
+                        None => Some(old_filename),
+                    };
+                    tx.commit().await?;
+                    Ok::<_, RepoError>(orphan)
+                }
+                .await;
+
+                let orphan = match saved {
+                    Ok(orphan) => orphan,
+                    Err(e) => {
+                        if blob.created {
+                            let _ = delete_file(&file_manager, &blob.key).await;
+                        }
+                        return Err(e);
+                    }
+                };
+
+                render_cache::invalidate_image(slug);
+                render_cache::invalidate_image(&image.slug);
+
+                match orphan {
+                    Some(orphan) if orphan != blob.key => delete_file(&file_manager, &orphan).await,
+                    _ => Ok(()),
+                }
+            }
+            .await;
+
+            client.release().await;
+            result
+        })
+        .await
+    }
+
+    async fn delete_image(
+        &self,
+        file_manager: Arc<ImageFileManager>,
+        slug: &str,
+    ) -> Result<(), RepoError> {
+        self.tracked(async {
+            let client = self.client(true).await?;
+            let (hash, filename) = Self::blob_hash_and_filename(&client, slug)
+                .await?
+                .ok_or_else(|| image_not_found(slug))?;
+            let media_hashes = Self::media_blob_hashes(&client, slug).await?;
+            let mut locked: Vec<&str> = media_hashes.iter().map(String::as_str).collect();
+            locked.extend(hash.as_deref());
+            let mut client = BlobLocks::lock(client, &locked).await?;
+
+            let result = async {
+                let tx = client.transaction().await?;
+                tx.execute(
+                    "DELETE FROM image_media WHERE image_id = (SELECT id FROM images WHERE slug = $1)",
+                    &[&slug],
+                )
+                .await?;
+                tx.execute(
+                    "DELETE FROM image_blobs WHERE image_id = (SELECT id FROM images WHERE slug = $1)",
+                    &[&slug],
+                )
+                .await?;
+                tx.execute("DELETE FROM images WHERE slug = $1", &[&slug])
+                    .await?;
+                let mut orphans = Vec::new();
+                orphans.extend(match &hash {
+                    Some(hash) => release_blob(&tx, hash).await?,
+                    None => Some(filename),
+                });
+                for hash in &media_hashes {
+                    orphans.extend(release_blob(&tx, hash).await?);
+                }
+                tx.commit().await?;
+                render_cache::invalidate_image(slug);
+
+                // The file is only removed once nothing references it
+                for orphan in orphans {
+                    delete_file(&file_manager, &orphan).await?;
+                }
+                Ok(())
+            }
+            .await;
+
+            client.release().await;
+            result
+        })
+        .await
+    }
+
+    async fn migrate_to_blobs(
+        &self,
+        file_manager: Arc<ImageFileManager>,
+    ) -> Result<usize, RepoError> {
+        self.tracked(async {
+            let legacy: Vec<(String, String)> = self
+                .client(false)
+                .await?
+                .query(
+                    "SELECT i.slug, i.filename
+                     FROM images i LEFT JOIN image_blobs b ON b.image_id = i.id
+                     WHERE b.image_id IS NULL ORDER BY i.id",
+                    &[],
+                )
+                .await?
+                .iter()
+                .map(|row| (row.get(0), row.get(1)))
+                .collect();
+
+            let mut migrated = 0;
+            for (slug, filename) in legacy {
+                let data = {
+                    let file_manager = file_manager.clone();
+                    let key = filename.clone();
+                    blocking(move || Ok(file_manager.read_file(&key)?)).await?
+                };
+                let mime_type = mime_guess::from_path(&filename).first_or_octet_stream();
+                let hash = files::content_hash(&data);
+                let mut client = BlobLocks::lock(self.client(true).await?, &[&hash]).await?;
+
+                let result = async {
+                    let blob = store_blob(&file_manager, data, mime_type).await?;
+                    let tx = client.transaction().await?;
+                    acquire_blob(&tx, &blob).await?;
+                    link_image_blob(&tx, &slug, &blob.hash).await?;
+                    tx.execute(
+                        "UPDATE images SET filename = $1 WHERE slug = $2",
+                        &[&blob.key, &slug],
+                    )
+                    .await?;
+                    tx.commit().await?;
+                    render_cache::invalidate_image(&slug);
+
+                    if filename != blob.key {
+                        delete_file(&file_manager, &filename).await?;
+                    }
+                    Ok::<_, RepoError>(())
+                }
+                .await;
+
+                client.release().await;
+                result?;
+                migrated += 1;
+            }
+
+            Ok(migrated)
+        })
+        .await
+    }
+
+    async fn media(&self, slug: &str) -> Result<Vec<Media>, RepoError> {
+        self.tracked(async {
+            let client = self.client(false).await?;
+            let rows = client
+                .query(
+                    "SELECT m.id, m.filename, m.alt, m.caption
+                     FROM image_media m JOIN images i ON i.id = m.image_id
+                     WHERE i.slug = $1 ORDER BY m.position, m.id",
+                    &[&slug],
+                )
+                .await?;
+            Ok(rows
+                .iter()
+                .map(|row| Media {
+                    id: row.get(0),
+                    filename: row.get(1),
+                    alt: row.get(2),
+                    caption: row.get(3),
+                })
+                .collect())
+        })
+        .await
+    }
+
+    async fn add_media(
+        &self,
+        file_manager: Arc<ImageFileManager>,
+        slug: &str,
+        uploads: Vec<MediaUpload>,
+    ) -> Result<(), RepoError> {
+        if uploads.is_empty() {
+            return Ok(());
+        }
+
+        self.tracked(async {
+            let hashes: Vec<String> = uploads
+                .iter()
+                .map(|upload| files::content_hash(&upload.data))
+                .collect();
+            let locked: Vec<&str> = hashes.iter().map(String::as_str).collect();
+            let mut client = BlobLocks::lock(self.client(true).await?, &locked).await?;
+
+            let result = async {
+                let mut blobs = Vec::with_capacity(uploads.len());
+                let mut entries = Vec::with_capacity(uploads.len());
+                let mut stored = Ok(());
+                for upload in uploads {
+                    match store_blob(&file_manager, upload.data, upload.mime_type).await {
+                        Ok(blob) => {
+                            blobs.push(blob);
+                            entries.push((upload.alt, upload.caption));
+                        }
+                        Err(e) => {
+                            stored = Err(e);
+                            break;
+                        }
+                    }
+                }
+
+                let saved = match stored {
+                    Ok(()) => {
+                        async {
+                            let tx = client.transaction().await?;
+                            for (blob, (alt, caption)) in blobs.iter().zip(&entries) {
+                                acquire_blob(&tx, blob).await?;
+                                let inserted = tx
+                                    .execute(
+                                        "INSERT INTO image_media
+                                             (image_id, position, filename, blob_hash, alt, caption)
+                                         SELECT i.id,
+                                                (SELECT COALESCE(MAX(position) + 1, 0)
+                                                 FROM image_media WHERE image_id = i.id),
+                                                $2, $3, $4, $5
+                                         FROM images i WHERE i.slug = $1",
+                                        &[&slug, &blob.key, &blob.hash, alt, caption],
+                                    )
+                                    .await?;
+                                if inserted == 0 {
+                                    return Err(image_not_found(slug));
+                                }
+                            }
+                            tx.execute(
+                                &format!("UPDATE images SET updated_at = {} WHERE slug = $1", NOW),
+                                &[&slug],
+                            )
+                            .await?;
+                            tx.commit().await?;
+                            Ok::<_, RepoError>(())
+                        }
+                        .await
+                    }
+                    Err(e) => Err(e),
+                };
+
+                if let Err(e) = saved {
+                    // Only remove files this upload created
+                    for blob in blobs.iter().filter(|blob| blob.created) {
+                        let _ = delete_file(&file_manager, &blob.key).await;
+                    }
+                    return Err(e);
+                }
+
+                render_cache::invalidate_image(slug);
+                Ok(())
+            }
+            .await;
+
+            client.release().await;
+            result
+        })
+        .await
+    }
+
+    async fn update_media(&self, slug: &str, media: Vec<Media>) -> Result<(), RepoError> {
+        self.tracked(async {
+            let mut client = self.client(true).await?;
+            let tx = client.transaction().await?;
+            // Locking the image holds off uploads, whose foreign key check
+            // needs it, between the check and the reorder
+            tx.execute("SELECT id FROM images WHERE slug = $1 FOR UPDATE", &[&slug])
+                .await?;
+            let current: Vec<i64> = tx
+                .query(
+                    "SELECT m.id FROM image_media m JOIN images i ON i.id = m.image_id
+                     WHERE i.slug = $1",
+                    &[&slug],
+                )
+                .await?
+                .iter()
+                .map(|row| row.get(0))
+                .collect();
+            check_media_list(&current, &media)?;
+            for (position, item) in media.iter().enumerate() {
+                let updated = tx
+                    .execute(
+                        "UPDATE image_media SET position = $1, alt = $2, caption = $3
+                         WHERE id = $4 AND image_id = (SELECT id FROM images WHERE slug = $5)",
+                        &[
+                            &(position as i32),
+                            &item.alt,
+                            &item.caption,
+                            &item.id,
+                            &slug,
+                        ],
+                    )
+                    .await?;
+                if updated == 0 {
+                    return Err(media_not_found(slug, item.id));
+                }
+            }
+            tx.execute(
+                &format!("UPDATE images SET updated_at = {} WHERE slug = $1", NOW),
+                &[&slug],
+            )
+            .await?;
+            tx.commit().await?;
+
+            render_cache::invalidate_image(slug);
+            Ok(())
+        })
+        .await
+    }
+
+    async fn delete_media(
+        &self,
+        file_manager: Arc<ImageFileManager>,
+        slug: &str,
+        id: i64,
+    ) -> Result<(), RepoError> {
+        self.tracked(async {
+            let client = self.client(true).await?;
+            let hash: String = client
+                .query_opt(
+                    "SELECT m.blob_hash FROM image_media m JOIN images i ON i.id = m.image_id
+                     WHERE m.id = $1 AND i.slug = $2",
+                    &[&id, &slug],
+                )
+                .await?
+                .ok_or_else(|| media_not_found(slug, id))?
+                .get(0);
+            let mut client = BlobLocks::lock(client, &[&hash]).await?;
+
+            let result = async {
+                let tx = client.transaction().await?;
+                let deleted = tx
+                    .execute("DELETE FROM image_media WHERE id = $1", &[&id])
+                    .await?;
+                if deleted == 0 {
+                    return Err(media_not_found(slug, id));
+                }
+                let orphan = release_blob(&tx, &hash).await?;
+                tx.execute(
+                    &format!("UPDATE images SET updated_at = {} WHERE slug = $1", NOW),
+                    &[&slug],
+                )
+                .await?;
+                tx.commit().await?;
+                render_cache::invalidate_image(slug);
+
+                match orphan {
+                    Some(orphan) => delete_file(&file_manager, &orphan).await,
+                    None => Ok(()),
+                }
+            }
+            .await;
+
+            client.release().await;
+            result
+        })
+        .await
+    }
+
+    async fn make_media_primary(&self, slug: &str, id: i64) -> Result<(), RepoError> {
+        self.tracked(async {
+            let mut client = self.client(true).await?;
+            let tx = client.transaction().await?;
+            // Both blobs stay referenced once, so no ref counts change
+            let image = tx
+                .query_opt(
+                    "SELECT i.id, i.filename, i.alt, b.blob_hash
+                     FROM images i LEFT JOIN image_blobs b ON b.image_id = i.id
+                     WHERE i.slug = $1 FOR UPDATE OF i",
+                    &[&slug],
+                )
+                .await?
+                .ok_or_else(|| image_not_found(slug))?;
+            let media = tx
+                .query_opt(
+                    "SELECT filename, alt, blob_hash FROM image_media
+                     WHERE id = $1 AND image_id = $2",
+                    &[&id, &image.get::<_, i64>(0)],
+                )
+                .await?
+                .ok_or_else(|| media_not_found(slug, id))?;
+            let image_hash: String = image
+                .get::<_, Option<String>>(3)
+                .ok_or_else(|| not_in_blob_storage(slug))?;
+
+            tx.execute(
+                "UPDATE image_media SET filename = $1, blob_hash = $2, alt = $3 WHERE id = $4",
+                &[
+                    &image.get::<_, String>(1),
+                    &image_hash,
+                    &image.get::<_, String>(2),
+                    &id,
+                ],
+            )
+            .await?;
+            tx.execute(
+                &format!(
+                    "UPDATE images SET filename = $1, alt = $2, updated_at = {} WHERE slug = $3",
+                    NOW
+                ),
+                &[
+                    &media.get::<_, String>(0),
+                    &media.get::<_, String>(1),
+                    &slug,
+                ],
+            )
+            .await?;
+            link_image_blob(&tx, slug, &media.get::<_, String>(2)).await?;
+            tx.commit().await?;
+
+            render_cache::invalidate_image(slug);
+            Ok(())
+        })
+        .await
+    }
+
+    // Pages
+
+    async fn pages(&self) -> Result<Vec<Page>, RepoError> {
+        self.tracked(async {
+            let client = self.client(false).await?;
+            let rows = client
+                .query(
+                    &format!(
+                        "SELECT {} FROM pages ORDER BY nav_order IS NULL, nav_order, title",
+                        page_columns()
+                    ),
+                    &[],
+                )
+                .await?;
+            Ok(rows.iter().map(page_from_row).collect())
+        })
+        .await
+    }
+
+    async fn page(&self, slug: &str) -> Result<Option<Page>, RepoError> {
+        self.tracked(async {
+            let client = self.client(false).await?;
+            let row = client
+                .query_opt(
+                    &format!("SELECT {} FROM pages WHERE slug = $1", page_columns()),
+                    &[&slug],
+                )
+                .await?;
+            Ok(row.as_ref().map(page_from_row))
+        })
+        .await
+    }
+
+    async fn insert_page(&self, page: Page) -> Result<(), RepoError> {
+        self.tracked(async {
+            let client = self.client(true).await?;
+            client
+                .execute(
+                    "INSERT INTO pages (slug, title, body, template, nav_order, published)
+                     VALUES ($1, $2, $3, $4, $5, $6)",
+                    &[
+                        &page.slug,
+                        &page.title,
+                        &page.body,
+                        &page.template,
+                        &page.nav_order,
+                        &page.published,
+                    ],
+                )
+                .await?;
+            pages::invalidate();
+            Ok(())
+        })
+        .await
+    }
+
+    async fn update_page(&self, slug: &str, page: Page) -> Result<(), RepoError> {
+        self.tracked(async {
+            let client = self.client(true).await?;
+            let updated = client
+                .execute(
+                    &format!(
+                        "UPDATE pages SET slug = $1, title = $2, body = $3, template = $4,
+                         nav_order = $5, published = $6, updated_at = {} WHERE slug = $7",
+                        NOW
+                    ),
+                    &[
+                        &page.slug,
+                        &page.title,
+                        &page.body,
+                        &page.template,
+                        &page.nav_order,
+                        &page.published,
+                        &slug,
+                    ],
+                )
+                .await?;
+            if updated == 0 {
+                return Err(page_not_found(slug));
+            }
+            pages::invalidate();
+            Ok(())
+        })
+        .await
+    }
+
+    async fn delete_page(&self, slug: &str) -> Result<(), RepoError> {
+        self.tracked(async {
+            let client = self.client(true).await?;
+            let deleted = client
+                .execute("DELETE FROM pages WHERE slug = $1", &[&slug])
+                .await?;
+            if deleted == 0 {
+                return Err(page_not_found(slug));
+            }
+            pages::invalidate();
+            Ok(())
+        })
+        .await
+    }
+
+    // Entries
+
+    async fn entries(&self, content_type: &str) -> Result<Vec<Entry>, RepoError> {
+        self.tracked(async {
+            let client = self.client(false).await?;
+            let rows = client
+                .query(
+                    &format!(
+                        "SELECT {} FROM entries WHERE content_type = $1 ORDER BY title",
+                        entry_columns()
+                    ),
+                    &[&content_type],
+                )
+                .await?;
+            Ok(rows.iter().map(entry_from_row).collect())
+        })
+        .await
+    }
+
+    async fn entry(&self, content_type: &str, slug: &str) -> Result<Option<Entry>, RepoError> {
+        self.tracked(async {
+            let client = self.client(false).await?;
+            let row = client
+                .query_opt(
+                    &format!(
+                        "SELECT {} FROM entries WHERE content_type = $1 AND slug = $2",
+                        entry_columns()
+                    ),
+                    &[&content_type, &slug],
+                )
+                .await?;
+            Ok(row.as_ref().map(entry_from_row))
+        })
+        .await
+    }
+
+    async fn insert_entry(&self, entry: Entry) -> Result<(), RepoError> {
+        self.tracked(async {
+            let client = self.client(true).await?;
+            client
+                .execute(
+                    "INSERT INTO entries (content_type, slug, title, fields, published)
+                     VALUES ($1, $2, $3, $4, $5)",
+                    &[
+                        &entry.content_type,
+                        &entry.slug,
+                        &entry.title,
+                        &serde_json::Value::Object(entry.fields.clone()).to_string(),
+                        &entry.published,
+                    ],
+                )
+                .await?;
+            render_cache::invalidate_entry(&entry.content_type, &entry.slug);
+            Ok(())
+        })
+        .await
+    }
+
+    async fn update_entry(&self, slug: &str, entry: Entry) -> Result<(), RepoError> {
+        self.tracked(async {
+            let client = self.client(true).await?;
+            let updated = client
+                .execute(
+                    &format!(
+                        "UPDATE entries SET slug = $1, title = $2, fields = $3, published = $4,
+                         updated_at = {} WHERE content_type = $5 AND slug = $6",
+                        NOW
+                    ),
+                    &[
+                        &entry.slug,
+                        &entry.title,
+                        &serde_json::Value::Object(entry.fields.clone()).to_string(),
+                        &entry.published,
+                        &entry.content_type,
+                        &slug,
+                    ],
+                )
+                .await?;
+            if updated == 0 {
+                return Err(entry_not_found(&entry.content_type, slug));
+            }
+            render_cache::invalidate_entry(&entry.content_type, slug);
+            render_cache::invalidate_entry(&entry.content_type, &entry.slug);
+            Ok(())
+        })
+        .await
+    }
+
+    async fn delete_entry(&self, content_type: &str, slug: &str) -> Result<(), RepoError> {
+        self.tracked(async {
+            let client = self.client(true).await?;
+            let deleted = client
+                .execute(
+                    "DELETE FROM entries WHERE content_type = $1 AND slug = $2",
+                    &[&content_type, &slug],
+                )
+                .await?;
+            if deleted == 0 {
+                return Err(entry_not_found(content_type, slug));
+            }
+            render_cache::invalidate_entry(content_type, slug);
+            Ok(())
+        })
+        .await
+    }
+
+    // Users and sessions
+
+    async fn create_user(&self, email: &str, password: &str) -> Result<(), RepoError> {
+        self.tracked(async {
+            let password = password.to_string();
+            let password_hash = blocking(move || Ok(database::hash_password(&password)?)).await?;
+            let client = self.client(true).await?;
+            client
+                .execute(
+                    "INSERT INTO users (email, password_hash) VALUES ($1, $2)",
+                    &[&email, &password_hash],
+                )
+                .await?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn list_users(&self) -> Result<Vec<User>, RepoError> {
+        self.tracked(async {
+            let client = self.client(false).await?;
+            let rows = client
+                .query(
+                    &format!(
+                        "SELECT id, email, disabled, {}, {} FROM users ORDER BY email",
+                        timestamp("created_at"),
+                        timestamp("last_login_at")
+                    ),
+                    &[],
+                )
+                .await?;
+            Ok(rows
+                .iter()
+                .map(|row| User {
+                    id: row.get(0),
+                    email: row.get(1),
+                    password_hash: String::new(),
+                    access_token: None,
+                    disabled: row.get(2),
+                    created_at: row.get(3),
+                    last_login_at: row.get(4),
+                })
+                .collect())
+        })
+        .await
+    }
+
+    async fn delete_user(&self, email: &str) -> Result<usize, RepoError> {
+        self.tracked(async {
+            let mut client = self.client(true).await?;
+            let tx = client.transaction().await?;
+            tx.execute(
+                "DELETE FROM sessions WHERE user_id = (SELECT id FROM users WHERE email = $1)",
+                &[&email],
+            )
+            .await?;
+            tx.execute(
+                "DELETE FROM password_resets WHERE user_id = (SELECT id FROM users WHERE email = $1)",
+                &[&email],
+            )
+            .await?;
+            let deleted = tx
+                .execute("DELETE FROM users WHERE email = $1", &[&email])
+                .await?;
+            tx.commit().await?;
+            Ok(deleted as usize)
+        })
+        .await
+    }
+
+    async fn set_user_password(&self, email: &str, password: &str) -> Result<usize, RepoError> {
+        self.tracked(async {
+            let password = password.to_string();
+            let password_hash = blocking(move || Ok(database::hash_password(&password)?)).await?;
+            let client = self.client(true).await?;
+            let updated = client
+                .execute(
+                    "UPDATE users SET password_hash = $1 WHERE email = $2",
+                    &[&password_hash, &email],
+                )
+                .await?;
+            Ok(updated as usize)
+        })
+        .await
+    }
+
+    async fn set_user_disabled(&self, email: &str, disabled: bool) -> Result<usize, RepoError> {
+        self.tracked(async {
+            let mut client = self.client(true).await?;
+            let tx = client.transaction().await?;
+            let updated = tx
+                .execute(
+                    "UPDATE users SET disabled = $1 WHERE email = $2",
+                    &[&disabled, &email],
+                )
+                .await?;
+            if disabled {
+                tx.execute(
+                    "DELETE FROM sessions WHERE user_id = (SELECT id FROM users WHERE email = $1)",
+                    &[&email],
+                )
+                .await?;
+            }
+            tx.commit().await?;
+            Ok(updated as usize)
+        })
+        .await
+    }
+
+    async fn session_user(&self, session_id: &str) -> Result<Option<String>, RepoError> {
+        self.tracked(async {
+            let client = self.client(false).await?;
+            let row = client
+                .query_opt(
+                    &format!(
+                        "SELECT u.email FROM sessions s JOIN users u ON u.id = s.user_id
+                         WHERE s.session_id = $1 AND s.expires_at > {}",
+                        NOW
+                    ),
+                    &[&session_id],
+                )
+                .await?;
+            Ok(row.map(|row| row.get(0)))
+        })
+        .await
+    }
+
+    async fn active_sessions(&self) -> Result<i64, RepoError> {
+        self.tracked(async {
+            let client = self.client(false).await?;
+            let row = client
+                .query_one(
+                    &format!("SELECT COUNT(*) FROM sessions WHERE expires_at > {}", NOW),
+                    &[],
+                )
+                .await?;
+            Ok(row.get(0))
+        })
+        .await
+    }
+
+    async fn login(&self, email: &str, password: &str) -> Result<Option<String>, RepoError> {
+        self.tracked(async {
+            let client = self.client(true).await?;
+            let Some(row) = client
+                .query_opt(
+                    "SELECT id, password_hash, disabled FROM users WHERE email = $1",
+                    &[&email],
+                )
+                .await?
+            else {
+                return Ok(None);
+            };
+
+            let user_id: i64 = row.get(0);
+            let password_hash: String = row.get(1);
+            if row.get::<_, bool>(2) {
+                return Ok(None);
+            }
+
+            let password = password.to_string();
+            let verified =
+                blocking(move || Ok(database::verify_password_hash(&password_hash, &password)?))
+                    .await?;
+            if !verified {
+                return Ok(None);
+            }
+
+            if let Err(e) = client
+                .execute(
+                    &format!("UPDATE users SET last_login_at = {} WHERE id = $1", NOW),
+                    &[&user_id],
+                )
+                .await
+            {
+                tracing::error!(error = %e, "Failed to record login");
+            }
+
+            let session_id = Uuid::new_v4().to_string();
+            client
+                .execute(
+                    &format!(
+                        "INSERT INTO sessions (session_id, user_id, expires_at)
+                         VALUES ($1, $2, {} + interval '24 hours')",
+                        NOW
+                    ),
+                    &[&session_id, &user_id],
+                )
+                .await?;
+            Ok(Some(session_id))
+        })
+        .await
+    }
+
+    async fn delete_session(&self, session_id: &str) -> Result<(), RepoError> {
+        self.tracked(async {
+            let client = self.client(true).await?;
+            client
+                .execute("DELETE FROM sessions WHERE session_id = $1", &[&session_id])
+                .await?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn create_password_reset(
+        &self,
+        email: &str,
+        ttl_minutes: i64,
+    ) -> Result<Option<String>, RepoError> {
+        self.tracked(async {
+            let client = self.client(true).await?;
+            let Some(row) = client
+                .query_opt(
+                    "SELECT id FROM users WHERE email = $1 AND NOT disabled",
+                    &[&email],
+                )
+                .await?
+            else {
+                return Ok(None);
+            };
+            let user_id: i64 = row.get(0);
+
+            let token = database::generate_reset_token();
+            client
+                .execute(
+                    &format!(
+                        "INSERT INTO password_resets (user_id, token_hash, expires_at)
+                         VALUES ($1, $2, {} + make_interval(mins => $3))",
+                        NOW
+                    ),
+                    &[
+                        &user_id,
+                        &database::hash_reset_token(&token),
+                        &(ttl_minutes as i32),
+                    ],
+                )
+                .await?;
+            Ok(Some(token))
+        })
+        .await
+    }
+
+    async fn consume_password_reset(&self, token: &str, password: &str) -> Result<bool, RepoError> {
+        self.tracked(async {
+            let password = password.to_string();
+            let password_hash = blocking(move || Ok(database::hash_password(&password)?)).await?;
+
+            let mut client = self.client(true).await?;
+            let tx = client.transaction().await?;
+            // Marking the token used first means two requests can't both redeem it
+            let Some(row) = tx
+                .query_opt(
+                    &format!(
+                        "UPDATE password_resets SET used_at = {now}
+                         WHERE token_hash = $1 AND used_at IS NULL AND expires_at > {now}
+                         RETURNING user_id",
+                        now = NOW
+                    ),
+                    &[&database::hash_reset_token(token)],
+                )
+                .await?
+            else {
+                return Ok(false);
+            };
+            let user_id: i64 = row.get(0);
+
+            tx.execute(
+                "UPDATE users SET password_hash = $1 WHERE id = $2",
+                &[&password_hash, &user_id],
+            )
+            .await?;
+            tx.execute(
+                &format!(
+                    "UPDATE password_resets SET used_at = {}
+                     WHERE user_id = $1 AND used_at IS NULL",
+                    NOW
+                ),
+                &[&user_id],
+            )
+            .await?;
+            tx.execute("DELETE FROM sessions WHERE user_id = $1", &[&user_id])
+                .await?;
+            tx.commit().await?;
+            Ok(true)
+        })
+        .await
+    }
+
+    async fn setting(&self, key: &str) -> Result<Option<String>, RepoError> {
+        self.tracked(async {
+            let client = self.client(false).await?;
+            let row = client
+                .query_opt("SELECT value FROM settings WHERE key = $1", &[&key])
+                .await?;
+            Ok(row.map(|row| row.get(0)))
+        })
+        .await
+    }
+
+    async fn set_setting(&self, key: &str, value: &str) -> Result<(), RepoError> {
+        self.tracked(async {
+            let client = self.client(true).await?;
+            client
+                .execute(
+                    &format!(
+                        "INSERT INTO settings (key, value) VALUES ($1, $2)
+                         ON CONFLICT (key) DO UPDATE SET value = EXCLUDED.value, updated_at = {}",
+                        NOW
+                    ),
+                    &[&key, &value],
+                )
+                .await?;
+            Ok(())
+        })
+        .await
+    }
+}
@@ -0,0 +1,486 @@
+use super::{entry_not_found, page_not_found, Counters, PoolStats, RepoError, Repository};
+use crate::commands;
+use crate::config::DatabaseConfig;
+use crate::database;
+use crate::files::ImageFileManager;
+use crate::models::{Entry, Image, Media, MediaUpload, Page, User};
+use crate::pages;
+use crate::render_cache;
+use async_trait::async_trait;
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{Connection, OpenFlags, OptionalExtension};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// SQLite access. Reads use a pool of read-only
+/// connections and writes are serialized through a single connection, which
+/// WAL mode lets run alongside the readers. All work happens on tokio's
+/// blocking threads so handlers never hold up the async workers.
+#[derive(Clone)]
+pub struct SqliteRepository {
+    readers: Pool<SqliteConnectionManager>,
+    writer: Arc<Mutex<Connection>>,
+    counters: Arc<Counters>,
+}
+
+impl SqliteRepository {
+    /// Opens the database, switches it to WAL mode and applies pending
+    /// migrations before any reader connects.
+    pub fn open(path: &Path, config: &DatabaseConfig) -> Result<Self, RepoError> {
+        let busy_timeout = Duration::from_millis(config.busy_timeout_ms);
+
+        let writer = database::init_db(path)?;
+        writer.busy_timeout(busy_timeout)?;
+        writer
+            .pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get::<_, String>(0))?;
+        writer.pragma_update(None, "synchronous", "NORMAL")?;
+        database::run_migrations(&writer)?;
+
+        let manager = SqliteConnectionManager::file(path)
+            .with_flags(OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX)
+            .with_init(move |conn| conn.busy_timeout(busy_timeout));
+        let readers = Pool::builder()
+            .max_size(config.max_readers.max(1))
+            .connection_timeout(busy_timeout)
+            .build(manager)?;
+
+        Ok(Self {
+            readers,
+            writer: Arc::new(Mutex::new(writer)),
+            counters: Arc::new(Counters::default()),
+        })
+    }
+
+    /// Runs `f` with a pooled read-only connection.
+    async fn read<T, F>(&self, f: F) -> Result<T, RepoError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> Result<T, RepoError> + Send + 'static,
+    {
+        let readers = self.readers.clone();
+        let counters = self.counters.clone();
+        let span = tracing::Span::current();
+        let result = tokio::task::spawn_blocking(move || {
+            let _entered = span.enter();
+            let started = Instant::now();
+            let conn = readers.get()?;
+            counters.record_checkout(false, started);
+            f(&conn)
+        })
+        .await
+        .map_err(RepoError::Task)
+        .and_then(|result| result);
+
+        self.counters.record_result(&result);
+        result
+    }
+
+    /// Runs `f` with the writer connection. Only one write runs at a time.
+    async fn write<T, F>(&self, f: F) -> Result<T, RepoError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> Result<T, RepoError> + Send + 'static,
+    {
+        let writer = self.writer.clone();
+        let counters = self.counters.clone();
+        let span = tracing::Span::current();
+        let result = tokio::task::spawn_blocking(move || {
+            let _entered = span.enter();
+            let started = Instant::now();
+            let conn = writer
+                .lock()
+                .map_err(|e| RepoError::Other(format!("Writer lock poisoned: {}", e)))?;
+            counters.record_checkout(true, started);
+            f(&conn)
+        })
+        .await
+        .map_err(RepoError::Task)
+        .and_then(|result| result);
+
+        self.counters.record_result(&result);
+        result
+    }
+}
+
+#[async_trait]
+impl Repository for SqliteRepository {
+    fn stats(&self) -> PoolStats {
+        let state = self.readers.state();
+        self.counters.stats(
+            "sqlite",
+            self.readers.max_size(),
+            state.connections,
+            state.idle_connections,
+        )
+    }
+
+    async fn schema_version(&self) -> Result<usize, RepoError> {
+        self.read(|conn| Ok(database::schema_version(conn)?)).await
+    }
+
+    // Images
+
+    async fn images(&self) -> Result<Vec<Image>, RepoError> {
+        self.read(|conn| Ok(database::get_images(conn)?)).await
+    }
+
+    async fn image(&self, slug: &str) -> Result<Option<Image>, RepoError> {
+        let slug = slug.to_string();
+        self.read(move |conn| match database::get_image_by_slug(conn, &slug) {
+            Ok(image) => Ok(Some(image)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.into()),
+        })
+        .await
+    }
+
+    async fn image_updated_at(&self, slug: &str) -> Result<Option<String>, RepoError> {
+        let slug = slug.to_string();
+        self.read(move |conn| Ok(database::get_image_updated_at(conn, &slug)?))
+            .await
+    }
+
+    async fn content_updated_at(&self) -> Result<Option<String>, RepoError> {
+        self.read(|conn| Ok(database::get_content_updated_at(conn)?))
+            .await
+    }
+
+    async fn image_counts(&self) -> Result<Vec<(String, i64)>, RepoError> {
+        self.read(|conn| Ok(database::count_images_by_status(conn)?))
+            .await
+    }
+
+    async fn blob_created_at(&self, hash: &str) -> Result<Option<String>, RepoError> {
+        let hash = hash.to_string();
+        self.read(move |conn| Ok(database::get_blob_created_at(conn, &hash)?))
+            .await
+    }
+
+    async fn find_duplicates(&self, data: bytes::Bytes) -> Result<Vec<Image>, RepoError> {
+        self.read(move |conn| Ok(commands::find_duplicates(conn, &data)?))
+            .await
+    }
+
+    async fn insert_image(
+        &self,
+        file_manager: Arc<ImageFileManager>,
+        data: Vec<u8>,
+        mime_type: mime::Mime,
+        image: Image,
+    ) -> Result<(), RepoError> {
+        self.write(move |conn| {
+            Ok(commands::insert_image(
+                conn,
+                &file_manager,
+                &data,
+                &mime_type,
+                image,
+            )?)
+        })
+        .await
+    }
+
+    async fn update_image(
+        &self,
+        file_manager: Arc<ImageFileManager>,
+        slug: &str,
+        image_data: Option<(Vec<u8>, mime::Mime)>,
+        image: Image,
+    ) -> Result<(), RepoError> {
+        let slug = slug.to_string();
+        self.write(move |conn| {
+            Ok(commands::update_image(
+                conn,
+                &file_manager,
+                &slug,
+                image_data,
+                image,
+            )?)
+        })
+        .await
+    }
+
+    async fn delete_image(
+        &self,
+        file_manager: Arc<ImageFileManager>,
+        slug: &str,
+    ) -> Result<(), RepoError> {
+        let slug = slug.to_string();
+        self.write(move |conn| Ok(commands::delete_image(conn, &file_manager, &slug)?))
+            .await
+    }
+
+    async fn migrate_to_blobs(
+        &self,
+        file_manager: Arc<ImageFileManager>,
+    ) -> Result<usize, RepoError> {
+        self.write(move |conn| Ok(commands::migrate_to_blobs(conn, &file_manager)?))
+            .await
+    }
+
+    async fn media(&self, slug: &str) -> Result<Vec<Media>, RepoError> {
+        let slug = slug.to_string();
+        self.read(move |conn| Ok(database::get_media(conn, &slug)?))
+            .await
+    }
+
+    async fn add_media(
+        &self,
+        file_manager: Arc<ImageFileManager>,
+        slug: &str,
+        uploads: Vec<MediaUpload>,
+    ) -> Result<(), RepoError> {
+        let slug = slug.to_string();
+        self.write(move |conn| Ok(commands::add_media(conn, &file_manager, &slug, uploads)?))
+            .await
+    }
+
+    async fn update_media(&self, slug: &str, media: Vec<Media>) -> Result<(), RepoError> {
+        let slug = slug.to_string();
+        self.write(move |conn| Ok(commands::update_media(conn, &slug, &media)?))
+            .await
+    }
+
+    async fn delete_media(
+        &self,
+        file_manager: Arc<ImageFileManager>,
+        slug: &str,
+        id: i64,
+    ) -> Result<(), RepoError> {
+        let slug = slug.to_string();
+        self.write(move |conn| Ok(commands::delete_media(conn, &file_manager, &slug, id)?))
+            .await
+    }
+
+    async fn make_media_primary(&self, slug: &str, id: i64) -> Result<(), RepoError> {
+        let slug = slug.to_string();
+        self.write(move |conn| Ok(commands::make_media_primary(conn, &slug, id)?))
+            .await
+    }
+
+    // Pages
+
+    async fn pages(&self) -> Result<Vec<Page>, RepoError> {
+        self.read(|conn| Ok(database::get_pages(conn)?)).await
+    }
+
+    async fn page(&self, slug: &str) -> Result<Option<Page>, RepoError> {
+        let slug = slug.to_string();
+        self.read(move |conn| Ok(database::get_page(conn, &slug)?))
+            .await
+    }
+
+    async fn insert_page(&self, page: Page) -> Result<(), RepoError> {
+        self.write(move |conn| Ok(database::insert_page(conn, &page)?))
+            .await?;
+        pages::invalidate();
+        Ok(())
+    }
+
+    async fn update_page(&self, slug: &str, page: Page) -> Result<(), RepoError> {
+        let slug = slug.to_string();
+        self.write(
+            move |conn| match database::update_page(conn, &slug, &page)? {
+                0 => Err(page_not_found(&slug)),
+                _ => Ok(()),
+            },
+        )
+        .await?;
+        pages::invalidate();
+        Ok(())
+    }
+
+    async fn delete_page(&self, slug: &str) -> Result<(), RepoError> {
+        let slug = slug.to_string();
+        self.write(move |conn| match database::delete_page(conn, &slug)? {
+            0 => Err(page_not_found(&slug)),
+            _ => Ok(()),
+        })
+        .await?;
+        pages::invalidate();
+        Ok(())
+    }
+
+    // Entries
+
+    async fn entries(&self, content_type: &str) -> Result<Vec<Entry>, RepoError> {
+        let content_type = content_type.to_string();
+        self.read(move |conn| Ok(database::get_entries(conn, &content_type)?))
+            .await
+    }
+
+    async fn entry(&self, content_type: &str, slug: &str) -> Result<Option<Entry>, RepoError> {
+        let content_type = content_type.to_string();
+        let slug = slug.to_string();
+        self.read(move |conn| Ok(database::get_entry(conn, &content_type, &slug)?))
+            .await
+    }
+
+    async fn insert_entry(&self, entry: Entry) -> Result<(), RepoError> {
+        let (content_type, slug) = (entry.content_type.clone(), entry.slug.clone());
+        self.write(move |conn| Ok(database::insert_entry(conn, &entry)?))
+            .await?;
+        render_cache::invalidate_entry(&content_type, &slug);
+        Ok(())
+    }
+
+    async fn update_entry(&self, slug: &str, entry: Entry) -> Result<(), RepoError> {
+        let (content_type, new_slug) = (entry.content_type.clone(), entry.slug.clone());
+        let slug = slug.to_string();
+        let old_slug = slug.clone();
+        self.write(
+            move |conn| match database::update_entry(conn, &slug, &entry)? {
+                0 => Err(entry_not_found(&entry.content_type, &slug)),
+                _ => Ok(()),
+            },
+        )
+        .await?;
+        render_cache::invalidate_entry(&content_type, &old_slug);
+        render_cache::invalidate_entry(&content_type, &new_slug);
+        Ok(())
+    }
+
+    async fn delete_entry(&self, content_type: &str, slug: &str) -> Result<(), RepoError> {
+        let content_type = content_type.to_string();
+        let slug = slug.to_string();
+        let (tag_type, tag_slug) = (content_type.clone(), slug.clone());
+        self.write(
+            move |conn| match database::delete_entry(conn, &content_type, &slug)? {
+                0 => Err(entry_not_found(&content_type, &slug)),
+                _ => Ok(()),
+            },
+        )
+        .await?;
+        render_cache::invalidate_entry(&tag_type, &tag_slug);
+        Ok(())
+    }
+
+    // Users and sessions
+
+    async fn create_user(&self, email: &str, password: &str) -> Result<(), RepoError> {
+        let email = email.to_string();
+        let password = password.to_string();
+        self.write(move |conn| Ok(database::create_user(conn, &email, &password)?))
+            .await
+    }
+
+    async fn list_users(&self) -> Result<Vec<User>, RepoError> {
+        self.read(|conn| Ok(database::list_users(conn)?)).await
+    }
+
+    async fn delete_user(&self, email: &str) -> Result<usize, RepoError> {
+        let email = email.to_string();
+        self.write(move |conn| Ok(database::delete_user(conn, &email)?))
+            .await
+    }
+
+    async fn set_user_password(&self, email: &str, password: &str) -> Result<usize, RepoError> {
+        let email = email.to_string();
+        let password = password.to_string();
+        self.write(move |conn| Ok(database::set_user_password(conn, &email, &password)?))
+            .await
+    }
+
+    async fn set_user_disabled(&self, email: &str, disabled: bool) -> Result<usize, RepoError> {
+        let email = email.to_string();
+        self.write(move |conn| Ok(database::set_user_disabled(conn, &email, disabled)?))
+            .await
+    }
+
+    async fn session_user(&self, session_id: &str) -> Result<Option<String>, RepoError> {
+        let session_id = session_id.to_string();
+        self.read(move |conn| Ok(database::get_session_user(conn, &session_id)?))
+            .await
+    }
+
+    async fn active_sessions(&self) -> Result<i64, RepoError> {
+        self.read(|conn| Ok(database::count_active_sessions(conn)?))
+            .await
+    }
+
+    async fn login(&self, email: &str, password: &str) -> Result<Option<String>, RepoError> {
+        let email = email.to_string();
+        let password = password.to_string();
+        // Hashing takes a reader, not the writer, so logins don't hold up writes
+        let user_id = self
+            .read(move |conn| {
+                let (user_id, hash, disabled): (i64, String, bool) = match conn.query_row(
+                    "SELECT id, password_hash, disabled FROM users WHERE email = ?",
+                    [&email],
+                    |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+                ) {
+                    Ok(user) => user,
+                    Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(None),
+                    Err(e) => return Err(e.into()),
+                };
+
+                if disabled || !database::verify_password_hash(&hash, &password)? {
+                    return Ok(None);
+                }
+                Ok(Some(user_id))
+            })
+            .await?;
+        let Some(user_id) = user_id else {
+            return Ok(None);
+        };
+
+        self.write(move |conn| {
+            // The account may have been disabled or removed while hashing
+            let enabled: Option<bool> = conn
+                .query_row(
+                    "SELECT NOT disabled FROM users WHERE id = ?",
+                    [user_id],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            if enabled != Some(true) {
+                return Ok(None);
+            }
+
+            if let Err(e) = database::record_login(conn, user_id) {
+                tracing::error!(error = %e, "Failed to record login");
+            }
+
+            Ok(Some(database::create_session(conn, user_id)?))
+        })
+        .await
+    }
+
+    async fn delete_session(&self, session_id: &str) -> Result<(), RepoError> {
+        let session_id = session_id.to_string();
+        self.write(move |conn| Ok(database::delete_session(conn, &session_id)?))
+            .await
+    }
+
+    async fn create_password_reset(
+        &self,
+        email: &str,
+        ttl_minutes: i64,
+    ) -> Result<Option<String>, RepoError> {
+        let email = email.to_string();
+        self.write(move |conn| Ok(database::create_password_reset(conn, &email, ttl_minutes)?))
+            .await
+    }
+
+    async fn consume_password_reset(&self, token: &str, password: &str) -> Result<bool, RepoError> {
+        let token = token.to_string();
+        let password = password.to_string();
+        self.write(move |conn| Ok(database::consume_password_reset(conn, &token, &password)?))
+            .await
+    }
+
+    async fn setting(&self, key: &str) -> Result<Option<String>, RepoError> {
+        let key = key.to_string();
+        self.read(move |conn| Ok(database::get_setting(conn, &key)?))
+            .await
+    }
+
+    async fn set_setting(&self, key: &str, value: &str) -> Result<(), RepoError> {
+        let key = key.to_string();
+        let value = value.to_string();
+        self.write(move |conn| Ok(database::set_setting(conn, &key, &value)?))
+            .await
+    }
+}
@@ -1,21 +1,28 @@
 use crate::config::Config;
 use crate::files::ImageFileManager;
+use crate::mailer::Mailer;
 use crate::{
     handlers::*,
-    middleware::{with_auth, with_file_manager},
-    with_config, with_db,
+    middleware::{with_auth, with_file_manager, with_mailer},
+    repository::Repository,
+    server::Routes,
+    with_config, with_repo,
 };
-use rusqlite::Connection;
-use std::sync::{Arc, Mutex};
-use warp::Filter;
+use std::sync::Arc;
+use warp::{Filter, Reply};
 
 use crate::admin_assets::AdminAssets;
 
+/// Largest image form accepted, which may carry several files of up to
+/// 10 MB each.
+const IMAGE_FORM_LIMIT: u64 = 100 * 1024 * 1024;
+
 pub fn admin_routes(
     config: Arc<Config>,
-    conn: Arc<Mutex<Connection>>,
+    repo: Arc<dyn Repository>,
     file_manager: Arc<ImageFileManager>,
-) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    mailer: Arc<dyn Mailer>,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
     let admin_base = warp::path("admin");
 
     // Login route - no auth required
@@ -29,37 +36,67 @@ pub fn admin_routes(
         .and(warp::path("login"))
         .and(warp::post())
         .and(warp::body::json())
-        .and(with_db(conn.clone()))
+        .and(with_repo(repo.clone()))
         .and_then(admin_login_handler);
 
     let admin_logout = admin_base
         .and(warp::path("logout"))
         .and(warp::post())
         .and(warp::cookie::optional("session"))
-        .and(with_db(conn.clone()))
+        .and(with_repo(repo.clone()))
         .and_then(admin_logout_handler);
 
+    // Password reset routes - no auth required
+    let admin_forgot_page = admin_base
+        .and(warp::path("forgot"))
+        .and(warp::get())
+        .and(with_config(config.clone()))
+        .and_then(admin_forgot_password_page_handler);
+
+    let admin_forgot = admin_base
+        .and(warp::path("forgot"))
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(with_config(config.clone()))
+        .and(with_repo(repo.clone()))
+        .and(with_mailer(mailer))
+        .and_then(admin_forgot_password_handler);
+
+    let admin_reset_page = admin_base
+        .and(warp::path("reset"))
+        .and(warp::get())
+        .and(warp::query())
+        .and(with_config(config.clone()))
+        .and_then(admin_reset_password_page_handler);
+
+    let admin_reset = admin_base
+        .and(warp::path("reset"))
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(with_repo(repo.clone()))
+        .and_then(admin_reset_password_handler);
+
     // Main admin page
     let admin_page = admin_base
         .and(warp::path::end())
-        .and(with_auth(conn.clone()))
+        .and(with_auth(repo.clone()))
         .and(with_config(config.clone()))
-        .and(with_db(conn.clone()))
+        .and(with_repo(repo.clone()))
         .and_then(admin_page_handler);
 
     // New image page
     let admin_new = admin_base
         .and(warp::path("new"))
-        .and(with_auth(conn.clone()))
+        .and(with_auth(repo.clone()))
         .and(with_config(config.clone()))
         .and_then(admin_new_image_handler);
 
     // Create image endpoint
     let admin_create = admin_base
         .and(warp::path("create"))
-        .and(with_auth(conn.clone()))
-        .and(warp::multipart::form())
-        .and(with_db(conn.clone()))
+        .and(with_auth(repo.clone()))
+        .and(warp::multipart::form().max_length(IMAGE_FORM_LIMIT))
+        .and(with_repo(repo.clone()))
         .and(with_file_manager(file_manager.clone())) // Add this line
         .and_then(admin_create_image_handler);
 
@@ -67,18 +104,18 @@ pub fn admin_routes(
     let admin_edit = admin_base
         .and(warp::path("edit"))
         .and(warp::path::param())
-        .and(with_auth(conn.clone()))
+        .and(with_auth(repo.clone()))
         .and(with_config(config.clone()))
-        .and(with_db(conn.clone()))
+        .and(with_repo(repo.clone()))
         .and_then(admin_edit_image_handler);
 
     // Update image endpoint
     let admin_update = admin_base
         .and(warp::path("update"))
         .and(warp::path::param())
-        .and(with_auth(conn.clone()))
-        .and(warp::multipart::form())
-        .and(with_db(conn.clone()))
+        .and(with_auth(repo.clone()))
+        .and(warp::multipart::form().max_length(IMAGE_FORM_LIMIT))
+        .and(with_repo(repo.clone()))
         .and(with_file_manager(file_manager.clone()))
         .and_then(admin_update_image_handler);
 
@@ -86,24 +123,329 @@ pub fn admin_routes(
     let admin_delete = admin_base
         .and(warp::path("delete"))
         .and(warp::path::param())
-        .and(with_auth(conn.clone()))
-        .and(with_db(conn.clone()))
+        .and(with_auth(repo.clone()))
+        .and(with_repo(repo.clone()))
         .and(with_file_manager(file_manager.clone()))
         .and_then(admin_delete_image_handler);
 
+    // Reordering, captioning, promoting and deleting an image's extra shots
+    let admin_update_media = admin_base
+        .and(warp::path("media"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(with_auth(repo.clone()))
+        .and(warp::body::content_length_limit(256 * 1024))
+        .and(warp::body::json())
+        .and(with_repo(repo.clone()))
+        .and_then(admin_update_media_handler);
+
+    let admin_delete_media = admin_base
+        .and(warp::path("media"))
+        .and(warp::path::param())
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::delete())
+        .and(with_auth(repo.clone()))
+        .and(with_repo(repo.clone()))
+        .and(with_file_manager(file_manager.clone()))
+        .and_then(admin_delete_media_handler);
+
+    let admin_primary_media = admin_base
+        .and(warp::path("media"))
+        .and(warp::path::param())
+        .and(warp::path::param())
+        .and(warp::path("primary"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(with_auth(repo.clone()))
+        .and(with_repo(repo.clone()))
+        .and_then(admin_primary_media_handler);
+
+    // Duplicate check for a file the user is about to upload
+    let admin_duplicates = admin_base
+        .and(warp::path("duplicates"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(with_auth(repo.clone()))
+        .and(warp::body::content_length_limit(10 * 1024 * 1024))
+        .and(warp::body::bytes())
+        .and(with_repo(repo.clone()))
+        .and_then(admin_duplicates_handler);
+
+    // Pages: list, forms and JSON create/update/delete
+    let admin_pages = admin_base
+        .and(warp::path("pages"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_auth(repo.clone()))
+        .and(with_config(config.clone()))
+        .and(with_repo(repo.clone()))
+        .and_then(admin_pages_handler);
+
+    let admin_new_page = admin_base
+        .and(warp::path("pages"))
+        .and(warp::path("new"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_auth(repo.clone()))
+        .and(with_config(config.clone()))
+        .and_then(admin_new_page_handler);
+
+    let admin_edit_page = admin_base
+        .and(warp::path("pages"))
+        .and(warp::path("edit"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_auth(repo.clone()))
+        .and(with_config(config.clone()))
+        .and(with_repo(repo.clone()))
+        .and_then(admin_edit_page_handler);
+
+    let admin_create_page = admin_base
+        .and(warp::path("pages"))
+        .and(warp::path("create"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(with_auth(repo.clone()))
+        .and(warp::body::content_length_limit(256 * 1024))
+        .and(warp::body::json())
+        .and(with_config(config.clone()))
+        .and(with_repo(repo.clone()))
+        .and_then(admin_create_page_handler);
+
+    let admin_update_page = admin_base
+        .and(warp::path("pages"))
+        .and(warp::path("update"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(with_auth(repo.clone()))
+        .and(warp::body::content_length_limit(256 * 1024))
+        .and(warp::body::json())
+        .and(with_config(config.clone()))
+        .and(with_repo(repo.clone()))
+        .and_then(admin_update_page_handler);
+
+    let admin_delete_page = admin_base
+        .and(warp::path("pages"))
+        .and(warp::path("delete"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::delete())
+        .and(with_auth(repo.clone()))
+        .and(with_repo(repo.clone()))
+        .and_then(admin_delete_page_handler);
+
+    // Content types written in the admin
+    let admin_content_types = admin_base
+        .and(warp::path("types"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_auth(repo.clone()))
+        .and(with_config(config.clone()))
+        .and_then(admin_content_types_handler);
+
+    let admin_save_content_type = admin_base
+        .and(warp::path("types"))
+        .and(warp::path("save"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(with_auth(repo.clone()))
+        .and(warp::body::content_length_limit(64 * 1024))
+        .and(warp::body::json())
+        .and(with_config(config.clone()))
+        .and(with_repo(repo.clone()))
+        .and_then(admin_save_content_type_handler);
+
+    let admin_delete_content_type = admin_base
+        .and(warp::path("types"))
+        .and(warp::path("delete"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::delete())
+        .and(with_auth(repo.clone()))
+        .and(with_config(config.clone()))
+        .and(with_repo(repo.clone()))
+        .and_then(admin_delete_content_type_handler);
+
+    // Entries of each content type: list, forms and JSON create/update/delete
+    let admin_entries = admin_base
+        .and(warp::path("content"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_auth(repo.clone()))
+        .and(with_config(config.clone()))
+        .and(with_repo(repo.clone()))
+        .and_then(admin_entries_handler);
+
+    let admin_new_entry = admin_base
+        .and(warp::path("content"))
+        .and(warp::path::param())
+        .and(warp::path("new"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_auth(repo.clone()))
+        .and(with_config(config.clone()))
+        .and(with_repo(repo.clone()))
+        .and_then(admin_new_entry_handler);
+
+    let admin_edit_entry = admin_base
+        .and(warp::path("content"))
+        .and(warp::path::param())
+        .and(warp::path("edit"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_auth(repo.clone()))
+        .and(with_config(config.clone()))
+        .and(with_repo(repo.clone()))
+        .and_then(admin_edit_entry_handler);
+
+    let admin_create_entry = admin_base
+        .and(warp::path("content"))
+        .and(warp::path::param())
+        .and(warp::path("create"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(with_auth(repo.clone()))
+        .and(warp::body::content_length_limit(256 * 1024))
+        .and(warp::body::json())
+        .and(with_repo(repo.clone()))
+        .and_then(admin_create_entry_handler);
+
+    let admin_update_entry = admin_base
+        .and(warp::path("content"))
+        .and(warp::path::param())
+        .and(warp::path("update"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(with_auth(repo.clone()))
+        .and(warp::body::content_length_limit(256 * 1024))
+        .and(warp::body::json())
+        .and(with_repo(repo.clone()))
+        .and_then(admin_update_entry_handler);
+
+    let admin_delete_entry = admin_base
+        .and(warp::path("content"))
+        .and(warp::path::param())
+        .and(warp::path("delete"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::delete())
+        .and(with_auth(repo.clone()))
+        .and(with_repo(repo.clone()))
+        .and_then(admin_delete_entry_handler);
+
+    // Rendered description for the image forms' preview
+    let admin_preview = admin_base
+        .and(warp::path("preview"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(with_auth(repo.clone()))
+        .and(warp::body::content_length_limit(64 * 1024))
+        .and(warp::body::json())
+        .and_then(admin_markdown_preview_handler);
+
+    // Render cache hit/miss counters
+    let admin_cache_stats = admin_base
+        .and(warp::path("cache"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_auth(repo.clone()))
+        .and_then(admin_cache_stats_handler);
+
+    // Connection pool counters
+    let admin_db_stats = admin_base
+        .and(warp::path("db"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_auth(repo.clone()))
+        .and(with_repo(repo.clone()))
+        .and_then(admin_db_stats_handler);
+
+    // Themes page, switching themes and the active theme's settings
+    let admin_themes = admin_base
+        .and(warp::path("themes"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_auth(repo.clone()))
+        .and(with_config(config.clone()))
+        .and_then(admin_themes_handler);
+
+    let admin_theme_settings = admin_base
+        .and(warp::path("themes"))
+        .and(warp::path("settings"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(with_auth(repo.clone()))
+        .and(warp::body::content_length_limit(64 * 1024))
+        .and(warp::body::json())
+        .and(with_repo(repo.clone()))
+        .and_then(admin_theme_settings_handler);
+
+    let admin_activate_theme = admin_base
+        .and(warp::path("themes"))
+        .and(warp::path::param())
+        .and(warp::path("activate"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(with_auth(repo.clone()))
+        .and(with_config(config.clone()))
+        .and(with_repo(repo.clone()))
+        .and_then(admin_activate_theme_handler);
+
     let admin_assets = warp::path("admin")
         .and(warp::path("assets"))
         .and(warp_embed::embed(&AdminAssets));
 
+    // Image routes, boxed to keep the combined filter type within the
+    // compiler's recursion limit
+    let admin_image_routes: Routes = admin_new
+        .or(admin_create)
+        .or(admin_edit)
+        .or(admin_update)
+        .or(admin_delete)
+        .or(admin_update_media)
+        .or(admin_delete_media)
+        .or(admin_primary_media)
+        .or(admin_duplicates)
+        .map(Reply::into_response)
+        .boxed();
+
     // Combine all routes
     admin_assets
         .or(admin_login)
         .or(admin_logout)
         .or(admin_login_page)
+        .or(admin_forgot_page)
+        .or(admin_forgot)
+        .or(admin_reset_page)
+        .or(admin_reset)
         .or(admin_page)
-        .or(admin_new)
-        .or(admin_create)
-        .or(admin_edit)
-        .or(admin_update)
-        .or(admin_delete)
+        .or(admin_image_routes)
+        .or(admin_pages)
+        .or(admin_new_page)
+        .or(admin_edit_page)
+        .or(admin_create_page)
+        .or(admin_update_page)
+        .or(admin_delete_page)
+        .or(admin_content_types)
+        .or(admin_save_content_type)
+        .or(admin_delete_content_type)
+        .or(admin_entries)
+        .or(admin_new_entry)
+        .or(admin_edit_entry)
+        .or(admin_create_entry)
+        .or(admin_update_entry)
+        .or(admin_delete_entry)
+        .or(admin_preview)
+        .or(admin_cache_stats)
+        .or(admin_db_stats)
+        .or(admin_themes)
+        .or(admin_theme_settings)
+        .or(admin_activate_theme)
 }
@@ -0,0 +1,131 @@
+use crate::config::Config;
+use rand::RngCore;
+use warp::http::header::{self, HeaderName, HeaderValue};
+use warp::reply::Response;
+
+/// Set by `render_template` on pages whose inline scripts carry a nonce, so
+/// the policy sent with the page names the same one. Removed before the
+/// response leaves the server.
+pub const NONCE_HEADER: &str = "x-craftcms-csp-nonce";
+
+const PERMISSIONS_POLICY: HeaderName = HeaderName::from_static("permissions-policy");
+const CSP_REPORT_ONLY: HeaderName = HeaderName::from_static("content-security-policy-report-only");
+
+pub fn generate_nonce() -> String {
+    let mut bytes = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The headers from `[security]`, prepared once at startup.
+pub struct SecurityHeaders {
+    enabled: bool,
+    report_only: bool,
+    public_csp: String,
+    admin_csp: String,
+    hsts: Option<HeaderValue>,
+    referrer_policy: Option<HeaderValue>,
+    permissions_policy: Option<HeaderValue>,
+}
+
+fn header_value(value: &str, key: &str) -> Option<HeaderValue> {
+    if value.is_empty() {
+        return None;
+    }
+    HeaderValue::from_str(value)
+        .map_err(|_| tracing::warn!(key, "Ignoring invalid security header value"))
+        .ok()
+}
+
+impl SecurityHeaders {
+    pub fn new(config: &Config) -> Self {
+        let security = &config.security;
+        let finish_policy = |policy: &str| {
+            let mut directives: Vec<String> = policy
+                .split(';')
+                .map(str::trim)
+                .filter(|directive| !directive.is_empty())
+                .map(str::to_string)
+                .collect();
+            if !security.frame_ancestors.is_empty() {
+                directives.push(format!("frame-ancestors {}", security.frame_ancestors));
+            }
+            if security.report {
+                directives.push("report-uri /csp-report".to_string());
+            }
+            directives.join("; ")
+        };
+
+        // Browsers ignore HSTS over plain HTTP, so only send it when the
+        // site is meant to be reached over HTTPS
+        let https = config.site.base_url.starts_with("https://") || config.server.tls.is_enabled();
+        let hsts = if https && security.hsts_max_age > 0 {
+            let mut value = format!("max-age={}", security.hsts_max_age);
+            if security.hsts_include_subdomains {
+                value.push_str("; includeSubDomains");
+            }
+            header_value(&value, "hsts_max_age")
+        } else {
+            None
+        };
+
+        SecurityHeaders {
+            enabled: security.enabled,
+            report_only: security.report_only,
+            public_csp: finish_policy(&security.public_csp),
+            admin_csp: finish_policy(&security.admin_csp),
+            hsts,
+            referrer_policy: header_value(&security.referrer_policy, "referrer_policy"),
+            permissions_policy: header_value(&security.permissions_policy, "permissions_policy"),
+        }
+    }
+
+    /// Adds the headers for a response to `path`. `/admin` gets the admin
+    /// policy, everything else the public one. Headers a handler already set
+    /// are left alone.
+    pub fn apply(&self, path: &str, mut response: Response) -> Response {
+        let nonce = response.headers_mut().remove(NONCE_HEADER);
+        if !self.enabled {
+            return response;
+        }
+
+        let admin = path == "/admin" || path.starts_with("/admin/");
+        let policy = if admin {
+            &self.admin_csp
+        } else {
+            &self.public_csp
+        };
+        let nonce = nonce
+            .and_then(|value| value.to_str().ok().map(str::to_string))
+            .unwrap_or_else(generate_nonce);
+        let csp_header = if self.report_only {
+            CSP_REPORT_ONLY
+        } else {
+            header::CONTENT_SECURITY_POLICY
+        };
+
+        let headers = response.headers_mut();
+        if let Some(policy) = header_value(&policy.replace("{nonce}", &nonce), "csp") {
+            headers.entry(csp_header).or_insert(policy);
+        }
+        headers
+            .entry(header::X_CONTENT_TYPE_OPTIONS)
+            .or_insert(HeaderValue::from_static("nosniff"));
+        if let Some(hsts) = &self.hsts {
+            headers
+                .entry(header::STRICT_TRANSPORT_SECURITY)
+                .or_insert(hsts.clone());
+        }
+        if let Some(referrer_policy) = &self.referrer_policy {
+            headers
+                .entry(header::REFERRER_POLICY)
+                .or_insert(referrer_policy.clone());
+        }
+        if let Some(permissions_policy) = &self.permissions_policy {
+            headers
+                .entry(PERMISSIONS_POLICY)
+                .or_insert(permissions_policy.clone());
+        }
+        response
+    }
+}
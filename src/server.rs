@@ -0,0 +1,331 @@
+use crate::config::TlsConfig;
+use arc_swap::ArcSwap;
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio_rustls::rustls::crypto::ring::sign::any_supported_type;
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use warp::filters::BoxedFilter;
+use warp::http::{header::LOCATION, Response, StatusCode};
+use warp::reply::Response as ReplyResponse;
+use warp::Filter;
+
+/// The fully assembled routes, boxed so every kind of listener can share
+/// one type.
+pub type Routes = BoxedFilter<(ReplyResponse,)>;
+
+/// How long a client gets to complete its TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Resolves once `run_server` starts shutting down.
+pub async fn shutdown_requested(mut shutdown: watch::Receiver<bool>) {
+    while !*shutdown.borrow() {
+        if shutdown.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Serves plain HTTP on `addr` until shutdown, then drains.
+pub fn serve_http(
+    routes: Routes,
+    addr: SocketAddr,
+    shutdown: watch::Receiver<bool>,
+) -> Result<JoinHandle<()>, String> {
+    let (addr, server) = warp::serve(routes)
+        .try_bind_with_graceful_shutdown(addr, shutdown_requested(shutdown))
+        .map_err(|e| format!("Failed to listen on {}: {}", addr, e))?;
+    tracing::info!(%addr, "Listening");
+    Ok(tokio::spawn(server))
+}
+
+/// A certificate and key as last read from disk.
+#[derive(Clone, PartialEq, Eq)]
+struct CertPair {
+    cert: Vec<u8>,
+    key: Vec<u8>,
+}
+
+impl CertPair {
+    fn read(tls: &TlsConfig) -> Result<Self, String> {
+        let read = |path: &Option<String>, key: &str| match path {
+            Some(path) => {
+                std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path, e))
+            }
+            None => Err(format!("server.tls.{} is not set", key)),
+        };
+        Ok(CertPair {
+            cert: read(&tls.cert_path, "cert_path")?,
+            key: read(&tls.key_path, "key_path")?,
+        })
+    }
+
+    /// Parses the pair, catching truncated or half-written files before
+    /// they're put in service.
+    fn certified_key(&self, tls: &TlsConfig) -> Result<CertifiedKey, String> {
+        let cert_path = tls.cert_path.as_deref().unwrap_or_default();
+        let key_path = tls.key_path.as_deref().unwrap_or_default();
+        let certs = rustls_pemfile::certs(&mut self.cert.as_slice())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Invalid certificate in {}: {}", cert_path, e))?;
+        if certs.is_empty() {
+            return Err(format!("No certificate found in {}", cert_path));
+        }
+        let key = match rustls_pemfile::private_key(&mut self.key.as_slice()) {
+            Ok(Some(key)) => key,
+            Ok(None) => return Err(format!("No private key found in {}", key_path)),
+            Err(e) => return Err(format!("Invalid private key in {}: {}", key_path, e)),
+        };
+        let key = any_supported_type(&key)
+            .map_err(|e| format!("Unsupported private key in {}: {}", key_path, e))?;
+        Ok(CertifiedKey::new(certs, key))
+    }
+}
+
+/// Hands every handshake whichever certificate was loaded last, so a
+/// renewal takes effect without touching the listener.
+#[derive(Debug)]
+struct ReloadableCert(ArcSwap<CertifiedKey>);
+
+impl ResolvesServerCert for ReloadableCert {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.0.load_full())
+    }
+}
+
+/// Accepts connections on `listener` and yields them once their TLS
+/// handshake completes. Handshakes run concurrently, so a slow client
+/// can't hold up the others.
+fn tls_incoming(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    shutdown: watch::Receiver<bool>,
+) -> impl futures::Stream<Item = Result<TlsStream<TcpStream>, Error>> {
+    let (tx, rx) = mpsc::channel(64);
+    tokio::spawn(async move {
+        loop {
+            let stream = tokio::select! {
+                _ = shutdown_requested(shutdown.clone()) => break,
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        tracing::warn!(error = %e, "Failed to accept connection");
+                        continue;
+                    }
+                },
+            };
+            let acceptor = acceptor.clone();
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => {
+                        let _ = tx.send(stream).await;
+                    }
+                    Ok(Err(e)) => tracing::debug!(error = %e, "TLS handshake failed"),
+                    Err(_) => tracing::debug!("TLS handshake timed out"),
+                }
+            });
+        }
+    });
+
+    futures::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|stream| (Ok(stream), rx))
+    })
+}
+
+/// Serves HTTPS on `addr`, checking the certificate and key for changes
+/// every `reload_seconds`. A changed pair is validated first, so a bad
+/// renewal leaves the current certificate in service.
+pub async fn serve_https(
+    routes: Routes,
+    addr: SocketAddr,
+    tls: TlsConfig,
+    shutdown: watch::Receiver<bool>,
+) -> Result<JoinHandle<()>, String> {
+    let mut current = CertPair::read(&tls)?;
+    let cert = Arc::new(ReloadableCert(ArcSwap::from_pointee(
+        current.certified_key(&tls)?,
+    )));
+
+    let mut config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_cert_resolver(cert.clone());
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    let listener = TcpListener::bind(addr)
+        .await
+        .map_err(|e| format!("Failed to listen on {}: {}", addr, e))?;
+    let incoming = tls_incoming(
+        listener,
+        TlsAcceptor::from(Arc::new(config)),
+        shutdown.clone(),
+    );
+    let server = tokio::spawn(
+        warp::serve(routes)
+            .serve_incoming_with_graceful_shutdown(incoming, shutdown_requested(shutdown.clone())),
+    );
+    tracing::info!(%addr, "Listening (TLS)");
+
+    Ok(tokio::spawn(async move {
+        let interval = Duration::from_secs(tls.reload_seconds.max(1));
+        // A bad pair is reported once, not on every check until it's fixed
+        let mut rejected = None;
+        loop {
+            tokio::select! {
+                _ = shutdown_requested(shutdown.clone()) => break,
+                _ = tokio::time::sleep(interval) => {}
+            }
+
+            let pair = match CertPair::read(&tls) {
+                Ok(pair) if pair == current || rejected.as_ref() == Some(&pair) => continue,
+                Ok(pair) => pair,
+                Err(e) => {
+                    tracing::warn!(error = %e, "Keeping current TLS certificate");
+                    continue;
+                }
+            };
+            match pair.certified_key(&tls) {
+                Ok(key) => {
+                    cert.0.store(Arc::new(key));
+                    tracing::info!(%addr, "Reloaded TLS certificate");
+                    current = pair;
+                }
+                Err(e) => {
+                    tracing::warn!(error = %e, "Keeping current TLS certificate");
+                    rejected = Some(pair);
+                }
+            }
+        }
+
+        let _ = server.await;
+    }))
+}
+
+/// The host a client asked for, without any port.
+fn host_without_port(host: &str) -> &str {
+    if host.starts_with('[') {
+        // [::1]:8080
+        host.find(']').map_or(host, |end| &host[..=end])
+    } else {
+        host.split(':').next().unwrap_or(host)
+    }
+}
+
+/// Answers every plain HTTP request on `addr` with a permanent redirect to
+/// the same URL over HTTPS on `https_port`.
+pub fn serve_redirect(
+    addr: SocketAddr,
+    https_port: u16,
+    shutdown: watch::Receiver<bool>,
+) -> Result<JoinHandle<()>, String> {
+    let redirect = warp::header::optional::<String>("host")
+        .and(warp::path::full())
+        .and(
+            warp::query::raw()
+                .map(Some)
+                .or(warp::any().map(|| None))
+                .unify(),
+        )
+        .map(
+            move |host: Option<String>, path: warp::path::FullPath, query: Option<String>| {
+                let host = match host {
+                    Some(host) => host,
+                    None => {
+                        return Response::builder()
+                            .status(StatusCode::BAD_REQUEST)
+                            .body("Missing Host header".to_string())
+                            .unwrap()
+                    }
+                };
+                let mut location = format!("https://{}", host_without_port(&host));
+                if https_port != 443 {
+                    location.push_str(&format!(":{}", https_port));
+                }
+                location.push_str(path.as_str());
+                if let Some(query) = query {
+                    location.push('?');
+                    location.push_str(&query);
+                }
+                Response::builder()
+                    .status(StatusCode::PERMANENT_REDIRECT)
+                    .header(LOCATION, location)
+                    .body(String::new())
+                    .unwrap_or_else(|_| {
+                        Response::builder()
+                            .status(StatusCode::BAD_REQUEST)
+                            .body("Invalid Host header".to_string())
+                            .unwrap()
+                    })
+            },
+        );
+
+    let (addr, server) = warp::serve(redirect)
+        .try_bind_with_graceful_shutdown(addr, shutdown_requested(shutdown))
+        .map_err(|e| format!("Failed to listen on {}: {}", addr, e))?;
+    tracing::info!(%addr, "Redirecting HTTP to HTTPS");
+    Ok(tokio::spawn(server))
+}
+
+/// Serves plain HTTP on a Unix domain socket, replacing a stale socket left
+/// by a previous run and removing it again on shutdown.
+#[cfg(unix)]
+pub fn serve_unix(
+    routes: Routes,
+    path: &Path,
+    mode: Option<u32>,
+    shutdown: watch::Receiver<bool>,
+) -> Result<JoinHandle<()>, String> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    let fail = |e: Error| format!("Failed to listen on {}: {}", path.display(), e);
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            std::fs::remove_file(path).map_err(fail)?
+        }
+        Ok(_) => {
+            return Err(fail(Error::new(
+                ErrorKind::AlreadyExists,
+                "path exists and is not a socket",
+            )))
+        }
+        Err(_) => {}
+    }
+    let listener = tokio::net::UnixListener::bind(path).map_err(fail)?;
+    if let Some(mode) = mode {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).map_err(fail)?;
+    }
+
+    let incoming = futures::stream::unfold(listener, |listener| async move {
+        let stream = listener.accept().await.map(|(stream, _)| stream);
+        Some((stream, listener))
+    });
+    let server = warp::serve(routes)
+        .serve_incoming_with_graceful_shutdown(incoming, shutdown_requested(shutdown));
+    tracing::info!(path = %path.display(), "Listening");
+
+    let path: PathBuf = path.to_path_buf();
+    Ok(tokio::spawn(async move {
+        server.await;
+        if let Err(e) = std::fs::remove_file(&path) {
+            tracing::warn!(path = %path.display(), error = %e, "Failed to remove socket");
+        }
+    }))
+}
+
+#[cfg(not(unix))]
+pub fn serve_unix(
+    _routes: Routes,
+    _path: &Path,
+    _mode: Option<u32>,
+    _shutdown: watch::Receiver<bool>,
+) -> Result<JoinHandle<()>, String> {
+    Err("Unix domain sockets are not supported on this platform".to_string())
+}
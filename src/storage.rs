@@ -0,0 +1,268 @@
+use crate::config::{S3Config, StorageBackendKind, StorageConfig};
+use s3::creds::Credentials;
+use s3::{Bucket, Region};
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Object storage for uploaded files, addressed by `/`-separated keys such
+/// as `blobs/ab/<hash>.png`. Implementations block, like the rest of the
+/// file handling.
+pub trait StorageBackend: Send + Sync {
+    fn put(&self, key: &str, data: &[u8], content_type: &str) -> std::io::Result<()>;
+
+    /// Fails with `ErrorKind::NotFound` if the key doesn't exist.
+    fn get(&self, key: &str) -> std::io::Result<Vec<u8>>;
+
+    /// Deleting a missing key is not an error.
+    fn delete(&self, key: &str) -> std::io::Result<()>;
+
+    fn rename(&self, from: &str, to: &str) -> std::io::Result<()>;
+
+    fn exists(&self, key: &str) -> std::io::Result<bool>;
+
+    fn list(&self, prefix: &str) -> std::io::Result<Vec<String>>;
+
+    /// A time-limited URL clients can fetch the object from directly, or
+    /// `None` if the backend can't serve files itself.
+    fn signed_url(&self, key: &str, expires_in: Duration) -> std::io::Result<Option<String>>;
+}
+
+pub fn from_config(
+    config: &StorageConfig,
+) -> Result<Box<dyn StorageBackend>, Box<dyn std::error::Error>> {
+    let backend: Box<dyn StorageBackend> = match config.backend {
+        StorageBackendKind::Local => Box::new(LocalStorage::new(config.images_path())),
+        StorageBackendKind::S3 => Box::new(S3Storage::new(&config.s3)?),
+    };
+    Ok(backend)
+}
+
+/// Rejects keys that could escape the storage root.
+fn validate_key(key: &str) -> std::io::Result<()> {
+    if key.is_empty() || key.contains("..") || key.starts_with('/') || key.contains('\\') {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Invalid storage key: {}", key),
+        ));
+    }
+    Ok(())
+}
+
+pub struct LocalStorage {
+    base_path: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(base_path: impl Into<PathBuf>) -> Self {
+        Self {
+            base_path: base_path.into(),
+        }
+    }
+
+    fn path_for(&self, key: &str) -> std::io::Result<PathBuf> {
+        validate_key(key)?;
+        Ok(self.base_path.join(key))
+    }
+}
+
+impl StorageBackend for LocalStorage {
+    fn put(&self, key: &str, data: &[u8], _content_type: &str) -> std::io::Result<()> {
+        let path = self.path_for(key)?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, data)
+    }
+
+    fn get(&self, key: &str) -> std::io::Result<Vec<u8>> {
+        std::fs::read(self.path_for(key)?)
+    }
+
+    fn delete(&self, key: &str) -> std::io::Result<()> {
+        match std::fs::remove_file(self.path_for(key)?) {
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    fn rename(&self, from: &str, to: &str) -> std::io::Result<()> {
+        std::fs::rename(self.path_for(from)?, self.path_for(to)?)
+    }
+
+    fn exists(&self, key: &str) -> std::io::Result<bool> {
+        Ok(self.path_for(key)?.is_file())
+    }
+
+    fn list(&self, prefix: &str) -> std::io::Result<Vec<String>> {
+        let mut keys = Vec::new();
+        let mut dirs = vec![self.base_path.clone()];
+        while let Some(dir) = dirs.pop() {
+            for entry in std::fs::read_dir(&dir)?.filter_map(|entry| entry.ok()) {
+                let path = entry.path();
+                if path.is_dir() {
+                    dirs.push(path);
+                    continue;
+                }
+                let key = path
+                    .strip_prefix(&self.base_path)
+                    .ok()
+                    .and_then(|relative| relative.to_str())
+                    .map(|relative| relative.replace(std::path::MAIN_SEPARATOR, "/"));
+                if let Some(key) = key.filter(|key| key.starts_with(prefix)) {
+                    keys.push(key);
+                }
+            }
+        }
+        keys.sort();
+        Ok(keys)
+    }
+
+    fn signed_url(&self, _key: &str, _expires_in: Duration) -> std::io::Result<Option<String>> {
+        Ok(None)
+    }
+}
+
+/// Works with AWS S3 and compatible services such as MinIO. Set `endpoint`
+/// and `path_style` for the latter.
+pub struct S3Storage {
+    bucket: Box<Bucket>,
+    prefix: String,
+}
+
+impl S3Storage {
+    pub fn new(config: &S3Config) -> Result<Self, Box<dyn std::error::Error>> {
+        let region = match &config.endpoint {
+            Some(endpoint) => Region::Custom {
+                region: config.region.clone(),
+                endpoint: endpoint.clone(),
+            },
+            None => config.region.parse()?,
+        };
+
+        let credentials = match (&config.access_key, &config.secret_key) {
+            (Some(access_key), Some(secret_key)) => {
+                Credentials::new(Some(access_key), Some(secret_key), None, None, None)?
+            }
+            _ => Credentials::default()?,
+        };
+
+        let mut bucket = Bucket::new(&config.bucket, region, credentials)?;
+        if config.path_style {
+            bucket = bucket.with_path_style();
+        }
+
+        Ok(Self {
+            bucket,
+            prefix: config.prefix.trim_matches('/').to_string(),
+        })
+    }
+
+    fn object_path(&self, key: &str) -> std::io::Result<String> {
+        validate_key(key)?;
+        if self.prefix.is_empty() {
+            Ok(key.to_string())
+        } else {
+            Ok(format!("{}/{}", self.prefix, key))
+        }
+    }
+}
+
+fn s3_error(e: s3::error::S3Error) -> Error {
+    match e {
+        s3::error::S3Error::HttpFailWithBody(404, _) => Error::new(ErrorKind::NotFound, e),
+        e => Error::other(e),
+    }
+}
+
+fn check_status(status: u16, action: &str, key: &str) -> std::io::Result<()> {
+    match status {
+        200..=299 => Ok(()),
+        404 => Err(Error::new(
+            ErrorKind::NotFound,
+            format!("{} not found", key),
+        )),
+        status => Err(Error::other(format!(
+            "S3 {} of {} failed with status {}",
+            action, key, status
+        ))),
+    }
+}
+
+impl StorageBackend for S3Storage {
+    fn put(&self, key: &str, data: &[u8], content_type: &str) -> std::io::Result<()> {
+        let path = self.object_path(key)?;
+        let response = self
+            .bucket
+            .put_object_with_content_type(&path, data, content_type)
+            .map_err(s3_error)?;
+        check_status(response.status_code(), "upload", key)
+    }
+
+    fn get(&self, key: &str) -> std::io::Result<Vec<u8>> {
+        let path = self.object_path(key)?;
+        let response = self.bucket.get_object(&path).map_err(s3_error)?;
+        check_status(response.status_code(), "download", key)?;
+        Ok(response.to_vec())
+    }
+
+    fn delete(&self, key: &str) -> std::io::Result<()> {
+        let path = self.object_path(key)?;
+        match self.bucket.delete_object(&path) {
+            Ok(response) if response.status_code() == 404 => Ok(()),
+            Ok(response) => check_status(response.status_code(), "delete", key),
+            Err(e) => match s3_error(e) {
+                e if e.kind() == ErrorKind::NotFound => Ok(()),
+                e => Err(e),
+            },
+        }
+    }
+
+    /// S3 has no rename, so this copies and then deletes the original.
+    fn rename(&self, from: &str, to: &str) -> std::io::Result<()> {
+        let from_path = self.object_path(from)?;
+        let to_path = self.object_path(to)?;
+        let status = self
+            .bucket
+            .copy_object_internal(&from_path, &to_path)
+            .map_err(s3_error)?;
+        check_status(status, "copy", from)?;
+        self.delete(from)
+    }
+
+    fn exists(&self, key: &str) -> std::io::Result<bool> {
+        let path = self.object_path(key)?;
+        self.bucket.object_exists(&path).map_err(s3_error)
+    }
+
+    fn list(&self, prefix: &str) -> std::io::Result<Vec<String>> {
+        let full_prefix = if self.prefix.is_empty() {
+            prefix.to_string()
+        } else {
+            format!("{}/{}", self.prefix, prefix)
+        };
+        let strip = if self.prefix.is_empty() {
+            String::new()
+        } else {
+            format!("{}/", self.prefix)
+        };
+
+        let results = self.bucket.list(full_prefix, None).map_err(s3_error)?;
+        let mut keys: Vec<String> = results
+            .into_iter()
+            .flat_map(|result| result.contents)
+            .map(|object| object.key.trim_start_matches(&strip).to_string())
+            .collect();
+        keys.sort();
+        Ok(keys)
+    }
+
+    fn signed_url(&self, key: &str, expires_in: Duration) -> std::io::Result<Option<String>> {
+        let path = self.object_path(key)?;
+        let seconds = expires_in.as_secs().clamp(1, 604_800) as u32;
+        self.bucket
+            .presign_get(&path, seconds, None)
+            .map(Some)
+            .map_err(s3_error)
+    }
+}
@@ -0,0 +1,467 @@
+//! Functions and filters registered with every template set, so themes can
+//! build links and image markup from the configuration instead of
+//! hardcoding paths. `themes/README.md` documents them for theme authors.
+
+use crate::cache;
+use crate::config::Config;
+use crate::content_types::{self, ContentType};
+use crate::files::VariantFormat;
+use crate::markdown;
+use crate::models::{EntryView, ImageView};
+use crate::render_cache;
+use crate::repository::Repository;
+use crate::themes;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock};
+use tera::{Tera, Value};
+
+/// Everything but unreserved URL characters is escaped in slugs.
+const SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+/// Static paths keep their slashes.
+const PATH: &AsciiSet = &SEGMENT.remove(b'/');
+
+const DEFAULT_DATE_FORMAT: &str = "%B %-d, %Y";
+const WORDS_PER_MINUTE: u64 = 200;
+
+struct Helpers {
+    config: Arc<Config>,
+    repo: Arc<dyn Repository>,
+}
+
+static HELPERS: OnceLock<Helpers> = OnceLock::new();
+
+/// Gives the helpers the configuration and content they read. Must be
+/// called before templates are first rendered; later calls are ignored.
+pub fn init(config: Arc<Config>, repo: Arc<dyn Repository>) {
+    let _ = HELPERS.set(Helpers { config, repo });
+}
+
+fn helpers() -> tera::Result<&'static Helpers> {
+    HELPERS
+        .get()
+        .ok_or_else(|| tera::Error::msg("Template helpers are only available in the server"))
+}
+
+/// A function whose output is fully percent-encoded and so needs no HTML
+/// escaping; without this every `/` would print as `&#x2F;`.
+struct Encoded<F>(F);
+
+impl<F> tera::Function for Encoded<F>
+where
+    F: Fn(&HashMap<String, Value>) -> tera::Result<Value> + Sync + Send,
+{
+    fn call(&self, args: &HashMap<String, Value>) -> tera::Result<Value> {
+        (self.0)(args)
+    }
+
+    fn is_safe(&self) -> bool {
+        true
+    }
+}
+
+pub fn register(tera: &mut Tera) {
+    tera.register_function("url_for", Encoded(url_for));
+    tera.register_function("image_url", Encoded(image_url));
+    tera.register_function("srcset", Encoded(srcset));
+    tera.register_function("get_images", get_images);
+    tera.register_function("get_entries", get_entries);
+    tera.register_filter("absolute_url", AbsoluteUrlFilter);
+    tera.register_filter("markdown", MarkdownFilter);
+    tera.register_filter("reading_time", reading_time);
+    tera.register_filter("excerpt", excerpt);
+    tera.register_filter("format_date", format_date);
+}
+
+fn string_arg<'a>(
+    args: &'a HashMap<String, Value>,
+    helper: &str,
+    name: &str,
+) -> tera::Result<Option<&'a str>> {
+    match args.get(name) {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::String(value)) => Ok(Some(value)),
+        Some(other) => Err(tera::Error::msg(format!(
+            "`{}` expects `{}` to be a string, got {}",
+            helper, name, other
+        ))),
+    }
+}
+
+fn required_string_arg<'a>(
+    args: &'a HashMap<String, Value>,
+    helper: &str,
+    name: &str,
+) -> tera::Result<&'a str> {
+    string_arg(args, helper, name)?
+        .ok_or_else(|| tera::Error::msg(format!("`{}` needs a `{}` argument", helper, name)))
+}
+
+fn number_arg(
+    args: &HashMap<String, Value>,
+    helper: &str,
+    name: &str,
+) -> tera::Result<Option<u64>> {
+    match args.get(name) {
+        None | Some(Value::Null) => Ok(None),
+        Some(value) => value.as_u64().map(Some).ok_or_else(|| {
+            tera::Error::msg(format!(
+                "`{}` expects `{}` to be a whole number, got {}",
+                helper, name, value
+            ))
+        }),
+    }
+}
+
+fn bool_arg(args: &HashMap<String, Value>, helper: &str, name: &str) -> tera::Result<bool> {
+    match args.get(name) {
+        None | Some(Value::Null) => Ok(false),
+        Some(Value::Bool(value)) => Ok(*value),
+        Some(other) => Err(tera::Error::msg(format!(
+            "`{}` expects `{}` to be true or false, got {}",
+            helper, name, other
+        ))),
+    }
+}
+
+fn string_value<'a>(value: &'a Value, filter: &str) -> tera::Result<&'a str> {
+    value.as_str().ok_or_else(|| {
+        tera::Error::msg(format!(
+            "`{}` can only be applied to text, got {}",
+            filter, value
+        ))
+    })
+}
+
+/// Prefixes site-relative paths with `site.base_url`; full URLs pass
+/// through.
+fn make_absolute(config: &Config, url: &str) -> String {
+    if url.starts_with("http://") || url.starts_with("https://") || url.starts_with("//") {
+        return url.to_string();
+    }
+    format!(
+        "{}/{}",
+        config.site.base_url.trim_end_matches('/'),
+        url.trim_start_matches('/')
+    )
+}
+
+fn finish(config: &Config, path: String, absolute: bool) -> Value {
+    Value::String(if absolute {
+        make_absolute(config, &path)
+    } else {
+        path
+    })
+}
+
+fn content_type_arg(args: &HashMap<String, Value>, helper: &str) -> tera::Result<Arc<ContentType>> {
+    let name = required_string_arg(args, helper, "type")?;
+    content_types::active().get(name).ok_or_else(|| {
+        tera::Error::msg(format!(
+            "`{}` doesn't know the content type '{}'",
+            helper, name
+        ))
+    })
+}
+
+/// `url_for(route="detail", slug=image.slug)`: the path of a route, built
+/// from `[routes]`. Routes are `home`; `detail`, `page` and `image`, which
+/// take a `slug`; `entries`, which takes a content `type`, and `entry`, which
+/// takes a `type` and a `slug`; and `static`, which takes a `path` and
+/// gets a `?v=` fingerprint of the file's content when the file exists.
+/// `absolute=true` adds the site's base URL.
+fn url_for(args: &HashMap<String, Value>) -> tera::Result<Value> {
+    let config = &helpers()?.config;
+    let route = required_string_arg(args, "url_for", "route")?;
+    let slug = || -> tera::Result<String> {
+        let slug = required_string_arg(args, "url_for", "slug")?;
+        Ok(utf8_percent_encode(slug, SEGMENT).to_string())
+    };
+
+    let path = match route {
+        "home" => "/".to_string(),
+        "detail" => format!("/{}/{}", config.routes.detail_path, slug()?),
+        "image" => format!("/{}/{}", config.routes.images_path, slug()?),
+        "page" => format!("/{}", slug()?),
+        "entries" => content_type_arg(args, "url_for")?.url(),
+        "entry" => content_type_arg(args, "url_for")?.entry_url(&slug()?),
+        "static" => {
+            let path = required_string_arg(args, "url_for", "path")?.trim_start_matches('/');
+            let url = format!("/static/{}", utf8_percent_encode(path, PATH));
+            match themes::static_file(&config.storage.static_path(), path) {
+                Some(data) => format!("{}?v={}", url, cache::asset_version(&data)),
+                None => url,
+            }
+        }
+        other => {
+            return Err(tera::Error::msg(format!(
+                "`url_for` doesn't know the route '{}'; use home, detail, page, image, entries, entry or static",
+                other
+            )))
+        }
+    };
+    Ok(finish(config, path, bool_arg(args, "url_for", "absolute")?))
+}
+
+fn variant_format(
+    args: &HashMap<String, Value>,
+    helper: &str,
+) -> tera::Result<Option<VariantFormat>> {
+    string_arg(args, helper, "fmt")?
+        .map(|name| {
+            VariantFormat::parse(name).ok_or_else(|| {
+                tera::Error::msg(format!(
+                    "`{}` can't make '{}' images; use jpg, png or webp",
+                    helper, name
+                ))
+            })
+        })
+        .transpose()
+}
+
+fn build_image_url(
+    config: &Config,
+    slug: &str,
+    width: Option<u32>,
+    format: Option<VariantFormat>,
+) -> String {
+    let mut url = format!(
+        "/{}/{}",
+        config.routes.images_path,
+        utf8_percent_encode(slug, PATH)
+    );
+    let mut query = Vec::new();
+    if let Some(width) = width.and_then(|width| config.images.snap_width(width)) {
+        query.push(format!("w={}", width));
+    }
+    if let Some(format) = format {
+        query.push(format!("fmt={}", format.extension()));
+    }
+    if !query.is_empty() {
+        url.push('?');
+        url.push_str(&query.join("&"));
+    }
+    url
+}
+
+/// `image_url(slug=image.slug, w=640, fmt="webp")`: the URL of an image,
+/// resized to the nearest configured width at or above `w` and converted to
+/// `fmt` when given. `slug` may also be a stored file's key.
+fn image_url(args: &HashMap<String, Value>) -> tera::Result<Value> {
+    let config = &helpers()?.config;
+    let slug = required_string_arg(args, "image_url", "slug")?;
+    let width = number_arg(args, "image_url", "w")?.map(|w| w.min(u32::MAX as u64) as u32);
+    let format = variant_format(args, "image_url")?;
+    let url = build_image_url(config, slug, width, format);
+    Ok(finish(
+        config,
+        url,
+        bool_arg(args, "image_url", "absolute")?,
+    ))
+}
+
+/// `srcset(image=image, fmt="webp")`: a `srcset` attribute value listing the
+/// image at every configured width. `image` is an image or its slug.
+fn srcset(args: &HashMap<String, Value>) -> tera::Result<Value> {
+    let config = &helpers()?.config;
+    let slug = match args.get("image") {
+        Some(Value::String(slug)) => slug.as_str(),
+        Some(Value::Object(image)) => image
+            .get("slug")
+            .and_then(Value::as_str)
+            .ok_or_else(|| tera::Error::msg("`srcset` expects `image` to have a slug"))?,
+        _ => return Err(tera::Error::msg("`srcset` needs an `image` argument")),
+    };
+    let format = variant_format(args, "srcset")?;
+
+    let mut widths = config.images.widths.clone();
+    widths.sort_unstable();
+    widths.dedup();
+    let entries: Vec<String> = widths
+        .iter()
+        .map(|&width| {
+            format!(
+                "{} {}w",
+                build_image_url(config, slug, Some(width), format),
+                width
+            )
+        })
+        .collect();
+    Ok(Value::String(entries.join(", ")))
+}
+
+/// Runs a repository call from inside a render. Renders happen on the
+/// server's worker threads, which may block as long as the runtime is told.
+fn block_on<F: std::future::Future>(future: F) -> tera::Result<F::Output> {
+    let handle = tokio::runtime::Handle::try_current()
+        .ok()
+        .filter(|handle| handle.runtime_flavor() == tokio::runtime::RuntimeFlavor::MultiThread)
+        .ok_or_else(|| tera::Error::msg("Content can only be fetched while serving pages"))?;
+    Ok(tokio::task::block_in_place(|| handle.block_on(future)))
+}
+
+/// `get_images(tag="mugs", limit=4)`: images for sidebar widgets and the
+/// like, newest first as on the home page, optionally only those with a
+/// keyword. `collection` names what to list; `images` is the only one.
+fn get_images(args: &HashMap<String, Value>) -> tera::Result<Value> {
+    let helpers = helpers()?;
+    let collection = string_arg(args, "get_images", "collection")?.unwrap_or("images");
+    if collection != "images" {
+        return Err(tera::Error::msg(format!(
+            "`get_images` doesn't know the collection '{}'",
+            collection
+        )));
+    }
+    let tag = string_arg(args, "get_images", "tag")?;
+    let limit = number_arg(args, "get_images", "limit")?;
+
+    render_cache::depend_on(render_cache::TAG_IMAGES);
+    let images = block_on(helpers.repo.images())?
+        .map_err(|e| tera::Error::msg(format!("Failed to get images: {}", e)))?;
+    let images: Vec<ImageView> = images
+        .into_iter()
+        .filter(|image| match tag {
+            Some(tag) => image
+                .keywords
+                .iter()
+                .any(|keyword| keyword.eq_ignore_ascii_case(tag)),
+            None => true,
+        })
+        .take(limit.map_or(usize::MAX, |limit| limit as usize))
+        .map(ImageView::from)
+        .collect();
+    Ok(tera::to_value(images)?)
+}
+
+/// `get_entries(type="events", limit=3)`: published entries of a content
+/// type in its listing order, for an "upcoming events" panel and the like.
+fn get_entries(args: &HashMap<String, Value>) -> tera::Result<Value> {
+    let helpers = helpers()?;
+    let content_type = content_type_arg(args, "get_entries")?;
+    let limit = number_arg(args, "get_entries", "limit")?;
+
+    render_cache::depend_on(&render_cache::entries_tag(&content_type.name));
+    let mut entries: Vec<_> = block_on(helpers.repo.entries(&content_type.name))?
+        .map_err(|e| tera::Error::msg(format!("Failed to get entries: {}", e)))?
+        .into_iter()
+        .filter(|entry| entry.published)
+        .collect();
+    content_type.sort_entries(&mut entries);
+    let entries: Vec<EntryView> = entries
+        .into_iter()
+        .take(limit.map_or(usize::MAX, |limit| limit as usize))
+        .map(|entry| EntryView::new(entry, &content_type))
+        .collect();
+    Ok(tera::to_value(entries)?)
+}
+
+/// `"/about" | absolute_url`: the full URL of a site-relative path. The
+/// filter escapes its own output, sparing slashes that autoescaping would
+/// print as `&#x2F;`.
+struct AbsoluteUrlFilter;
+
+impl tera::Filter for AbsoluteUrlFilter {
+    fn filter(&self, value: &Value, _: &HashMap<String, Value>) -> tera::Result<Value> {
+        let config = &helpers()?.config;
+        let url = make_absolute(config, string_value(value, "absolute_url")?);
+        Ok(Value::String(
+            tera::escape_html(&url).replace("&#x2F;", "/"),
+        ))
+    }
+
+    fn is_safe(&self) -> bool {
+        true
+    }
+}
+
+/// `image.description | markdown`: Markdown rendered to sanitized HTML,
+/// printed without escaping.
+struct MarkdownFilter;
+
+impl tera::Filter for MarkdownFilter {
+    fn filter(&self, value: &Value, _: &HashMap<String, Value>) -> tera::Result<Value> {
+        Ok(Value::String(markdown::to_html(string_value(
+            value, "markdown",
+        )?)))
+    }
+
+    fn is_safe(&self) -> bool {
+        true
+    }
+}
+
+/// `text | reading_time`: whole minutes to read, at least one.
+fn reading_time(value: &Value, args: &HashMap<String, Value>) -> tera::Result<Value> {
+    let words = markdown::to_text(string_value(value, "reading_time")?)
+        .split_whitespace()
+        .count() as u64;
+    let per_minute = number_arg(args, "reading_time", "wpm")?
+        .unwrap_or(WORDS_PER_MINUTE)
+        .max(1);
+    Ok(Value::from(words.div_ceil(per_minute).max(1)))
+}
+
+/// `text | excerpt(length=120)`: plain text cut at a word boundary.
+fn excerpt(value: &Value, args: &HashMap<String, Value>) -> tera::Result<Value> {
+    let length = number_arg(args, "excerpt", "length")?
+        .map_or(markdown::EXCERPT_LENGTH, |length| length as usize);
+    let text = markdown::to_text(string_value(value, "excerpt")?);
+    Ok(Value::String(markdown::truncate(&text, length)))
+}
+
+/// Timestamps as the databases and importers write them.
+fn parse_date(value: &Value) -> Option<DateTime<Utc>> {
+    if let Some(seconds) = value.as_i64() {
+        return DateTime::from_timestamp(seconds, 0);
+    }
+    let value = value.as_str()?.trim();
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Some(time.with_timezone(&Utc));
+    }
+    for format in ["%Y-%m-%d %H:%M:%S%.f%#z", "%Y-%m-%d %H:%M:%S%#z"] {
+        if let Ok(time) = DateTime::parse_from_str(value, format) {
+            return Some(time.with_timezone(&Utc));
+        }
+    }
+    for format in ["%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M:%S%.f"] {
+        if let Ok(time) = NaiveDateTime::parse_from_str(value, format) {
+            return Some(time.and_utc());
+        }
+    }
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .ok()
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .map(|time| time.and_utc())
+}
+
+/// `updated_at | format_date(format="%-d %B %Y", locale="fr_CA")`: a date
+/// in strftime format, with month and day names in `locale`, which defaults
+/// to `site.locale`.
+fn format_date(value: &Value, args: &HashMap<String, Value>) -> tera::Result<Value> {
+    let config = &helpers()?.config;
+    let time = parse_date(value)
+        .ok_or_else(|| tera::Error::msg(format!("`format_date` can't read the date {}", value)))?;
+    let format = string_arg(args, "format_date", "format")?.unwrap_or(DEFAULT_DATE_FORMAT);
+    let locale_name = string_arg(args, "format_date", "locale")?.unwrap_or(&config.site.locale);
+    let locale = chrono::Locale::try_from(locale_name).map_err(|_| {
+        tera::Error::msg(format!(
+            "`format_date` doesn't know the locale '{}'",
+            locale_name
+        ))
+    })?;
+
+    // An invalid format only fails once it's written out
+    let mut formatted = String::new();
+    std::fmt::write(
+        &mut formatted,
+        format_args!("{}", time.format_localized(format, locale)),
+    )
+    .map_err(|_| tera::Error::msg(format!("`format_date` can't use the format '{}'", format)))?;
+    Ok(Value::String(formatted))
+}
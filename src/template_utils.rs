@@ -1,18 +1,21 @@
-use crate::models::CustomError;
+use crate::error::AppError;
+use crate::security;
 use tera::Context;
 use warp::Reply;
 
+/// Renders a page with a fresh `csp_nonce` in its context, for inline
+/// scripts the Content-Security-Policy should allow.
 pub async fn render_template(
     template_name: &str,
     context: &Context,
 ) -> Result<impl Reply, warp::Rejection> {
-    crate::templates::TEMPLATES
-        .render(template_name, context)
-        .map(warp::reply::html)
-        .map_err(|e| {
-            eprintln!("Template rendering error: {:?}", e);
-            warp::reject::custom(CustomError {
-                message: "Failed to render page".to_string(),
-            })
-        })
+    let nonce = security::generate_nonce();
+    let mut context = context.clone();
+    context.insert("csp_nonce", &nonce);
+
+    crate::metrics::time_render(template_name, || {
+        crate::templates::TEMPLATES.render(template_name, &context)
+    })
+    .map(|html| warp::reply::with_header(warp::reply::html(html), security::NONCE_HEADER, nonce))
+    .map_err(|e| AppError::Internal(format!("Failed to render {}: {:?}", template_name, e)).into())
 }
@@ -1,23 +1,135 @@
 use crate::admin_assets::AdminAssets;
+use crate::pages;
+use crate::template_helpers;
+use crate::themes::{self, Theme};
 use lazy_static::lazy_static;
-use tera::Tera;
+use std::error::Error;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{OnceLock, RwLock};
+use tera::{Context, Tera};
 
-lazy_static! {
-    pub static ref TEMPLATES: Tera = {
-        let mut tera = Tera::new("templates/**/*").unwrap();
-
-        for file_path in AdminAssets::iter() {
-            if file_path.ends_with(".html") {
-                if let Some(content) = AdminAssets::get(&file_path) {
-                    if let Ok(template_str) = String::from_utf8(content.data.to_vec()) {
-                        // Add with admin/ prefix to avoid naming conflicts
-                        tera.add_raw_template(&format!("admin/{}", file_path), &template_str)
-                            .unwrap_or_else(|e| eprintln!("Failed to add template {}: {}", file_path, e));
-                    }
+static OVERRIDE_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Set in `serve --dev --live-reload`, so rendered pages pick up changes
+/// without a manual refresh.
+static LIVE_RELOAD: AtomicBool = AtomicBool::new(false);
+
+/// Loaded in the page ahead of `</body>` when live reload is on.
+pub const LIVE_RELOAD_SCRIPT: &str = "<script src=\"/__dev/reload.js\"></script>";
+
+/// Sets the directory whose templates take precedence over the active
+/// theme's. Must be called before the first `reload`; later calls are
+/// ignored.
+pub fn set_override_dir(dir: PathBuf) {
+    let _ = OVERRIDE_DIR.set(dir);
+}
+
+pub fn set_live_reload(enabled: bool) {
+    LIVE_RELOAD.store(enabled, Ordering::Relaxed);
+}
+
+/// Tera's own message only names the template; the cause, such as the
+/// parse error with its line and column, is further down the chain.
+fn describe(e: &tera::Error) -> String {
+    let mut message = e.to_string().trim().to_string();
+    let mut source = e.source();
+    while let Some(cause) = source {
+        message.push_str(&format!("\n  caused by: {}", cause));
+        source = cause.source();
+    }
+    message
+}
+
+/// Compiles the site templates for `theme`: the bundled theme's, replaced
+/// by the theme's own and then by any in the override directory, followed
+/// by the embedded admin ones.
+fn load(theme: &Theme) -> Result<Tera, String> {
+    let mut sources = std::collections::BTreeMap::new();
+    if !theme.is_bundled() {
+        sources.extend(Theme::bundled().templates()?);
+    }
+    sources.extend(theme.templates()?);
+    if let Some(dir) = OVERRIDE_DIR.get() {
+        sources.extend(themes::read_templates(dir)?);
+    }
+
+    for file_path in AdminAssets::iter() {
+        if file_path.ends_with(".html") {
+            if let Some(content) = AdminAssets::get(&file_path) {
+                if let Ok(template_str) = String::from_utf8(content.data.to_vec()) {
+                    // Add with admin/ prefix to avoid naming conflicts
+                    sources.insert(format!("admin/{}", file_path), template_str);
                 }
             }
         }
+    }
+
+    let mut tera = Tera::default();
+    template_helpers::register(&mut tera);
+    tera.add_raw_templates(sources).map_err(|e| describe(&e))?;
+    Ok(tera)
+}
 
-        tera
+/// Checks that a theme's templates compile without putting them in service.
+pub fn check(theme: &Theme) -> Result<(), String> {
+    load(theme).map(|_| ())
+}
+
+/// The compiled templates, swapped out whole when they are reloaded so a
+/// render never sees a half-updated set.
+pub struct Templates {
+    tera: RwLock<Tera>,
+}
+
+impl Templates {
+    /// Renders with the active theme available as `theme` and the page menu
+    /// as `nav`.
+    pub fn render(&self, template_name: &str, context: &Context) -> tera::Result<String> {
+        let mut context = context.clone();
+        context.insert("theme", &themes::active().context());
+        context.insert("nav", &*pages::nav());
+        let html = self.tera.read().unwrap().render(template_name, &context)?;
+        if !LIVE_RELOAD.load(Ordering::Relaxed) {
+            return Ok(html);
+        }
+        Ok(match html.rfind("</body>") {
+            Some(end) => format!("{}{}{}", &html[..end], LIVE_RELOAD_SCRIPT, &html[end..]),
+            None => html,
+        })
+    }
+
+    pub fn template_names(&self) -> Vec<String> {
+        self.tera
+            .read()
+            .unwrap()
+            .get_template_names()
+            .map(str::to_string)
+            .collect()
+    }
+
+    pub fn has_template(&self, template_name: &str) -> bool {
+        self.tera
+            .read()
+            .unwrap()
+            .get_template_names()
+            .any(|name| name == template_name)
+    }
+}
+
+lazy_static! {
+    /// Empty until `reload` is first called at startup.
+    pub static ref TEMPLATES: Templates = Templates {
+        tera: RwLock::new(Tera::default()),
     };
 }
+
+/// Compiles the active theme's templates, at startup, when the theme
+/// changes and whenever `serve --dev` sees them change. On failure the error
+/// describes the template that failed to parse, and the ones already loaded
+/// stay in service.
+pub fn reload() -> Result<(), String> {
+    let tera = load(&themes::active().theme)?;
+    *TEMPLATES.tera.write().unwrap() = tera;
+    Ok(())
+}
@@ -0,0 +1,692 @@
+use crate::render_cache::RENDER_CACHE;
+use crate::repository::{RepoError, Repository};
+use crate::templates;
+use lazy_static::lazy_static;
+use rust_embed::RustEmbed;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+/// The theme built into the binary. It is used when no other theme is
+/// active and fills in whatever templates or static files another theme
+/// leaves out.
+#[derive(RustEmbed)]
+#[folder = "themes/default"]
+pub struct DefaultTheme;
+
+pub const DEFAULT_THEME: &str = "default";
+pub const MANIFEST_FILE: &str = "theme.toml";
+
+/// Settings keys for the active theme's name and for each theme's saved
+/// values, stored as a JSON object.
+pub const ACTIVE_THEME_KEY: &str = "theme.active";
+
+pub fn settings_key(theme: &str) -> String {
+    format!("theme.{}.settings", theme)
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SettingKind {
+    #[default]
+    Text,
+    /// A hex color, exposed to stylesheets by `/theme.css`
+    Color,
+    /// A font family name, also exposed by `/theme.css`
+    Font,
+    /// A link: a path on this site, an http(s) URL or a `mailto:` address
+    Url,
+    /// A path on this site or an http(s) URL
+    Image,
+    Boolean,
+}
+
+/// TOML authors write `default = false` for booleans; values are kept as
+/// strings either way.
+fn string_or_bool<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Raw {
+        String(String),
+        Bool(bool),
+    }
+    Ok(match Raw::deserialize(deserializer)? {
+        Raw::String(value) => value,
+        Raw::Bool(value) => value.to_string(),
+    })
+}
+
+/// One `[[settings]]` entry in `theme.toml`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SettingSpec {
+    pub key: String,
+    #[serde(rename = "type", default)]
+    pub kind: SettingKind,
+    #[serde(default)]
+    pub label: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default, deserialize_with = "string_or_bool")]
+    pub default: String,
+    /// When not empty, the value must be one of these
+    #[serde(default)]
+    pub options: Vec<String>,
+}
+
+fn is_hex_color(value: &str) -> bool {
+    value.strip_prefix('#').is_some_and(|hex| {
+        matches!(hex.len(), 3 | 4 | 6 | 8) && hex.chars().all(|c| c.is_ascii_hexdigit())
+    })
+}
+
+/// Values end up in attributes and stylesheets, so links are kept to the
+/// schemes a theme has any use for and to characters that need no quoting.
+fn is_link(value: &str, allow_mailto: bool) -> bool {
+    let scheme_ok = value.starts_with('/')
+        || value.starts_with("https://")
+        || value.starts_with("http://")
+        || (allow_mailto && value.starts_with("mailto:"));
+    scheme_ok
+        && !value
+            .chars()
+            .any(|c| c.is_whitespace() || c.is_control() || "\"'<>\\()".contains(c))
+}
+
+impl SettingSpec {
+    pub fn label(&self) -> &str {
+        if self.label.is_empty() {
+            &self.key
+        } else {
+            &self.label
+        }
+    }
+
+    /// Checks a value from the admin or a manifest default, returning it
+    /// trimmed.
+    pub fn validate(&self, value: &str) -> Result<String, String> {
+        let value = value.trim();
+        let label = self.label();
+        if !self.options.is_empty() && !self.options.iter().any(|option| option == value) {
+            return Err(format!(
+                "{} must be one of: {}",
+                label,
+                self.options.join(", ")
+            ));
+        }
+        let error = match self.kind {
+            SettingKind::Text if value.len() > 1000 => Some("is too long"),
+            SettingKind::Text => None,
+            SettingKind::Color if !is_hex_color(value) => {
+                Some("must be a hex color such as #a1b2c3")
+            }
+            SettingKind::Font
+                if value.is_empty()
+                    || !value
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == ' ' || c == '-') =>
+            {
+                Some("may only contain letters, digits, spaces and hyphens")
+            }
+            SettingKind::Url if !value.is_empty() && !is_link(value, true) => {
+                Some("must be a path starting with /, an http(s) URL or a mailto: link")
+            }
+            SettingKind::Image if !value.is_empty() && !is_link(value, false) => {
+                Some("must be a path starting with / or an http(s) URL")
+            }
+            SettingKind::Boolean if value != "true" && value != "false" => {
+                Some("must be true or false")
+            }
+            _ => None,
+        };
+        match error {
+            Some(error) => Err(format!("{} {}", label, error)),
+            None => Ok(value.to_string()),
+        }
+    }
+
+    fn context_value(&self, value: &str) -> serde_json::Value {
+        match self.kind {
+            SettingKind::Boolean => serde_json::Value::Bool(value == "true"),
+            _ => serde_json::Value::String(value.to_string()),
+        }
+    }
+}
+
+/// A theme's `theme.toml`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    pub name: String,
+    #[serde(default)]
+    pub title: String,
+    #[serde(default)]
+    pub version: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub author: String,
+    #[serde(default)]
+    pub settings: Vec<SettingSpec>,
+}
+
+fn is_identifier(value: &str, extra: &[char]) -> bool {
+    !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || extra.contains(&c))
+}
+
+impl Manifest {
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let mut manifest: Manifest =
+            toml::from_str(source).map_err(|e| format!("Invalid {}: {}", MANIFEST_FILE, e))?;
+        if !is_identifier(&manifest.name, &['-']) {
+            return Err(format!(
+                "Invalid theme name '{}': use lowercase letters, digits, '-' and '_'",
+                manifest.name
+            ));
+        }
+        if manifest.title.is_empty() {
+            manifest.title = manifest.name.clone();
+        }
+
+        let mut seen = std::collections::HashSet::new();
+        for spec in &mut manifest.settings {
+            if !is_identifier(&spec.key, &[]) {
+                return Err(format!(
+                    "Invalid setting key '{}': use lowercase letters, digits and '_'",
+                    spec.key
+                ));
+            }
+            if !seen.insert(spec.key.clone()) {
+                return Err(format!("Setting '{}' is declared twice", spec.key));
+            }
+            spec.default = spec
+                .validate(&spec.default)
+                .map_err(|e| format!("Invalid default for '{}': {}", spec.key, e))?;
+        }
+        Ok(manifest)
+    }
+}
+
+/// Reads every file under `dir` as a template named by its path there,
+/// e.g. `partials/nav.html`. Hidden files are skipped. A missing directory
+/// has no templates.
+pub fn read_templates(dir: &Path) -> Result<Vec<(String, String)>, String> {
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut found = Vec::new();
+    for entry in walkdir::WalkDir::new(dir).follow_links(true) {
+        let entry = entry.map_err(|e| format!("Failed to read {}: {}", dir.display(), e))?;
+        let hidden = entry.file_name().to_string_lossy().starts_with('.');
+        if !entry.file_type().is_file() || hidden {
+            continue;
+        }
+        let name = entry
+            .path()
+            .strip_prefix(dir)
+            .unwrap_or(entry.path())
+            .components()
+            .map(|part| part.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        let source = fs::read_to_string(entry.path())
+            .map_err(|e| format!("Failed to read {}: {}", entry.path().display(), e))?;
+        found.push((name, source));
+    }
+    Ok(found)
+}
+
+/// Turns the tail of a `/static/...` URL into a relative path, refusing
+/// anything that could step outside the directory it's resolved against.
+pub fn static_path(tail: &str) -> Option<PathBuf> {
+    let decoded = percent_encoding::percent_decode_str(tail)
+        .decode_utf8()
+        .ok()?;
+    let mut path = PathBuf::new();
+    for segment in decoded.split('/') {
+        if segment.is_empty() || segment.starts_with('.') || segment.contains(['\\', ':', '\0']) {
+            return None;
+        }
+        path.push(segment);
+    }
+    Some(path)
+}
+
+/// Reads `relative` from under `base`, as long as the file really is inside
+/// it once symlinks are resolved.
+fn read_inside(base: &Path, relative: &Path) -> Option<Vec<u8>> {
+    let base = base.canonicalize().ok()?;
+    let path = base.join(relative).canonicalize().ok()?;
+    if !path.starts_with(&base) || !path.is_file() {
+        return None;
+    }
+    fs::read(path).ok()
+}
+
+/// A theme, either installed in its own directory or the bundled one.
+#[derive(Debug, Clone)]
+pub struct Theme {
+    pub manifest: Manifest,
+    /// `None` for the bundled theme, which is read from the binary
+    pub dir: Option<PathBuf>,
+}
+
+impl Theme {
+    pub fn bundled() -> Theme {
+        let source = DefaultTheme::get(MANIFEST_FILE).expect("bundled theme has no theme.toml");
+        let manifest = Manifest::parse(&String::from_utf8_lossy(&source.data))
+            .expect("bundled theme.toml is invalid");
+        Theme {
+            manifest,
+            dir: None,
+        }
+    }
+
+    /// Opens the theme installed in `dir`.
+    pub fn open(dir: &Path) -> Result<Theme, String> {
+        let manifest_path = dir.join(MANIFEST_FILE);
+        let source = fs::read_to_string(&manifest_path)
+            .map_err(|e| format!("Failed to read {}: {}", manifest_path.display(), e))?;
+        let manifest = Manifest::parse(&source)?;
+        if manifest.name == DEFAULT_THEME {
+            return Err(format!(
+                "'{}' is reserved for the bundled theme; give the theme another name",
+                DEFAULT_THEME
+            ));
+        }
+        Ok(Theme {
+            manifest,
+            dir: Some(dir.to_path_buf()),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.manifest.name
+    }
+
+    pub fn is_bundled(&self) -> bool {
+        self.dir.is_none()
+    }
+
+    pub fn templates(&self) -> Result<Vec<(String, String)>, String> {
+        match &self.dir {
+            Some(dir) => read_templates(&dir.join("templates")),
+            None => Ok(DefaultTheme::iter()
+                .filter_map(|path| {
+                    let name = path.strip_prefix("templates/")?.to_string();
+                    let file = DefaultTheme::get(&path)?;
+                    Some((name, String::from_utf8_lossy(&file.data).into_owned()))
+                })
+                .collect()),
+        }
+    }
+
+    pub fn static_file(&self, relative: &Path) -> Option<Vec<u8>> {
+        match &self.dir {
+            Some(dir) => read_inside(&dir.join("static"), relative),
+            None => {
+                let path = format!("static/{}", relative.to_string_lossy().replace('\\', "/"));
+                DefaultTheme::get(&path).map(|file| file.data.into_owned())
+            }
+        }
+    }
+}
+
+/// A file under `/static/`: from the override directory if it's there,
+/// then from the active theme, then from the bundled one.
+pub fn static_file(override_dir: &Path, tail: &str) -> Option<Vec<u8>> {
+    let relative = static_path(tail)?;
+    if let Some(data) = read_inside(override_dir, &relative) {
+        return Some(data);
+    }
+    let active = active();
+    active.theme.static_file(&relative).or_else(|| {
+        (!active.theme.is_bundled())
+            .then(|| Theme::bundled().static_file(&relative))
+            .flatten()
+    })
+}
+
+/// The bundled theme followed by every valid theme installed in
+/// `themes_dir`, sorted by name. Directories without a usable manifest are
+/// skipped with a warning.
+pub fn discover(themes_dir: &Path) -> Vec<Theme> {
+    let mut themes = Vec::new();
+    if let Ok(entries) = fs::read_dir(themes_dir) {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let hidden = entry.file_name().to_string_lossy().starts_with('.');
+            if hidden || !path.join(MANIFEST_FILE).is_file() {
+                continue;
+            }
+            match Theme::open(&path) {
+                Ok(theme) => themes.push(theme),
+                // The bundled theme's sources sit in ./themes/default in a checkout
+                Err(_) if path.ends_with(DEFAULT_THEME) => {}
+                Err(e) => tracing::warn!(path = %path.display(), error = %e, "Skipping theme"),
+            }
+        }
+    }
+    themes.sort_by(|a, b| a.name().cmp(b.name()));
+    themes.insert(0, Theme::bundled());
+    themes
+}
+
+/// Finds a theme by name, the bundled one included.
+pub fn find(themes_dir: &Path, name: &str) -> Result<Theme, String> {
+    if name == DEFAULT_THEME {
+        return Ok(Theme::bundled());
+    }
+    if !is_identifier(name, &['-']) {
+        return Err(format!("No theme named '{}'", name));
+    }
+    let dir = themes_dir.join(name);
+    if !dir.join(MANIFEST_FILE).is_file() {
+        return Err(format!("No theme named '{}'", name));
+    }
+    let theme = Theme::open(&dir)?;
+    if theme.name() != name {
+        return Err(format!(
+            "{} declares the name '{}'; the directory must have the same name",
+            dir.join(MANIFEST_FILE).display(),
+            theme.name()
+        ));
+    }
+    Ok(theme)
+}
+
+/// The theme in service and its settings, defaults filled in.
+#[derive(Debug)]
+pub struct ActiveTheme {
+    pub theme: Theme,
+    pub values: BTreeMap<String, String>,
+    /// Changes whenever the theme or a setting does, to bust caches of
+    /// `/theme.css`
+    pub revision: String,
+    context: serde_json::Value,
+}
+
+impl ActiveTheme {
+    /// Saved values the manifest no longer declares, or that no longer
+    /// validate, are dropped in favour of the defaults.
+    pub fn new(theme: Theme, saved: BTreeMap<String, String>) -> Self {
+        let mut values = BTreeMap::new();
+        for spec in &theme.manifest.settings {
+            let value = match saved.get(&spec.key).map(|value| spec.validate(value)) {
+                Some(Ok(value)) => value,
+                Some(Err(e)) => {
+                    tracing::warn!(theme = %theme.name(), error = %e, "Ignoring saved theme setting");
+                    spec.default.clone()
+                }
+                None => spec.default.clone(),
+            };
+            values.insert(spec.key.clone(), value);
+        }
+
+        let fingerprint = serde_json::json!([theme.name(), theme.manifest.version, values]);
+        let revision =
+            crate::files::content_hash(fingerprint.to_string().as_bytes())[..12].to_string();
+
+        // Templates the theme leaves out come from the bundled theme, so the
+        // bundled settings they read get their defaults
+        let mut settings = serde_json::Map::new();
+        if !theme.is_bundled() {
+            for spec in Theme::bundled().manifest.settings {
+                settings.insert(spec.key.clone(), spec.context_value(&spec.default));
+            }
+        }
+        for spec in &theme.manifest.settings {
+            let value = values.get(&spec.key).map_or("", String::as_str);
+            settings.insert(spec.key.clone(), spec.context_value(value));
+        }
+        let context = serde_json::json!({
+            "name": theme.name(),
+            "title": theme.manifest.title,
+            "version": theme.manifest.version,
+            "revision": revision,
+            "settings": settings,
+        });
+
+        ActiveTheme {
+            theme,
+            values,
+            revision,
+            context,
+        }
+    }
+
+    /// `theme` in every template's context.
+    pub fn context(&self) -> &serde_json::Value {
+        &self.context
+    }
+
+    /// Color and font settings as CSS variables, `accent_color` becoming
+    /// `--accent-color`.
+    pub fn css(&self) -> String {
+        let mut css = String::from(":root {\n");
+        for spec in &self.theme.manifest.settings {
+            let value = self.values.get(&spec.key).map_or("", String::as_str);
+            let value = match spec.kind {
+                SettingKind::Color => value.to_string(),
+                SettingKind::Font => format!("\"{}\"", value),
+                _ => continue,
+            };
+            css.push_str(&format!(
+                "    --{}: {};\n",
+                spec.key.replace('_', "-"),
+                value
+            ));
+        }
+        css.push_str("}\n");
+        css
+    }
+
+    /// Validates a full set of values from the admin. Keys the theme
+    /// doesn't declare are rejected; declared keys left out keep their
+    /// current value.
+    pub fn validate(
+        &self,
+        input: &BTreeMap<String, String>,
+    ) -> Result<BTreeMap<String, String>, String> {
+        if let Some(key) = input.keys().find(|key| {
+            !self
+                .theme
+                .manifest
+                .settings
+                .iter()
+                .any(|spec| &spec.key == *key)
+        }) {
+            return Err(format!("Unknown setting '{}'", key));
+        }
+        let mut values = self.values.clone();
+        for spec in &self.theme.manifest.settings {
+            if let Some(value) = input.get(&spec.key) {
+                values.insert(spec.key.clone(), spec.validate(value)?);
+            }
+        }
+        Ok(values)
+    }
+}
+
+lazy_static! {
+    static ref ACTIVE: RwLock<Arc<ActiveTheme>> = RwLock::new(Arc::new(ActiveTheme::new(
+        Theme::bundled(),
+        BTreeMap::new()
+    )));
+}
+
+pub fn active() -> Arc<ActiveTheme> {
+    ACTIVE.read().unwrap().clone()
+}
+
+/// Puts a theme in service and recompiles the templates, keeping the
+/// previous theme if they don't compile. Rendered pages are dropped either
+/// way, since the settings may have changed.
+pub fn apply(theme: Theme, values: BTreeMap<String, String>) -> Result<(), String> {
+    let previous = std::mem::replace(
+        &mut *ACTIVE.write().unwrap(),
+        Arc::new(ActiveTheme::new(theme, values)),
+    );
+    if let Err(e) = templates::reload() {
+        *ACTIVE.write().unwrap() = previous;
+        return Err(e);
+    }
+    RENDER_CACHE.clear();
+    Ok(())
+}
+
+/// Re-reads the active theme from disk, for `serve --dev`.
+pub fn refresh() -> Result<(), String> {
+    let active = active();
+    let theme = match &active.theme.dir {
+        Some(dir) => Theme::open(dir)?,
+        None => Theme::bundled(),
+    };
+    apply(theme, active.values.clone())
+}
+
+pub async fn saved_values(
+    repo: &dyn Repository,
+    theme: &str,
+) -> Result<BTreeMap<String, String>, RepoError> {
+    Ok(match repo.setting(&settings_key(theme)).await? {
+        Some(json) => serde_json::from_str(&json).unwrap_or_else(|e| {
+            tracing::warn!(theme, error = %e, "Ignoring unreadable theme settings");
+            BTreeMap::new()
+        }),
+        None => BTreeMap::new(),
+    })
+}
+
+/// Loads the theme recorded in the database at startup. A theme that has
+/// gone missing or no longer compiles is reported and the bundled theme is
+/// used instead.
+pub async fn load_active(repo: &dyn Repository, themes_dir: &Path) -> Result<(), String> {
+    let name = repo
+        .setting(ACTIVE_THEME_KEY)
+        .await
+        .map_err(|e| format!("Failed to read the active theme: {}", e))?
+        .unwrap_or_else(|| DEFAULT_THEME.to_string());
+
+    if name != DEFAULT_THEME {
+        let loaded = match find(themes_dir, &name) {
+            Ok(theme) => {
+                let values = saved_values(repo, &name)
+                    .await
+                    .map_err(|e| format!("Failed to read theme settings: {}", e))?;
+                apply(theme, values)
+            }
+            Err(e) => Err(e),
+        };
+        match loaded {
+            Ok(()) => {
+                tracing::info!(theme = %name, "Using theme");
+                return Ok(());
+            }
+            Err(e) => {
+                tracing::error!(theme = %name, error = %e, "Falling back to the default theme")
+            }
+        }
+    }
+
+    let values = saved_values(repo, DEFAULT_THEME)
+        .await
+        .map_err(|e| format!("Failed to read theme settings: {}", e))?;
+    apply(Theme::bundled(), values)
+}
+
+fn copy_dir(from: &Path, to: &Path) -> std::io::Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
+/// Unpacks or copies `source` into `staging`, returning the directory that
+/// holds `theme.toml`. Archives often wrap everything in one top-level
+/// directory, which is looked inside.
+fn stage(source: &Path, staging: &Path) -> Result<PathBuf, String> {
+    let name = source.to_string_lossy().to_lowercase();
+    let unpacked = if source.is_dir() {
+        copy_dir(source, staging).map_err(|e| e.to_string())
+    } else if name.ends_with(".zip") {
+        fs::File::open(source)
+            .map_err(|e| e.to_string())
+            .and_then(|file| zip::ZipArchive::new(file).map_err(|e| e.to_string()))
+            .and_then(|mut archive| archive.extract(staging).map_err(|e| e.to_string()))
+    } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+        fs::File::open(source)
+            .map(|file| tar::Archive::new(flate2::read::GzDecoder::new(file)))
+            .and_then(|mut archive| archive.unpack(staging))
+            .map_err(|e| e.to_string())
+    } else {
+        return Err(format!(
+            "{} is not a directory or a .zip, .tar.gz or .tgz archive",
+            source.display()
+        ));
+    };
+    unpacked.map_err(|e| format!("Failed to unpack {}: {}", source.display(), e))?;
+
+    if staging.join(MANIFEST_FILE).is_file() {
+        return Ok(staging.to_path_buf());
+    }
+    let dirs: Vec<PathBuf> = fs::read_dir(staging)
+        .map_err(|e| e.to_string())?
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir())
+        .collect();
+    match dirs.as_slice() {
+        [only] if only.join(MANIFEST_FILE).is_file() => Ok(only.clone()),
+        _ => Err(format!(
+            "No {} found in {}",
+            MANIFEST_FILE,
+            source.display()
+        )),
+    }
+}
+
+/// Installs a theme from a directory or archive into `themes_dir`, after
+/// checking its manifest and that its templates compile. An installed theme
+/// of the same name is only replaced with `replace`.
+pub fn install(source: &Path, themes_dir: &Path, replace: bool) -> Result<Manifest, String> {
+    fs::create_dir_all(themes_dir)
+        .map_err(|e| format!("Failed to create {}: {}", themes_dir.display(), e))?;
+    let staging = themes_dir.join(format!(".install-{}", uuid::Uuid::new_v4()));
+
+    let result = (|| {
+        let root = stage(source, &staging)?;
+        let theme = Theme::open(&root)?;
+        templates::check(&theme)?;
+
+        let target = themes_dir.join(theme.name());
+        if target.exists() {
+            if !replace {
+                return Err(format!(
+                    "Theme '{}' is already installed; pass --force to replace it",
+                    theme.name()
+                ));
+            }
+            fs::remove_dir_all(&target)
+                .map_err(|e| format!("Failed to remove {}: {}", target.display(), e))?;
+        }
+        fs::rename(&root, &target)
+            .map_err(|e| format!("Failed to install into {}: {}", target.display(), e))?;
+        Ok(theme.manifest)
+    })();
+
+    if staging.exists() {
+        let _ = fs::remove_dir_all(&staging);
+    }
+    result
+}
@@ -0,0 +1,44 @@
+//! How `error::render` picks between JSON and the site's error page.
+
+use craftcms::config::Config;
+use craftcms::error;
+use warp::http::{header, StatusCode};
+
+async fn not_found(path: &str, accept: Option<&str>) -> warp::reply::Response {
+    let response = error::handle_rejection(warp::reject::not_found())
+        .await
+        .unwrap();
+    error::render(response, path, accept, None, &Config::default())
+}
+
+fn content_type(response: &warp::reply::Response) -> &str {
+    response.headers()[header::CONTENT_TYPE].to_str().unwrap()
+}
+
+#[tokio::test]
+async fn api_errors_are_always_json() {
+    for accept in [None, Some("*/*"), Some("text/html,*/*;q=0.8")] {
+        for path in ["/api", "/api/posts", "/api/posts/missing"] {
+            let response = not_found(path, accept).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+            assert_eq!(
+                content_type(&response),
+                "application/json",
+                "{} {:?}",
+                path,
+                accept
+            );
+        }
+    }
+}
+
+#[tokio::test]
+async fn site_errors_are_pages_unless_json_is_asked_for() {
+    for accept in [None, Some("*/*")] {
+        let response = not_found("/apiary", accept).await;
+        assert_ne!(content_type(&response), "application/json", "{:?}", accept);
+    }
+
+    let response = not_found("/about", Some("application/json")).await;
+    assert_eq!(content_type(&response), "application/json");
+}
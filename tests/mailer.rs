@@ -0,0 +1,127 @@
+//! The forgot-password flow end to end, with mail written by `FileMailer`.
+
+use craftcms::config::{Config, MailBackend};
+use craftcms::handlers::{admin_forgot_password_handler, admin_reset_password_handler};
+use craftcms::mailer;
+use craftcms::models::{ForgotPasswordRequest, ResetPasswordRequest};
+use craftcms::repository;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use warp::http::StatusCode;
+use warp::Reply;
+
+/// Mail goes out on a blocking task after the handler answers, so the file
+/// is polled until a message has been written in full.
+async fn read_mail(path: &Path) -> String {
+    for _ in 0..100 {
+        if let Ok(mail) = std::fs::read_to_string(path) {
+            if mail.ends_with("\r\n\r\n") {
+                return mail;
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("No mail was written to {}", path.display());
+}
+
+/// Undoes the quoted-printable encoding lettre picks for long lines, as a
+/// mail client would before showing the link.
+fn decode_quoted_printable(text: &str) -> String {
+    let text = text.replace("=\r\n", "");
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut rest = text.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let escaped = tail
+            .get(..2)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (byte, escaped) {
+            (b'=', Some(decoded)) => {
+                bytes.push(decoded);
+                rest = &tail[2..];
+            }
+            _ => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8(bytes).unwrap()
+}
+
+#[tokio::test]
+async fn forgot_password_mails_a_working_reset_link() {
+    let dir =
+        std::env::temp_dir().join(format!("craftcms-mailer-{}", uuid::Uuid::new_v4().simple()));
+    let mut config = Config::default();
+    config.storage.data_dir = dir.to_string_lossy().into_owned();
+    config.site.base_url = "https://example.com".to_string();
+    config.mail.backend = MailBackend::File;
+    config.storage.ensure_dirs().unwrap();
+
+    let repo = repository::open(&config).await.unwrap();
+    let mailer: Arc<dyn mailer::Mailer> = Arc::from(mailer::from_config(&config).unwrap());
+    let config = Arc::new(config);
+    repo.create_user("owner@example.com", "old-password")
+        .await
+        .unwrap();
+
+    // Unknown accounts get the same answer and no mail
+    for email in ["nobody@example.com", " owner@example.com "] {
+        let response = admin_forgot_password_handler(
+            ForgotPasswordRequest {
+                email: email.to_string(),
+            },
+            config.clone(),
+            repo.clone(),
+            mailer.clone(),
+        )
+        .await
+        .unwrap()
+        .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    let mail = decode_quoted_printable(&read_mail(&config.mail_log_path()).await);
+    assert_eq!(mail.matches("To: owner@example.com").count(), 1, "{}", mail);
+    assert!(!mail.contains("nobody@example.com"), "{}", mail);
+
+    let link = "https://example.com/admin/reset?token=";
+    let start = mail
+        .find(link)
+        .unwrap_or_else(|| panic!("No reset link in {}", mail))
+        + link.len();
+    let token: String = mail[start..]
+        .chars()
+        .take_while(|c| !c.is_whitespace())
+        .collect();
+    assert!(!token.is_empty());
+
+    let reset = |token: String| {
+        admin_reset_password_handler(
+            ResetPasswordRequest {
+                token,
+                password: "new-password".to_string(),
+            },
+            repo.clone(),
+        )
+    };
+    let response = reset(token.clone()).await.unwrap().into_response();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(repo
+        .login("owner@example.com", "new-password")
+        .await
+        .unwrap()
+        .is_some());
+    assert!(repo
+        .login("owner@example.com", "old-password")
+        .await
+        .unwrap()
+        .is_none());
+
+    // The link works once
+    assert!(reset(token).await.is_err());
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
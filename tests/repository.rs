@@ -0,0 +1,390 @@
+//! The `Repository` contract, run against SQLite and, when `DATABASE_URL`
+//! names a PostgreSQL database, against PostgreSQL too. Slugs and emails
+//! carry a per-run suffix so a shared database can be reused between runs.
+
+use craftcms::config::Config;
+use craftcms::files::ImageFileManager;
+use craftcms::migrations::MIGRATIONS;
+use craftcms::models::{Image, Media, MediaUpload};
+use craftcms::repository::{self, RepoError, Repository};
+use craftcms::storage::LocalStorage;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+struct Fixture {
+    config: Config,
+    repo: Arc<dyn Repository>,
+    file_manager: Arc<ImageFileManager>,
+    run: String,
+    dir: PathBuf,
+}
+
+impl Fixture {
+    async fn open(database_url: Option<String>) -> Self {
+        let run = uuid::Uuid::new_v4().simple().to_string()[..12].to_string();
+        let dir = std::env::temp_dir().join(format!("craftcms-repository-{}", run));
+        let mut config = Config::default();
+        config.storage.data_dir = dir.to_string_lossy().into_owned();
+        config.database.url = database_url;
+        config.storage.ensure_dirs().unwrap();
+
+        let repo = repository::open(&config).await.unwrap();
+        let file_manager = Arc::new(ImageFileManager::new(Arc::new(LocalStorage::new(
+            config.storage.images_path(),
+        ))));
+        Fixture {
+            config,
+            repo,
+            file_manager,
+            run,
+            dir,
+        }
+    }
+
+    fn slug(&self, name: &str) -> String {
+        format!("{}-{}", name, self.run)
+    }
+
+    /// Bytes unique to this run, so blobs from earlier runs against the
+    /// same database are never shared.
+    fn png(&self, name: &str) -> Vec<u8> {
+        let mut data = b"\x89PNG\r\n\x1a\n".to_vec();
+        data.extend_from_slice(format!("{}-{}", name, self.run).as_bytes());
+        data
+    }
+
+    fn stored(&self, key: &str) -> bool {
+        self.file_manager.storage().exists(key).unwrap()
+    }
+
+    async fn filename(&self, slug: &str) -> String {
+        self.repo.image(slug).await.unwrap().unwrap().filename
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn image(slug: &str, alt: &str) -> Image {
+    Image {
+        alt: alt.to_string(),
+        description: String::new(),
+        slug: slug.to_string(),
+        keywords: vec!["test".to_string()],
+        filename: String::new(),
+    }
+}
+
+fn upload(data: Vec<u8>, alt: &str) -> MediaUpload {
+    MediaUpload {
+        data,
+        mime_type: mime::IMAGE_PNG,
+        alt: alt.to_string(),
+        caption: String::new(),
+    }
+}
+
+async fn check_migrations(fixture: &Fixture) {
+    assert_eq!(
+        fixture.repo.schema_version().await.unwrap(),
+        MIGRATIONS.len()
+    );
+
+    // Opening an up-to-date database applies nothing
+    let reopened = repository::open(&fixture.config).await.unwrap();
+    assert_eq!(reopened.schema_version().await.unwrap(), MIGRATIONS.len());
+}
+
+async fn check_images(fixture: &Fixture) {
+    let repo = &fixture.repo;
+    let first = fixture.slug("first");
+    let second = fixture.slug("second");
+    let renamed = fixture.slug("renamed");
+    let shared = fixture.png("shared");
+
+    repo.insert_image(
+        fixture.file_manager.clone(),
+        shared.clone(),
+        mime::IMAGE_PNG,
+        image(&first, "First"),
+    )
+    .await
+    .unwrap();
+    repo.insert_image(
+        fixture.file_manager.clone(),
+        shared.clone(),
+        mime::IMAGE_PNG,
+        image(&second, "Second"),
+    )
+    .await
+    .unwrap();
+
+    // Identical uploads share one blob
+    let shared_key = fixture.filename(&first).await;
+    assert_eq!(fixture.filename(&second).await, shared_key);
+    assert!(fixture.stored(&shared_key));
+    let duplicates = repo.find_duplicates(shared.clone().into()).await.unwrap();
+    let mut duplicate_slugs: Vec<&str> = duplicates.iter().map(|i| i.slug.as_str()).collect();
+    duplicate_slugs.sort_unstable();
+    assert_eq!(duplicate_slugs, [first.as_str(), second.as_str()]);
+
+    let taken = repo
+        .insert_image(
+            fixture.file_manager.clone(),
+            fixture.png("other"),
+            mime::IMAGE_PNG,
+            image(&first, "Taken"),
+        )
+        .await
+        .unwrap_err();
+    assert!(taken.is_unique_violation(), "{}", taken);
+
+    // The blob outlives the first of its two images
+    repo.delete_image(fixture.file_manager.clone(), &second)
+        .await
+        .unwrap();
+    assert!(repo.image(&second).await.unwrap().is_none());
+    assert!(fixture.stored(&shared_key));
+
+    // Replacing the file releases the last reference to the old one
+    repo.update_image(
+        fixture.file_manager.clone(),
+        &first,
+        Some((fixture.png("replacement"), mime::IMAGE_PNG)),
+        image(&first, "First"),
+    )
+    .await
+    .unwrap();
+    let replacement_key = fixture.filename(&first).await;
+    assert_ne!(replacement_key, shared_key);
+    assert!(!fixture.stored(&shared_key));
+    assert!(fixture.stored(&replacement_key));
+
+    // A metadata-only update keeps the file, even across a slug change
+    repo.update_image(
+        fixture.file_manager.clone(),
+        &first,
+        None,
+        image(&renamed, "Renamed"),
+    )
+    .await
+    .unwrap();
+    assert!(repo.image(&first).await.unwrap().is_none());
+    let updated = repo.image(&renamed).await.unwrap().unwrap();
+    assert_eq!(updated.alt, "Renamed");
+    assert_eq!(updated.filename, replacement_key);
+
+    repo.delete_image(fixture.file_manager.clone(), &renamed)
+        .await
+        .unwrap();
+    assert!(!fixture.stored(&replacement_key));
+    let missing = repo
+        .delete_image(fixture.file_manager.clone(), &renamed)
+        .await
+        .unwrap_err();
+    assert!(missing.is_not_found(), "{}", missing);
+}
+
+async fn check_media(fixture: &Fixture) {
+    let repo = &fixture.repo;
+    let slug = fixture.slug("gallery");
+
+    repo.insert_image(
+        fixture.file_manager.clone(),
+        fixture.png("primary"),
+        mime::IMAGE_PNG,
+        image(&slug, "Primary"),
+    )
+    .await
+    .unwrap();
+    let primary_key = fixture.filename(&slug).await;
+    repo.add_media(
+        fixture.file_manager.clone(),
+        &slug,
+        vec![
+            upload(fixture.png("side"), "Side"),
+            upload(fixture.png("detail"), "Detail"),
+        ],
+    )
+    .await
+    .unwrap();
+
+    let media = repo.media(&slug).await.unwrap();
+    let alts: Vec<&str> = media.iter().map(|item| item.alt.as_str()).collect();
+    assert_eq!(alts, ["Side", "Detail"]);
+
+    let missing = repo
+        .add_media(
+            fixture.file_manager.clone(),
+            &fixture.slug("missing"),
+            vec![upload(fixture.png("orphan"), "Orphan")],
+        )
+        .await
+        .unwrap_err();
+    assert!(missing.is_not_found(), "{}", missing);
+
+    // Reordering must name every item once
+    let reordered: Vec<Media> = media.iter().rev().cloned().collect();
+    repo.update_media(&slug, reordered).await.unwrap();
+    let alts: Vec<String> = repo
+        .media(&slug)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|item| item.alt)
+        .collect();
+    assert_eq!(alts, ["Detail", "Side"]);
+    let partial = repo
+        .update_media(&slug, media[..1].to_vec())
+        .await
+        .unwrap_err();
+    assert!(matches!(partial, RepoError::Invalid(_)), "{}", partial);
+
+    // Promoting a shot swaps it with the primary one
+    let side = media.iter().find(|item| item.alt == "Side").unwrap();
+    repo.make_media_primary(&slug, side.id).await.unwrap();
+    let promoted = repo.image(&slug).await.unwrap().unwrap();
+    assert_eq!(promoted.filename, side.filename);
+    assert_eq!(promoted.alt, "Side");
+    let demoted = repo
+        .media(&slug)
+        .await
+        .unwrap()
+        .into_iter()
+        .find(|item| item.id == side.id)
+        .unwrap();
+    assert_eq!(demoted.filename, primary_key);
+    assert_eq!(demoted.alt, "Primary");
+
+    repo.delete_media(fixture.file_manager.clone(), &slug, side.id)
+        .await
+        .unwrap();
+    assert!(!fixture.stored(&primary_key));
+    assert_eq!(repo.media(&slug).await.unwrap().len(), 1);
+
+    // Deleting the image releases its media too
+    let detail_key = repo.media(&slug).await.unwrap()[0].filename.clone();
+    repo.delete_image(fixture.file_manager.clone(), &slug)
+        .await
+        .unwrap();
+    assert!(!fixture.stored(&side.filename));
+    assert!(!fixture.stored(&detail_key));
+    assert!(repo.media(&slug).await.unwrap().is_empty());
+}
+
+async fn check_users(fixture: &Fixture) {
+    let repo = &fixture.repo;
+    let email = format!("{}@example.com", fixture.slug("user"));
+
+    repo.create_user(&email, "first-password").await.unwrap();
+    let taken = repo.create_user(&email, "other").await.unwrap_err();
+    assert!(taken.is_unique_violation(), "{}", taken);
+    assert!(repo
+        .list_users()
+        .await
+        .unwrap()
+        .iter()
+        .any(|user| user.email == email && !user.disabled));
+
+    assert!(repo.login(&email, "wrong").await.unwrap().is_none());
+    let session = repo.login(&email, "first-password").await.unwrap().unwrap();
+    assert_eq!(
+        repo.session_user(&session).await.unwrap().as_deref(),
+        Some(email.as_str())
+    );
+    repo.delete_session(&session).await.unwrap();
+    assert!(repo.session_user(&session).await.unwrap().is_none());
+
+    // Disabling ends sessions and blocks logins until re-enabled
+    let session = repo.login(&email, "first-password").await.unwrap().unwrap();
+    assert_eq!(repo.set_user_disabled(&email, true).await.unwrap(), 1);
+    assert!(repo.session_user(&session).await.unwrap().is_none());
+    assert!(repo
+        .login(&email, "first-password")
+        .await
+        .unwrap()
+        .is_none());
+    assert!(repo
+        .create_password_reset(&email, 30)
+        .await
+        .unwrap()
+        .is_none());
+    assert_eq!(repo.set_user_disabled(&email, false).await.unwrap(), 1);
+
+    // Reset tokens work once and end the user's sessions
+    let session = repo.login(&email, "first-password").await.unwrap().unwrap();
+    let token = repo
+        .create_password_reset(&email, 30)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(repo
+        .create_password_reset("nobody@example.com", 30)
+        .await
+        .unwrap()
+        .is_none());
+    assert!(repo
+        .consume_password_reset(&token, "second-password")
+        .await
+        .unwrap());
+    assert!(!repo
+        .consume_password_reset(&token, "third-password")
+        .await
+        .unwrap());
+    assert!(repo.session_user(&session).await.unwrap().is_none());
+    assert!(repo
+        .login(&email, "first-password")
+        .await
+        .unwrap()
+        .is_none());
+    assert!(repo
+        .login(&email, "second-password")
+        .await
+        .unwrap()
+        .is_some());
+
+    assert_eq!(
+        repo.set_user_password(&email, "fourth-password")
+            .await
+            .unwrap(),
+        1
+    );
+    assert!(repo
+        .login(&email, "fourth-password")
+        .await
+        .unwrap()
+        .is_some());
+    assert_eq!(repo.delete_user(&email).await.unwrap(), 1);
+    assert_eq!(repo.delete_user(&email).await.unwrap(), 0);
+    assert!(repo
+        .login(&email, "fourth-password")
+        .await
+        .unwrap()
+        .is_none());
+}
+
+async fn check_contract(fixture: &Fixture) {
+    check_migrations(fixture).await;
+    check_images(fixture).await;
+    check_media(fixture).await;
+    check_users(fixture).await;
+}
+
+#[tokio::test]
+async fn sqlite_repository() {
+    let fixture = Fixture::open(None).await;
+    check_contract(&fixture).await;
+}
+
+#[tokio::test]
+async fn postgres_repository() {
+    let Ok(url) = std::env::var("DATABASE_URL") else {
+        eprintln!("DATABASE_URL is not set; skipping the PostgreSQL repository tests");
+        return;
+    };
+    let fixture = Fixture::open(Some(url)).await;
+    check_contract(&fixture).await;
+}
@@ -0,0 +1,254 @@
+//! Renders templates through a `Tera` with the helpers registered, the way
+//! themes use them.
+
+use craftcms::config::Config;
+use craftcms::files::ImageFileManager;
+use craftcms::models::Image;
+use craftcms::repository;
+use craftcms::storage::LocalStorage;
+use craftcms::template_helpers;
+use std::sync::Arc;
+use tera::{Context, Tera};
+use tokio::sync::OnceCell;
+
+static SETUP: OnceCell<()> = OnceCell::const_new();
+
+/// The helpers read one process-wide configuration and repository, so
+/// every test shares a site with three images: two tagged `mugs`.
+async fn setup() {
+    SETUP
+        .get_or_init(|| async {
+            // Shared for the whole run, so it's left in target/tmp
+            let dir = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!(
+                "template-helpers-{}",
+                uuid::Uuid::new_v4().simple()
+            ));
+            let mut config = Config::default();
+            config.storage.data_dir = dir.to_string_lossy().into_owned();
+            config.site.base_url = "https://example.com/".to_string();
+            config.site.locale = "de_DE".to_string();
+            config.routes.detail_path = "work".to_string();
+            config.images.widths = vec![640, 320];
+            config.storage.ensure_dirs().unwrap();
+
+            let repo = repository::open(&config).await.unwrap();
+            let file_manager = Arc::new(ImageFileManager::new(Arc::new(LocalStorage::new(
+                config.storage.images_path(),
+            ))));
+            for (slug, keyword) in [("blue-mug", "mugs"), ("red-mug", "Mugs"), ("vase", "vases")] {
+                let image = Image {
+                    alt: slug.replace('-', " "),
+                    description: String::new(),
+                    slug: slug.to_string(),
+                    keywords: vec![keyword.to_string()],
+                    filename: String::new(),
+                };
+                let mut data = b"\x89PNG\r\n\x1a\n".to_vec();
+                data.extend_from_slice(slug.as_bytes());
+                repo.insert_image(file_manager.clone(), data, mime::IMAGE_PNG, image)
+                    .await
+                    .unwrap();
+            }
+
+            template_helpers::init(Arc::new(config), repo);
+        })
+        .await;
+}
+
+/// Renders `source` as an `.html` template, so autoescaping applies as it
+/// does in themes.
+fn render(source: &str, context: &Context) -> tera::Result<String> {
+    let mut tera = Tera::default();
+    template_helpers::register(&mut tera);
+    tera.add_raw_template("test.html", source)?;
+    tera.render("test.html", context)
+}
+
+fn render_ok(source: &str) -> String {
+    render(source, &Context::new()).unwrap()
+}
+
+#[tokio::test]
+async fn url_for_builds_route_paths() {
+    setup().await;
+
+    assert_eq!(render_ok(r#"{{ url_for(route="home") }}"#), "/");
+    assert_eq!(
+        render_ok(r#"{{ url_for(route="detail", slug="blue mug") }}"#),
+        "/work/blue%20mug"
+    );
+    assert_eq!(
+        render_ok(r#"{{ url_for(route="image", slug="vase") }}"#),
+        "/images/vase"
+    );
+    assert_eq!(
+        render_ok(r#"{{ url_for(route="page", slug="about", absolute=true) }}"#),
+        "https://example.com/about"
+    );
+    // Files that exist are fingerprinted by content; missing ones can't be
+    assert_eq!(
+        render_ok(r#"{{ url_for(route="static", path="/css/site.css") }}"#),
+        "/static/css/site.css"
+    );
+    let style = render_ok(r#"{{ url_for(route="static", path="css/style.css") }}"#);
+    let version = style.strip_prefix("/static/css/style.css?v=").unwrap();
+    assert_eq!(version.len(), 12, "{}", style);
+    assert!(version.chars().all(|c| c.is_ascii_hexdigit()), "{}", style);
+
+    let error = render(r#"{{ url_for(route="nowhere") }}"#, &Context::new()).unwrap_err();
+    assert!(format!("{:?}", error).contains("doesn't know the route 'nowhere'"));
+}
+
+#[tokio::test]
+async fn image_url_snaps_widths_and_picks_formats() {
+    setup().await;
+
+    assert_eq!(render_ok(r#"{{ image_url(slug="vase") }}"#), "/images/vase");
+    assert_eq!(
+        render_ok(r#"{{ image_url(slug="vase", w=500, fmt="webp") }}"#),
+        "/images/vase?w=640&fmt=webp"
+    );
+    // Wider than any configured width falls back to the largest
+    assert_eq!(
+        render_ok(r#"{{ image_url(slug="vase", w=4000, absolute=true) }}"#),
+        "https://example.com/images/vase?w=640"
+    );
+
+    let error = render(
+        r#"{{ image_url(slug="vase", fmt="bmp") }}"#,
+        &Context::new(),
+    )
+    .unwrap_err();
+    assert!(format!("{:?}", error).contains("can't make 'bmp' images"));
+}
+
+#[tokio::test]
+async fn srcset_lists_every_width() {
+    setup().await;
+
+    let mut context = Context::new();
+    context.insert("image", &serde_json::json!({ "slug": "blue-mug" }));
+    assert_eq!(
+        render(r#"{{ srcset(image=image, fmt="jpg") }}"#, &context).unwrap(),
+        "/images/blue-mug?w=320&fmt=jpg 320w, /images/blue-mug?w=640&fmt=jpg 640w"
+    );
+    assert_eq!(
+        render_ok(r#"{{ srcset(image="vase") }}"#),
+        "/images/vase?w=320 320w, /images/vase?w=640 640w"
+    );
+}
+
+#[tokio::test]
+async fn absolute_url_prefixes_relative_paths() {
+    setup().await;
+
+    assert_eq!(
+        render_ok(r#"{{ "/about" | absolute_url }}"#),
+        "https://example.com/about"
+    );
+    assert_eq!(
+        render_ok(r#"{{ "https://cdn.example.net/a.png" | absolute_url }}"#),
+        "https://cdn.example.net/a.png"
+    );
+    // Only slashes are spared escaping
+    let mut context = Context::new();
+    context.insert("path", "/search?q=\"a\"&b=<c>");
+    assert_eq!(
+        render("{{ path | absolute_url }}", &context).unwrap(),
+        "https://example.com/search?q=&quot;a&quot;&amp;b=&lt;c&gt;"
+    );
+}
+
+#[tokio::test]
+async fn markdown_renders_sanitized_html() {
+    setup().await;
+
+    let mut context = Context::new();
+    context.insert(
+        "text",
+        "Some **bold** text <script>alert(1)</script> \
+         [link](javascript:alert(1)) <img src=x onerror=alert(1)>",
+    );
+    let html = render("{{ text | markdown }}", &context).unwrap();
+    assert!(html.contains("<strong>bold</strong>"), "{}", html);
+    assert!(!html.contains("<script"), "{}", html);
+    assert!(!html.contains("javascript:"), "{}", html);
+    assert!(!html.contains("onerror"), "{}", html);
+}
+
+#[tokio::test]
+async fn reading_time_and_excerpt_use_plain_text() {
+    setup().await;
+
+    let mut context = Context::new();
+    context.insert("long", &"word ".repeat(450));
+    context.insert("short", "# Heading\n\nA **short** note");
+    assert_eq!(render("{{ long | reading_time }}", &context).unwrap(), "3");
+    assert_eq!(
+        render("{{ long | reading_time(wpm=100) }}", &context).unwrap(),
+        "5"
+    );
+    assert_eq!(render("{{ short | reading_time }}", &context).unwrap(), "1");
+
+    assert_eq!(
+        render("{{ short | excerpt }}", &context).unwrap(),
+        "Heading A short note"
+    );
+    assert_eq!(
+        render("{{ short | excerpt(length=12) }}", &context).unwrap(),
+        "Heading A…"
+    );
+}
+
+#[tokio::test]
+async fn format_date_uses_the_locale() {
+    setup().await;
+
+    // The site's locale is de_DE
+    assert_eq!(
+        render_ok(r#"{{ "2024-03-05 10:00:00" | format_date }}"#),
+        "März 5, 2024"
+    );
+    assert_eq!(
+        render_ok(
+            r#"{{ "2024-03-05T10:00:00Z" | format_date(format="%A %-d %B %Y", locale="fr_FR") }}"#
+        ),
+        "mardi 5 mars 2024"
+    );
+    assert_eq!(
+        render_ok(r#"{{ "2024-03-05" | format_date(format="%d.%m.%Y") }}"#),
+        "05.03.2024"
+    );
+
+    let error = render(r#"{{ "soon" | format_date }}"#, &Context::new()).unwrap_err();
+    assert!(format!("{:?}", error).contains("can't read the date"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn get_images_filters_by_tag_and_limit() {
+    setup().await;
+
+    let slugs = |source: &str| {
+        let mut slugs: Vec<String> = render_ok(source)
+            .split(',')
+            .filter(|slug| !slug.is_empty())
+            .map(str::to_string)
+            .collect();
+        slugs.sort();
+        slugs
+    };
+
+    assert_eq!(
+        slugs(r#"{% for image in get_images() %}{{ image.slug }},{% endfor %}"#),
+        ["blue-mug", "red-mug", "vase"]
+    );
+    // Tags match keywords regardless of case
+    assert_eq!(
+        slugs(r#"{% for image in get_images(tag="mugs") %}{{ image.slug }},{% endfor %}"#),
+        ["blue-mug", "red-mug"]
+    );
+    let limited =
+        slugs(r#"{% for image in get_images(tag="mugs", limit=1) %}{{ image.slug }},{% endfor %}"#);
+    assert_eq!(limited.len(), 1);
+    assert!(limited[0].ends_with("-mug"));
+}